@@ -0,0 +1,16 @@
+use std::env;
+use std::path::PathBuf;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Builds do not need a protoc installation; an explicit PROTOC still wins.
+    if env::var_os("PROTOC").is_none() {
+        env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+
+    tonic_build::configure()
+        .build_client(false)
+        .file_descriptor_set_path(out_dir.join("hospital_descriptor.bin"))
+        .compile_protos(&["proto/hospital.proto"], &["proto"])?;
+    Ok(())
+}
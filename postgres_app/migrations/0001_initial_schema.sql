@@ -0,0 +1,31 @@
+CREATE TABLE IF NOT EXISTS patients (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    surname TEXT NOT NULL,
+    birth_date TEXT NOT NULL,
+    phone_number TEXT NOT NULL,
+    passport_number TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS doctors (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    surname TEXT NOT NULL,
+    speciality TEXT NOT NULL,
+    phone_number TEXT NOT NULL,
+    passport_number TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS tickets (
+    id SERIAL PRIMARY KEY,
+    date TEXT NOT NULL,
+    time TEXT NOT NULL,
+    office_number INT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS schedule (
+    id SERIAL PRIMARY KEY,
+    ticket_id INT NOT NULL REFERENCES tickets (id),
+    doctor_id INT NOT NULL REFERENCES doctors (id),
+    patient_id INT NOT NULL REFERENCES patients (id)
+);
@@ -0,0 +1,130 @@
+-- Every version of a row is kept with the interval during which it was current.
+-- The open version of a live row has valid_to = NULL.
+
+CREATE TABLE IF NOT EXISTS patients_history (
+    history_id SERIAL PRIMARY KEY,
+    id INT NOT NULL,
+    name TEXT NOT NULL,
+    surname TEXT NOT NULL,
+    birth_date TEXT NOT NULL,
+    phone_number TEXT NOT NULL,
+    passport_number TEXT NOT NULL,
+    operation TEXT NOT NULL,
+    valid_from TIMESTAMPTZ NOT NULL,
+    valid_to TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS patients_history_id_idx ON patients_history (id, valid_from);
+
+CREATE TABLE IF NOT EXISTS doctors_history (
+    history_id SERIAL PRIMARY KEY,
+    id INT NOT NULL,
+    name TEXT NOT NULL,
+    surname TEXT NOT NULL,
+    speciality TEXT NOT NULL,
+    phone_number TEXT NOT NULL,
+    passport_number TEXT NOT NULL,
+    operation TEXT NOT NULL,
+    valid_from TIMESTAMPTZ NOT NULL,
+    valid_to TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS doctors_history_id_idx ON doctors_history (id, valid_from);
+
+CREATE TABLE IF NOT EXISTS tickets_history (
+    history_id SERIAL PRIMARY KEY,
+    id INT NOT NULL,
+    date TEXT NOT NULL,
+    time TEXT NOT NULL,
+    office_number INT NOT NULL,
+    operation TEXT NOT NULL,
+    valid_from TIMESTAMPTZ NOT NULL,
+    valid_to TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS tickets_history_id_idx ON tickets_history (id, valid_from);
+
+-- Rows that existed before history was introduced are treated as valid since forever.
+INSERT INTO patients_history (id, name, surname, birth_date, phone_number, passport_number, operation, valid_from)
+SELECT id, name, surname, birth_date, phone_number, passport_number, 'INSERT', '-infinity'
+FROM patients;
+
+INSERT INTO doctors_history (id, name, surname, speciality, phone_number, passport_number, operation, valid_from)
+SELECT id, name, surname, speciality, phone_number, passport_number, 'INSERT', '-infinity'
+FROM doctors;
+
+INSERT INTO tickets_history (id, date, time, office_number, operation, valid_from)
+SELECT id, date, time, office_number, 'INSERT', '-infinity'
+FROM tickets;
+
+CREATE OR REPLACE FUNCTION patients_history_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        UPDATE patients_history SET valid_to = clock_timestamp()
+        WHERE id = OLD.id AND valid_to IS NULL;
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        INSERT INTO patients_history (id, name, surname, birth_date, phone_number, passport_number, operation, valid_from)
+        VALUES (NEW.id, NEW.name, NEW.surname, NEW.birth_date, NEW.phone_number, NEW.passport_number, TG_OP, clock_timestamp());
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION doctors_history_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        UPDATE doctors_history SET valid_to = clock_timestamp()
+        WHERE id = OLD.id AND valid_to IS NULL;
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        INSERT INTO doctors_history (id, name, surname, speciality, phone_number, passport_number, operation, valid_from)
+        VALUES (NEW.id, NEW.name, NEW.surname, NEW.speciality, NEW.phone_number, NEW.passport_number, TG_OP, clock_timestamp());
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION tickets_history_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        UPDATE tickets_history SET valid_to = clock_timestamp()
+        WHERE id = OLD.id AND valid_to IS NULL;
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        INSERT INTO tickets_history (id, date, time, office_number, operation, valid_from)
+        VALUES (NEW.id, NEW.date, NEW.time, NEW.office_number, TG_OP, clock_timestamp());
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS patients_history_insert_delete ON patients;
+CREATE TRIGGER patients_history_insert_delete
+AFTER INSERT OR DELETE ON patients
+FOR EACH ROW EXECUTE FUNCTION patients_history_trigger();
+
+DROP TRIGGER IF EXISTS patients_history_update ON patients;
+CREATE TRIGGER patients_history_update
+AFTER UPDATE ON patients
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*) EXECUTE FUNCTION patients_history_trigger();
+
+DROP TRIGGER IF EXISTS doctors_history_insert_delete ON doctors;
+CREATE TRIGGER doctors_history_insert_delete
+AFTER INSERT OR DELETE ON doctors
+FOR EACH ROW EXECUTE FUNCTION doctors_history_trigger();
+
+DROP TRIGGER IF EXISTS doctors_history_update ON doctors;
+CREATE TRIGGER doctors_history_update
+AFTER UPDATE ON doctors
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*) EXECUTE FUNCTION doctors_history_trigger();
+
+DROP TRIGGER IF EXISTS tickets_history_insert_delete ON tickets;
+CREATE TRIGGER tickets_history_insert_delete
+AFTER INSERT OR DELETE ON tickets
+FOR EACH ROW EXECUTE FUNCTION tickets_history_trigger();
+
+DROP TRIGGER IF EXISTS tickets_history_update ON tickets;
+CREATE TRIGGER tickets_history_update
+AFTER UPDATE ON tickets
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*) EXECUTE FUNCTION tickets_history_trigger();
@@ -0,0 +1,34 @@
+-- Row versions back the ETag / If-Match optimistic concurrency checks.
+-- The version is bumped by a trigger so that every kind of update is covered.
+
+ALTER TABLE patients ADD COLUMN IF NOT EXISTS version INT NOT NULL DEFAULT 1;
+ALTER TABLE doctors ADD COLUMN IF NOT EXISTS version INT NOT NULL DEFAULT 1;
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS version INT NOT NULL DEFAULT 1;
+ALTER TABLE schedule ADD COLUMN IF NOT EXISTS version INT NOT NULL DEFAULT 1;
+
+CREATE OR REPLACE FUNCTION bump_row_version() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.version := OLD.version + 1;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS patients_bump_version ON patients;
+CREATE TRIGGER patients_bump_version
+BEFORE UPDATE ON patients
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*) EXECUTE FUNCTION bump_row_version();
+
+DROP TRIGGER IF EXISTS doctors_bump_version ON doctors;
+CREATE TRIGGER doctors_bump_version
+BEFORE UPDATE ON doctors
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*) EXECUTE FUNCTION bump_row_version();
+
+DROP TRIGGER IF EXISTS tickets_bump_version ON tickets;
+CREATE TRIGGER tickets_bump_version
+BEFORE UPDATE ON tickets
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*) EXECUTE FUNCTION bump_row_version();
+
+DROP TRIGGER IF EXISTS schedule_bump_version ON schedule;
+CREATE TRIGGER schedule_bump_version
+BEFORE UPDATE ON schedule
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*) EXECUTE FUNCTION bump_row_version();
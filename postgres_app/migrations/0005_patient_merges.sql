@@ -0,0 +1,61 @@
+-- Remember which operation closed a history version, so that merges can be told apart from
+-- ordinary updates and deletes. The operation can be overridden for a transaction with
+-- SELECT set_config('hospital.history_operation', '<operation>', true).
+
+ALTER TABLE patients_history ADD COLUMN IF NOT EXISTS closed_by TEXT;
+ALTER TABLE doctors_history ADD COLUMN IF NOT EXISTS closed_by TEXT;
+ALTER TABLE tickets_history ADD COLUMN IF NOT EXISTS closed_by TEXT;
+
+CREATE TABLE IF NOT EXISTS patient_merges (
+    id SERIAL PRIMARY KEY,
+    survivor_id INT NOT NULL,
+    loser_id INT NOT NULL,
+    moved_schedule_entries INT NOT NULL,
+    merged_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE OR REPLACE FUNCTION history_operation(tg_op TEXT) RETURNS TEXT AS $$
+    SELECT COALESCE(NULLIF(current_setting('hospital.history_operation', true), ''), tg_op);
+$$ LANGUAGE sql STABLE;
+
+CREATE OR REPLACE FUNCTION patients_history_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        UPDATE patients_history SET valid_to = clock_timestamp(), closed_by = history_operation(TG_OP)
+        WHERE id = OLD.id AND valid_to IS NULL;
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        INSERT INTO patients_history (id, name, surname, birth_date, phone_number, passport_number, operation, valid_from)
+        VALUES (NEW.id, NEW.name, NEW.surname, NEW.birth_date, NEW.phone_number, NEW.passport_number, history_operation(TG_OP), clock_timestamp());
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION doctors_history_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        UPDATE doctors_history SET valid_to = clock_timestamp(), closed_by = history_operation(TG_OP)
+        WHERE id = OLD.id AND valid_to IS NULL;
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        INSERT INTO doctors_history (id, name, surname, speciality, phone_number, passport_number, operation, valid_from)
+        VALUES (NEW.id, NEW.name, NEW.surname, NEW.speciality, NEW.phone_number, NEW.passport_number, history_operation(TG_OP), clock_timestamp());
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION tickets_history_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        UPDATE tickets_history SET valid_to = clock_timestamp(), closed_by = history_operation(TG_OP)
+        WHERE id = OLD.id AND valid_to IS NULL;
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        INSERT INTO tickets_history (id, date, time, office_number, operation, valid_from)
+        VALUES (NEW.id, NEW.date, NEW.time, NEW.office_number, history_operation(TG_OP), clock_timestamp());
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
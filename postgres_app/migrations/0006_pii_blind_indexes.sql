@@ -0,0 +1,12 @@
+-- phone_number and passport_number may now hold AES-GCM ciphertext, so exact-match filters
+-- go through HMAC blind indexes. Existing rows are encrypted and indexed on startup.
+
+ALTER TABLE patients ADD COLUMN IF NOT EXISTS phone_number_index TEXT;
+ALTER TABLE patients ADD COLUMN IF NOT EXISTS passport_number_index TEXT;
+ALTER TABLE doctors ADD COLUMN IF NOT EXISTS phone_number_index TEXT;
+ALTER TABLE doctors ADD COLUMN IF NOT EXISTS passport_number_index TEXT;
+
+CREATE INDEX IF NOT EXISTS patients_phone_number_index_idx ON patients (phone_number_index);
+CREATE INDEX IF NOT EXISTS patients_passport_number_index_idx ON patients (passport_number_index);
+CREATE INDEX IF NOT EXISTS doctors_phone_number_index_idx ON doctors (phone_number_index);
+CREATE INDEX IF NOT EXISTS doctors_passport_number_index_idx ON doctors (passport_number_index);
@@ -0,0 +1,11 @@
+-- Erased patients keep their row and schedule entries, so that schedule statistics stay intact,
+-- but every name, birth date, phone and passport number is replaced. This table records who
+-- was erased and when.
+
+CREATE TABLE IF NOT EXISTS patient_erasures (
+    id SERIAL PRIMARY KEY,
+    patient_id INT NOT NULL,
+    erased_ids INT[] NOT NULL,
+    history_entries_scrubbed INT NOT NULL,
+    erased_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
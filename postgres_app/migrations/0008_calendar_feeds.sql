@@ -0,0 +1,51 @@
+-- Calendar feeds are fetched by calendar apps, which cannot log in, so every feed URL carries
+-- a token. Only a SHA-256 hash of the token is stored.
+
+CREATE TABLE IF NOT EXISTS calendar_tokens (
+    id SERIAL PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    doctor_id INT REFERENCES doctors (id) ON DELETE CASCADE,
+    patient_id INT REFERENCES patients (id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked_at TIMESTAMPTZ,
+    CHECK ((doctor_id IS NULL) <> (patient_id IS NULL))
+);
+
+-- Schedule entries are deleted outright, so appointments that left a doctor's or a patient's
+-- calendar are remembered here and published as cancelled events.
+
+CREATE TABLE IF NOT EXISTS schedule_cancellations (
+    id SERIAL PRIMARY KEY,
+    schedule_id INT NOT NULL,
+    doctor_id INT NOT NULL,
+    patient_id INT NOT NULL,
+    date TEXT NOT NULL,
+    time TEXT NOT NULL,
+    office_number INT NOT NULL,
+    sequence INT NOT NULL,
+    cancelled_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS schedule_cancellations_doctor_idx ON schedule_cancellations (doctor_id, cancelled_at);
+CREATE INDEX IF NOT EXISTS schedule_cancellations_patient_idx ON schedule_cancellations (patient_id, cancelled_at);
+
+-- An entry moved to another doctor or patient is cancelled for the previous one. The sequence
+-- continues the one published for the live event, which is the sum of both row versions.
+CREATE OR REPLACE FUNCTION schedule_cancellations_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.doctor_id = NEW.doctor_id AND OLD.patient_id = NEW.patient_id THEN
+        RETURN NULL;
+    END IF;
+    INSERT INTO schedule_cancellations (schedule_id, doctor_id, patient_id, date, time, office_number, sequence)
+    SELECT OLD.id, OLD.doctor_id, OLD.patient_id, tickets.date, tickets.time, tickets.office_number,
+        OLD.version + tickets.version + 1
+    FROM tickets
+    WHERE tickets.id = OLD.ticket_id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS schedule_cancellations ON schedule;
+CREATE TRIGGER schedule_cancellations
+AFTER UPDATE OR DELETE ON schedule
+FOR EACH ROW EXECUTE FUNCTION schedule_cancellations_trigger();
@@ -0,0 +1,65 @@
+-- Committed schedule changes are announced on the schedule_events channel, so that every app
+-- instance can push them to its own subscribers. Payloads carry ids only; listeners load the
+-- rest. Updates also carry where the entry was before, for subscribers filtering by doctor,
+-- patient or office.
+
+CREATE OR REPLACE FUNCTION schedule_events_trigger() RETURNS TRIGGER AS $$
+DECLARE
+    previous JSON;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('schedule_events', json_build_object(
+            'kind', 'cancelled', 'schedule_id', OLD.id,
+            'ticket_id', OLD.ticket_id, 'doctor_id', OLD.doctor_id, 'patient_id', OLD.patient_id
+        )::TEXT);
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP = 'UPDATE' THEN
+        SELECT json_build_object(
+            'ticket_id', OLD.ticket_id, 'doctor_id', OLD.doctor_id, 'patient_id', OLD.patient_id,
+            'date', tickets.date, 'time', tickets.time, 'office_number', tickets.office_number
+        )
+        INTO previous
+        FROM tickets
+        WHERE tickets.id = OLD.ticket_id;
+    END IF;
+
+    PERFORM pg_notify('schedule_events', json_build_object(
+        'kind', CASE TG_OP WHEN 'INSERT' THEN 'created' ELSE 'updated' END, 'schedule_id', NEW.id,
+        'ticket_id', NEW.ticket_id, 'doctor_id', NEW.doctor_id, 'patient_id', NEW.patient_id,
+        'previous', previous
+    )::TEXT);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS schedule_events ON schedule;
+CREATE TRIGGER schedule_events
+AFTER INSERT OR UPDATE OR DELETE ON schedule
+FOR EACH ROW EXECUTE FUNCTION schedule_events_trigger();
+
+-- Moving a ticket moves the entries booked on it.
+CREATE OR REPLACE FUNCTION ticket_schedule_events_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    IF OLD.date = NEW.date AND OLD.time = NEW.time AND OLD.office_number = NEW.office_number THEN
+        RETURN NULL;
+    END IF;
+    PERFORM pg_notify('schedule_events', json_build_object(
+        'kind', 'updated', 'schedule_id', schedule.id,
+        'ticket_id', schedule.ticket_id, 'doctor_id', schedule.doctor_id, 'patient_id', schedule.patient_id,
+        'previous', json_build_object(
+            'ticket_id', schedule.ticket_id, 'doctor_id', schedule.doctor_id, 'patient_id', schedule.patient_id,
+            'date', OLD.date, 'time', OLD.time, 'office_number', OLD.office_number
+        )
+    )::TEXT)
+    FROM schedule
+    WHERE schedule.ticket_id = NEW.id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS ticket_schedule_events ON tickets;
+CREATE TRIGGER ticket_schedule_events
+AFTER UPDATE ON tickets
+FOR EACH ROW EXECUTE FUNCTION ticket_schedule_events_trigger();
@@ -0,0 +1,138 @@
+-- Webhook subscribers are told when appointments are created, cancelled or rescheduled.
+-- Events are written to an outbox by triggers, in the transaction that changed the schedule,
+-- so an event is recorded if and only if its change is committed. A dispatcher delivers them
+-- afterwards and retries failures.
+
+CREATE TABLE IF NOT EXISTS webhook_subscriptions (
+    id SERIAL PRIMARY KEY,
+    url TEXT NOT NULL,
+    -- Encrypted like phone and passport numbers; the dispatcher needs the plaintext to sign.
+    secret TEXT NOT NULL,
+    event_types TEXT[] NOT NULL,
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS webhook_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    event_type TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    subscription_id INT NOT NULL REFERENCES webhook_subscriptions (id) ON DELETE CASCADE,
+    event_id BIGINT NOT NULL REFERENCES webhook_outbox (id) ON DELETE CASCADE,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'delivered', 'failed')),
+    attempts INT NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_attempt_at TIMESTAMPTZ,
+    response_status INT,
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_due_idx ON webhook_deliveries (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS webhook_deliveries_subscription_idx ON webhook_deliveries (subscription_id, id);
+
+-- Records an event and a delivery for every active subscription to its type. Subscriptions
+-- created later do not receive earlier events.
+CREATE OR REPLACE FUNCTION enqueue_webhook_event(event_type TEXT, payload JSONB) RETURNS VOID AS $$
+DECLARE
+    event_id BIGINT;
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM webhook_subscriptions
+        WHERE active AND enqueue_webhook_event.event_type = ANY(webhook_subscriptions.event_types)
+    ) THEN
+        RETURN;
+    END IF;
+
+    INSERT INTO webhook_outbox (event_type, payload)
+    VALUES (event_type, payload)
+    RETURNING id INTO event_id;
+
+    INSERT INTO webhook_deliveries (subscription_id, event_id)
+    SELECT id, event_id
+    FROM webhook_subscriptions
+    WHERE active AND enqueue_webhook_event.event_type = ANY(webhook_subscriptions.event_types);
+END;
+$$ LANGUAGE plpgsql;
+
+-- Payloads carry ids, the appointment time and office, but no personal data. Rescheduled
+-- events also carry where the appointment was before.
+CREATE OR REPLACE FUNCTION schedule_webhooks_trigger() RETURNS TRIGGER AS $$
+DECLARE
+    previous JSONB;
+    entry RECORD;
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.ticket_id = NEW.ticket_id AND OLD.doctor_id = NEW.doctor_id
+        AND OLD.patient_id = NEW.patient_id THEN
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP <> 'INSERT' THEN
+        SELECT tickets.date, tickets.time, tickets.office_number
+        INTO entry
+        FROM tickets
+        WHERE tickets.id = OLD.ticket_id;
+        previous := jsonb_build_object(
+            'schedule_id', OLD.id, 'ticket_id', OLD.ticket_id, 'doctor_id', OLD.doctor_id,
+            'patient_id', OLD.patient_id, 'date', entry.date, 'time', entry.time,
+            'office_number', entry.office_number
+        );
+    END IF;
+
+    IF TG_OP = 'DELETE' THEN
+        PERFORM enqueue_webhook_event('appointment.cancelled', previous);
+        RETURN NULL;
+    END IF;
+
+    SELECT tickets.date, tickets.time, tickets.office_number
+    INTO entry
+    FROM tickets
+    WHERE tickets.id = NEW.ticket_id;
+
+    PERFORM enqueue_webhook_event(
+        CASE TG_OP WHEN 'INSERT' THEN 'appointment.created' ELSE 'appointment.rescheduled' END,
+        jsonb_build_object(
+            'schedule_id', NEW.id, 'ticket_id', NEW.ticket_id, 'doctor_id', NEW.doctor_id,
+            'patient_id', NEW.patient_id, 'date', entry.date, 'time', entry.time,
+            'office_number', entry.office_number, 'previous', previous
+        )
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS schedule_webhooks ON schedule;
+CREATE TRIGGER schedule_webhooks
+AFTER INSERT OR UPDATE OR DELETE ON schedule
+FOR EACH ROW EXECUTE FUNCTION schedule_webhooks_trigger();
+
+-- Moving a ticket reschedules the appointments booked on it.
+CREATE OR REPLACE FUNCTION ticket_webhooks_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    IF OLD.date = NEW.date AND OLD.time = NEW.time AND OLD.office_number = NEW.office_number THEN
+        RETURN NULL;
+    END IF;
+    PERFORM enqueue_webhook_event('appointment.rescheduled', jsonb_build_object(
+        'schedule_id', schedule.id, 'ticket_id', schedule.ticket_id, 'doctor_id', schedule.doctor_id,
+        'patient_id', schedule.patient_id, 'date', NEW.date, 'time', NEW.time,
+        'office_number', NEW.office_number,
+        'previous', jsonb_build_object(
+            'schedule_id', schedule.id, 'ticket_id', schedule.ticket_id, 'doctor_id', schedule.doctor_id,
+            'patient_id', schedule.patient_id, 'date', OLD.date, 'time', OLD.time,
+            'office_number', OLD.office_number
+        )
+    ))
+    FROM schedule
+    WHERE schedule.ticket_id = NEW.id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS ticket_webhooks ON tickets;
+CREATE TRIGGER ticket_webhooks
+AFTER UPDATE ON tickets
+FOR EACH ROW EXECUTE FUNCTION ticket_webhooks_trigger();
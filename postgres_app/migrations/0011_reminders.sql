@@ -0,0 +1,32 @@
+-- Patients are reminded of appointments 24 and 2 hours ahead by email and SMS. Patients
+-- without preferences get SMS reminders to their phone number in the default language.
+
+CREATE TABLE IF NOT EXISTS notification_preferences (
+    patient_id INT PRIMARY KEY REFERENCES patients (id) ON DELETE CASCADE,
+    -- Encrypted like phone and passport numbers.
+    email TEXT,
+    locale TEXT CHECK (locale IN ('ru', 'en')),
+    email_enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    sms_enabled BOOLEAN NOT NULL DEFAULT TRUE
+);
+
+-- One row per reminder sent, or being sent, on a channel. The row is claimed before sending,
+-- so that instances running the scheduler side by side never send the same reminder twice.
+-- A rescheduled appointment has another appointment_at and is reminded again. Recipients are
+-- not logged, the log outlives cancelled entries and erased patients.
+CREATE TABLE IF NOT EXISTS notification_log (
+    id BIGSERIAL PRIMARY KEY,
+    schedule_id INT NOT NULL,
+    patient_id INT NOT NULL,
+    reminder TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    appointment_at TIMESTAMPTZ NOT NULL,
+    status TEXT NOT NULL DEFAULT 'sending' CHECK (status IN ('sending', 'sent', 'failed')),
+    attempts INT NOT NULL DEFAULT 1,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (schedule_id, reminder, channel, appointment_at)
+);
+
+CREATE INDEX IF NOT EXISTS notification_log_patient_idx ON notification_log (patient_id, id);
@@ -0,0 +1,29 @@
+-- Long imports and exports run as background jobs. Workers claim jobs with SKIP LOCKED and
+-- hold them for a lease they renew after every batch. A job whose lease ran out, because its
+-- instance stopped, is claimed again and resumes from its checkpoint.
+
+CREATE TABLE IF NOT EXISTS jobs (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    params JSONB NOT NULL DEFAULT '{}',
+    status TEXT NOT NULL DEFAULT 'queued'
+        CHECK (status IN ('queued', 'running', 'succeeded', 'failed', 'cancelled')),
+    cancel_requested BOOLEAN NOT NULL DEFAULT FALSE,
+    processed BIGINT NOT NULL DEFAULT 0,
+    failed BIGINT NOT NULL DEFAULT 0,
+    total BIGINT,
+    -- Last record handled by a committed batch, in whatever form the job kind uses.
+    checkpoint TEXT,
+    result JSONB,
+    -- The first record errors, the job goes on after them.
+    errors JSONB NOT NULL DEFAULT '[]',
+    -- Why the job as a whole failed.
+    error TEXT,
+    attempts INT NOT NULL DEFAULT 0,
+    locked_until TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    started_at TIMESTAMPTZ,
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS jobs_unfinished_idx ON jobs (id) WHERE status IN ('queued', 'running');
@@ -0,0 +1,23 @@
+-- Idempotency keys are scoped to the method and path they were sent with, and stored
+-- responses are encrypted with the PII keys. Responses stored so far hold plaintext PII and
+-- are only replayed for a day, so they are dropped rather than migrated.
+DROP TABLE IF EXISTS idempotency_keys;
+
+CREATE TABLE idempotency_keys (
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    key TEXT NOT NULL,
+    request_hash TEXT NOT NULL,
+    -- A row without a status belongs to a request that is still being processed.
+    status SMALLINT,
+    content_type TEXT,
+    -- Encrypted like phone and passport numbers, see FieldCipher.
+    body TEXT,
+    -- Id of the created entry, so that erasing a patient can find responses about them.
+    resource_id BIGINT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (method, path, key)
+);
+
+CREATE INDEX idempotency_keys_created_at_idx ON idempotency_keys (created_at);
+CREATE INDEX idempotency_keys_resource_idx ON idempotency_keys (path, resource_id);
@@ -0,0 +1,76 @@
+-- Calendar apps ignore an update whose SEQUENCE is not greater than the one they have seen.
+-- The sum of the schedule and ticket versions goes down when an entry is moved to an older
+-- ticket, so every entry gets its own counter that only ever goes up.
+
+CREATE TABLE IF NOT EXISTS calendar_sequences (
+    schedule_id INT PRIMARY KEY,
+    sequence INT NOT NULL
+);
+
+-- Existing entries continue from the sequence published so far.
+INSERT INTO calendar_sequences (schedule_id, sequence)
+SELECT schedule.id, schedule.version + tickets.version
+FROM schedule
+JOIN tickets ON schedule.ticket_id = tickets.id
+ON CONFLICT (schedule_id) DO NOTHING;
+
+CREATE OR REPLACE FUNCTION schedule_calendar_sequence_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO calendar_sequences (schedule_id, sequence) VALUES (NEW.id, 0)
+    ON CONFLICT (schedule_id) DO UPDATE SET sequence = calendar_sequences.sequence + 1;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS schedule_calendar_sequence_insert ON schedule;
+CREATE TRIGGER schedule_calendar_sequence_insert
+AFTER INSERT ON schedule
+FOR EACH ROW EXECUTE FUNCTION schedule_calendar_sequence_trigger();
+
+-- Fires before schedule_cancellations, triggers on the same event run in name order.
+DROP TRIGGER IF EXISTS schedule_calendar_sequence ON schedule;
+CREATE TRIGGER schedule_calendar_sequence
+AFTER UPDATE ON schedule
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*)
+EXECUTE FUNCTION schedule_calendar_sequence_trigger();
+
+CREATE OR REPLACE FUNCTION tickets_calendar_sequence_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    UPDATE calendar_sequences SET sequence = calendar_sequences.sequence + 1
+    FROM schedule
+    WHERE schedule.ticket_id = NEW.id AND calendar_sequences.schedule_id = schedule.id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS tickets_calendar_sequence ON tickets;
+CREATE TRIGGER tickets_calendar_sequence
+AFTER UPDATE ON tickets
+FOR EACH ROW WHEN ((OLD.date, OLD.time, OLD.office_number) IS DISTINCT FROM (NEW.date, NEW.time, NEW.office_number))
+EXECUTE FUNCTION tickets_calendar_sequence_trigger();
+
+-- A cancellation takes the next sequence of the entry, so that the event published again
+-- after the entry is moved back supersedes the cancellation. A deleted entry is never
+-- published again and its counter goes away.
+CREATE OR REPLACE FUNCTION schedule_cancellations_trigger() RETURNS TRIGGER AS $$
+DECLARE
+    next_sequence INT;
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.doctor_id = NEW.doctor_id AND OLD.patient_id = NEW.patient_id THEN
+        RETURN NULL;
+    END IF;
+    IF TG_OP = 'DELETE' THEN
+        DELETE FROM calendar_sequences WHERE schedule_id = OLD.id
+        RETURNING sequence + 1 INTO next_sequence;
+    ELSE
+        UPDATE calendar_sequences SET sequence = sequence + 1 WHERE schedule_id = OLD.id
+        RETURNING sequence INTO next_sequence;
+    END IF;
+    INSERT INTO schedule_cancellations (schedule_id, doctor_id, patient_id, date, time, office_number, sequence)
+    SELECT OLD.id, OLD.doctor_id, OLD.patient_id, tickets.date, tickets.time, tickets.office_number,
+        COALESCE(next_sequence, OLD.version + tickets.version + 1)
+    FROM tickets
+    WHERE tickets.id = OLD.ticket_id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,23 @@
+-- CSV and NDJSON imports run as background jobs too. The uploaded file is kept in chunks
+-- until its job finishes, so that whichever instance claims the job can read it, and is
+-- dropped then because it may hold personal data.
+
+CREATE TABLE IF NOT EXISTS job_uploads (
+    job_id BIGINT NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    position INT NOT NULL,
+    data BYTEA NOT NULL,
+    PRIMARY KEY (job_id, position)
+);
+
+CREATE OR REPLACE FUNCTION job_uploads_cleanup_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    DELETE FROM job_uploads WHERE job_id = NEW.id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS job_uploads_cleanup ON jobs;
+CREATE TRIGGER job_uploads_cleanup
+AFTER UPDATE OF status ON jobs
+FOR EACH ROW WHEN (NEW.status IN ('succeeded', 'failed', 'cancelled'))
+EXECUTE FUNCTION job_uploads_cleanup_trigger();
@@ -0,0 +1,5 @@
+-- Import uploads are hashed while their handler streams them instead of being buffered, so
+-- the hash of a request is only known, and stored together with its response, once it has
+-- been processed.
+
+ALTER TABLE idempotency_keys ALTER COLUMN request_hash DROP NOT NULL;
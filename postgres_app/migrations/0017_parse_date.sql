@@ -0,0 +1,18 @@
+-- Ticket and birth dates are free text. parse_date reads the YYYY-MM-DD form the application
+-- writes, like reports::parse_date, and gives NULL for anything else, impossible dates
+-- included, so that date ranges can be filtered in SQL without comparing text.
+
+CREATE OR REPLACE FUNCTION parse_date(value TEXT) RETURNS DATE AS $$
+DECLARE
+    parts TEXT[];
+BEGIN
+    parts := regexp_match(value, '^\s*(\d{1,4})-(\d{1,2})-(\d{1,2})\s*$');
+    IF parts IS NULL THEN
+        RETURN NULL;
+    END IF;
+    RETURN make_date(parts[1]::INT, parts[2]::INT, parts[3]::INT);
+EXCEPTION
+    WHEN datetime_field_overflow THEN
+        RETURN NULL;
+END;
+$$ LANGUAGE plpgsql IMMUTABLE STRICT;
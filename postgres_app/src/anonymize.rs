@@ -0,0 +1,243 @@
+use crate::models::{Doctor, Patient};
+use crate::reports::{format_date, parse_date};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use time::{Date, Duration, Month};
+
+const FIRST_NAMES: &[&str] = &[
+    "Alexander",
+    "Anna",
+    "Boris",
+    "Daria",
+    "Dmitry",
+    "Elena",
+    "Fedor",
+    "Galina",
+    "Igor",
+    "Irina",
+    "Kirill",
+    "Ksenia",
+    "Leonid",
+    "Maria",
+    "Nikolai",
+    "Olga",
+    "Pavel",
+    "Polina",
+    "Sergei",
+    "Tatiana",
+    "Viktor",
+    "Yulia",
+];
+
+const SURNAMES: &[&str] = &[
+    "Alekseev",
+    "Belov",
+    "Vasiliev",
+    "Gromov",
+    "Danilov",
+    "Egorov",
+    "Zaitsev",
+    "Ilyin",
+    "Kozlov",
+    "Lebedev",
+    "Morozov",
+    "Novikov",
+    "Orlov",
+    "Pavlov",
+    "Romanov",
+    "Sokolov",
+    "Tarasov",
+    "Ulyanov",
+    "Fedorov",
+    "Kharitonov",
+    "Tsvetkov",
+    "Sharov",
+];
+
+/// Birth dates are moved by up to this many days in either direction.
+const MAX_BIRTH_DATE_SHIFT_DAYS: u64 = 365;
+
+/// Sled database that receives anonymized exports, kept apart from the regular snapshot.
+pub struct AnonymizedSled(pub sled::Db);
+
+/// Replaces personal data with pseudonyms for copying production data into test environments.
+///
+/// Pseudonyms are an HMAC of the real value keyed with the anonymization key, so the same name,
+/// phone or passport number always gets the same pseudonym, in patients and doctors alike.
+/// Ids are kept as they are, so schedule entries still line up. Without the key a random one
+/// is generated at startup, which keeps pseudonyms consistent only until the next restart.
+pub struct Pseudonymizer {
+    key: Vec<u8>,
+}
+
+impl Pseudonymizer {
+    pub fn new(key: Option<&str>) -> Self {
+        let key = match key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => {
+                let mut key = vec![0; 32];
+                OsRng.fill_bytes(&mut key);
+                key
+            }
+        };
+
+        Pseudonymizer { key }
+    }
+
+    fn hash(&self, field: &str, value: &str) -> u64 {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(field.as_bytes());
+        mac.update(&[0]);
+        mac.update(value.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        u64::from_be_bytes(digest[..8].try_into().unwrap())
+    }
+
+    fn pick(&self, field: &str, value: &str, words: &[&str]) -> String {
+        words[(self.hash(field, value) % words.len() as u64) as usize].to_string()
+    }
+
+    fn digits(&self, field: &str, value: &str, count: u32) -> String {
+        let number = self.hash(field, value) % 10u64.pow(count);
+        format!("{:0width$}", number, width = count as usize)
+    }
+
+    pub fn name(&self, name: &str) -> String {
+        self.pick("name", name, FIRST_NAMES)
+    }
+
+    pub fn surname(&self, surname: &str) -> String {
+        self.pick("surname", surname, SURNAMES)
+    }
+
+    pub fn phone_number(&self, phone_number: &str) -> String {
+        format!("+7{}", self.digits("phone_number", phone_number, 10))
+    }
+
+    pub fn passport_number(&self, passport_number: &str) -> String {
+        self.digits("passport_number", passport_number, 10)
+    }
+
+    /// Shifts a `YYYY-MM-DD`, `DD.MM.YYYY` or `DD/MM/YYYY` date by a number of days derived
+    /// from the patient id, so every export moves the same patient by the same amount. The
+    /// result is `YYYY-MM-DD`. Of other values only a year is kept, if there is one.
+    pub fn birth_date(&self, patient_id: i32, birth_date: &str) -> String {
+        let date = match parse_date(birth_date).or_else(|| parse_day_first_date(birth_date)) {
+            Some(date) => date,
+            None => return birth_year(birth_date).unwrap_or_default().to_string(),
+        };
+
+        let shift =
+            self.hash("birth_date", &patient_id.to_string()) % (2 * MAX_BIRTH_DATE_SHIFT_DAYS + 1);
+        let shift = Duration::days(shift as i64 - MAX_BIRTH_DATE_SHIFT_DAYS as i64);
+        date.checked_add(shift).map(format_date).unwrap_or_default()
+    }
+
+    pub fn anonymize_patient(&self, patient: &Patient) -> Patient {
+        Patient {
+            id: patient.id,
+            name: self.name(&patient.name),
+            surname: self.surname(&patient.surname),
+            birth_date: self.birth_date(patient.id, &patient.birth_date),
+            phone_number: self.phone_number(&patient.phone_number),
+            passport_number: self.passport_number(&patient.passport_number),
+        }
+    }
+
+    pub fn anonymize_doctor(&self, doctor: &Doctor) -> Doctor {
+        Doctor {
+            id: doctor.id,
+            name: self.name(&doctor.name),
+            surname: self.surname(&doctor.surname),
+            speciality: doctor.speciality.clone(),
+            phone_number: self.phone_number(&doctor.phone_number),
+            passport_number: self.passport_number(&doctor.passport_number),
+        }
+    }
+}
+
+/// `DD.MM.YYYY` or `DD/MM/YYYY`, as dates are often typed in.
+fn parse_day_first_date(value: &str) -> Option<Date> {
+    let mut parts = value.trim().splitn(3, ['.', '/']);
+    let day = parts.next()?.parse().ok()?;
+    let month: u8 = parts.next()?.parse().ok()?;
+    let year = parts.next()?.parse().ok()?;
+    Date::from_calendar_date(year, Month::try_from(month).ok()?, day).ok()
+}
+
+/// The only four-digit number of a value, such as `1990` or `born 1990`.
+fn birth_year(value: &str) -> Option<&str> {
+    let mut years = value
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|part| part.len() == 4);
+    let year = years.next()?;
+    years.next().is_none().then_some(year)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pseudonyms_depend_only_on_the_key_and_the_value() {
+        let first = Pseudonymizer::new(Some("export key"));
+        let second = Pseudonymizer::new(Some("export key"));
+        assert_eq!(first.name("Anna"), second.name("Anna"));
+        assert_eq!(first.surname("Petrova"), second.surname("Petrova"));
+        assert_eq!(
+            first.phone_number("+7 900 000-00-00"),
+            second.phone_number("+7 900 000-00-00")
+        );
+        assert_eq!(
+            first.passport_number("4510 123456"),
+            second.passport_number("4510 123456")
+        );
+        assert!(FIRST_NAMES.contains(&first.name("Anna").as_str()));
+
+        let phone_number = first.phone_number("+7 900 000-00-00");
+        assert_eq!(phone_number.len(), 12);
+        assert!(phone_number.starts_with("+7"));
+        assert_eq!(first.passport_number("4510 123456").len(), 10);
+
+        let other = Pseudonymizer::new(Some("another key"));
+        assert_ne!(
+            first.passport_number("4510 123456"),
+            other.passport_number("4510 123456")
+        );
+    }
+
+    #[test]
+    fn birth_dates_move_by_a_stable_bounded_shift() {
+        let pseudonymizer = Pseudonymizer::new(Some("export key"));
+        let birth_date = parse_date("1990-04-02").unwrap();
+        for patient_id in 1..500 {
+            let shifted = pseudonymizer.birth_date(patient_id, "1990-04-02");
+            assert_eq!(shifted, pseudonymizer.birth_date(patient_id, "1990-04-02"));
+            let shift = (parse_date(&shifted).unwrap() - birth_date).whole_days();
+            assert!(shift.unsigned_abs() <= MAX_BIRTH_DATE_SHIFT_DAYS);
+
+            // Every date of a patient moves by the same number of days.
+            let other = parse_date(&pseudonymizer.birth_date(patient_id, "2001-12-31")).unwrap();
+            assert_eq!(
+                (other - parse_date("2001-12-31").unwrap()).whole_days(),
+                shift
+            );
+        }
+    }
+
+    #[test]
+    fn birth_dates_in_other_formats_are_shifted_or_reduced_to_the_year() {
+        let pseudonymizer = Pseudonymizer::new(Some("export key"));
+        let shifted = pseudonymizer.birth_date(7, "1990-04-02");
+        assert_eq!(pseudonymizer.birth_date(7, "02.04.1990"), shifted);
+        assert_eq!(pseudonymizer.birth_date(7, " 2/4/1990 "), shifted);
+        assert_eq!(pseudonymizer.birth_date(7, "1990"), "1990");
+        assert_eq!(pseudonymizer.birth_date(7, "around 1990"), "1990");
+        assert_eq!(pseudonymizer.birth_date(7, "1990 or 1991"), "");
+        assert_eq!(pseudonymizer.birth_date(7, "31.02.1990"), "1990");
+        assert_eq!(pseudonymizer.birth_date(7, ""), "");
+    }
+}
@@ -9,54 +9,181 @@ use utoipa::OpenApi;
         handlers::add_patient,
         handlers::update_patient,
         handlers::delete_patient,
+        handlers::export_patients_ndjson,
+        handlers::import_patients_ndjson,
+        handlers::export_patients_csv,
+        handlers::import_patients_csv,
         handlers::export_patients,
         handlers::import_patients,
+        handlers::get_patient_duplicates,
+        handlers::merge_patients,
+        handlers::get_patient,
+        handlers::get_patient_history,
+        handlers::export_patient_data,
+        handlers::erase_patient,
+        handlers::issue_patient_calendar_token,
+        handlers::get_patient_calendar,
+        handlers::patch_patient,
+        handlers::put_patient,
+        handlers::delete_patient_by_id,
 
         handlers::get_doctors,
         handlers::add_doctor,
         handlers::update_doctor,
         handlers::delete_doctor,
+        handlers::export_doctors_ndjson,
+        handlers::import_doctors_ndjson,
+        handlers::export_doctors_csv,
+        handlers::import_doctors_csv,
         handlers::export_doctors,
         handlers::import_doctors,
+        handlers::get_doctor,
+        handlers::get_doctor_history,
+        handlers::get_doctor_roster,
+        handlers::issue_doctor_calendar_token,
+        handlers::get_doctor_calendar,
+        handlers::revoke_calendar_token,
+        handlers::patch_doctor,
+        handlers::put_doctor,
+        handlers::delete_doctor_by_id,
 
         handlers::get_tickets,
         handlers::add_ticket,
         handlers::update_ticket,
         handlers::delete_ticket,
+        handlers::export_tickets_ndjson,
+        handlers::import_tickets_ndjson,
+        handlers::export_tickets_csv,
+        handlers::import_tickets_csv,
         handlers::export_tickets,
         handlers::import_tickets,
+        handlers::get_ticket,
+        handlers::get_ticket_history,
+        handlers::patch_ticket,
+        handlers::put_ticket,
+        handlers::delete_ticket_by_id,
 
         handlers::get_schedule,
         handlers::add_schedule_entry,
         handlers::update_schedule_entry,
         handlers::delete_schedule_entry,
+        handlers::export_schedule_xlsx,
+        handlers::export_schedule_ndjson,
+        handlers::import_schedule_ndjson,
+        handlers::export_schedule_csv,
+        handlers::import_schedule_csv,
         handlers::export_schedule,
         handlers::import_schedule,
+        handlers::stream_schedule,
+        handlers::schedule_websocket,
+        handlers::get_schedule_entry,
+        handlers::get_schedule_entry_slip,
+        handlers::patch_schedule_entry,
+        handlers::put_schedule_entry,
+        handlers::delete_schedule_entry_by_id,
+
+        handlers::reencrypt_pii,
+
+        handlers::get_fhir_metadata,
+        handlers::search_fhir_resources,
+        handlers::create_fhir_resource,
+        handlers::read_fhir_resource,
+
+        handlers::graphql_request,
+        handlers::graphiql,
+        handlers::get_webhooks,
+        handlers::add_webhook,
+        handlers::get_webhook,
+        handlers::patch_webhook,
+        handlers::delete_webhook,
+        handlers::get_webhook_deliveries,
+        handlers::get_notification_preferences,
+        handlers::put_notification_preferences,
+        handlers::get_notification_log,
+        handlers::get_jobs,
+        handlers::get_job,
+        handlers::cancel_job,
+        handlers::health_live,
+        handlers::health_ready,
+        handlers::health_details,
+        handlers::get_metrics,
     ),
     components(schemas(
         models::Patient,
         models::NewPatient,
         models::OptionPatient,
         models::UpdatePatient,
+        models::PatientHistoryEntry,
         models::Doctor,
         models::NewDoctor,
         models::OptionDoctor,
         models::UpdateDoctor,
+        models::DoctorHistoryEntry,
         models::Ticket,
         models::NewTicket,
         models::OptionTicket,
         models::UpdateTicket,
+        models::TicketHistoryEntry,
         models::ScheduleEntry,
         models::NewScheduleEntry,
         models::OptionScheduleEntry,
         models::UpdateScheduleEntry,
         models::FullScheduleEntry,
+        models::ScheduleEventKind,
+        models::PreviousAppointment,
+        models::ScheduleEvent,
+        models::ScheduleEventFilter,
+        models::AsOf,
+        models::DuplicateFilter,
+        models::DuplicateCandidate,
+        models::MergePatients,
+        models::MergeResult,
+        models::SledExportOptions,
+        models::SledDatasetOptions,
+        models::CsvImportOptions,
+        models::CsvRowError,
+        models::NdjsonExportOptions,
+        models::ScheduleReportFilter,
+        models::RosterOptions,
+        models::CalendarToken,
+        models::CalendarFeedOptions,
+        models::ReencryptResult,
+        models::PatientMergeEntry,
+        models::PatientErasureEntry,
+        models::PatientDataExport,
+        models::ErasureResult,
+        models::WebhookSubscription,
+        models::NewWebhookSubscription,
+        models::UpdateWebhookSubscription,
+        models::WebhookDelivery,
+        models::WebhookDeliveryFilter,
+        models::NotificationPreferences,
+        models::UpdateNotificationPreferences,
+        models::NotificationLogEntry,
+        models::NotificationLogFilter,
+        models::Job,
+        models::JobFilter,
+        models::HealthCheck,
+        models::Readiness,
+        models::BuildInfo,
+        models::DatabaseHealth,
+        models::SledHealth,
+        models::HealthDetails,
     )),
     tags(
         (name = "Patients", description = "Operations related to relation \"patients\""),
         (name = "Doctors", description = "Operations related to relation \"doctors\""),
         (name = "Tickets", description = "Operations related to relation \"tickets\""),
-        (name = "Schedule", description = "Operations related to relation \"schedule\"")
+        (name = "Schedule", description = "Operations related to relation \"schedule\""),
+        (name = "Calendar", description = "Tokens of calendar feed URLs"),
+        (name = "FHIR", description = "FHIR R4 facade over patients, doctors, tickets and schedule"),
+        (name = "GraphQL", description = "GraphQL queries and mutations over all four relations"),
+        (name = "Webhooks", description = "Subscriptions to appointment events and their delivery log"),
+        (name = "Reminders", description = "Appointment reminders by email and SMS"),
+        (name = "Jobs", description = "Background imports and exports, their progress and cancellation"),
+        (name = "Health", description = "Liveness and readiness probes and server state"),
+        (name = "Metrics", description = "Prometheus metrics"),
+        (name = "Admin", description = "Maintenance operations")
     )
 )]
 pub struct ApiDoc;
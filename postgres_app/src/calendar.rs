@@ -0,0 +1,466 @@
+use crate::reports::{parse_date, parse_time};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+
+pub const CONTENT_TYPE: &str = "text/calendar; charset=utf-8";
+
+/// Cancelled events stay in the feed this long, so that every client gets to see them.
+const CANCELLATION_RETENTION_DAYS: i32 = 90;
+
+/// RFC 5545 lines longer than this many octets must be folded.
+const MAX_LINE_OCTETS: usize = 75;
+
+pub struct CalendarSettings {
+    /// IANA time zone of ticket dates and times, e.g. `Europe/Moscow`. Events are published in
+    /// UTC converted from it. Without it events use floating time and are shown at the same
+    /// wall clock time in every time zone.
+    pub time_zone: Option<String>,
+    /// Tickets have no end time, so every appointment is shown this long.
+    pub appointment_minutes: u32,
+    /// Right-hand side of event UIDs, `schedule-<id>@<domain>`.
+    pub uid_domain: String,
+}
+
+#[derive(Clone, Copy)]
+pub enum CalendarOwner {
+    Doctor(i32),
+    Patient(i32),
+}
+
+impl CalendarOwner {
+    fn ids(self) -> (Option<i32>, Option<i32>) {
+        match self {
+            CalendarOwner::Doctor(id) => (Some(id), None),
+            CalendarOwner::Patient(id) => (None, Some(id)),
+        }
+    }
+
+    /// Who a request holding a valid feed token acts for, in logs and traces.
+    pub fn principal(self) -> String {
+        match self {
+            CalendarOwner::Doctor(id) => format!("calendar:doctor:{}", id),
+            CalendarOwner::Patient(id) => format!("calendar:patient:{}", id),
+        }
+    }
+
+    pub fn feed_path(self) -> String {
+        match self {
+            CalendarOwner::Doctor(id) => format!("/doctors/{}/calendar.ics", id),
+            CalendarOwner::Patient(id) => format!("/patients/{}/calendar.ics", id),
+        }
+    }
+}
+
+fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Creates a new feed token. Returns `None` when the doctor or patient does not exist.
+pub async fn issue_token(
+    pool: &PgPool,
+    owner: CalendarOwner,
+) -> Result<Option<(i32, String)>, sqlx::Error> {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = BASE64_URL.encode(bytes);
+
+    let (doctor_id, patient_id) = owner.ids();
+    let id = sqlx::query_scalar!(
+        "INSERT INTO calendar_tokens (token_hash, doctor_id, patient_id)
+        SELECT $1, $2, $3
+        WHERE EXISTS (SELECT 1 FROM doctors WHERE id = $2)
+            OR EXISTS (SELECT 1 FROM patients WHERE id = $3)
+        RETURNING id",
+        token_hash(&token),
+        doctor_id,
+        patient_id,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(id.map(|id| (id, token)))
+}
+
+pub async fn revoke_token(pool: &PgPool, id: i32) -> Result<bool, sqlx::Error> {
+    let revoked = sqlx::query!(
+        "UPDATE calendar_tokens SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL",
+        id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(revoked.rows_affected() > 0)
+}
+
+/// Checks that the token was issued for this very feed and has not been revoked.
+pub async fn token_is_valid(
+    pool: &PgPool,
+    owner: CalendarOwner,
+    token: &str,
+) -> Result<bool, sqlx::Error> {
+    let (doctor_id, patient_id) = owner.ids();
+    let found = sqlx::query_scalar!(
+        "SELECT id FROM calendar_tokens
+        WHERE token_hash = $1 AND revoked_at IS NULL AND
+            doctor_id IS NOT DISTINCT FROM $2 AND patient_id IS NOT DISTINCT FROM $3",
+        token_hash(token),
+        doctor_id,
+        patient_id,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(found.is_some())
+}
+
+pub struct CalendarEvent {
+    pub schedule_id: i32,
+    /// iCalendar date and time, in UTC like `20240131T063000Z` or floating like
+    /// `20240131T093000`.
+    pub start: String,
+    pub office_number: i32,
+    pub sequence: i32,
+    pub summary: String,
+    pub description: String,
+    pub cancelled: bool,
+}
+
+/// Checks that Postgres knows the time zone, which converts ticket times to UTC.
+pub async fn check_time_zone(pool: &PgPool, time_zone: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!("SELECT now() AT TIME ZONE $1 as now", time_zone)
+        .fetch_one(pool)
+        .await?;
+    Ok(())
+}
+
+/// Converts `20240131T093000` local times in the time zone to `20240131T063000Z`, with the
+/// time zone database of Postgres.
+async fn to_utc(
+    pool: &PgPool,
+    starts: &[String],
+    time_zone: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT to_char(
+            to_timestamp(local, 'YYYYMMDD"T"HH24MISS')::TIMESTAMP AT TIME ZONE $2 AT TIME ZONE 'UTC',
+            'YYYYMMDD"T"HH24MISS"Z"'
+        ) as "start!"
+        FROM unnest($1::TEXT[]) WITH ORDINALITY AS starts (local, position)
+        ORDER BY position"#,
+        starts,
+        time_zone,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Loads the calendar name and every appointment of a doctor or a patient, followed by the
+/// appointments they recently lost. Returns `None` when the owner does not exist. Tickets with
+/// a date or time in another format are left out.
+pub async fn fetch_feed(
+    pool: &PgPool,
+    owner: CalendarOwner,
+    settings: &CalendarSettings,
+) -> Result<Option<(String, Vec<CalendarEvent>)>, sqlx::Error> {
+    let (doctor_id, patient_id) = owner.ids();
+
+    let name = match owner {
+        CalendarOwner::Doctor(id) => {
+            sqlx::query!("SELECT name, surname FROM doctors WHERE id = $1", id)
+                .fetch_optional(pool)
+                .await?
+                .map(|doctor| format!("{} {}", doctor.surname, doctor.name))
+        }
+        CalendarOwner::Patient(id) => {
+            sqlx::query!("SELECT name, surname FROM patients WHERE id = $1", id)
+                .fetch_optional(pool)
+                .await?
+                .map(|patient| format!("{} {}", patient.surname, patient.name))
+        }
+    };
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    let rows = sqlx::query!(
+        r#"SELECT schedule.id, tickets.date, tickets.time, tickets.office_number,
+            calendar_sequences.sequence,
+            doctors.name as doctor_name, doctors.surname as doctor_surname, doctors.speciality,
+            patients.name as patient_name, patients.surname as patient_surname
+        FROM schedule
+        JOIN tickets ON schedule.ticket_id = tickets.id
+        JOIN doctors ON schedule.doctor_id = doctors.id
+        JOIN patients ON schedule.patient_id = patients.id
+        JOIN calendar_sequences ON calendar_sequences.schedule_id = schedule.id
+        WHERE
+            ($1::INT IS NULL OR schedule.doctor_id = $1) AND
+            ($2::INT IS NULL OR schedule.patient_id = $2)
+        ORDER BY tickets.date, tickets.time, schedule.id"#,
+        doctor_id,
+        patient_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut events: Vec<CalendarEvent> = rows
+        .into_iter()
+        .filter_map(|row| {
+            let start = local_date_time(&row.date, &row.time)?;
+            let doctor = format!("{} {}", row.doctor_surname, row.doctor_name);
+            let patient = format!("{} {}", row.patient_surname, row.patient_name);
+            let summary = match owner {
+                CalendarOwner::Doctor(_) => patient.clone(),
+                CalendarOwner::Patient(_) => format!("{}, {}", row.speciality, doctor),
+            };
+            Some(CalendarEvent {
+                schedule_id: row.id,
+                start,
+                office_number: row.office_number,
+                sequence: row.sequence,
+                summary,
+                description: format!(
+                    "Doctor: {} ({})\nPatient: {}",
+                    doctor, row.speciality, patient
+                ),
+                cancelled: false,
+            })
+        })
+        .collect();
+
+    let cancellations = sqlx::query!(
+        "SELECT DISTINCT ON (schedule_id) schedule_id, date, time, office_number, sequence
+        FROM schedule_cancellations
+        WHERE
+            ($1::INT IS NULL OR doctor_id = $1) AND
+            ($2::INT IS NULL OR patient_id = $2) AND
+            cancelled_at > now() - make_interval(days => $3) AND
+            NOT EXISTS (
+                SELECT 1 FROM schedule
+                WHERE schedule.id = schedule_cancellations.schedule_id AND
+                    ($1::INT IS NULL OR schedule.doctor_id = $1) AND
+                    ($2::INT IS NULL OR schedule.patient_id = $2)
+            )
+        ORDER BY schedule_id, cancelled_at DESC",
+        doctor_id,
+        patient_id,
+        CANCELLATION_RETENTION_DAYS,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    events.extend(cancellations.into_iter().filter_map(|row| {
+        Some(CalendarEvent {
+            schedule_id: row.schedule_id,
+            start: local_date_time(&row.date, &row.time)?,
+            office_number: row.office_number,
+            sequence: row.sequence,
+            summary: "Cancelled appointment".to_string(),
+            description: String::new(),
+            cancelled: true,
+        })
+    }));
+
+    if let Some(time_zone) = &settings.time_zone {
+        let starts: Vec<String> = events.iter().map(|event| event.start.clone()).collect();
+        let starts = to_utc(pool, &starts, time_zone).await?;
+        for (event, start) in events.iter_mut().zip(starts) {
+            event.start = start;
+        }
+    }
+
+    Ok(Some((name, events)))
+}
+
+fn escape_text(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Appends a content line, folded into CRLF-terminated lines of at most 75 octets without
+/// splitting UTF-8 sequences.
+fn push_line(output: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            output.push_str("\r\n ");
+            octets = 1;
+        }
+        output.push(c);
+        octets += c.len_utf8();
+    }
+    output.push_str("\r\n");
+}
+
+/// `20240131T093000` for a ticket date and time. `None` for values in other formats.
+fn local_date_time(date: &str, time: &str) -> Option<String> {
+    let date = parse_date(date)?;
+    let (hour, minute) = parse_time(time)?;
+    Some(format!(
+        "{:04}{:02}{:02}T{:02}{:02}00",
+        date.year(),
+        date.month() as u8,
+        date.day(),
+        hour,
+        minute
+    ))
+}
+
+/// Renders an iCalendar feed. Every schedule entry keeps the UID `schedule-<id>@<domain>` for
+/// its lifetime, so calendar apps update events in place instead of duplicating them.
+pub fn render(name: &str, events: &[CalendarEvent], settings: &CalendarSettings) -> String {
+    let now = OffsetDateTime::now_utc();
+    let stamp = format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        now.year(),
+        now.month() as u8,
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+    let mut output = String::new();
+    push_line(&mut output, "BEGIN:VCALENDAR");
+    push_line(&mut output, "VERSION:2.0");
+    push_line(&mut output, "PRODID:-//Hospital//Schedule//EN");
+    push_line(&mut output, "CALSCALE:GREGORIAN");
+    push_line(&mut output, &format!("X-WR-CALNAME:{}", escape_text(name)));
+    if let Some(time_zone) = &settings.time_zone {
+        push_line(&mut output, &format!("X-WR-TIMEZONE:{}", time_zone));
+    }
+
+    for event in events {
+        push_line(&mut output, "BEGIN:VEVENT");
+        push_line(
+            &mut output,
+            &format!("UID:schedule-{}@{}", event.schedule_id, settings.uid_domain),
+        );
+        push_line(&mut output, &format!("DTSTAMP:{}", stamp));
+        push_line(&mut output, &format!("DTSTART:{}", event.start));
+        push_line(
+            &mut output,
+            &format!("DURATION:PT{}M", settings.appointment_minutes),
+        );
+        push_line(&mut output, &format!("SEQUENCE:{}", event.sequence));
+        push_line(
+            &mut output,
+            &format!("SUMMARY:{}", escape_text(&event.summary)),
+        );
+        push_line(
+            &mut output,
+            &format!("LOCATION:Office {}", event.office_number),
+        );
+        if !event.description.is_empty() {
+            push_line(
+                &mut output,
+                &format!("DESCRIPTION:{}", escape_text(&event.description)),
+            );
+        }
+        if event.cancelled {
+            push_line(&mut output, "STATUS:CANCELLED");
+        } else {
+            push_line(&mut output, "STATUS:CONFIRMED");
+        }
+        push_line(&mut output, "END:VEVENT");
+    }
+
+    push_line(&mut output, "END:VCALENDAR");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> CalendarSettings {
+        CalendarSettings {
+            time_zone: Some("Europe/Moscow".to_string()),
+            appointment_minutes: 30,
+            uid_domain: "hospital.test".to_string(),
+        }
+    }
+
+    #[test]
+    fn escape_text_escapes_special_characters() {
+        assert_eq!(
+            escape_text("Smith, John; room\\2\r\nsecond line"),
+            "Smith\\, John\\; room\\\\2\\nsecond line"
+        );
+        assert_eq!(escape_text("plain"), "plain");
+    }
+
+    #[test]
+    fn push_line_folds_long_lines() {
+        let mut output = String::new();
+        push_line(&mut output, &"a".repeat(160));
+
+        let lines: Vec<&str> = output.split("\r\n").collect();
+        assert_eq!(
+            lines,
+            [
+                "a".repeat(75),
+                format!(" {}", "a".repeat(74)),
+                format!(" {}", "a".repeat(11)),
+                String::new()
+            ]
+        );
+    }
+
+    #[test]
+    fn push_line_does_not_split_utf8_sequences() {
+        let mut output = String::new();
+        push_line(&mut output, &format!("{}{}", "a".repeat(74), "ж".repeat(3)));
+
+        for line in output.split("\r\n") {
+            assert!(line.len() <= MAX_LINE_OCTETS);
+        }
+        let unfolded = output.replace("\r\n ", "");
+        assert_eq!(unfolded, format!("{}{}\r\n", "a".repeat(74), "ж".repeat(3)));
+    }
+
+    #[test]
+    fn local_date_time_rejects_other_formats() {
+        assert_eq!(
+            local_date_time("2024-01-31", "9:30").as_deref(),
+            Some("20240131T093000")
+        );
+        assert_eq!(local_date_time("31.01.2024", "09:30"), None);
+        assert_eq!(local_date_time("2024-01-31", "25:00"), None);
+    }
+
+    #[test]
+    fn render_publishes_start_without_time_zone_parameter() {
+        let events = [CalendarEvent {
+            schedule_id: 7,
+            start: "20240131T063000Z".to_string(),
+            office_number: 12,
+            sequence: 3,
+            summary: "Ivanov Ivan".to_string(),
+            description: String::new(),
+            cancelled: true,
+        }];
+        let output = render("Petrov Petr", &events, &settings());
+
+        assert!(output.contains("\r\nUID:schedule-7@hospital.test\r\n"));
+        assert!(output.contains("\r\nDTSTART:20240131T063000Z\r\n"));
+        assert!(output.contains("\r\nSEQUENCE:3\r\n"));
+        assert!(output.contains("\r\nSTATUS:CANCELLED\r\n"));
+        assert!(!output.contains("TZID"));
+        assert!(!output.contains("DESCRIPTION"));
+    }
+}
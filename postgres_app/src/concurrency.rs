@@ -0,0 +1,156 @@
+use actix_web::http::header::{ETag, EntityTag, Header, IfMatch, IF_MATCH};
+use actix_web::{HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+pub struct ConcurrencySettings {
+    /// When set, updates and deletes, by id or by filter, are rejected unless they carry an
+    /// `If-Match` header.
+    pub strict_if_match: bool,
+}
+
+pub fn etag(version: i32) -> ETag {
+    ETag(EntityTag::new_strong(version.to_string()))
+}
+
+pub enum IfMatchError {
+    Missing,
+    Malformed,
+}
+
+impl IfMatchError {
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            IfMatchError::Missing => {
+                HttpResponse::PreconditionRequired().body("If-Match header is required")
+            }
+            IfMatchError::Malformed => HttpResponse::BadRequest().body("Invalid If-Match header"),
+        }
+    }
+}
+
+/// Reads `If-Match` and returns the row versions the client expects.
+///
+/// `Ok(None)` means that any version is acceptable: the header is `*`, or it is absent
+/// and strict mode is off. Weak tags never match, as `If-Match` uses the strong comparison.
+pub fn expected_versions(
+    req: &HttpRequest,
+    settings: &ConcurrencySettings,
+) -> Result<Option<Vec<i32>>, IfMatchError> {
+    if !req.headers().contains_key(IF_MATCH) {
+        if settings.strict_if_match {
+            return Err(IfMatchError::Missing);
+        }
+        return Ok(None);
+    }
+
+    match IfMatch::parse(req) {
+        Ok(IfMatch::Any) => Ok(None),
+        // The parser skips items that are not entity tags instead of failing.
+        Ok(IfMatch::Items(tags)) if tags.len() != header_items(req) => Err(IfMatchError::Malformed),
+        Ok(IfMatch::Items(tags)) => Ok(Some(
+            tags.iter()
+                .filter(|tag| !tag.weak)
+                .filter_map(|tag| tag.tag().parse::<i32>().ok())
+                .collect(),
+        )),
+        Err(_) => Err(IfMatchError::Malformed),
+    }
+}
+
+/// Number of non-empty comma-separated items over every `If-Match` header.
+fn header_items(req: &HttpRequest) -> usize {
+    req.headers()
+        .get_all(IF_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter(|item| !item.trim().is_empty())
+        .count()
+}
+
+pub async fn row_exists(pool: &PgPool, table: &'static str, id: i32) -> bool {
+    let exists = sqlx::query(&format!("SELECT 1 FROM {} WHERE id = $1", table))
+        .bind(id)
+        .fetch_optional(pool)
+        .await;
+
+    matches!(exists, Ok(Some(_)))
+}
+
+pub fn precondition_failed() -> HttpResponse {
+    HttpResponse::PreconditionFailed().body("Entry was modified by someone else")
+}
+
+/// Tells apart a conditional write that found no row from one that lost the version race.
+pub async fn precondition_failed_or_not_found(
+    pool: &PgPool,
+    table: &'static str,
+    id: i32,
+) -> HttpResponse {
+    if row_exists(pool, table, id).await {
+        precondition_failed()
+    } else {
+        HttpResponse::NotFound().body("Entry not found")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn expected(if_match: Option<&str>, strict_if_match: bool) -> Result<Option<Vec<i32>>, u16> {
+        let mut req = TestRequest::default();
+        if let Some(if_match) = if_match {
+            req = req.insert_header((IF_MATCH, if_match));
+        }
+        expected_versions(
+            &req.to_http_request(),
+            &ConcurrencySettings { strict_if_match },
+        )
+        .map_err(|e| e.response().status().as_u16())
+    }
+
+    #[test]
+    fn any_version_matches_a_star_or_a_missing_header() {
+        assert_eq!(expected(Some("*"), false), Ok(None));
+        assert_eq!(expected(Some("*"), true), Ok(None));
+        assert_eq!(expected(None, false), Ok(None));
+    }
+
+    #[test]
+    fn strict_mode_requires_the_header() {
+        assert_eq!(expected(None, true), Err(428));
+        assert_eq!(expected(Some("\"3\""), true), Ok(Some(vec![3])));
+    }
+
+    #[test]
+    fn lists_give_every_strong_version() {
+        assert_eq!(expected(Some("\"3\""), false), Ok(Some(vec![3])));
+        assert_eq!(
+            expected(Some("\"3\", \"5\",\"8\""), false),
+            Ok(Some(vec![3, 5, 8]))
+        );
+        // Tags that are not versions of this service cannot match any row.
+        assert_eq!(expected(Some("\"3\", \"abc\""), false), Ok(Some(vec![3])));
+    }
+
+    #[test]
+    fn weak_tags_never_match() {
+        assert_eq!(expected(Some("W/\"3\""), false), Ok(Some(vec![])));
+        // An empty list is valid and matches nothing either.
+        assert_eq!(expected(Some(""), false), Ok(Some(vec![])));
+        assert_eq!(expected(Some("W/\"3\", \"4\""), false), Ok(Some(vec![4])));
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        assert_eq!(expected(Some("3"), false), Err(400));
+        assert_eq!(expected(Some("\"3"), false), Err(400));
+        assert_eq!(expected(Some("\"3\", 4"), false), Err(400));
+    }
+
+    #[test]
+    fn etags_are_strong_versions() {
+        assert_eq!(etag(7).to_string(), "\"7\"");
+    }
+}
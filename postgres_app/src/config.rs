@@ -0,0 +1,1297 @@
+use crate::calendar::CalendarSettings;
+use crate::concurrency::ConcurrencySettings;
+use crate::crypto::FieldCipher;
+use crate::fhir::{parse_offset, FhirSettings};
+use crate::grpc::GrpcSettings;
+use crate::hl7::Hl7Settings;
+use crate::idempotency::IdempotencySettings;
+use crate::jobs::JobSettings;
+use crate::reminders::{Locale, ReminderSettings, LOCALES};
+use crate::telemetry::REQUEST_ID_HEADER;
+use crate::webhooks::WebhookSettings;
+use actix_cors::Cors;
+use actix_web::http::header::{self, HeaderName};
+use clap::builder::BoolishValueParser;
+use clap::Parser;
+use lettre::message::Mailbox;
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use time::UtcOffset;
+use tracing_subscriber::EnvFilter;
+
+/// Read when it exists and no other file is given.
+const DEFAULT_CONFIG_FILE: &str = "hospital.toml";
+
+/// Postgres notices, as those of idempotent migrations, are only logged from warnings on.
+const DEFAULT_LOG_FILTER: &str = "info,sqlx::postgres::notice=warn";
+
+/// Server settings, layered from lowest to highest precedence: built-in defaults, the TOML
+/// configuration file, environment variables (also read from `.env`) and command line flags.
+pub struct ServerConfig {
+    pub bind_address: SocketAddr,
+    /// HTTP worker threads. `None` starts one per CPU core.
+    pub workers: Option<usize>,
+    pub database: DatabaseConfig,
+    /// Time a client has to send the request head. Zero disables the limit.
+    pub request_timeout: Duration,
+    /// Zero closes connections after each response.
+    pub keep_alive: Duration,
+    /// Time between a shutdown signal and the server stopping, while readiness checks fail.
+    pub shutdown_drain: Duration,
+    /// Time in-flight requests get to finish on shutdown.
+    pub shutdown_timeout: Duration,
+    pub sled_path: PathBuf,
+    pub anonymized_sled_path: PathBuf,
+    /// Serves Swagger UI at /swagger-ui/ and the OpenAPI document at /api-docs/openapi.json.
+    pub swagger: bool,
+    pub cors: CorsConfig,
+    /// Serves HTTPS instead of HTTP when set.
+    pub tls: Option<TlsConfig>,
+    pub logging: LoggingConfig,
+    pub pii: PiiConfig,
+    /// Keys the pseudonyms of anonymized exports. `None` generates a key at startup.
+    pub anonymization_key: Option<String>,
+    pub concurrency: ConcurrencySettings,
+    pub idempotency: IdempotencySettings,
+    pub calendar: CalendarSettings,
+    pub fhir: FhirSettings,
+    pub grpc: GrpcSettings,
+    pub hl7: Hl7Settings,
+    pub webhooks: WebhookSettings,
+    pub jobs: JobSettings,
+    pub reminders: ReminderSettings,
+    /// Sends email reminders when set.
+    pub smtp: Option<SmtpConfig>,
+    /// Sends SMS reminders when set.
+    pub sms: Option<SmsConfig>,
+}
+
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout: Duration,
+    /// Idle connections above `min_connections` are closed after this. `None` keeps them.
+    pub idle_timeout: Option<Duration>,
+}
+
+#[derive(Clone)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API from browsers. Empty disables CORS, `*` allows any.
+    pub allowed_origins: Vec<String>,
+    pub max_age: Duration,
+}
+
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first. Reloaded together with the key when either file
+    /// changes or the process receives SIGHUP.
+    pub cert_path: PathBuf,
+    /// PEM private key, PKCS#8, PKCS#1 or SEC1.
+    pub key_path: PathBuf,
+    /// PEM certificates of the CAs issuing client certificates. When set, import, export and
+    /// admin routes need a client certificate signed by one of them.
+    pub client_ca_path: Option<PathBuf>,
+    /// Plain HTTP listener redirecting every request to HTTPS.
+    pub redirect_address: Option<SocketAddr>,
+}
+
+pub struct LoggingConfig {
+    /// `tracing` filter directives, such as `info,postgres_app=debug`.
+    pub filter: String,
+    pub format: LogFormat,
+    /// OTLP/gRPC collector spans are exported to, such as http://localhost:4317. `None` exports
+    /// nothing.
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+}
+
+#[derive(Default)]
+pub struct PiiConfig {
+    /// `id:base64` AES-256 keys separated by commas or new lines, the current one first. Empty
+    /// stores phone and passport numbers in plaintext.
+    pub keys: String,
+    /// Base64 HMAC key of the blind indexes. Required with keys.
+    pub blind_index_key: Option<String>,
+}
+
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    /// Username and password, when the server needs them.
+    pub credentials: Option<(String, String)>,
+    pub from: Mailbox,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// No encryption, for local relays and test sinks.
+    Plain,
+    StartTls,
+    /// Implicit TLS.
+    Tls,
+}
+
+/// Reminders are posted as JSON to `gateway_url`, with `gateway_token` as a bearer token.
+pub struct SmsConfig {
+    pub gateway_url: String,
+    pub gateway_token: Option<String>,
+    pub sender: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Command line flags. Each can also be set with the environment variable named after it.
+#[derive(Parser)]
+#[command(about = "Hospital scheduling server")]
+struct Flags {
+    /// TOML configuration file. `hospital.toml` is read when it exists.
+    #[arg(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// Address the HTTP server listens on, 127.0.0.1:8080 by default.
+    #[arg(long, env = "BIND_ADDRESS")]
+    bind_address: Option<String>,
+    /// HTTP worker threads, one per CPU core by default.
+    #[arg(long, env = "HTTP_WORKERS")]
+    workers: Option<usize>,
+    #[arg(long, env = "DATABASE_URL", hide_env_values = true)]
+    database_url: Option<String>,
+    /// Connections in the database pool, 5 by default.
+    #[arg(long, env = "DATABASE_MAX_CONNECTIONS")]
+    database_max_connections: Option<u32>,
+    #[arg(long, env = "DATABASE_MIN_CONNECTIONS")]
+    database_min_connections: Option<u32>,
+    /// Time to wait for a free connection, 30 by default.
+    #[arg(long, env = "DATABASE_ACQUIRE_TIMEOUT_SECONDS")]
+    database_acquire_timeout_seconds: Option<u64>,
+    /// 600 by default, 0 keeps idle connections open.
+    #[arg(long, env = "DATABASE_IDLE_TIMEOUT_SECONDS")]
+    database_idle_timeout_seconds: Option<u64>,
+    /// 5 by default, 0 disables the limit.
+    #[arg(long, env = "REQUEST_TIMEOUT_SECONDS")]
+    request_timeout_seconds: Option<u64>,
+    /// 5 by default, 0 disables keep-alive.
+    #[arg(long, env = "KEEP_ALIVE_SECONDS")]
+    keep_alive_seconds: Option<u64>,
+    /// Seconds readiness fails before shutting down, 5 by default.
+    #[arg(long, env = "SHUTDOWN_DRAIN_SECONDS")]
+    shutdown_drain_seconds: Option<u64>,
+    /// 30 by default.
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECONDS")]
+    shutdown_timeout_seconds: Option<u64>,
+    /// `hospital` by default.
+    #[arg(long, env = "SLED_PATH")]
+    sled_path: Option<PathBuf>,
+    /// `hospital_anonymized` by default.
+    #[arg(long, env = "ANONYMIZED_SLED_PATH")]
+    anonymized_sled_path: Option<PathBuf>,
+    /// Serve Swagger UI, true by default.
+    #[arg(long, env = "SWAGGER_ENABLED")]
+    swagger: Option<bool>,
+    /// Comma-separated origins allowed by CORS.
+    #[arg(long, env = "CORS_ALLOWED_ORIGINS", value_delimiter = ',')]
+    cors_allowed_origins: Option<Vec<String>>,
+    /// How long browsers may cache preflight responses, 3600 by default.
+    #[arg(long, env = "CORS_MAX_AGE_SECONDS")]
+    cors_max_age_seconds: Option<u64>,
+    #[arg(long, env = "TLS_CERT_PATH")]
+    tls_cert_path: Option<PathBuf>,
+    #[arg(long, env = "TLS_KEY_PATH")]
+    tls_key_path: Option<PathBuf>,
+    /// CA certificates of client certificates required by import, export and admin routes.
+    #[arg(long, env = "TLS_CLIENT_CA_PATH")]
+    tls_client_ca_path: Option<PathBuf>,
+    /// Plain HTTP address redirecting to HTTPS, such as 0.0.0.0:80.
+    #[arg(long, env = "TLS_REDIRECT_ADDRESS")]
+    tls_redirect_address: Option<String>,
+    /// Which events are logged, `info,sqlx::postgres::notice=warn` by default.
+    #[arg(long, env = "LOG_FILTER")]
+    log_filter: Option<String>,
+    /// `text` or `json`, `text` by default.
+    #[arg(long, env = "LOG_FORMAT")]
+    log_format: Option<String>,
+    /// OTLP/gRPC collector to export spans to, such as http://localhost:4317.
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+    /// Service name of exported spans, `postgres_app` by default.
+    #[arg(long, env = "OTEL_SERVICE_NAME")]
+    otel_service_name: Option<String>,
+    /// Comma-separated `id:base64` AES-256 keys encrypting phone and passport numbers, the
+    /// current one first. Values are stored in plaintext without keys.
+    #[arg(long, env = "PII_ENCRYPTION_KEYS", hide_env_values = true)]
+    pii_encryption_keys: Option<String>,
+    /// File holding the PII encryption keys, one per line.
+    #[arg(long, env = "PII_ENCRYPTION_KEYS_FILE")]
+    pii_encryption_keys_file: Option<PathBuf>,
+    /// Base64 HMAC key of the blind indexes, required with PII encryption keys.
+    #[arg(long, env = "PII_BLIND_INDEX_KEY", hide_env_values = true)]
+    pii_blind_index_key: Option<String>,
+    /// Key of the pseudonyms in anonymized exports, a random one per start by default.
+    #[arg(long, env = "ANONYMIZATION_KEY", hide_env_values = true)]
+    anonymization_key: Option<String>,
+    /// Reject updates and deletes without an If-Match header, false by default.
+    #[arg(long, env = "STRICT_IF_MATCH", value_parser = BoolishValueParser::new())]
+    strict_if_match: Option<bool>,
+    /// How long responses to requests with an Idempotency-Key are replayed, 86400 by default.
+    #[arg(long, env = "IDEMPOTENCY_TTL_SECONDS")]
+    idempotency_ttl_seconds: Option<i64>,
+    /// IANA time zone of ticket dates and times in calendar feeds, such as Europe/Moscow.
+    /// Floating time by default.
+    #[arg(long, env = "CALENDAR_TIME_ZONE")]
+    calendar_time_zone: Option<String>,
+    /// Length of appointments in calendar feeds, 30 by default.
+    #[arg(long, env = "CALENDAR_APPOINTMENT_MINUTES")]
+    calendar_appointment_minutes: Option<u32>,
+    /// Domain of calendar event UIDs, `hospital.local` by default.
+    #[arg(long, env = "CALENDAR_UID_DOMAIN")]
+    calendar_uid_domain: Option<String>,
+    /// Offset of ticket dates and times from UTC in FHIR resources, such as +03:00. Z by
+    /// default.
+    #[arg(long, env = "FHIR_UTC_OFFSET")]
+    fhir_utc_offset: Option<String>,
+    /// Length of FHIR Slots and Appointments, 30 by default.
+    #[arg(long, env = "FHIR_SLOT_MINUTES")]
+    fhir_slot_minutes: Option<i64>,
+    /// Loopback address of the gRPC server, such as 127.0.0.1:50051. Off by default.
+    #[arg(long, env = "GRPC_ADDRESS")]
+    grpc_address: Option<String>,
+    /// Loopback address HL7 v2 messages are accepted at over MLLP, such as 127.0.0.1:2575.
+    /// Off by default.
+    #[arg(long, env = "HL7_MLLP_ADDRESS")]
+    hl7_mllp_address: Option<String>,
+    /// MSH-3 of HL7 acknowledgements, `HOSPITAL` by default.
+    #[arg(long, env = "HL7_APPLICATION")]
+    hl7_application: Option<String>,
+    /// MSH-4 of HL7 acknowledgements, `HOSPITAL` by default.
+    #[arg(long, env = "HL7_FACILITY")]
+    hl7_facility: Option<String>,
+    /// Attempts after which a webhook delivery fails, 8 by default.
+    #[arg(long, env = "WEBHOOK_MAX_ATTEMPTS")]
+    webhook_max_attempts: Option<i32>,
+    /// Delay before the first webhook retry, doubled for each further one. 30 by default.
+    #[arg(long, env = "WEBHOOK_RETRY_BASE_SECONDS")]
+    webhook_retry_base_seconds: Option<u64>,
+    /// Longest delay between webhook retries, 21600 by default.
+    #[arg(long, env = "WEBHOOK_RETRY_MAX_SECONDS")]
+    webhook_retry_max_seconds: Option<u64>,
+    /// 10 by default.
+    #[arg(long, env = "WEBHOOK_TIMEOUT_SECONDS")]
+    webhook_timeout_seconds: Option<u64>,
+    /// Let webhooks target loopback, private and link-local addresses, false by default.
+    #[arg(long, env = "WEBHOOK_ALLOW_PRIVATE_TARGETS", value_parser = BoolishValueParser::new())]
+    webhook_allow_private_targets: Option<bool>,
+    /// Background jobs run at once by this instance, 2 by default.
+    #[arg(long, env = "JOB_WORKERS")]
+    job_workers: Option<usize>,
+    /// Offset of ticket dates and times from UTC in reminders, such as +03:00. Z by default.
+    #[arg(long, env = "REMINDER_UTC_OFFSET")]
+    reminder_utc_offset: Option<String>,
+    /// Language of reminders to patients who have not chosen one, `ru` by default.
+    #[arg(long, env = "REMINDER_LOCALE")]
+    reminder_locale: Option<String>,
+    /// SMTP server email reminders are sent through. No email is sent by default.
+    #[arg(long, env = "SMTP_HOST")]
+    smtp_host: Option<String>,
+    /// 587 with `starttls`, 465 with `tls` and 25 with `none` by default.
+    #[arg(long, env = "SMTP_PORT")]
+    smtp_port: Option<u16>,
+    /// `starttls`, `tls` or `none`, `starttls` by default.
+    #[arg(long, env = "SMTP_TLS")]
+    smtp_tls: Option<String>,
+    #[arg(long, env = "SMTP_USERNAME")]
+    smtp_username: Option<String>,
+    #[arg(long, env = "SMTP_PASSWORD", hide_env_values = true)]
+    smtp_password: Option<String>,
+    /// Sender of email reminders, required with an SMTP host.
+    #[arg(long, env = "SMTP_FROM")]
+    smtp_from: Option<String>,
+    /// Gateway SMS reminders are posted to. No SMS is sent by default.
+    #[arg(long, env = "SMS_GATEWAY_URL")]
+    sms_gateway_url: Option<String>,
+    /// Bearer token of the SMS gateway.
+    #[arg(long, env = "SMS_GATEWAY_TOKEN", hide_env_values = true)]
+    sms_gateway_token: Option<String>,
+    /// Sender name passed to the SMS gateway.
+    #[arg(long, env = "SMS_SENDER")]
+    sms_sender: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    bind_address: Option<String>,
+    workers: Option<usize>,
+    sled_path: Option<PathBuf>,
+    anonymized_sled_path: Option<PathBuf>,
+    swagger: Option<bool>,
+    database: DatabaseSection,
+    http: HttpSection,
+    cors: CorsSection,
+    tls: TlsSection,
+    logging: LoggingSection,
+    pii: PiiSection,
+    anonymization: AnonymizationSection,
+    concurrency: ConcurrencySection,
+    idempotency: IdempotencySection,
+    calendar: CalendarSection,
+    fhir: FhirSection,
+    grpc: GrpcSection,
+    hl7: Hl7Section,
+    webhooks: WebhooksSection,
+    jobs: JobsSection,
+    reminders: RemindersSection,
+    smtp: SmtpSection,
+    sms: SmsSection,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct DatabaseSection {
+    url: Option<String>,
+    max_connections: Option<u32>,
+    min_connections: Option<u32>,
+    acquire_timeout_seconds: Option<u64>,
+    idle_timeout_seconds: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct HttpSection {
+    request_timeout_seconds: Option<u64>,
+    keep_alive_seconds: Option<u64>,
+    shutdown_drain_seconds: Option<u64>,
+    shutdown_timeout_seconds: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct CorsSection {
+    allowed_origins: Option<Vec<String>>,
+    max_age_seconds: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+    cert_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
+    client_ca_path: Option<PathBuf>,
+    redirect_address: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LoggingSection {
+    filter: Option<String>,
+    format: Option<String>,
+    otlp_endpoint: Option<String>,
+    service_name: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct PiiSection {
+    keys: Option<Vec<String>>,
+    keys_file: Option<PathBuf>,
+    blind_index_key: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct AnonymizationSection {
+    key: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConcurrencySection {
+    strict_if_match: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct IdempotencySection {
+    ttl_seconds: Option<i64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct CalendarSection {
+    time_zone: Option<String>,
+    appointment_minutes: Option<u32>,
+    uid_domain: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FhirSection {
+    utc_offset: Option<String>,
+    slot_minutes: Option<i64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct GrpcSection {
+    address: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Hl7Section {
+    mllp_address: Option<String>,
+    application: Option<String>,
+    facility: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct WebhooksSection {
+    max_attempts: Option<i32>,
+    retry_base_seconds: Option<u64>,
+    retry_max_seconds: Option<u64>,
+    timeout_seconds: Option<u64>,
+    allow_private_targets: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct JobsSection {
+    workers: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RemindersSection {
+    utc_offset: Option<String>,
+    locale: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SmtpSection {
+    host: Option<String>,
+    port: Option<u16>,
+    tls: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    from: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SmsSection {
+    gateway_url: Option<String>,
+    gateway_token: Option<String>,
+    sender: Option<String>,
+}
+
+impl ServerConfig {
+    /// Reads and validates the configuration. The error lists every problem found, one per line.
+    /// Invalid flags and unparsable flag or environment values end the process with usage help.
+    pub fn load() -> Result<Self, String> {
+        let flags = Flags::parse();
+        let file = match &flags.config {
+            Some(path) => read_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                read_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => ConfigFile::default(),
+        };
+        Self::from_layers(flags, file)
+    }
+
+    fn from_layers(flags: Flags, file: ConfigFile) -> Result<Self, String> {
+        let mut errors = Vec::new();
+
+        let bind_address = flags
+            .bind_address
+            .or(file.bind_address)
+            .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+        let bind_address = bind_address.parse().unwrap_or_else(|_| {
+            errors.push(format!(
+                "bind_address `{}` must look like 127.0.0.1:8080",
+                bind_address
+            ));
+            SocketAddr::from(([127, 0, 0, 1], 8080))
+        });
+
+        let workers = flags.workers.or(file.workers);
+        if workers == Some(0) {
+            errors.push("workers must be at least 1".to_string());
+        }
+
+        let database = DatabaseConfig {
+            url: flags.database_url.or(file.database.url).unwrap_or_default(),
+            max_connections: flags
+                .database_max_connections
+                .or(file.database.max_connections)
+                .unwrap_or(5),
+            min_connections: flags
+                .database_min_connections
+                .or(file.database.min_connections)
+                .unwrap_or(0),
+            acquire_timeout: Duration::from_secs(
+                flags
+                    .database_acquire_timeout_seconds
+                    .or(file.database.acquire_timeout_seconds)
+                    .unwrap_or(30),
+            ),
+            idle_timeout: Some(
+                flags
+                    .database_idle_timeout_seconds
+                    .or(file.database.idle_timeout_seconds)
+                    .unwrap_or(600),
+            )
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs),
+        };
+        if database.url.is_empty() {
+            errors.push(
+                "The database URL is not set, set DATABASE_URL or `url` in the [database] section"
+                    .to_string(),
+            );
+        } else if !database.url.starts_with("postgres://")
+            && !database.url.starts_with("postgresql://")
+        {
+            errors
+                .push("The database URL must start with postgres:// or postgresql://".to_string());
+        }
+        if database.max_connections == 0 {
+            errors.push("database max_connections must be at least 1".to_string());
+        }
+        if database.min_connections > database.max_connections {
+            errors.push(format!(
+                "database min_connections {} exceeds max_connections {}",
+                database.min_connections, database.max_connections
+            ));
+        }
+        if database.acquire_timeout.is_zero() {
+            errors.push("database acquire_timeout_seconds must be at least 1".to_string());
+        }
+
+        let sled_path = flags
+            .sled_path
+            .or(file.sled_path)
+            .unwrap_or_else(|| PathBuf::from("hospital"));
+        let anonymized_sled_path = flags
+            .anonymized_sled_path
+            .or(file.anonymized_sled_path)
+            .unwrap_or_else(|| PathBuf::from("hospital_anonymized"));
+        if sled_path == anonymized_sled_path {
+            errors.push(format!(
+                "sled_path and anonymized_sled_path are both `{}`, anonymized exports must not mix with regular ones",
+                sled_path.display()
+            ));
+        }
+
+        let cors = CorsConfig {
+            allowed_origins: flags
+                .cors_allowed_origins
+                .or(file.cors.allowed_origins)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            max_age: Duration::from_secs(
+                flags
+                    .cors_max_age_seconds
+                    .or(file.cors.max_age_seconds)
+                    .unwrap_or(3600),
+            ),
+        };
+        for origin in &cors.allowed_origins {
+            if origin != "*"
+                && (!(origin.starts_with("http://") || origin.starts_with("https://"))
+                    || origin.ends_with('/'))
+            {
+                errors.push(format!(
+                    "CORS origin `{}` must be `*` or look like https://example.org",
+                    origin
+                ));
+            }
+        }
+
+        let client_ca_path = flags.tls_client_ca_path.or(file.tls.client_ca_path);
+        let redirect_address = flags
+            .tls_redirect_address
+            .or(file.tls.redirect_address)
+            .and_then(|address| match address.parse::<SocketAddr>() {
+                Ok(address) if address == bind_address => {
+                    errors.push(format!(
+                        "The TLS redirect address {} is also the bind address",
+                        address
+                    ));
+                    None
+                }
+                Ok(address) => Some(address),
+                Err(_) => {
+                    errors.push(format!(
+                        "TLS redirect address `{}` must look like 0.0.0.0:80",
+                        address
+                    ));
+                    None
+                }
+            });
+        let tls = match (
+            flags.tls_cert_path.or(file.tls.cert_path),
+            flags.tls_key_path.or(file.tls.key_path),
+        ) {
+            (Some(cert_path), Some(key_path)) => {
+                for path in [Some(&cert_path), Some(&key_path), client_ca_path.as_ref()]
+                    .into_iter()
+                    .flatten()
+                {
+                    if !path.is_file() {
+                        errors.push(format!("TLS file `{}` does not exist", path.display()));
+                    }
+                }
+                Some(TlsConfig {
+                    cert_path,
+                    key_path,
+                    client_ca_path,
+                    redirect_address,
+                })
+            }
+            (None, None) => {
+                if client_ca_path.is_some() || redirect_address.is_some() {
+                    errors.push(
+                        "Client certificates and the HTTPS redirect need a TLS certificate and key"
+                            .to_string(),
+                    );
+                }
+                None
+            }
+            _ => {
+                errors.push("TLS needs both a certificate and a key path".to_string());
+                None
+            }
+        };
+
+        let filter = flags
+            .log_filter
+            .or(file.logging.filter)
+            .unwrap_or_else(|| DEFAULT_LOG_FILTER.to_string());
+        if let Err(e) = EnvFilter::try_new(&filter) {
+            errors.push(format!("Invalid log filter `{}`: {}", filter, e));
+        }
+        let format = match flags
+            .log_format
+            .or(file.logging.format)
+            .as_deref()
+            .unwrap_or("text")
+        {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            format => {
+                errors.push(format!("Log format `{}` must be `text` or `json`", format));
+                LogFormat::Text
+            }
+        };
+        let otlp_endpoint = flags
+            .otlp_endpoint
+            .or(file.logging.otlp_endpoint)
+            .filter(|endpoint| !endpoint.is_empty());
+        if let Some(endpoint) = &otlp_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                errors.push(format!(
+                    "OTLP endpoint `{}` must look like http://localhost:4317",
+                    endpoint
+                ));
+            }
+        }
+        let logging = LoggingConfig {
+            filter,
+            format,
+            otlp_endpoint,
+            service_name: flags
+                .otel_service_name
+                .or(file.logging.service_name)
+                .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string()),
+        };
+
+        let keys = match (flags.pii_encryption_keys, flags.pii_encryption_keys_file) {
+            (Some(keys), _) => Some(keys),
+            (None, Some(path)) => read_keys(&path, &mut errors),
+            (None, None) => match (file.pii.keys, file.pii.keys_file) {
+                (Some(keys), _) => Some(keys.join(",")),
+                (None, Some(path)) => read_keys(&path, &mut errors),
+                (None, None) => None,
+            },
+        };
+        let pii = PiiConfig {
+            keys: keys.unwrap_or_default(),
+            blind_index_key: layered(flags.pii_blind_index_key, file.pii.blind_index_key),
+        };
+        if let Err(e) = FieldCipher::new(&pii) {
+            errors.push(e);
+        }
+        let anonymization_key = layered(flags.anonymization_key, file.anonymization.key);
+
+        let idempotency = IdempotencySettings {
+            ttl_seconds: flags
+                .idempotency_ttl_seconds
+                .or(file.idempotency.ttl_seconds)
+                .unwrap_or(24 * 60 * 60),
+        };
+        if idempotency.ttl_seconds <= 0 {
+            errors.push("idempotency ttl_seconds must be at least 1".to_string());
+        }
+
+        let calendar = CalendarSettings {
+            time_zone: layered(flags.calendar_time_zone, file.calendar.time_zone),
+            appointment_minutes: flags
+                .calendar_appointment_minutes
+                .or(file.calendar.appointment_minutes)
+                .unwrap_or(30),
+            uid_domain: layered(flags.calendar_uid_domain, file.calendar.uid_domain)
+                .unwrap_or_else(|| "hospital.local".to_string()),
+        };
+        if calendar.appointment_minutes == 0 {
+            errors.push("calendar appointment_minutes must be at least 1".to_string());
+        }
+
+        let fhir = FhirSettings {
+            utc_offset: utc_offset(
+                "fhir utc_offset",
+                layered(flags.fhir_utc_offset, file.fhir.utc_offset),
+                &mut errors,
+            ),
+            slot_minutes: flags
+                .fhir_slot_minutes
+                .or(file.fhir.slot_minutes)
+                .unwrap_or(30),
+        };
+        if fhir.slot_minutes <= 0 {
+            errors.push("fhir slot_minutes must be at least 1".to_string());
+        }
+
+        let grpc = GrpcSettings {
+            address: layered(flags.grpc_address, file.grpc.address)
+                .and_then(|address| loopback_address("gRPC", &address, &mut errors)),
+        };
+
+        let hl7 = Hl7Settings {
+            address: layered(flags.hl7_mllp_address, file.hl7.mllp_address)
+                .and_then(|address| loopback_address("MLLP", &address, &mut errors)),
+            application: layered(flags.hl7_application, file.hl7.application)
+                .unwrap_or_else(|| "HOSPITAL".to_string()),
+            facility: layered(flags.hl7_facility, file.hl7.facility)
+                .unwrap_or_else(|| "HOSPITAL".to_string()),
+        };
+
+        let webhooks = WebhookSettings {
+            max_attempts: flags
+                .webhook_max_attempts
+                .or(file.webhooks.max_attempts)
+                .unwrap_or(8),
+            retry_base: Duration::from_secs(
+                flags
+                    .webhook_retry_base_seconds
+                    .or(file.webhooks.retry_base_seconds)
+                    .unwrap_or(30),
+            ),
+            retry_max: Duration::from_secs(
+                flags
+                    .webhook_retry_max_seconds
+                    .or(file.webhooks.retry_max_seconds)
+                    .unwrap_or(6 * 60 * 60),
+            ),
+            timeout: Duration::from_secs(
+                flags
+                    .webhook_timeout_seconds
+                    .or(file.webhooks.timeout_seconds)
+                    .unwrap_or(10),
+            ),
+            allow_private_targets: flags
+                .webhook_allow_private_targets
+                .or(file.webhooks.allow_private_targets)
+                .unwrap_or(false),
+        };
+        if webhooks.max_attempts <= 0 {
+            errors.push("webhooks max_attempts must be at least 1".to_string());
+        }
+        if webhooks.retry_base.is_zero() || webhooks.timeout.is_zero() {
+            errors.push(
+                "webhooks retry_base_seconds and timeout_seconds must be at least 1".to_string(),
+            );
+        }
+        if webhooks.retry_max < webhooks.retry_base {
+            errors.push(
+                "webhooks retry_max_seconds must not be less than retry_base_seconds".to_string(),
+            );
+        }
+
+        let jobs = JobSettings {
+            workers: flags.job_workers.or(file.jobs.workers).unwrap_or(2),
+        };
+        if jobs.workers == 0 {
+            errors.push("jobs workers must be at least 1".to_string());
+        }
+
+        let default_locale = match layered(flags.reminder_locale, file.reminders.locale) {
+            Some(locale) => Locale::parse(&locale).unwrap_or_else(|| {
+                errors.push(format!(
+                    "reminders locale `{}` must be one of {}",
+                    locale,
+                    LOCALES.join(", ")
+                ));
+                Locale::Ru
+            }),
+            None => Locale::Ru,
+        };
+        let reminders = ReminderSettings {
+            utc_offset: utc_offset(
+                "reminders utc_offset",
+                layered(flags.reminder_utc_offset, file.reminders.utc_offset),
+                &mut errors,
+            ),
+            default_locale,
+        };
+
+        let smtp = match layered(flags.smtp_host, file.smtp.host) {
+            Some(host) => {
+                let security = match layered(flags.smtp_tls, file.smtp.tls).as_deref() {
+                    None | Some("starttls") => SmtpSecurity::StartTls,
+                    Some("tls") => SmtpSecurity::Tls,
+                    Some("none") => SmtpSecurity::Plain,
+                    Some(security) => {
+                        errors.push(format!(
+                            "smtp tls `{}` must be starttls, tls or none",
+                            security
+                        ));
+                        SmtpSecurity::StartTls
+                    }
+                };
+                let port = flags
+                    .smtp_port
+                    .or(file.smtp.port)
+                    .unwrap_or(match security {
+                        SmtpSecurity::Plain => 25,
+                        SmtpSecurity::StartTls => 587,
+                        SmtpSecurity::Tls => 465,
+                    });
+                let credentials = match (
+                    layered(flags.smtp_username, file.smtp.username),
+                    layered(flags.smtp_password, file.smtp.password),
+                ) {
+                    (Some(username), Some(password)) => Some((username, password)),
+                    (None, None) => None,
+                    _ => {
+                        errors.push("SMTP needs both a username and a password".to_string());
+                        None
+                    }
+                };
+                let from = match layered(flags.smtp_from, file.smtp.from) {
+                    Some(from) => from
+                        .parse()
+                        .map_err(|_| {
+                            errors.push(format!("smtp from `{}` is not an email address", from))
+                        })
+                        .ok(),
+                    None => {
+                        errors.push("smtp from must be set together with the host".to_string());
+                        None
+                    }
+                };
+                from.map(|from| SmtpConfig {
+                    host,
+                    port,
+                    security,
+                    credentials,
+                    from,
+                })
+            }
+            None => None,
+        };
+
+        let sms = layered(flags.sms_gateway_url, file.sms.gateway_url).map(|gateway_url| {
+            if !gateway_url.starts_with("http://") && !gateway_url.starts_with("https://") {
+                errors.push(format!(
+                    "SMS gateway URL `{}` must start with http:// or https://",
+                    gateway_url
+                ));
+            }
+            SmsConfig {
+                gateway_url,
+                gateway_token: layered(flags.sms_gateway_token, file.sms.gateway_token),
+                sender: layered(flags.sms_sender, file.sms.sender),
+            }
+        });
+
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+
+        Ok(ServerConfig {
+            bind_address,
+            workers,
+            database,
+            request_timeout: Duration::from_secs(
+                flags
+                    .request_timeout_seconds
+                    .or(file.http.request_timeout_seconds)
+                    .unwrap_or(5),
+            ),
+            keep_alive: Duration::from_secs(
+                flags
+                    .keep_alive_seconds
+                    .or(file.http.keep_alive_seconds)
+                    .unwrap_or(5),
+            ),
+            shutdown_drain: Duration::from_secs(
+                flags
+                    .shutdown_drain_seconds
+                    .or(file.http.shutdown_drain_seconds)
+                    .unwrap_or(5),
+            ),
+            shutdown_timeout: Duration::from_secs(
+                flags
+                    .shutdown_timeout_seconds
+                    .or(file.http.shutdown_timeout_seconds)
+                    .unwrap_or(30),
+            ),
+            sled_path,
+            anonymized_sled_path,
+            swagger: flags.swagger.or(file.swagger).unwrap_or(true),
+            cors,
+            tls,
+            logging,
+            pii,
+            anonymization_key,
+            concurrency: ConcurrencySettings {
+                strict_if_match: flags
+                    .strict_if_match
+                    .or(file.concurrency.strict_if_match)
+                    .unwrap_or(false),
+            },
+            idempotency,
+            calendar,
+            fhir,
+            grpc,
+            hl7,
+            webhooks,
+            jobs,
+            reminders,
+            smtp,
+            sms,
+        })
+    }
+
+    pub fn base_url(&self) -> String {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        format!("{}://{}", scheme, self.bind_address)
+    }
+}
+
+impl CorsConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+
+    /// Lets browsers send any method and header, and read the headers clients of this API need.
+    pub fn middleware(&self) -> Cors {
+        let mut cors = Cors::default()
+            .allow_any_method()
+            .allow_any_header()
+            .expose_headers([
+                header::ETAG,
+                header::LOCATION,
+                header::CONTENT_DISPOSITION,
+                HeaderName::from_static(REQUEST_ID_HEADER),
+            ])
+            .max_age(self.max_age.as_secs() as usize);
+        for origin in &self.allowed_origins {
+            cors = if origin == "*" {
+                cors.allow_any_origin()
+            } else {
+                cors.allowed_origin(origin)
+            };
+        }
+        cors
+    }
+}
+
+/// The flag or environment value, else the file value. Empty values, as those of empty
+/// environment variables, count as not set.
+fn layered(flag: Option<String>, file: Option<String>) -> Option<String> {
+    let non_empty = |value: Option<String>| value.filter(|value| !value.is_empty());
+    non_empty(flag).or(non_empty(file))
+}
+
+/// gRPC and MLLP are served without TLS or authentication, so they only listen on loopback
+/// addresses. Clients on other hosts reach them through a TLS-terminating proxy.
+fn loopback_address(protocol: &str, address: &str, errors: &mut Vec<String>) -> Option<SocketAddr> {
+    match address.parse::<SocketAddr>() {
+        Ok(address) if address.ip().is_loopback() => Some(address),
+        Ok(address) => {
+            errors.push(format!(
+                "The {} address {} must be a loopback address, {} is served without TLS",
+                protocol, address, protocol
+            ));
+            None
+        }
+        Err(_) => {
+            errors.push(format!(
+                "The {} address `{}` must look like 127.0.0.1:{}",
+                protocol,
+                address,
+                if protocol == "gRPC" { 50051 } else { 2575 }
+            ));
+            None
+        }
+    }
+}
+
+/// UTC when not set.
+fn utc_offset(name: &str, value: Option<String>, errors: &mut Vec<String>) -> UtcOffset {
+    match value {
+        Some(value) => parse_offset(&value).unwrap_or_else(|| {
+            errors.push(format!("{} `{}` must look like +03:00", name, value));
+            UtcOffset::UTC
+        }),
+        None => UtcOffset::UTC,
+    }
+}
+
+fn read_keys(path: &Path, errors: &mut Vec<String>) -> Option<String> {
+    fs::read_to_string(path)
+        .map_err(|e| {
+            errors.push(format!(
+                "Could not read the PII encryption keys file `{}`: {}",
+                path.display(),
+                e
+            ))
+        })
+        .ok()
+}
+
+fn read_file(path: &Path) -> Result<ConfigFile, String> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        format!(
+            "Could not read the configuration file `{}`: {}",
+            path.display(),
+            e
+        )
+    })?;
+    toml::from_str(&text)
+        .map_err(|e| format!("Invalid configuration file `{}`: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(args: &[&str], file: &str) -> Result<ServerConfig, String> {
+        let flags = Flags::try_parse_from(
+            [
+                "postgres_app",
+                "--database-url",
+                "postgres://localhost/hospital",
+            ]
+            .into_iter()
+            .chain(args.iter().copied()),
+        )
+        .unwrap();
+        ServerConfig::from_layers(flags, toml::from_str(file).unwrap())
+    }
+
+    #[test]
+    fn defaults_apply_without_file_or_flags() {
+        let config = load(&[], "").unwrap();
+
+        assert_eq!(
+            config.bind_address,
+            SocketAddr::from(([127, 0, 0, 1], 8080))
+        );
+        assert_eq!(config.jobs.workers, 2);
+        assert_eq!(config.webhooks.max_attempts, 8);
+        assert_eq!(config.webhooks.retry_base, Duration::from_secs(30));
+        assert!(!config.webhooks.allow_private_targets);
+        assert_eq!(config.idempotency.ttl_seconds, 24 * 60 * 60);
+        assert_eq!(config.calendar.appointment_minutes, 30);
+        assert_eq!(config.fhir.utc_offset, UtcOffset::UTC);
+        assert!(config.grpc.address.is_none());
+        assert!(config.hl7.address.is_none());
+        assert!(config.smtp.is_none());
+        assert!(config.sms.is_none());
+        assert!(config.pii.keys.is_empty());
+    }
+
+    #[test]
+    fn file_overrides_defaults_and_flags_override_file() {
+        let file = r#"
+            [jobs]
+            workers = 4
+
+            [webhooks]
+            max_attempts = 3
+            allow_private_targets = true
+
+            [fhir]
+            utc_offset = "+03:00"
+            slot_minutes = 20
+
+            [concurrency]
+            strict_if_match = false
+        "#;
+
+        let config = load(&[], file).unwrap();
+        assert_eq!(config.jobs.workers, 4);
+        assert_eq!(config.webhooks.max_attempts, 3);
+        assert!(config.webhooks.allow_private_targets);
+        assert_eq!(
+            config.fhir.utc_offset,
+            UtcOffset::from_hms(3, 0, 0).unwrap()
+        );
+        assert!(!config.concurrency.strict_if_match);
+
+        let config = load(
+            &[
+                "--job-workers",
+                "6",
+                "--fhir-slot-minutes",
+                "45",
+                "--strict-if-match",
+                "1",
+            ],
+            file,
+        )
+        .unwrap();
+        assert_eq!(config.jobs.workers, 6);
+        assert_eq!(config.fhir.slot_minutes, 45);
+        assert!(config.concurrency.strict_if_match);
+        assert_eq!(config.webhooks.max_attempts, 3);
+        assert_eq!(
+            config.fhir.utc_offset,
+            UtcOffset::from_hms(3, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn environment_overrides_file_and_flags_override_environment() {
+        // Only this test sets these variables, the others leave them alone.
+        std::env::set_var("HL7_FACILITY", "WARD");
+        std::env::set_var("CALENDAR_UID_DOMAIN", "");
+        let file = r#"
+            [hl7]
+            facility = "CLINIC"
+            application = "HIS"
+
+            [calendar]
+            uid_domain = "clinic.example"
+        "#;
+
+        let from_environment = load(&[], file);
+        let from_flags = load(&["--hl7-facility", "ICU"], file);
+        std::env::remove_var("HL7_FACILITY");
+        std::env::remove_var("CALENDAR_UID_DOMAIN");
+
+        let config = from_environment.unwrap();
+        assert_eq!(config.hl7.facility, "WARD");
+        assert_eq!(config.hl7.application, "HIS");
+        assert_eq!(config.calendar.uid_domain, "clinic.example");
+        assert_eq!(from_flags.unwrap().hl7.facility, "ICU");
+    }
+
+    #[test]
+    fn plaintext_listeners_only_bind_loopback_addresses() {
+        let config = load(
+            &[
+                "--grpc-address",
+                "127.0.0.1:50051",
+                "--hl7-mllp-address",
+                "[::1]:2575",
+            ],
+            "",
+        )
+        .unwrap();
+        assert_eq!(
+            config.grpc.address,
+            Some(SocketAddr::from(([127, 0, 0, 1], 50051)))
+        );
+        assert!(config
+            .hl7
+            .address
+            .is_some_and(|address| address.ip().is_loopback()));
+
+        let errors = load(
+            &[
+                "--grpc-address",
+                "0.0.0.0:50051",
+                "--hl7-mllp-address",
+                "10.0.0.5:2575",
+            ],
+            "",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            errors,
+            "The gRPC address 0.0.0.0:50051 must be a loopback address, gRPC is served without TLS\n\
+             The MLLP address 10.0.0.5:2575 must be a loopback address, MLLP is served without TLS"
+        );
+    }
+
+    #[test]
+    fn smtp_port_follows_the_security_unless_set() {
+        let file = r#"
+            [smtp]
+            host = "mail.hospital.local"
+            tls = "tls"
+            from = "Hospital <noreply@hospital.local>"
+        "#;
+
+        let smtp = load(&[], file).unwrap().smtp.unwrap();
+        assert_eq!(smtp.security, SmtpSecurity::Tls);
+        assert_eq!(smtp.port, 465);
+        assert!(smtp.credentials.is_none());
+
+        let smtp = load(&["--smtp-tls", "none", "--smtp-port", "2525"], file)
+            .unwrap()
+            .smtp
+            .unwrap();
+        assert_eq!(smtp.security, SmtpSecurity::Plain);
+        assert_eq!(smtp.port, 2525);
+    }
+
+    #[test]
+    fn every_invalid_setting_is_reported() {
+        let file = r#"
+            [jobs]
+            workers = 0
+
+            [reminders]
+            locale = "de"
+
+            [grpc]
+            address = "localhost"
+
+            [smtp]
+            host = "mail.hospital.local"
+            username = "hospital"
+
+            [pii]
+            keys = ["current:c2hvcnQ="]
+        "#;
+
+        let errors = load(&["--fhir-utc-offset", "3"], file).err().unwrap();
+        let errors: Vec<_> = errors.lines().collect();
+        assert_eq!(errors.len(), 7, "{:#?}", errors);
+        assert!(errors.contains(&"jobs workers must be at least 1"));
+        assert!(errors.contains(&"reminders locale `de` must be one of ru, en"));
+        assert!(errors.contains(&"The gRPC address `localhost` must look like 127.0.0.1:50051"));
+        assert!(errors.contains(&"fhir utc_offset `3` must look like +03:00"));
+        assert!(errors.contains(&"SMTP needs both a username and a password"));
+        assert!(errors.contains(&"smtp from must be set together with the host"));
+        assert!(errors.contains(&"PII key `current` must be 32 bytes long"));
+    }
+}
@@ -0,0 +1,498 @@
+use crate::config::PiiConfig;
+use crate::models::{
+    Doctor, DoctorHistoryEntry, FullScheduleEntry, Patient, PatientHistoryEntry, ReencryptResult,
+};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::{PgPool, Row};
+use tracing::warn;
+
+/// Prefix of encrypted values: `enc:<key id>:<base64 of nonce and ciphertext>`.
+const ENCRYPTED_PREFIX: &str = "enc:";
+const NONCE_LENGTH: usize = 12;
+
+/// Rows re-encrypted per transaction, so that a large table is neither loaded nor locked as a
+/// whole.
+const REENCRYPT_BATCH_SIZE: i64 = 500;
+
+/// Application-level encryption of `phone_number` and `passport_number`.
+///
+/// Keys are configured as `id:base64` pairs separated by commas or new lines. The first key
+/// encrypts new values, the rest are only used to decrypt values written before a rotation.
+/// Without keys values are stored as plaintext.
+///
+/// Encrypted values cannot be compared in SQL, so every encrypted column has a blind index
+/// column holding an HMAC of the plaintext keyed with the blind index key.
+pub struct FieldCipher {
+    keys: Vec<(String, Aes256Gcm)>,
+    blind_index_key: Vec<u8>,
+}
+
+impl FieldCipher {
+    pub fn new(config: &PiiConfig) -> Result<Self, String> {
+        let keys = config
+            .keys
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (id, key) = entry
+                    .split_once(':')
+                    .ok_or_else(|| format!("PII key `{}` must look like id:base64", entry))?;
+                let key = BASE64
+                    .decode(key)
+                    .map_err(|_| format!("PII key `{}` is not valid base64", id))?;
+                if key.len() != 32 {
+                    return Err(format!("PII key `{}` must be 32 bytes long", id));
+                }
+                Ok((
+                    id.to_string(),
+                    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+                ))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        // A repeated id would decrypt values with whichever key comes first.
+        for (position, (id, _)) in keys.iter().enumerate() {
+            if keys[..position].iter().any(|(other, _)| other == id) {
+                return Err(format!("PII key id `{}` is configured more than once", id));
+            }
+        }
+
+        let blind_index_key = match &config.blind_index_key {
+            Some(key) => BASE64
+                .decode(key.trim())
+                .map_err(|_| "The PII blind index key is not valid base64".to_string())?,
+            None if keys.is_empty() => Vec::new(),
+            None => {
+                return Err(
+                    "A PII blind index key must be set when PII encryption is enabled".into(),
+                )
+            }
+        };
+
+        Ok(FieldCipher {
+            keys,
+            blind_index_key,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub fn encrypt(&self, value: &str) -> String {
+        let (id, cipher) = match self.keys.first() {
+            Some(current) => current,
+            None => return value.to_string(),
+        };
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, value.as_bytes())
+            .expect("AES-GCM encryption does not fail for in-memory buffers");
+
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&ciphertext);
+        format!("{}{}:{}", ENCRYPTED_PREFIX, id, BASE64.encode(payload))
+    }
+
+    /// Returns plaintext values unchanged, so rows written before encryption was enabled
+    /// keep working until they are re-encrypted.
+    pub fn decrypt(&self, value: &str) -> String {
+        match self.try_decrypt(value) {
+            Some(plaintext) => plaintext,
+            None => {
+                warn!("Could not decrypt a PII value, is its key configured?");
+                value.to_string()
+            }
+        }
+    }
+
+    pub fn try_decrypt(&self, value: &str) -> Option<String> {
+        let encrypted = match value.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(encrypted) => encrypted,
+            None => return Some(value.to_string()),
+        };
+
+        let (id, payload) = encrypted.split_once(':')?;
+        let (_, cipher) = self.keys.iter().find(|(key_id, _)| key_id == id)?;
+        let payload = BASE64.decode(payload).ok()?;
+        if payload.len() < NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LENGTH);
+        let plaintext = cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+
+    /// Tells whether a stored value is already in the form `encrypt` would produce now.
+    pub fn is_current(&self, value: &str) -> bool {
+        match self.keys.first() {
+            Some((id, _)) => value.starts_with(&format!("{}{}:", ENCRYPTED_PREFIX, id)),
+            None => !value.starts_with(ENCRYPTED_PREFIX),
+        }
+    }
+
+    pub fn blind_index(&self, value: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.blind_index_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(value.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Blind index for an optional query filter, where an empty value means "no filter".
+    pub fn blind_index_filter(&self, value: &Option<String>) -> Option<String> {
+        value
+            .as_deref()
+            .filter(|value| !value.is_empty())
+            .map(|value| self.blind_index(value))
+    }
+
+    pub fn decrypt_patient(&self, mut patient: Patient) -> Patient {
+        patient.phone_number = self.decrypt(&patient.phone_number);
+        patient.passport_number = self.decrypt(&patient.passport_number);
+        patient
+    }
+
+    pub fn decrypt_patient_history(&self, mut entry: PatientHistoryEntry) -> PatientHistoryEntry {
+        entry.phone_number = self.decrypt(&entry.phone_number);
+        entry.passport_number = self.decrypt(&entry.passport_number);
+        entry
+    }
+
+    pub fn decrypt_doctor(&self, mut doctor: Doctor) -> Doctor {
+        doctor.phone_number = self.decrypt(&doctor.phone_number);
+        doctor.passport_number = self.decrypt(&doctor.passport_number);
+        doctor
+    }
+
+    pub fn decrypt_doctor_history(&self, mut entry: DoctorHistoryEntry) -> DoctorHistoryEntry {
+        entry.phone_number = self.decrypt(&entry.phone_number);
+        entry.passport_number = self.decrypt(&entry.passport_number);
+        entry
+    }
+
+    pub fn decrypt_schedule_entry(&self, mut entry: FullScheduleEntry) -> FullScheduleEntry {
+        entry.doctor_phone_number = self.decrypt(&entry.doctor_phone_number);
+        entry.doctor_passport_number = self.decrypt(&entry.doctor_passport_number);
+        entry.patient_phone_number = self.decrypt(&entry.patient_phone_number);
+        entry.patient_passport_number = self.decrypt(&entry.patient_passport_number);
+        entry
+    }
+}
+
+/// Re-encrypts live rows, their history and stored idempotent responses that are not
+/// encrypted with the current key, and recomputes blind indexes that are missing or were made
+/// with another blind index key.
+pub async fn reencrypt_outdated(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+) -> Result<ReencryptResult, sqlx::Error> {
+    // Live rows go first, the history versions their rewrite closes are re-encrypted after.
+    let patients_updated = reencrypt_table(pool, cipher, "patients", "id", true).await?;
+    let doctors_updated = reencrypt_table(pool, cipher, "doctors", "id", true).await?;
+    let history_entries_updated =
+        reencrypt_table(pool, cipher, "patients_history", "history_id", false).await?
+            + reencrypt_table(pool, cipher, "doctors_history", "history_id", false).await?;
+    let stored_responses_updated = reencrypt_stored_responses(pool, cipher).await?;
+
+    Ok(ReencryptResult {
+        patients_updated,
+        doctors_updated,
+        history_entries_updated,
+        stored_responses_updated,
+    })
+}
+
+/// Re-encrypts the phone and passport numbers of a table batch by batch, each batch in its
+/// own transaction. `indexed` tables also get blind indexes that differ from the HMAC under the
+/// current blind index key recomputed, which needs every row decrypted.
+async fn reencrypt_table(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    table: &'static str,
+    key: &'static str,
+    indexed: bool,
+) -> Result<u64, sqlx::Error> {
+    let index_columns = if indexed {
+        ", phone_number_index, passport_number_index"
+    } else {
+        ""
+    };
+    let select = format!(
+        "SELECT {key}, phone_number, passport_number{index_columns} FROM {table}
+        WHERE {key} > $1
+        ORDER BY {key}
+        LIMIT $2
+        FOR UPDATE"
+    );
+    let update = if indexed {
+        format!(
+            "UPDATE {table}
+            SET phone_number = $2, passport_number = $3,
+                phone_number_index = $4, passport_number_index = $5
+            WHERE {key} = $1"
+        )
+    } else {
+        format!("UPDATE {table} SET phone_number = $2, passport_number = $3 WHERE {key} = $1")
+    };
+
+    let mut updated = 0;
+    let mut last_key = 0;
+    loop {
+        let mut transaction = pool.begin().await?;
+        sqlx::query!("SELECT set_config('hospital.history_operation', 'REENCRYPT', true)")
+            .fetch_one(&mut *transaction)
+            .await?;
+
+        let rows = sqlx::query(&select)
+            .bind(last_key)
+            .bind(REENCRYPT_BATCH_SIZE)
+            .fetch_all(&mut *transaction)
+            .await?;
+        for row in &rows {
+            let id: i32 = row.try_get(key)?;
+            let phone_number: String = row.try_get("phone_number")?;
+            let passport_number: String = row.try_get("passport_number")?;
+            let current = cipher.is_current(&phone_number) && cipher.is_current(&passport_number);
+            if current && !indexed {
+                continue;
+            }
+
+            let (phone_number, passport_number) = match (
+                cipher.try_decrypt(&phone_number),
+                cipher.try_decrypt(&passport_number),
+            ) {
+                (Some(phone_number), Some(passport_number)) => (phone_number, passport_number),
+                _ => {
+                    warn!(table, id, "Could not decrypt PII of a row, skipping it");
+                    continue;
+                }
+            };
+            if current
+                && row.try_get::<Option<String>, _>("phone_number_index")?
+                    == Some(cipher.blind_index(&phone_number))
+                && row.try_get::<Option<String>, _>("passport_number_index")?
+                    == Some(cipher.blind_index(&passport_number))
+            {
+                continue;
+            }
+
+            let mut query = sqlx::query(&update)
+                .bind(id)
+                .bind(cipher.encrypt(&phone_number))
+                .bind(cipher.encrypt(&passport_number));
+            if indexed {
+                query = query
+                    .bind(cipher.blind_index(&phone_number))
+                    .bind(cipher.blind_index(&passport_number));
+            }
+            query.execute(&mut *transaction).await?;
+            updated += 1;
+        }
+        transaction.commit().await?;
+
+        match rows.last() {
+            Some(row) if rows.len() as i64 == REENCRYPT_BATCH_SIZE => {
+                last_key = row.try_get(key)?
+            }
+            _ => return Ok(updated),
+        }
+    }
+}
+
+/// Re-encrypts responses kept for `Idempotency-Key` retries, including any stored before
+/// encryption was enabled.
+async fn reencrypt_stored_responses(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+) -> Result<u64, sqlx::Error> {
+    let mut updated = 0;
+    let (mut method, mut path, mut key) = (String::new(), String::new(), String::new());
+    loop {
+        let mut transaction = pool.begin().await?;
+        let rows = sqlx::query!(
+            r#"SELECT method, path, key, body as "body!" FROM idempotency_keys
+            WHERE body IS NOT NULL AND (method, path, key) > ($1, $2, $3)
+            ORDER BY method, path, key
+            LIMIT $4
+            FOR UPDATE"#,
+            method,
+            path,
+            key,
+            REENCRYPT_BATCH_SIZE,
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        let batch_size = rows.len() as i64;
+        for row in rows {
+            if !cipher.is_current(&row.body) {
+                match cipher.try_decrypt(&row.body) {
+                    Some(body) => {
+                        sqlx::query!(
+                            "UPDATE idempotency_keys SET body = $4
+                            WHERE method = $1 AND path = $2 AND key = $3",
+                            row.method,
+                            row.path,
+                            row.key,
+                            cipher.encrypt(&body),
+                        )
+                        .execute(&mut *transaction)
+                        .await?;
+                        updated += 1;
+                    }
+                    None => warn!("Could not decrypt a stored response, skipping it"),
+                }
+            }
+            (method, path, key) = (row.method, row.path, row.key);
+        }
+        transaction.commit().await?;
+
+        if batch_size < REENCRYPT_BATCH_SIZE {
+            return Ok(updated);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> String {
+        BASE64.encode([byte; 32])
+    }
+
+    fn cipher(keys: &[(&str, u8)], blind_index_key: u8) -> FieldCipher {
+        let keys = keys
+            .iter()
+            .map(|(id, byte)| format!("{}:{}", id, key(*byte)))
+            .collect::<Vec<_>>()
+            .join(",");
+        FieldCipher::new(&PiiConfig {
+            keys,
+            blind_index_key: Some(key(blind_index_key)),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let cipher = cipher(&[("2026", 1)], 9);
+        let encrypted = cipher.encrypt("+7 900 000-00-00");
+        assert!(encrypted.starts_with("enc:2026:"));
+        assert_ne!(encrypted, cipher.encrypt("+7 900 000-00-00"));
+        assert_eq!(cipher.try_decrypt(&encrypted).unwrap(), "+7 900 000-00-00");
+        assert!(cipher.is_current(&encrypted));
+    }
+
+    #[test]
+    fn values_under_an_old_key_decrypt_but_are_not_current() {
+        let encrypted = cipher(&[("2025", 1)], 9).encrypt("4510 123456");
+        let rotated = cipher(&[("2026", 2), ("2025", 1)], 9);
+        assert_eq!(rotated.try_decrypt(&encrypted).unwrap(), "4510 123456");
+        assert!(!rotated.is_current(&encrypted));
+        assert!(rotated.is_current(&rotated.encrypt("4510 123456")));
+    }
+
+    #[test]
+    fn tampered_values_are_rejected() {
+        let cipher = cipher(&[("2026", 1)], 9);
+        let encrypted = cipher.encrypt("4510 123456");
+        let payload = encrypted.strip_prefix("enc:2026:").unwrap();
+        let mut bytes = BASE64.decode(payload).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(cipher
+            .try_decrypt(&format!("enc:2026:{}", BASE64.encode(bytes)))
+            .is_none());
+        assert!(cipher.try_decrypt("enc:2026:AAAA").is_none());
+        assert!(cipher.try_decrypt("enc:2024:AAAA").is_none());
+    }
+
+    #[test]
+    fn plaintext_passes_through() {
+        let cipher = FieldCipher::new(&PiiConfig::default()).unwrap();
+        assert!(!cipher.is_enabled());
+        assert_eq!(cipher.encrypt("4510 123456"), "4510 123456");
+        assert!(cipher.is_current("4510 123456"));
+        assert!(!cipher.is_current("enc:2026:AAAA"));
+    }
+
+    #[test]
+    fn invalid_key_lists_are_rejected() {
+        let config = |keys: String, blind_index_key: Option<String>| PiiConfig {
+            keys,
+            blind_index_key,
+        };
+        let duplicate = config(format!("a:{},a:{}", key(1), key(2)), Some(key(9)));
+        assert_eq!(
+            FieldCipher::new(&duplicate).err().unwrap(),
+            "PII key id `a` is configured more than once"
+        );
+        assert!(FieldCipher::new(&config(format!("a:{}", key(1)), None)).is_err());
+        assert!(FieldCipher::new(&config("a:AAAA".into(), Some(key(9)))).is_err());
+        assert!(FieldCipher::new(&config("a".into(), Some(key(9)))).is_err());
+    }
+
+    #[sqlx::test]
+    async fn reencryption_follows_key_and_blind_index_key_rotations(pool: PgPool) {
+        let old = cipher(&[("2025", 1)], 8);
+        let id = sqlx::query_scalar!(
+            "INSERT INTO patients
+            (name, surname, birth_date, phone_number, passport_number,
+                phone_number_index, passport_number_index)
+            VALUES ('Anna', 'Petrova', '1990-04-12', $1, $2, $3, $4)
+            RETURNING id",
+            old.encrypt("+7 900 000-00-00"),
+            old.encrypt("4510 123456"),
+            old.blind_index("+7 900 000-00-00"),
+            old.blind_index("4510 123456"),
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        // Same encryption keys, only the blind index key changes.
+        let reindexed = cipher(&[("2025", 1)], 9);
+        let result = reencrypt_outdated(&pool, &reindexed).await.unwrap();
+        assert_eq!(result.patients_updated, 1);
+        let row = sqlx::query!(
+            "SELECT phone_number, phone_number_index, passport_number_index
+            FROM patients WHERE id = $1",
+            id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            row.phone_number_index.unwrap(),
+            reindexed.blind_index("+7 900 000-00-00")
+        );
+        assert_eq!(
+            row.passport_number_index.unwrap(),
+            reindexed.blind_index("4510 123456")
+        );
+        assert_eq!(
+            reindexed.try_decrypt(&row.phone_number).unwrap(),
+            "+7 900 000-00-00"
+        );
+
+        let result = reencrypt_outdated(&pool, &reindexed).await.unwrap();
+        assert_eq!(result.patients_updated, 0);
+
+        let rotated = cipher(&[("2026", 2), ("2025", 1)], 9);
+        let result = reencrypt_outdated(&pool, &rotated).await.unwrap();
+        assert_eq!(result.patients_updated, 1);
+        let phone_number =
+            sqlx::query_scalar!("SELECT phone_number FROM patients WHERE id = $1", id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert!(phone_number.starts_with("enc:2026:"));
+    }
+}
@@ -0,0 +1,496 @@
+use crate::crypto::FieldCipher;
+use crate::models::{CsvImportOptions, CsvRowError};
+use actix_multipart::Multipart;
+use actix_web::web::Bytes;
+use csv_core::ReadRecordResult;
+use futures_util::stream::{BoxStream, LocalBoxStream};
+use futures_util::StreamExt;
+use sqlx::PgConnection;
+use std::collections::HashMap;
+
+/// Name of the multipart field holding the uploaded file.
+pub const FILE_FIELD: &str = "file";
+
+pub const PATIENT_COLUMNS: &[&str] = &[
+    "name",
+    "surname",
+    "birth_date",
+    "phone_number",
+    "passport_number",
+];
+pub const DOCTOR_COLUMNS: &[&str] = &[
+    "name",
+    "surname",
+    "speciality",
+    "phone_number",
+    "passport_number",
+];
+pub const TICKET_COLUMNS: &[&str] = &["date", "time", "office_number"];
+pub const SCHEDULE_COLUMNS: &[&str] = &["ticket_id", "doctor_id", "patient_id"];
+
+/// Spellings of column names that are recognised without an explicit mapping.
+const ALIASES: &[(&str, &str)] = &[
+    ("first_name", "name"),
+    ("имя", "name"),
+    ("last_name", "surname"),
+    ("фамилия", "surname"),
+    ("date_of_birth", "birth_date"),
+    ("birthday", "birth_date"),
+    ("дата_рождения", "birth_date"),
+    ("phone", "phone_number"),
+    ("телефон", "phone_number"),
+    ("passport", "passport_number"),
+    ("паспорт", "passport_number"),
+    ("specialty", "speciality"),
+    ("специальность", "speciality"),
+    ("дата", "date"),
+    ("время", "time"),
+    ("office", "office_number"),
+    ("кабинет", "office_number"),
+    ("ticket", "ticket_id"),
+    ("doctor", "doctor_id"),
+    ("patient", "patient_id"),
+];
+
+fn normalize_header(header: &str) -> String {
+    header
+        .trim_start_matches('\u{feff}')
+        .trim()
+        .to_lowercase()
+        .replace([' ', '-'], "_")
+}
+
+/// Header line of an export: `id` followed by `columns`.
+pub fn header_line(columns: &[&str]) -> Bytes {
+    let header: Vec<&str> = std::iter::once("id")
+        .chain(columns.iter().copied())
+        .collect();
+    csv_line(&header)
+}
+
+/// Encodes one CSV line, quoting fields where needed.
+pub fn csv_line(fields: &[&str]) -> Bytes {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    writer
+        .write_record(fields)
+        .expect("Writing CSV to memory does not fail");
+    Bytes::from(writer.into_inner().expect("Flushing memory does not fail"))
+}
+
+/// Splits a byte stream into CSV records as chunks arrive, so that uploads are never held
+/// in memory as a whole.
+struct CsvDecoder {
+    reader: csv_core::Reader,
+    input: Vec<u8>,
+    consumed: usize,
+    output: Vec<u8>,
+    output_len: usize,
+    ends: Vec<usize>,
+    ends_len: usize,
+}
+
+impl CsvDecoder {
+    fn new(delimiter: u8) -> Self {
+        CsvDecoder {
+            reader: csv_core::ReaderBuilder::new().delimiter(delimiter).build(),
+            input: Vec::new(),
+            consumed: 0,
+            output: vec![0; 1024],
+            output_len: 0,
+            ends: vec![0; 16],
+            ends_len: 0,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        self.input.drain(..self.consumed);
+        self.consumed = 0;
+        self.input.extend_from_slice(chunk);
+    }
+
+    /// Returns the next complete record, or `None` when more input is needed.
+    /// Once `eof` is set, `None` means that there are no records left.
+    fn next_record(&mut self, eof: bool) -> Option<Vec<Vec<u8>>> {
+        loop {
+            let (result, nin, nout, nend) = self.reader.read_record(
+                &self.input[self.consumed..],
+                &mut self.output[self.output_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            self.consumed += nin;
+            self.output_len += nout;
+            self.ends_len += nend;
+
+            match result {
+                ReadRecordResult::InputEmpty if eof && self.consumed == self.input.len() => {
+                    // An empty input tells the reader that the stream has ended.
+                    continue;
+                }
+                ReadRecordResult::InputEmpty => return None,
+                ReadRecordResult::OutputFull => {
+                    let len = self.output.len();
+                    self.output.resize(len * 2, 0);
+                }
+                ReadRecordResult::OutputEndsFull => {
+                    let len = self.ends.len();
+                    self.ends.resize(len * 2, 0);
+                }
+                ReadRecordResult::Record => {
+                    let mut start = 0;
+                    let fields = self.ends[..self.ends_len]
+                        .iter()
+                        .map(|&end| {
+                            let field = self.output[start..end].to_vec();
+                            start = end;
+                            field
+                        })
+                        .collect();
+                    self.output_len = 0;
+                    self.ends_len = 0;
+                    return Some(fields);
+                }
+                ReadRecordResult::End => return None,
+            }
+        }
+    }
+}
+
+/// One data row of an upload, keyed by column name.
+pub struct CsvRow {
+    pub row: u64,
+    values: HashMap<&'static str, String>,
+}
+
+impl CsvRow {
+    pub fn required(&self, column: &str) -> Result<String, String> {
+        match self.values.get(column).map(|value| value.trim()) {
+            Some(value) if !value.is_empty() => Ok(value.to_string()),
+            _ => Err(format!("`{}` is required", column)),
+        }
+    }
+
+    pub fn required_int(&self, column: &str) -> Result<i32, String> {
+        let value = self.required(column)?;
+        value
+            .parse()
+            .map_err(|_| format!("`{}` must be an integer, got `{}`", column, value))
+    }
+
+    /// The `id` column is optional: rows with an id update that entry, rows without one are
+    /// inserted as new entries.
+    pub fn id(&self) -> Result<Option<i32>, String> {
+        match self.values.get("id").map(|value| value.trim()) {
+            Some(value) if !value.is_empty() => value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("`id` must be an integer, got `{}`", value)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// The file field of a multipart upload, as it arrives.
+pub async fn file_field(
+    mut multipart: Multipart,
+) -> Result<LocalBoxStream<'static, Result<Bytes, String>>, String> {
+    let mut field = loop {
+        match multipart.next().await {
+            Some(Ok(field)) if field.name() == Some(FILE_FIELD) => break field,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(format!("Invalid multipart upload: {}", e)),
+            None => return Err(format!("Multipart field `{}` is missing", FILE_FIELD)),
+        }
+    };
+
+    Ok(Box::pin(async_stream::stream! {
+        // Keeps the multipart stream alive while its field is read.
+        let _multipart = multipart;
+        while let Some(chunk) = field.next().await {
+            yield chunk.map_err(|e| format!("Failed to read the upload: {}", e));
+        }
+    }))
+}
+
+/// Reads a CSV file row by row.
+pub struct CsvUpload<'a> {
+    chunks: BoxStream<'a, Result<Bytes, String>>,
+    decoder: CsvDecoder,
+    eof: bool,
+    columns: Vec<Option<&'static str>>,
+    pub ignored_columns: Vec<String>,
+    row: u64,
+}
+
+impl<'a> CsvUpload<'a> {
+    /// Maps the header row to `columns`. Headers are matched by name, by a known alias, or by
+    /// the `mapping` option (`CSV header:column` pairs separated by commas). An `id` column is
+    /// always accepted.
+    pub async fn open(
+        chunks: BoxStream<'a, Result<Bytes, String>>,
+        columns: &'static [&'static str],
+        options: &CsvImportOptions,
+    ) -> Result<Self, String> {
+        let delimiter = match options.delimiter.as_deref() {
+            None | Some("") => b',',
+            Some("\\t") | Some("tab") => b'\t',
+            Some(delimiter) if delimiter.len() == 1 => delimiter.as_bytes()[0],
+            Some(_) => return Err("`delimiter` must be a single character".to_string()),
+        };
+
+        let mut mapping = HashMap::new();
+        for pair in options
+            .mapping
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+        {
+            let (header, column) = pair
+                .rsplit_once(':')
+                .ok_or_else(|| format!("Mapping `{}` must look like header:column", pair))?;
+            let column = normalize_header(column);
+            let column = std::iter::once("id")
+                .chain(columns.iter().copied())
+                .find(|known| *known == column)
+                .ok_or_else(|| format!("Unknown column `{}` in mapping", column))?;
+            mapping.insert(normalize_header(header), column);
+        }
+
+        let mut upload = CsvUpload {
+            chunks,
+            decoder: CsvDecoder::new(delimiter),
+            eof: false,
+            columns: Vec::new(),
+            ignored_columns: Vec::new(),
+            row: 0,
+        };
+
+        let header = match upload.next_record().await? {
+            Some(header) => header,
+            None => return Err("The file is empty".to_string()),
+        };
+        for header in header {
+            let header = String::from_utf8_lossy(&header).to_string();
+            let normalized = normalize_header(&header);
+            let column = mapping.get(&normalized).copied().or_else(|| {
+                let name = ALIASES
+                    .iter()
+                    .find(|(alias, _)| *alias == normalized)
+                    .map_or(normalized.as_str(), |(_, column)| column);
+                std::iter::once("id")
+                    .chain(columns.iter().copied())
+                    .find(|column| *column == name)
+            });
+            if column.is_none() {
+                upload.ignored_columns.push(header);
+            }
+            upload.columns.push(column);
+        }
+
+        let missing: Vec<&str> = columns
+            .iter()
+            .copied()
+            .filter(|column| !upload.columns.contains(&Some(column)))
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("Missing columns: {}", missing.join(", ")));
+        }
+
+        Ok(upload)
+    }
+
+    async fn next_record(&mut self) -> Result<Option<Vec<Vec<u8>>>, String> {
+        loop {
+            if let Some(record) = self.decoder.next_record(self.eof) {
+                self.row += 1;
+                return Ok(Some(record));
+            }
+            if self.eof {
+                return Ok(None);
+            }
+            match self.chunks.next().await {
+                Some(Ok(chunk)) => self.decoder.push(&chunk),
+                Some(Err(message)) => return Err(message),
+                None => self.eof = true,
+            }
+        }
+    }
+
+    /// Returns the next data row. Rows numbers count the header as row 1, like spreadsheets do.
+    pub async fn next_row(&mut self) -> Result<Option<Result<CsvRow, CsvRowError>>, String> {
+        let record = match self.next_record().await? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+
+        let mut values = HashMap::new();
+        for (column, value) in self.columns.iter().zip(record) {
+            let column = match column {
+                Some(column) => *column,
+                None => continue,
+            };
+            match String::from_utf8(value) {
+                Ok(value) => {
+                    values.insert(column, value);
+                }
+                Err(_) => {
+                    return Ok(Some(Err(CsvRowError {
+                        row: self.row,
+                        message: format!("`{}` is not valid UTF-8", column),
+                    })))
+                }
+            }
+        }
+
+        Ok(Some(Ok(CsvRow {
+            row: self.row,
+            values,
+        })))
+    }
+}
+
+/// Rows inserted with explicit ids do not advance the id sequence. Sequences are not
+/// transactional, so this only ever moves it forward.
+pub async fn advance_id_sequence(
+    connection: &mut PgConnection,
+    table: &'static str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "SELECT setval(pg_get_serial_sequence('{0}', 'id'), MAX(id))
+        FROM {0}
+        HAVING MAX(id) > COALESCE(pg_sequence_last_value(pg_get_serial_sequence('{0}', 'id')::regclass), 0)",
+        table
+    ))
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+pub fn database_error(error: sqlx::Error) -> String {
+    match error {
+        sqlx::Error::Database(error) => error.message().to_string(),
+        error => error.to_string(),
+    }
+}
+
+pub async fn import_patient_row(
+    connection: &mut PgConnection,
+    cipher: &FieldCipher,
+    row: CsvRow,
+) -> Result<(), String> {
+    let id = row.id()?;
+    let name = row.required("name")?;
+    let surname = row.required("surname")?;
+    let birth_date = row.required("birth_date")?;
+    let phone_number = row.required("phone_number")?;
+    let passport_number = row.required("passport_number")?;
+
+    sqlx::query!(
+        "INSERT INTO patients (id, name, surname, birth_date, phone_number, passport_number, phone_number_index, passport_number_index)
+        VALUES (COALESCE($1, nextval(pg_get_serial_sequence('patients', 'id'))::INT), $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (id) DO UPDATE SET
+            name = EXCLUDED.name, surname = EXCLUDED.surname, birth_date = EXCLUDED.birth_date,
+            phone_number = EXCLUDED.phone_number, passport_number = EXCLUDED.passport_number,
+            phone_number_index = EXCLUDED.phone_number_index,
+            passport_number_index = EXCLUDED.passport_number_index",
+        id,
+        name,
+        surname,
+        birth_date,
+        cipher.encrypt(&phone_number),
+        cipher.encrypt(&passport_number),
+        cipher.blind_index(&phone_number),
+        cipher.blind_index(&passport_number),
+    )
+    .execute(connection)
+    .await
+    .map_err(database_error)?;
+
+    Ok(())
+}
+
+pub async fn import_doctor_row(
+    connection: &mut PgConnection,
+    cipher: &FieldCipher,
+    row: CsvRow,
+) -> Result<(), String> {
+    let id = row.id()?;
+    let name = row.required("name")?;
+    let surname = row.required("surname")?;
+    let speciality = row.required("speciality")?;
+    let phone_number = row.required("phone_number")?;
+    let passport_number = row.required("passport_number")?;
+
+    sqlx::query!(
+        "INSERT INTO doctors (id, name, surname, speciality, phone_number, passport_number, phone_number_index, passport_number_index)
+        VALUES (COALESCE($1, nextval(pg_get_serial_sequence('doctors', 'id'))::INT), $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (id) DO UPDATE SET
+            name = EXCLUDED.name, surname = EXCLUDED.surname, speciality = EXCLUDED.speciality,
+            phone_number = EXCLUDED.phone_number, passport_number = EXCLUDED.passport_number,
+            phone_number_index = EXCLUDED.phone_number_index,
+            passport_number_index = EXCLUDED.passport_number_index",
+        id,
+        name,
+        surname,
+        speciality,
+        cipher.encrypt(&phone_number),
+        cipher.encrypt(&passport_number),
+        cipher.blind_index(&phone_number),
+        cipher.blind_index(&passport_number),
+    )
+    .execute(connection)
+    .await
+    .map_err(database_error)?;
+
+    Ok(())
+}
+
+pub async fn import_ticket_row(connection: &mut PgConnection, row: CsvRow) -> Result<(), String> {
+    let id = row.id()?;
+    let date = row.required("date")?;
+    let time = row.required("time")?;
+    let office_number = row.required_int("office_number")?;
+
+    sqlx::query!(
+        "INSERT INTO tickets (id, date, time, office_number)
+        VALUES (COALESCE($1, nextval(pg_get_serial_sequence('tickets', 'id'))::INT), $2, $3, $4)
+        ON CONFLICT (id) DO UPDATE SET
+            date = EXCLUDED.date, time = EXCLUDED.time, office_number = EXCLUDED.office_number",
+        id,
+        date,
+        time,
+        office_number,
+    )
+    .execute(connection)
+    .await
+    .map_err(database_error)?;
+
+    Ok(())
+}
+
+pub async fn import_schedule_row(connection: &mut PgConnection, row: CsvRow) -> Result<(), String> {
+    let id = row.id()?;
+    let ticket_id = row.required_int("ticket_id")?;
+    let doctor_id = row.required_int("doctor_id")?;
+    let patient_id = row.required_int("patient_id")?;
+
+    sqlx::query!(
+        "INSERT INTO schedule (id, ticket_id, doctor_id, patient_id)
+        VALUES (COALESCE($1, nextval(pg_get_serial_sequence('schedule', 'id'))::INT), $2, $3, $4)
+        ON CONFLICT (id) DO UPDATE SET
+            ticket_id = EXCLUDED.ticket_id, doctor_id = EXCLUDED.doctor_id,
+            patient_id = EXCLUDED.patient_id",
+        id,
+        ticket_id,
+        doctor_id,
+        patient_id,
+    )
+    .execute(connection)
+    .await
+    .map_err(database_error)?;
+
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use crate::crypto::FieldCipher;
+use crate::models::{Doctor, NewDoctor, OptionDoctor};
+use sqlx::PgPool;
+
+/// Registers a doctor. Returns them decrypted, with the version of the row.
+pub async fn create(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    new_doctor: &NewDoctor,
+) -> Result<(Doctor, i32), sqlx::Error> {
+    let row = sqlx::query!(
+        "INSERT INTO doctors (name, surname, speciality, phone_number, passport_number, phone_number_index, passport_number_index)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id, name, surname, speciality, phone_number, passport_number, version",
+        new_doctor.name,
+        new_doctor.surname,
+        new_doctor.speciality,
+        cipher.encrypt(&new_doctor.phone_number),
+        cipher.encrypt(&new_doctor.passport_number),
+        cipher.blind_index(&new_doctor.phone_number),
+        cipher.blind_index(&new_doctor.passport_number),
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let doctor = Doctor {
+        id: row.id,
+        name: row.name,
+        surname: row.surname,
+        speciality: row.speciality,
+        phone_number: row.phone_number,
+        passport_number: row.passport_number,
+    };
+    Ok((cipher.decrypt_doctor(doctor), row.version))
+}
+
+/// Changes the given fields of a doctor whose version is one of `expected_versions`, or of
+/// any version without them. `None` when no such doctor was found.
+pub async fn update(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    id: i32,
+    option_doctor: &OptionDoctor,
+    expected_versions: Option<&[i32]>,
+) -> Result<Option<(Doctor, i32)>, sqlx::Error> {
+    let row = sqlx::query!(
+        "UPDATE doctors
+        SET name = COALESCE($2, name),
+            surname = COALESCE($3, surname),
+            speciality = COALESCE($4, speciality),
+            phone_number = COALESCE($5, phone_number),
+            passport_number = COALESCE($6, passport_number),
+            phone_number_index = COALESCE($8, phone_number_index),
+            passport_number_index = COALESCE($9, passport_number_index)
+        WHERE id = $1 AND ($7::INT[] IS NULL OR version = ANY($7))
+        RETURNING id, name, surname, speciality, phone_number, passport_number, version",
+        id,
+        option_doctor.name,
+        option_doctor.surname,
+        option_doctor.speciality,
+        option_doctor
+            .phone_number
+            .as_deref()
+            .map(|value| cipher.encrypt(value)),
+        option_doctor
+            .passport_number
+            .as_deref()
+            .map(|value| cipher.encrypt(value)),
+        expected_versions,
+        option_doctor
+            .phone_number
+            .as_deref()
+            .map(|value| cipher.blind_index(value)),
+        option_doctor
+            .passport_number
+            .as_deref()
+            .map(|value| cipher.blind_index(value)),
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| {
+        let doctor = Doctor {
+            id: row.id,
+            name: row.name,
+            surname: row.surname,
+            speciality: row.speciality,
+            phone_number: row.phone_number,
+            passport_number: row.passport_number,
+        };
+        (cipher.decrypt_doctor(doctor), row.version)
+    }))
+}
+
+/// Deletes a doctor whose version is one of `expected_versions`, or of any version without
+/// them. `false` when no such doctor was found.
+pub async fn delete(
+    pool: &PgPool,
+    id: i32,
+    expected_versions: Option<&[i32]>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM doctors WHERE id = $1 AND ($2::INT[] IS NULL OR version = ANY($2))",
+        id,
+        expected_versions,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
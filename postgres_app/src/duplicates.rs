@@ -0,0 +1,249 @@
+use crate::models::{DuplicateCandidate, Patient};
+use std::collections::{BTreeSet, HashMap};
+
+pub const DEFAULT_MIN_SCORE: f64 = 0.6;
+
+const PASSPORT_WEIGHT: f64 = 0.4;
+const PHONE_WEIGHT: f64 = 0.2;
+const NAME_WEIGHT: f64 = 0.25;
+const BIRTH_DATE_WEIGHT: f64 = 0.15;
+
+/// Names at least this similar are reported as a name match.
+const NAME_MATCH_THRESHOLD: f64 = 0.9;
+
+/// Patients sharing a value are compared pairwise, so a value shared by more patients than
+/// this, as a common surname or birth date, is skipped as a block. Its members are still
+/// compared through their other values.
+const MAX_BLOCK_SIZE: usize = 100;
+
+fn normalize_passport(passport_number: &str) -> String {
+    passport_number
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+/// Keeps the last ten digits, so that "+7 (999) 123-45-67" and "89991234567" are the same phone.
+fn normalize_phone(phone_number: &str) -> String {
+    let digits: Vec<char> = phone_number.chars().filter(char::is_ascii_digit).collect();
+    digits[digits.len().saturating_sub(10)..].iter().collect()
+}
+
+fn full_name(patient: &Patient) -> String {
+    format!("{} {}", patient.name.trim(), patient.surname.trim()).to_lowercase()
+}
+
+fn same_non_empty(a: &str, b: &str) -> bool {
+    !a.is_empty() && a == b
+}
+
+/// Scores how likely two patients are the same person, from 0 to 1.
+pub fn score(a: &Patient, b: &Patient) -> (f64, Vec<String>) {
+    let mut score = 0.0;
+    let mut matched_on = Vec::new();
+
+    if same_non_empty(
+        &normalize_passport(&a.passport_number),
+        &normalize_passport(&b.passport_number),
+    ) {
+        score += PASSPORT_WEIGHT;
+        matched_on.push("passport_number".to_string());
+    }
+
+    if same_non_empty(
+        &normalize_phone(&a.phone_number),
+        &normalize_phone(&b.phone_number),
+    ) {
+        score += PHONE_WEIGHT;
+        matched_on.push("phone_number".to_string());
+    }
+
+    let name_similarity = strsim::jaro_winkler(&full_name(a), &full_name(b));
+    score += NAME_WEIGHT * name_similarity;
+    if name_similarity >= NAME_MATCH_THRESHOLD {
+        matched_on.push("name".to_string());
+    }
+
+    if same_non_empty(a.birth_date.trim(), b.birth_date.trim()) {
+        score += BIRTH_DATE_WEIGHT;
+        matched_on.push("birth_date".to_string());
+    }
+
+    (score, matched_on)
+}
+
+/// Finds likely duplicate pairs, best matches first.
+///
+/// Only patients sharing a passport, phone, birth date or surname are compared. Comparisons
+/// within a block are pairwise, and blocks are capped at `MAX_BLOCK_SIZE`, so the search makes
+/// at most `4 * MAX_BLOCK_SIZE` comparisons per patient.
+pub fn find_duplicates(patients: &[Patient], min_score: f64) -> Vec<DuplicateCandidate> {
+    let mut blocks: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, patient) in patients.iter().enumerate() {
+        let keys = [
+            ("passport", normalize_passport(&patient.passport_number)),
+            ("phone", normalize_phone(&patient.phone_number)),
+            ("birth_date", patient.birth_date.trim().to_string()),
+            ("surname", patient.surname.trim().to_lowercase()),
+        ];
+        for (kind, value) in keys {
+            if !value.is_empty() {
+                blocks
+                    .entry(format!("{}:{}", kind, value))
+                    .or_default()
+                    .push(index);
+            }
+        }
+    }
+
+    let mut pairs = BTreeSet::new();
+    for members in blocks
+        .values()
+        .filter(|members| members.len() <= MAX_BLOCK_SIZE)
+    {
+        for (position, &a) in members.iter().enumerate() {
+            for &b in &members[position + 1..] {
+                pairs.insert((a, b));
+            }
+        }
+    }
+
+    let mut candidates: Vec<DuplicateCandidate> = pairs
+        .into_iter()
+        .filter_map(|(a, b)| {
+            let (score, matched_on) = score(&patients[a], &patients[b]);
+            (score >= min_score).then(|| DuplicateCandidate {
+                patient: patients[a].clone(),
+                duplicate: patients[b].clone(),
+                score,
+                matched_on,
+            })
+        })
+        .collect();
+
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patient(
+        id: i32,
+        name: &str,
+        surname: &str,
+        birth_date: &str,
+        phone: &str,
+        passport: &str,
+    ) -> Patient {
+        Patient {
+            id,
+            name: name.to_string(),
+            surname: surname.to_string(),
+            birth_date: birth_date.to_string(),
+            phone_number: phone.to_string(),
+            passport_number: passport.to_string(),
+        }
+    }
+
+    #[test]
+    fn score_normalizes_passports_and_phones() {
+        let a = patient(
+            1,
+            "Ivan",
+            "Petrov",
+            "1990-01-01",
+            "+7 (999) 123-45-67",
+            "45 06 123456",
+        );
+        let b = patient(
+            2,
+            "Ivan",
+            "Petrov",
+            "1990-01-01",
+            "89991234567",
+            "4506-123456",
+        );
+
+        let (score, matched_on) = score(&a, &b);
+
+        assert!((score - 1.0).abs() < 1e-9);
+        assert_eq!(
+            matched_on,
+            ["passport_number", "phone_number", "name", "birth_date"]
+        );
+    }
+
+    #[test]
+    fn score_ignores_empty_values() {
+        let a = patient(1, "Anna", "Smirnova", "", "", "");
+        let b = patient(2, "Oleg", "Kuznetsov", "", "", "");
+
+        let (score, matched_on) = score(&a, &b);
+
+        assert!(score < NAME_WEIGHT);
+        assert!(matched_on.is_empty());
+    }
+
+    #[test]
+    fn find_duplicates_reports_best_matches_first() {
+        let patients = [
+            patient(
+                1,
+                "Ivan",
+                "Petrov",
+                "1990-01-01",
+                "89991234567",
+                "4506123456",
+            ),
+            patient(
+                2,
+                "Ivan",
+                "Petrov",
+                "1990-01-01",
+                "+79991234567",
+                "4506 123456",
+            ),
+            patient(
+                3,
+                "Ivan",
+                "Petrova",
+                "1990-01-01",
+                "89990000000",
+                "1111111111",
+            ),
+            patient(
+                4,
+                "Maria",
+                "Sidorova",
+                "1985-05-05",
+                "89995555555",
+                "2222222222",
+            ),
+        ];
+
+        let candidates = find_duplicates(&patients, 0.5);
+
+        let pairs: Vec<(i32, i32)> = candidates
+            .iter()
+            .map(|candidate| (candidate.patient.id, candidate.duplicate.id))
+            .collect();
+        assert_eq!(pairs[0], (1, 2));
+        assert!(pairs.iter().all(|&(a, b)| a != 4 && b != 4));
+        assert!(candidates
+            .windows(2)
+            .all(|pair| pair[0].score >= pair[1].score));
+    }
+
+    #[test]
+    fn find_duplicates_skips_oversized_blocks() {
+        let patients: Vec<Patient> = (0..=MAX_BLOCK_SIZE as i32)
+            .map(|id| patient(id, &format!("Name{}", id), "Ivanov", "", "", ""))
+            .collect();
+
+        assert!(find_duplicates(&patients, 0.0).is_empty());
+        assert!(!find_duplicates(&patients[..2], 0.0).is_empty());
+    }
+}
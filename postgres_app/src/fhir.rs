@@ -0,0 +1,1369 @@
+use crate::crypto::FieldCipher;
+use crate::models::{
+    Doctor, FullScheduleEntry, NewDoctor, NewPatient, NewScheduleEntry, NewTicket, Patient,
+};
+use crate::reports::{format_date, parse_date, parse_time};
+use crate::{doctors, patients, schedule, tickets};
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use time::{Duration, PrimitiveDateTime, Time, UtcOffset};
+use tracing::error;
+
+pub const CONTENT_TYPE: &str = "application/fhir+json";
+
+pub const FHIR_VERSION: &str = "4.0.1";
+
+/// Identifier system of passport numbers.
+pub const PASSPORT_SYSTEM: &str = "urn:hospital:passport";
+
+/// Extension carrying the office number of a Slot, which has no element of its own for it.
+pub const OFFICE_EXTENSION: &str = "http://hospital.local/fhir/StructureDefinition/office-number";
+
+const DEFAULT_COUNT: i64 = 100;
+const MAX_COUNT: i64 = 1000;
+
+/// A resource type with its interactions and its search parameters and their types.
+type ResourceCapability = (
+    &'static str,
+    &'static [&'static str],
+    &'static [(&'static str, &'static str)],
+);
+
+/// Resources of the facade, as published in the CapabilityStatement.
+const RESOURCES: &[ResourceCapability] = &[
+    (
+        "Patient",
+        &["read", "search-type", "create"],
+        &[
+            ("_id", "token"),
+            ("family", "string"),
+            ("given", "string"),
+            ("name", "string"),
+            ("birthdate", "date"),
+            ("identifier", "token"),
+            ("telecom", "token"),
+        ],
+    ),
+    (
+        "Practitioner",
+        &["read", "search-type", "create"],
+        &[
+            ("_id", "token"),
+            ("family", "string"),
+            ("given", "string"),
+            ("name", "string"),
+            ("identifier", "token"),
+            ("telecom", "token"),
+        ],
+    ),
+    (
+        "PractitionerRole",
+        &["read", "search-type"],
+        &[
+            ("_id", "token"),
+            ("practitioner", "reference"),
+            ("specialty", "token"),
+        ],
+    ),
+    (
+        "Slot",
+        &["read", "search-type", "create"],
+        &[("_id", "token"), ("start", "date"), ("status", "token")],
+    ),
+    (
+        "Appointment",
+        &["read", "search-type", "create"],
+        &[
+            ("_id", "token"),
+            ("date", "date"),
+            ("patient", "reference"),
+            ("practitioner", "reference"),
+            ("slot", "reference"),
+        ],
+    ),
+];
+
+pub struct FhirSettings {
+    /// Offset of ticket dates and times from UTC, `+03:00` style. FHIR instants need one.
+    pub utc_offset: UtcOffset,
+    /// Tickets have no end time, so Slots and Appointments end this many minutes after start.
+    pub slot_minutes: i64,
+}
+
+pub fn parse_offset(value: &str) -> Option<UtcOffset> {
+    if value == "Z" {
+        return Some(UtcOffset::UTC);
+    }
+    let sign = match value.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let (hours, minutes) = value.get(1..)?.split_once(':')?;
+    let hours: i8 = hours.parse().ok()?;
+    let minutes: i8 = minutes.parse().ok()?;
+    UtcOffset::from_hms(sign * hours, sign * minutes, 0).ok()
+}
+
+fn format_offset(offset: UtcOffset) -> String {
+    if offset.is_utc() {
+        return "Z".to_string();
+    }
+    let (hours, minutes, _) = offset.as_hms();
+    let sign = if offset.is_negative() { '-' } else { '+' };
+    format!("{}{:02}:{:02}", sign, hours.abs(), minutes.abs())
+}
+
+fn format_instant(date_time: PrimitiveDateTime, offset: UtcOffset) -> String {
+    format!(
+        "{}T{:02}:{:02}:00{}",
+        format_date(date_time.date()),
+        date_time.hour(),
+        date_time.minute(),
+        format_offset(offset)
+    )
+}
+
+/// Start and end instants of a ticket. `None` for dates and times in other formats.
+fn ticket_period(date: &str, time: &str, settings: &FhirSettings) -> Option<(String, String)> {
+    let (hour, minute) = parse_time(time)?;
+    let start = PrimitiveDateTime::new(
+        parse_date(date)?,
+        Time::from_hms(hour as u8, minute, 0).ok()?,
+    );
+    let end = start + Duration::minutes(settings.slot_minutes);
+    Some((
+        format_instant(start, settings.utc_offset),
+        format_instant(end, settings.utc_offset),
+    ))
+}
+
+/// Reads a `dateTime` such as `2024-01-31T09:30:00+03:00` into the ticket date and time,
+/// converted to the configured offset.
+fn parse_ticket_start(value: &str, settings: &FhirSettings) -> Option<(String, String)> {
+    let date = parse_date(value.get(..10)?)?;
+    let rest = value.get(10..)?.strip_prefix('T')?;
+    let (hour, minute) = parse_time(rest.get(..5)?)?;
+    let offset = match rest.find(['Z', '+', '-']) {
+        Some(position) => parse_offset(&rest[position..])?,
+        None => settings.utc_offset,
+    };
+
+    let start = PrimitiveDateTime::new(date, Time::from_hms(hour as u8, minute, 0).ok()?)
+        .assume_offset(offset)
+        .to_offset(settings.utc_offset);
+    Some((
+        format_date(start.date()),
+        format!("{:02}:{:02}", start.hour(), start.minute()),
+    ))
+}
+
+pub fn response(status: StatusCode, resource: &Value) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type(CONTENT_TYPE)
+        .body(resource.to_string())
+}
+
+/// An OperationOutcome with a single error issue. `code` is a FHIR issue type such as
+/// `not-found` or `invalid`.
+pub fn outcome(status: StatusCode, code: &str, diagnostics: &str) -> HttpResponse {
+    response(
+        status,
+        &json!({
+            "resourceType": "OperationOutcome",
+            "issue": [{
+                "severity": "error",
+                "code": code,
+                "diagnostics": diagnostics,
+            }],
+        }),
+    )
+}
+
+pub fn not_found(resource_type: &str, id: &str) -> HttpResponse {
+    outcome(
+        StatusCode::NOT_FOUND,
+        "not-found",
+        &format!("{}/{} is not known", resource_type, id),
+    )
+}
+
+pub fn invalid(diagnostics: &str) -> HttpResponse {
+    outcome(StatusCode::BAD_REQUEST, "invalid", diagnostics)
+}
+
+pub fn database_error() -> HttpResponse {
+    outcome(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "exception",
+        "The database request failed",
+    )
+}
+
+pub fn created(req: &HttpRequest, resource: &Value) -> HttpResponse {
+    let location = format!(
+        "{}/{}/{}",
+        base_url(req),
+        resource["resourceType"].as_str().unwrap_or_default(),
+        resource["id"].as_str().unwrap_or_default()
+    );
+    HttpResponse::Created()
+        .content_type(CONTENT_TYPE)
+        .insert_header((header::LOCATION, location))
+        .body(resource.to_string())
+}
+
+pub fn base_url(req: &HttpRequest) -> String {
+    let connection = req.connection_info();
+    format!("{}://{}/fhir", connection.scheme(), connection.host())
+}
+
+/// Logical ids are database ids. Anything else cannot exist.
+pub fn parse_id(id: &str) -> Option<i32> {
+    id.parse().ok().filter(|id| *id > 0)
+}
+
+/// Accepts `Patient/12`, an absolute URL ending in it, or a bare `12`.
+fn parse_reference(value: &str, resource_types: &[&str]) -> Option<i32> {
+    let mut parts = value.trim_end_matches('/').rsplit('/');
+    let id = parts.next()?;
+    match parts.next() {
+        Some(resource_type) if !resource_types.contains(&resource_type) => None,
+        _ => parse_id(id),
+    }
+}
+
+pub fn capability_statement(req: &HttpRequest) -> Value {
+    let resources: Vec<Value> = RESOURCES
+        .iter()
+        .map(|(resource_type, interactions, parameters)| {
+            json!({
+                "type": resource_type,
+                "interaction": interactions
+                    .iter()
+                    .map(|code| json!({ "code": code }))
+                    .collect::<Vec<_>>(),
+                "searchParam": parameters
+                    .iter()
+                    .map(|(name, kind)| json!({ "name": name, "type": kind }))
+                    .chain([
+                        json!({ "name": "_count", "type": "number" }),
+                        json!({ "name": "_offset", "type": "number" }),
+                    ])
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+
+    json!({
+        "resourceType": "CapabilityStatement",
+        "status": "active",
+        "date": format_date(time::OffsetDateTime::now_utc().date()),
+        "kind": "instance",
+        "fhirVersion": FHIR_VERSION,
+        "format": ["json"],
+        "implementation": {
+            "description": "Hospital schedule",
+            "url": base_url(req),
+        },
+        "rest": [{
+            "mode": "server",
+            "resource": resources,
+        }],
+    })
+}
+
+/// URL of the same search starting at another `_offset`.
+fn page_url(req: &HttpRequest, params: &SearchParams, offset: i64) -> String {
+    let mut query: Vec<(&str, String)> = params
+        .0
+        .iter()
+        .filter(|(key, _)| key != "_offset")
+        .map(|(key, value)| (key.as_str(), value.clone()))
+        .collect();
+    query.push(("_offset", offset.to_string()));
+    let connection = req.connection_info();
+    format!(
+        "{}://{}{}?{}",
+        connection.scheme(),
+        connection.host(),
+        req.path(),
+        serde_urlencoded::to_string(query).unwrap_or_default()
+    )
+}
+
+/// Searchset Bundle of one page of results, linking to the next and previous pages.
+pub fn bundle(
+    req: &HttpRequest,
+    params: &SearchParams,
+    total: i64,
+    resources: Vec<Value>,
+) -> Value {
+    let base = base_url(req);
+    let entries: Vec<Value> = resources
+        .into_iter()
+        .map(|resource| {
+            json!({
+                "fullUrl": format!(
+                    "{}/{}/{}",
+                    base,
+                    resource["resourceType"].as_str().unwrap_or_default(),
+                    resource["id"].as_str().unwrap_or_default()
+                ),
+                "resource": resource,
+                "search": { "mode": "match" },
+            })
+        })
+        .collect();
+    let connection = req.connection_info();
+    let mut links = vec![json!({
+        "relation": "self",
+        "url": format!("{}://{}{}", connection.scheme(), connection.host(), req.uri()),
+    })];
+    // Parameters were validated by the search.
+    let count = params.count().unwrap_or(DEFAULT_COUNT);
+    let offset = params.offset().unwrap_or(0);
+    if count > 0 && offset + count < total {
+        links.push(json!({ "relation": "next", "url": page_url(req, params, offset + count) }));
+    }
+    if count > 0 && offset > 0 {
+        let previous = (offset - count).max(0);
+        links.push(json!({ "relation": "previous", "url": page_url(req, params, previous) }));
+    }
+
+    json!({
+        "resourceType": "Bundle",
+        "type": "searchset",
+        "total": total,
+        "link": links,
+        "entry": entries,
+    })
+}
+
+/// Search parameters as sent, repeated ones included. Parameters the facade does not know are
+/// ignored, as FHIR servers do by default.
+pub struct SearchParams(pub Vec<(String, String)>);
+
+impl SearchParams {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, value)| key == name && !value.is_empty())
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(key, value)| key == name && !value.is_empty())
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn id(&self) -> Result<Option<i32>, String> {
+        match self.get("_id") {
+            None => Ok(None),
+            Some(id) => Ok(Some(parse_id(id).unwrap_or(0))),
+        }
+    }
+
+    fn count(&self) -> Result<i64, String> {
+        match self.get("_count") {
+            None => Ok(DEFAULT_COUNT),
+            Some(count) => count
+                .parse::<i64>()
+                .ok()
+                .filter(|count| *count >= 0)
+                .map(|count| count.min(MAX_COUNT))
+                .ok_or_else(|| "_count must be a non-negative number".to_string()),
+        }
+    }
+
+    /// Number of matches to skip, for paging.
+    fn offset(&self) -> Result<i64, String> {
+        match self.get("_offset") {
+            None => Ok(0),
+            Some(offset) => offset
+                .parse::<i64>()
+                .ok()
+                .filter(|offset| *offset >= 0)
+                .ok_or_else(|| "_offset must be a non-negative number".to_string()),
+        }
+    }
+
+    /// `ILIKE` pattern matching names that start with the parameter, as `family`, `given` and
+    /// `name` do. Case folding follows the database's `LC_CTYPE`.
+    fn name_prefix(&self, name: &str) -> Option<String> {
+        self.get(name)
+            .map(|prefix| format!("{}%", escape_like(prefix)))
+    }
+
+    /// Token value without its system: `urn:hospital:passport|4500123456` gives `4500123456`.
+    fn token(&self, name: &str) -> Option<String> {
+        self.get(name).map(|value| match value.split_once('|') {
+            Some((_, code)) => code.to_string(),
+            None => value.to_string(),
+        })
+    }
+
+    fn reference(&self, name: &str, resource_types: &[&str]) -> Result<Option<i32>, String> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(value) => parse_reference(value, resource_types)
+                .map(Some)
+                .ok_or_else(|| format!("`{}` must reference a {}", name, resource_types[0])),
+        }
+    }
+
+    /// Combines every value of a date parameter into inclusive `YYYY-MM-DD` bounds. Supports
+    /// the `eq`, `ge`, `gt`, `le` and `lt` prefixes; times are ignored.
+    fn date_bounds(&self, name: &str) -> Result<(Option<String>, Option<String>), String> {
+        let mut from = None;
+        let mut to = None;
+        for value in self.all(name) {
+            let (prefix, value) = match value.get(..2) {
+                Some(prefix @ ("eq" | "ge" | "gt" | "le" | "lt")) => (prefix, &value[2..]),
+                _ => ("eq", value),
+            };
+            let date = value
+                .get(..10)
+                .and_then(parse_date)
+                .ok_or_else(|| format!("`{}` must be a date like 2024-01-31", name))?;
+
+            let (lower, upper) = match prefix {
+                "ge" => (Some(date), None),
+                "gt" => (Some(date + Duration::days(1)), None),
+                "le" => (None, Some(date)),
+                "lt" => (None, Some(date - Duration::days(1))),
+                _ => (Some(date), Some(date)),
+            };
+            if let Some(lower) = lower {
+                from = Some(from.map_or(lower, |from: time::Date| from.max(lower)));
+            }
+            if let Some(upper) = upper {
+                to = Some(to.map_or(upper, |to: time::Date| to.min(upper)));
+            }
+        }
+        Ok((from.map(format_date), to.map(format_date)))
+    }
+}
+
+/// Escapes the `LIKE` wildcards and the escape character itself.
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn human_name(surname: &str, name: &str) -> Value {
+    json!([{
+        "use": "official",
+        "family": surname,
+        "given": [name],
+        "text": format!("{} {}", name, surname),
+    }])
+}
+
+fn telecom(phone_number: &str) -> Value {
+    if phone_number.is_empty() {
+        return json!([]);
+    }
+    json!([{ "system": "phone", "value": phone_number }])
+}
+
+fn passport(passport_number: &str) -> Value {
+    if passport_number.is_empty() {
+        return json!([]);
+    }
+    json!([{ "system": PASSPORT_SYSTEM, "value": passport_number }])
+}
+
+pub fn patient_resource(patient: &Patient) -> Value {
+    let mut resource = json!({
+        "resourceType": "Patient",
+        "id": patient.id.to_string(),
+        "identifier": passport(&patient.passport_number),
+        "active": true,
+        "name": human_name(&patient.surname, &patient.name),
+        "telecom": telecom(&patient.phone_number),
+    });
+    if let Some(birth_date) = parse_date(&patient.birth_date) {
+        resource["birthDate"] = json!(format_date(birth_date));
+    }
+    resource
+}
+
+pub fn practitioner_resource(doctor: &Doctor) -> Value {
+    json!({
+        "resourceType": "Practitioner",
+        "id": doctor.id.to_string(),
+        "identifier": passport(&doctor.passport_number),
+        "active": true,
+        "name": human_name(&doctor.surname, &doctor.name),
+        "telecom": telecom(&doctor.phone_number),
+        "qualification": [{ "code": { "text": doctor.speciality } }],
+    })
+}
+
+/// Every doctor has exactly one role, which shares the doctor's id.
+pub fn practitioner_role_resource(doctor: &Doctor) -> Value {
+    json!({
+        "resourceType": "PractitionerRole",
+        "id": doctor.id.to_string(),
+        "active": true,
+        "practitioner": {
+            "reference": format!("Practitioner/{}", doctor.id),
+            "display": format!("{} {}", doctor.name, doctor.surname),
+        },
+        "specialty": [{ "text": doctor.speciality }],
+    })
+}
+
+pub struct SlotRow {
+    pub id: i32,
+    pub date: String,
+    pub time: String,
+    pub office_number: i32,
+    pub busy: bool,
+}
+
+pub fn slot_resource(slot: &SlotRow, settings: &FhirSettings) -> Value {
+    let mut resource = json!({
+        "resourceType": "Slot",
+        "id": slot.id.to_string(),
+        "extension": [{ "url": OFFICE_EXTENSION, "valueInteger": slot.office_number }],
+        "schedule": { "display": format!("Office {}", slot.office_number) },
+        "status": if slot.busy { "busy" } else { "free" },
+    });
+    if let Some((start, end)) = ticket_period(&slot.date, &slot.time, settings) {
+        resource["start"] = json!(start);
+        resource["end"] = json!(end);
+    }
+    resource
+}
+
+pub fn appointment_resource(entry: &FullScheduleEntry, settings: &FhirSettings) -> Value {
+    let mut resource = json!({
+        "resourceType": "Appointment",
+        "id": entry.schedule_id.to_string(),
+        "status": "booked",
+        "specialty": [{ "text": entry.doctor_speciality }],
+        "slot": [{ "reference": format!("Slot/{}", entry.ticket_id) }],
+        "participant": [
+            {
+                "actor": {
+                    "reference": format!("Patient/{}", entry.patient_id),
+                    "display": format!("{} {}", entry.patient_name, entry.patient_surname),
+                },
+                "required": "required",
+                "status": "accepted",
+            },
+            {
+                "actor": {
+                    "reference": format!("Practitioner/{}", entry.doctor_id),
+                    "display": format!("{} {}", entry.doctor_name, entry.doctor_surname),
+                },
+                "required": "required",
+                "status": "accepted",
+            },
+        ],
+        "description": format!("Office {}", entry.ticket_office_number),
+    });
+    if let Some((start, end)) = ticket_period(&entry.ticket_date, &entry.ticket_time, settings) {
+        resource["start"] = json!(start);
+        resource["end"] = json!(end);
+    }
+    resource
+}
+
+/// Parses a request body and checks its `resourceType`.
+pub fn parse_resource(body: &[u8], resource_type: &str) -> Result<Value, String> {
+    let resource: Value =
+        serde_json::from_slice(body).map_err(|e| format!("Body is not valid JSON: {}", e))?;
+    match resource["resourceType"].as_str() {
+        Some(found) if found == resource_type => Ok(resource),
+        Some(found) => Err(format!("Expected a {}, got a {}", resource_type, found)),
+        None => Err(format!("Expected a {}", resource_type)),
+    }
+}
+
+/// Family and first given name of the first name.
+fn read_name(resource: &Value) -> Result<(String, String), String> {
+    let name = &resource["name"][0];
+    let family = name["family"].as_str().filter(|family| !family.is_empty());
+    let given = name["given"][0].as_str().filter(|given| !given.is_empty());
+    match (family, given) {
+        (Some(family), Some(given)) => Ok((family.to_string(), given.to_string())),
+        _ => Err("name[0] must have a family and a given name".to_string()),
+    }
+}
+
+fn read_phone_number(resource: &Value) -> String {
+    resource["telecom"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|telecom| telecom["system"] == "phone")
+        .and_then(|telecom| telecom["value"].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn read_passport_number(resource: &Value) -> String {
+    resource["identifier"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|identifier| identifier["system"] == PASSPORT_SYSTEM)
+        .and_then(|identifier| identifier["value"].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+pub fn new_patient(resource: &Value) -> Result<NewPatient, String> {
+    let (surname, name) = read_name(resource)?;
+    let birth_date = match resource["birthDate"].as_str() {
+        None => String::new(),
+        Some(birth_date) => parse_date(birth_date)
+            .map(format_date)
+            .ok_or_else(|| "birthDate must be a full date like 2024-01-31".to_string())?,
+    };
+
+    Ok(NewPatient {
+        name,
+        surname,
+        birth_date,
+        phone_number: read_phone_number(resource),
+        passport_number: read_passport_number(resource),
+    })
+}
+
+/// The speciality is the text of the first qualification.
+pub fn new_doctor(resource: &Value) -> Result<NewDoctor, String> {
+    let (surname, name) = read_name(resource)?;
+    let code = &resource["qualification"][0]["code"];
+    let speciality = code["text"]
+        .as_str()
+        .or_else(|| code["coding"][0]["display"].as_str())
+        .filter(|speciality| !speciality.is_empty())
+        .ok_or_else(|| "qualification[0].code.text must name the speciality".to_string())?;
+
+    Ok(NewDoctor {
+        name,
+        surname,
+        speciality: speciality.to_string(),
+        phone_number: read_phone_number(resource),
+        passport_number: read_passport_number(resource),
+    })
+}
+
+/// The office comes from the office-number extension.
+pub fn new_ticket(resource: &Value, settings: &FhirSettings) -> Result<NewTicket, String> {
+    let (date, time) = resource["start"]
+        .as_str()
+        .and_then(|start| parse_ticket_start(start, settings))
+        .ok_or_else(|| "start must be a dateTime like 2024-01-31T09:30:00+03:00".to_string())?;
+    let office_number = resource["extension"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|extension| extension["url"] == OFFICE_EXTENSION)
+        .and_then(|extension| extension["valueInteger"].as_i64())
+        .and_then(|office_number| i32::try_from(office_number).ok())
+        .ok_or_else(|| format!("A Slot needs an {} extension", OFFICE_EXTENSION))?;
+
+    Ok(NewTicket {
+        date,
+        time,
+        office_number,
+    })
+}
+
+/// Takes the Slot and the Patient and Practitioner participants. A PractitionerRole stands
+/// for its practitioner, as they share the id.
+pub fn new_schedule_entry(resource: &Value) -> Result<NewScheduleEntry, String> {
+    let ticket_id = resource["slot"][0]["reference"]
+        .as_str()
+        .and_then(|reference| parse_reference(reference, &["Slot"]))
+        .ok_or_else(|| "slot[0] must reference a Slot".to_string())?;
+
+    let mut patient_id = None;
+    let mut doctor_id = None;
+    for participant in resource["participant"].as_array().into_iter().flatten() {
+        let reference = participant["actor"]["reference"]
+            .as_str()
+            .unwrap_or_default();
+        if reference.contains("Patient/") {
+            patient_id = parse_reference(reference, &["Patient"]);
+        } else if reference.contains("Practitioner") {
+            doctor_id = parse_reference(reference, &["Practitioner", "PractitionerRole"]);
+        }
+    }
+
+    match (patient_id, doctor_id) {
+        (Some(patient_id), Some(doctor_id)) => Ok(NewScheduleEntry {
+            ticket_id,
+            doctor_id,
+            patient_id,
+        }),
+        _ => Err("participant must reference a Patient and a Practitioner".to_string()),
+    }
+}
+
+pub enum SearchError {
+    Invalid(String),
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for SearchError {
+    fn from(error: sqlx::Error) -> Self {
+        SearchError::Database(error)
+    }
+}
+
+impl From<String> for SearchError {
+    fn from(message: String) -> Self {
+        SearchError::Invalid(message)
+    }
+}
+
+impl SearchError {
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            SearchError::Invalid(message) => invalid(message),
+            SearchError::Database(e) => {
+                error!(error = %e, "FHIR search failed");
+                database_error()
+            }
+        }
+    }
+}
+
+/// Search results with the number of all matches, which may exceed `_count`.
+pub type SearchResult = Result<(i64, Vec<Value>), SearchError>;
+
+pub async fn search_patients(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    params: &SearchParams,
+) -> SearchResult {
+    let id = params.id()?;
+    let birth_date = params.get("birthdate");
+    let phone_number_index = cipher.blind_index_filter(&params.token("telecom"));
+    let passport_number_index = cipher.blind_index_filter(&params.token("identifier"));
+    let family = params.name_prefix("family");
+    let given = params.name_prefix("given");
+    let name = params.name_prefix("name");
+
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!"
+        FROM patients
+        WHERE
+            ($1::INT IS NULL OR id = $1) AND
+            ($2::TEXT IS NULL OR birth_date = $2) AND
+            ($3::TEXT IS NULL OR phone_number_index = $3) AND
+            ($4::TEXT IS NULL OR passport_number_index = $4) AND
+            ($5::TEXT IS NULL OR surname ILIKE $5) AND
+            ($6::TEXT IS NULL OR name ILIKE $6) AND
+            ($7::TEXT IS NULL OR name ILIKE $7 OR surname ILIKE $7)"#,
+        id,
+        birth_date,
+        phone_number_index,
+        passport_number_index,
+        family,
+        given,
+        name,
+    )
+    .fetch_one(pool)
+    .await?;
+    let patients = sqlx::query_as!(
+        Patient,
+        "SELECT id, name, surname, birth_date, phone_number, passport_number
+        FROM patients
+        WHERE
+            ($1::INT IS NULL OR id = $1) AND
+            ($2::TEXT IS NULL OR birth_date = $2) AND
+            ($3::TEXT IS NULL OR phone_number_index = $3) AND
+            ($4::TEXT IS NULL OR passport_number_index = $4) AND
+            ($5::TEXT IS NULL OR surname ILIKE $5) AND
+            ($6::TEXT IS NULL OR name ILIKE $6) AND
+            ($7::TEXT IS NULL OR name ILIKE $7 OR surname ILIKE $7)
+        ORDER BY id
+        LIMIT $8 OFFSET $9",
+        id,
+        birth_date,
+        phone_number_index,
+        passport_number_index,
+        family,
+        given,
+        name,
+        params.count()?,
+        params.offset()?,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let resources = patients
+        .into_iter()
+        .map(|patient| patient_resource(&cipher.decrypt_patient(patient)))
+        .collect();
+    Ok((total, resources))
+}
+
+/// Practitioners and practitioner roles are both searched in `doctors`.
+pub async fn search_doctors(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    params: &SearchParams,
+    to_resource: fn(&Doctor) -> Value,
+) -> SearchResult {
+    let id = params.id()?;
+    let phone_number_index = cipher.blind_index_filter(&params.token("telecom"));
+    let passport_number_index = cipher.blind_index_filter(&params.token("identifier"));
+    let practitioner = params.reference("practitioner", &["Practitioner"])?;
+    let family = params.name_prefix("family");
+    let given = params.name_prefix("given");
+    let name = params.name_prefix("name");
+    let specialty = params
+        .token("specialty")
+        .map(|specialty| escape_like(&specialty));
+
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!"
+        FROM doctors
+        WHERE
+            ($1::INT IS NULL OR id = $1) AND
+            ($2::TEXT IS NULL OR phone_number_index = $2) AND
+            ($3::TEXT IS NULL OR passport_number_index = $3) AND
+            ($4::INT IS NULL OR id = $4) AND
+            ($5::TEXT IS NULL OR surname ILIKE $5) AND
+            ($6::TEXT IS NULL OR name ILIKE $6) AND
+            ($7::TEXT IS NULL OR name ILIKE $7 OR surname ILIKE $7) AND
+            ($8::TEXT IS NULL OR speciality ILIKE $8)"#,
+        id,
+        phone_number_index,
+        passport_number_index,
+        practitioner,
+        family,
+        given,
+        name,
+        specialty,
+    )
+    .fetch_one(pool)
+    .await?;
+    let doctors = sqlx::query_as!(
+        Doctor,
+        "SELECT id, name, surname, speciality, phone_number, passport_number
+        FROM doctors
+        WHERE
+            ($1::INT IS NULL OR id = $1) AND
+            ($2::TEXT IS NULL OR phone_number_index = $2) AND
+            ($3::TEXT IS NULL OR passport_number_index = $3) AND
+            ($4::INT IS NULL OR id = $4) AND
+            ($5::TEXT IS NULL OR surname ILIKE $5) AND
+            ($6::TEXT IS NULL OR name ILIKE $6) AND
+            ($7::TEXT IS NULL OR name ILIKE $7 OR surname ILIKE $7) AND
+            ($8::TEXT IS NULL OR speciality ILIKE $8)
+        ORDER BY id
+        LIMIT $9 OFFSET $10",
+        id,
+        phone_number_index,
+        passport_number_index,
+        practitioner,
+        family,
+        given,
+        name,
+        specialty,
+        params.count()?,
+        params.offset()?,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let resources = doctors
+        .into_iter()
+        .map(|doctor| to_resource(&cipher.decrypt_doctor(doctor)))
+        .collect();
+    Ok((total, resources))
+}
+
+pub async fn search_slots(
+    pool: &PgPool,
+    settings: &FhirSettings,
+    params: &SearchParams,
+) -> SearchResult {
+    let id = params.id()?;
+    let (from, to) = params.date_bounds("start")?;
+    let busy = match params.get("status") {
+        None => None,
+        Some("busy") => Some(true),
+        Some("free") => Some(false),
+        Some(_) => {
+            return Err(SearchError::Invalid(
+                "status must be free or busy".to_string(),
+            ))
+        }
+    };
+
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!"
+        FROM tickets
+        WHERE
+            ($1::INT IS NULL OR id = $1) AND
+            ($2::TEXT IS NULL OR date >= $2) AND
+            ($3::TEXT IS NULL OR date <= $3) AND
+            ($4::BOOL IS NULL OR EXISTS (SELECT 1 FROM schedule WHERE schedule.ticket_id = tickets.id) = $4)"#,
+        id,
+        from,
+        to,
+        busy,
+    )
+    .fetch_one(pool)
+    .await?;
+    let rows = sqlx::query_as!(
+        SlotRow,
+        r#"SELECT id, date, time, office_number,
+            EXISTS (SELECT 1 FROM schedule WHERE schedule.ticket_id = tickets.id) as "busy!"
+        FROM tickets
+        WHERE
+            ($1::INT IS NULL OR id = $1) AND
+            ($2::TEXT IS NULL OR date >= $2) AND
+            ($3::TEXT IS NULL OR date <= $3) AND
+            ($4::BOOL IS NULL OR EXISTS (SELECT 1 FROM schedule WHERE schedule.ticket_id = tickets.id) = $4)
+        ORDER BY date, time, id
+        LIMIT $5 OFFSET $6"#,
+        id,
+        from,
+        to,
+        busy,
+        params.count()?,
+        params.offset()?,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let resources = rows
+        .iter()
+        .map(|slot| slot_resource(slot, settings))
+        .collect();
+    Ok((total, resources))
+}
+
+pub async fn search_appointments(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    settings: &FhirSettings,
+    params: &SearchParams,
+) -> SearchResult {
+    let id = params.id()?;
+    let (from, to) = params.date_bounds("date")?;
+    let patient_id = params.reference("patient", &["Patient"])?;
+    let doctor_id = params.reference("practitioner", &["Practitioner"])?;
+    let ticket_id = params.reference("slot", &["Slot"])?;
+
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!"
+        FROM schedule
+        JOIN tickets ON schedule.ticket_id = tickets.id
+        WHERE
+            ($1::INT IS NULL OR schedule.id = $1) AND
+            ($2::TEXT IS NULL OR tickets.date >= $2) AND
+            ($3::TEXT IS NULL OR tickets.date <= $3) AND
+            ($4::INT IS NULL OR schedule.patient_id = $4) AND
+            ($5::INT IS NULL OR schedule.doctor_id = $5) AND
+            ($6::INT IS NULL OR schedule.ticket_id = $6)"#,
+        id,
+        from,
+        to,
+        patient_id,
+        doctor_id,
+        ticket_id,
+    )
+    .fetch_one(pool)
+    .await?;
+    let rows = sqlx::query_as!(
+        FullScheduleEntry,
+        "SELECT schedule.id as schedule_id, tickets.id as ticket_id, doctors.id as doctor_id, patients.id as patient_id,
+        tickets.date as ticket_date, tickets.time as ticket_time, tickets.office_number as ticket_office_number,
+        doctors.name as doctor_name, doctors.surname as doctor_surname, doctors.speciality as doctor_speciality,
+        doctors.phone_number as doctor_phone_number, doctors.passport_number as doctor_passport_number,
+        patients.name as patient_name, patients.surname as patient_surname, patients.birth_date as patient_birth_date,
+        patients.phone_number as patient_phone_number, patients.passport_number as patient_passport_number
+        FROM schedule
+        JOIN tickets ON schedule.ticket_id = tickets.id
+        JOIN doctors ON schedule.doctor_id = doctors.id
+        JOIN patients ON schedule.patient_id = patients.id
+        WHERE
+            ($1::INT IS NULL OR schedule.id = $1) AND
+            ($2::TEXT IS NULL OR tickets.date >= $2) AND
+            ($3::TEXT IS NULL OR tickets.date <= $3) AND
+            ($4::INT IS NULL OR schedule.patient_id = $4) AND
+            ($5::INT IS NULL OR schedule.doctor_id = $5) AND
+            ($6::INT IS NULL OR schedule.ticket_id = $6)
+        ORDER BY tickets.date, tickets.time, schedule.id
+        LIMIT $7 OFFSET $8",
+        id,
+        from,
+        to,
+        patient_id,
+        doctor_id,
+        ticket_id,
+        params.count()?,
+        params.offset()?,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let resources = rows
+        .into_iter()
+        .map(|entry| appointment_resource(&cipher.decrypt_schedule_entry(entry), settings))
+        .collect();
+    Ok((total, resources))
+}
+
+/// Runs a search on one resource type. `None` for types the facade does not serve.
+pub async fn search(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    settings: &FhirSettings,
+    resource_type: &str,
+    params: &SearchParams,
+) -> Option<SearchResult> {
+    Some(match resource_type {
+        "Patient" => search_patients(pool, cipher, params).await,
+        "Practitioner" => search_doctors(pool, cipher, params, practitioner_resource).await,
+        "PractitionerRole" => {
+            search_doctors(pool, cipher, params, practitioner_role_resource).await
+        }
+        "Slot" => search_slots(pool, settings, params).await,
+        "Appointment" => search_appointments(pool, cipher, settings, params).await,
+        _ => return None,
+    })
+}
+
+/// Reads one resource through a search on `_id`.
+pub async fn read(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    settings: &FhirSettings,
+    resource_type: &str,
+    id: i32,
+) -> Option<Result<Option<Value>, SearchError>> {
+    let params = SearchParams(vec![("_id".to_string(), id.to_string())]);
+    let result = search(pool, cipher, settings, resource_type, &params).await?;
+    Some(result.map(|(_, resources)| resources.into_iter().next()))
+}
+
+pub enum CreateError {
+    Invalid(String),
+    Conflict(String),
+    Database(sqlx::Error),
+}
+
+impl From<String> for CreateError {
+    fn from(message: String) -> Self {
+        CreateError::Invalid(message)
+    }
+}
+
+impl CreateError {
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            CreateError::Invalid(message) => invalid(message),
+            CreateError::Conflict(message) => outcome(StatusCode::CONFLICT, "conflict", message),
+            CreateError::Database(e) => {
+                error!(error = %e, "FHIR create failed");
+                database_error()
+            }
+        }
+    }
+}
+
+/// Creates a resource from a request body and returns the id of the new row. `None` for types
+/// that cannot be created.
+pub async fn create(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    settings: &FhirSettings,
+    resource_type: &str,
+    body: &[u8],
+) -> Option<Result<i32, CreateError>> {
+    if !matches!(
+        resource_type,
+        "Patient" | "Practitioner" | "Slot" | "Appointment"
+    ) {
+        return None;
+    }
+    Some(create_row(pool, cipher, settings, resource_type, body).await)
+}
+
+async fn create_row(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    settings: &FhirSettings,
+    resource_type: &str,
+    body: &[u8],
+) -> Result<i32, CreateError> {
+    let resource = parse_resource(body, resource_type)?;
+    // Constraint violations come from references to missing rows and malformed fields.
+    let invalid_input = |_| CreateError::Invalid("Invalid input".to_string());
+
+    match resource_type {
+        "Patient" => {
+            let new_patient = new_patient(&resource)?;
+            let (patient, _) = patients::create(pool, cipher, &new_patient)
+                .await
+                .map_err(invalid_input)?;
+            Ok(patient.id)
+        }
+        "Practitioner" => {
+            let new_doctor = new_doctor(&resource)?;
+            let (doctor, _) = doctors::create(pool, cipher, &new_doctor)
+                .await
+                .map_err(invalid_input)?;
+            Ok(doctor.id)
+        }
+        "Slot" => {
+            let new_ticket = new_ticket(&resource, settings)?;
+            let (ticket, _) = tickets::create(pool, &new_ticket)
+                .await
+                .map_err(invalid_input)?;
+            Ok(ticket.id)
+        }
+        _ => {
+            let new_schedule_entry = new_schedule_entry(&resource)?;
+            match schedule::book(pool, &new_schedule_entry).await {
+                Ok(Some(id)) => Ok(id),
+                Ok(None) => Err(CreateError::Conflict(format!(
+                    "Slot/{} is already booked",
+                    new_schedule_entry.ticket_id
+                ))),
+                Err(sqlx::Error::Database(_)) => Err(CreateError::Invalid(
+                    "The slot, patient or practitioner does not exist".to_string(),
+                )),
+                Err(e) => Err(CreateError::Database(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn params(query: &[(&str, &str)]) -> SearchParams {
+        SearchParams(
+            query
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn settings() -> FhirSettings {
+        FhirSettings {
+            utc_offset: parse_offset("+03:00").unwrap(),
+            slot_minutes: 30,
+        }
+    }
+
+    #[test]
+    fn search_parameters_are_parsed() {
+        let empty = params(&[]);
+        assert_eq!(empty.count().unwrap(), DEFAULT_COUNT);
+        assert_eq!(empty.offset().unwrap(), 0);
+        assert_eq!(empty.id().unwrap(), None);
+
+        let paged = params(&[("_count", "5000"), ("_offset", "20"), ("_id", "x")]);
+        assert_eq!(paged.count().unwrap(), MAX_COUNT);
+        assert_eq!(paged.offset().unwrap(), 20);
+        // An id that cannot exist matches nothing rather than failing the search.
+        assert_eq!(paged.id().unwrap(), Some(0));
+        assert!(params(&[("_count", "-1")]).count().is_err());
+        assert!(params(&[("_offset", "ten")]).offset().is_err());
+
+        let filters = params(&[
+            ("identifier", "urn:hospital:passport|4510 123456"),
+            ("telecom", "+7 900"),
+            ("family", "50%_\\"),
+            ("given", ""),
+            ("practitioner", "http://host/fhir/Practitioner/7"),
+            ("patient", "Practitioner/7"),
+        ]);
+        assert_eq!(filters.token("identifier").unwrap(), "4510 123456");
+        assert_eq!(filters.token("telecom").unwrap(), "+7 900");
+        assert_eq!(filters.name_prefix("family").unwrap(), "50\\%\\_\\\\%");
+        assert_eq!(filters.name_prefix("given"), None);
+        assert_eq!(
+            filters
+                .reference("practitioner", &["Practitioner"])
+                .unwrap(),
+            Some(7)
+        );
+        assert!(filters.reference("patient", &["Patient"]).is_err());
+    }
+
+    #[test]
+    fn date_parameters_combine_into_inclusive_bounds() {
+        let bounds = |values: &[&str]| {
+            let query: Vec<_> = values.iter().map(|value| ("date", *value)).collect();
+            params(&query).date_bounds("date")
+        };
+        let bound = |date: &str| Some(date.to_string());
+        assert_eq!(
+            bounds(&["2024-01-31"]).unwrap(),
+            (bound("2024-01-31"), bound("2024-01-31"))
+        );
+        assert_eq!(
+            bounds(&["gt2024-01-31", "lt2024-02-10T12:00"]).unwrap(),
+            (bound("2024-02-01"), bound("2024-02-09"))
+        );
+        assert_eq!(
+            bounds(&["ge2024-01-01", "ge2024-03-01", "le2024-12-31"]).unwrap(),
+            (bound("2024-03-01"), bound("2024-12-31"))
+        );
+        assert!(bounds(&["31.01.2024"]).is_err());
+    }
+
+    #[test]
+    fn rows_map_to_resources() {
+        let patient = patient_resource(&Patient {
+            id: 3,
+            name: "Anna".into(),
+            surname: "Petrova".into(),
+            birth_date: "1990-4-2".into(),
+            phone_number: String::new(),
+            passport_number: "4510 123456".into(),
+        });
+        assert_eq!(patient["id"], "3");
+        assert_eq!(patient["birthDate"], "1990-04-02");
+        assert_eq!(patient["name"][0]["family"], "Petrova");
+        assert_eq!(patient["name"][0]["given"], json!(["Anna"]));
+        assert_eq!(patient["telecom"], json!([]));
+        assert_eq!(
+            patient["identifier"],
+            json!([{ "system": PASSPORT_SYSTEM, "value": "4510 123456" }])
+        );
+
+        let doctor = Doctor {
+            id: 7,
+            name: "Ivan".into(),
+            surname: "Sidorov".into(),
+            speciality: "Surgeon".into(),
+            phone_number: "+7 900 000-00-00".into(),
+            passport_number: String::new(),
+        };
+        let practitioner = practitioner_resource(&doctor);
+        assert_eq!(practitioner["qualification"][0]["code"]["text"], "Surgeon");
+        assert_eq!(practitioner["telecom"][0]["value"], "+7 900 000-00-00");
+        let role = practitioner_role_resource(&doctor);
+        assert_eq!(role["id"], "7");
+        assert_eq!(role["practitioner"]["reference"], "Practitioner/7");
+
+        let slot = slot_resource(
+            &SlotRow {
+                id: 12,
+                date: "2024-01-31".into(),
+                time: "09:30".into(),
+                office_number: 204,
+                busy: true,
+            },
+            &settings(),
+        );
+        assert_eq!(slot["status"], "busy");
+        assert_eq!(slot["start"], "2024-01-31T09:30:00+03:00");
+        assert_eq!(slot["end"], "2024-01-31T10:00:00+03:00");
+        assert_eq!(slot["extension"][0]["valueInteger"], 204);
+    }
+
+    #[test]
+    fn resources_map_to_rows() {
+        let resource = parse_resource(
+            br#"{
+                "resourceType": "Patient",
+                "name": [{ "family": "Petrova", "given": ["Anna"] }],
+                "birthDate": "1990-04-02",
+                "telecom": [{ "system": "phone", "value": "+7 900 000-00-00" }],
+                "identifier": [{ "system": "urn:hospital:passport", "value": "4510 123456" }]
+            }"#,
+            "Patient",
+        )
+        .unwrap();
+        let patient = new_patient(&resource).unwrap();
+        assert_eq!(patient.surname, "Petrova");
+        assert_eq!(patient.phone_number, "+7 900 000-00-00");
+        assert_eq!(patient.passport_number, "4510 123456");
+        assert!(parse_resource(br#"{"resourceType": "Patient"}"#, "Practitioner").is_err());
+    }
+
+    #[sqlx::test]
+    async fn searches_page_in_the_database(pool: PgPool) {
+        for (name, surname) in [
+            ("Anna", "Petrova"),
+            ("Boris", "Petrov"),
+            ("Vera", "petrenko"),
+            ("Petr", "Ivanov"),
+            ("Oleg", "Pe_trov"),
+        ] {
+            sqlx::query!(
+                "INSERT INTO patients (name, surname, birth_date, phone_number, passport_number)
+                VALUES ($1, $2, '1990-04-02', '', '')",
+                name,
+                surname,
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        let cipher = FieldCipher::new(&Default::default()).unwrap();
+
+        let search = params(&[("family", "PETR"), ("_count", "2"), ("_offset", "1")]);
+        let (total, resources) = search_patients(&pool, &cipher, &search).await.ok().unwrap();
+        assert_eq!(total, 3);
+        let surnames: Vec<_> = resources.iter().map(|r| &r["name"][0]["family"]).collect();
+        assert_eq!(surnames, ["Petrov", "petrenko"]);
+
+        let req = TestRequest::get()
+            .uri("/fhir/Patient?family=PETR&_count=2&_offset=1")
+            .to_http_request();
+        let page = bundle(&req, &search, total, resources);
+        assert_eq!(page["total"], 3);
+        assert_eq!(page["link"][0]["relation"], "self");
+        assert_eq!(page["link"].as_array().unwrap().len(), 2);
+        assert_eq!(page["link"][1]["relation"], "previous");
+        assert!(page["link"][1]["url"]
+            .as_str()
+            .unwrap()
+            .ends_with("/fhir/Patient?family=PETR&_count=2&_offset=0"));
+
+        let first_page = params(&[("name", "pe"), ("_count", "2")]);
+        let (total, _) = search_patients(&pool, &cipher, &first_page)
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(total, 5);
+        let req = TestRequest::get()
+            .uri("/fhir/Patient?name=pe&_count=2")
+            .to_http_request();
+        let page = bundle(&req, &first_page, total, Vec::new());
+        assert_eq!(page["link"][1]["relation"], "next");
+        assert!(page["link"][1]["url"]
+            .as_str()
+            .unwrap()
+            .ends_with("/fhir/Patient?name=pe&_count=2&_offset=2"));
+
+        // `_` is not a wildcard.
+        let (total, _) = search_patients(&pool, &cipher, &params(&[("family", "pe_")]))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(total, 1);
+    }
+}
@@ -0,0 +1,929 @@
+use crate::concurrency::{row_exists, ConcurrencySettings};
+use crate::crypto::FieldCipher;
+use crate::models::{
+    Doctor, NewDoctor, NewPatient, NewScheduleEntry, NewTicket, OptionDoctor, OptionPatient,
+    OptionScheduleEntry, OptionTicket, Patient, ScheduleEntry, Ticket,
+};
+use crate::{doctors, patients, schedule, tickets};
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::{
+    ComplexObject, Context, EmptySubscription, Error, ErrorExtensions, Object, Request, Result,
+    Schema, SimpleObject,
+};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{error, warn};
+
+pub type HospitalSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+/// Queries nesting deeper than this are rejected before they run. Appointments link back to
+/// patients and doctors, so without a limit one query could walk the whole schedule over and
+/// over. The introspection query of GraphiQL nests about a dozen levels deep.
+const MAX_DEPTH: usize = 15;
+
+/// Queries selecting more fields than this, counting every field once, are rejected too.
+const MAX_COMPLEXITY: usize = 500;
+
+/// Builds the schema. Loaders cache rows, so they are added to every request instead, by
+/// [`with_loaders`].
+pub fn schema(
+    pool: PgPool,
+    cipher: Arc<FieldCipher>,
+    settings: Arc<ConcurrencySettings>,
+) -> HospitalSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        .data(pool)
+        .data(cipher)
+        .data(settings)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+pub fn with_loaders(request: Request, pool: &PgPool, cipher: &Arc<FieldCipher>) -> Request {
+    let loaders = Loaders {
+        pool: pool.clone(),
+        cipher: cipher.clone(),
+    };
+    request.data(DataLoader::new(loaders, tokio::spawn))
+}
+
+/// Errors carry a `code` extension, the GraphQL counterpart of the REST status codes.
+fn error(message: &str, code: &str) -> Error {
+    Error::new(message).extend_with(|_, extensions| extensions.set("code", code))
+}
+
+fn invalid_input(e: sqlx::Error) -> Error {
+    warn!(error = %e, "Database request failed");
+    error("Invalid input", "BAD_USER_INPUT")
+}
+
+fn load_error(e: Arc<sqlx::Error>) -> Error {
+    error!(error = %e, "GraphQL failed to load rows");
+    error("Failed to load", "INTERNAL_SERVER_ERROR")
+}
+
+/// Reads `expectedVersion`, which stands in for `If-Match` and follows the same strict mode.
+fn expected_versions(ctx: &Context<'_>, expected_version: Option<i32>) -> Result<Option<Vec<i32>>> {
+    match expected_version {
+        Some(version) => Ok(Some(vec![version])),
+        None if ctx
+            .data_unchecked::<Arc<ConcurrencySettings>>()
+            .strict_if_match =>
+        {
+            Err(error(
+                "expectedVersion is required",
+                "PRECONDITION_REQUIRED",
+            ))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Tells apart a conditional write that found no row from one that lost the version race.
+async fn precondition_failed_or_not_found(pool: &PgPool, table: &'static str, id: i32) -> Error {
+    if row_exists(pool, table, id).await {
+        error("Entry was modified by someone else", "PRECONDITION_FAILED")
+    } else {
+        error("Entry not found", "NOT_FOUND")
+    }
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(name = "Patient", complex)]
+pub struct PatientRow {
+    pub id: i32,
+    pub name: String,
+    pub surname: String,
+    pub birth_date: String,
+    pub phone_number: String,
+    pub passport_number: String,
+    /// Pass it as `expectedVersion` to update or delete only this version.
+    pub version: i32,
+}
+
+impl PatientRow {
+    fn decrypt(mut self, cipher: &FieldCipher) -> Self {
+        self.phone_number = cipher.decrypt(&self.phone_number);
+        self.passport_number = cipher.decrypt(&self.passport_number);
+        self
+    }
+}
+
+impl From<(Patient, i32)> for PatientRow {
+    fn from((patient, version): (Patient, i32)) -> Self {
+        PatientRow {
+            id: patient.id,
+            name: patient.name,
+            surname: patient.surname,
+            birth_date: patient.birth_date,
+            phone_number: patient.phone_number,
+            passport_number: patient.passport_number,
+            version,
+        }
+    }
+}
+
+#[ComplexObject]
+impl PatientRow {
+    /// Appointments of the patient in ticket order, from the `from` date on when given.
+    async fn appointments(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<String>,
+    ) -> Result<Vec<ScheduleEntryRow>> {
+        appointments(ctx, AppointmentsOf::Patient(self.id), from).await
+    }
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(name = "Doctor", complex)]
+pub struct DoctorRow {
+    pub id: i32,
+    pub name: String,
+    pub surname: String,
+    pub speciality: String,
+    pub phone_number: String,
+    pub passport_number: String,
+    /// Pass it as `expectedVersion` to update or delete only this version.
+    pub version: i32,
+}
+
+impl DoctorRow {
+    fn decrypt(mut self, cipher: &FieldCipher) -> Self {
+        self.phone_number = cipher.decrypt(&self.phone_number);
+        self.passport_number = cipher.decrypt(&self.passport_number);
+        self
+    }
+}
+
+impl From<(Doctor, i32)> for DoctorRow {
+    fn from((doctor, version): (Doctor, i32)) -> Self {
+        DoctorRow {
+            id: doctor.id,
+            name: doctor.name,
+            surname: doctor.surname,
+            speciality: doctor.speciality,
+            phone_number: doctor.phone_number,
+            passport_number: doctor.passport_number,
+            version,
+        }
+    }
+}
+
+#[ComplexObject]
+impl DoctorRow {
+    /// Appointments of the doctor in ticket order, from the `from` date on when given.
+    async fn appointments(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<String>,
+    ) -> Result<Vec<ScheduleEntryRow>> {
+        appointments(ctx, AppointmentsOf::Doctor(self.id), from).await
+    }
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(name = "Ticket", complex)]
+pub struct TicketRow {
+    pub id: i32,
+    pub date: String,
+    pub time: String,
+    pub office_number: i32,
+    /// Pass it as `expectedVersion` to update or delete only this version.
+    pub version: i32,
+}
+
+impl From<(Ticket, i32)> for TicketRow {
+    fn from((ticket, version): (Ticket, i32)) -> Self {
+        TicketRow {
+            id: ticket.id,
+            date: ticket.date,
+            time: ticket.time,
+            office_number: ticket.office_number,
+            version,
+        }
+    }
+}
+
+#[ComplexObject]
+impl TicketRow {
+    /// The appointment booked on the ticket, if any.
+    async fn appointment(&self, ctx: &Context<'_>) -> Result<Option<ScheduleEntryRow>> {
+        Ok(appointments(ctx, AppointmentsOf::Ticket(self.id), None)
+            .await?
+            .into_iter()
+            .next())
+    }
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(name = "ScheduleEntry", complex)]
+pub struct ScheduleEntryRow {
+    pub id: i32,
+    pub ticket_id: i32,
+    pub doctor_id: i32,
+    pub patient_id: i32,
+    /// Pass it as `expectedVersion` to update or delete only this version.
+    pub version: i32,
+    #[graphql(skip)]
+    pub date: String,
+}
+
+/// Adds the date of the ticket to an entry returned by a mutation.
+async fn schedule_entry_row(
+    ctx: &Context<'_>,
+    (entry, version): (ScheduleEntry, i32),
+) -> Result<ScheduleEntryRow> {
+    let ticket: TicketRow = load(ctx, TicketId(entry.ticket_id)).await?;
+    Ok(ScheduleEntryRow {
+        id: entry.id,
+        ticket_id: entry.ticket_id,
+        doctor_id: entry.doctor_id,
+        patient_id: entry.patient_id,
+        version,
+        date: ticket.date,
+    })
+}
+
+#[ComplexObject]
+impl ScheduleEntryRow {
+    async fn ticket(&self, ctx: &Context<'_>) -> Result<TicketRow> {
+        load(ctx, TicketId(self.ticket_id)).await
+    }
+
+    async fn doctor(&self, ctx: &Context<'_>) -> Result<DoctorRow> {
+        load(ctx, DoctorId(self.doctor_id)).await
+    }
+
+    async fn patient(&self, ctx: &Context<'_>) -> Result<PatientRow> {
+        load(ctx, PatientId(self.patient_id)).await
+    }
+}
+
+/// Batches the lookups of one request: all patients, doctors, tickets and appointments asked
+/// for at the same depth of a query are loaded with one query per table.
+pub struct Loaders {
+    pool: PgPool,
+    cipher: Arc<FieldCipher>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PatientId(i32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DoctorId(i32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TicketId(i32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AppointmentsOf {
+    Patient(i32),
+    Doctor(i32),
+    Ticket(i32),
+}
+
+impl Loader<PatientId> for Loaders {
+    type Value = PatientRow;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(
+        &self,
+        keys: &[PatientId],
+    ) -> Result<HashMap<PatientId, PatientRow>, Self::Error> {
+        let ids: Vec<i32> = keys.iter().map(|key| key.0).collect();
+        let rows = sqlx::query_as!(
+            PatientRow,
+            "SELECT id, name, surname, birth_date, phone_number, passport_number, version
+            FROM patients
+            WHERE id = ANY($1)",
+            &ids,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (PatientId(row.id), row.decrypt(&self.cipher)))
+            .collect())
+    }
+}
+
+impl Loader<DoctorId> for Loaders {
+    type Value = DoctorRow;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[DoctorId]) -> Result<HashMap<DoctorId, DoctorRow>, Self::Error> {
+        let ids: Vec<i32> = keys.iter().map(|key| key.0).collect();
+        let rows = sqlx::query_as!(
+            DoctorRow,
+            "SELECT id, name, surname, speciality, phone_number, passport_number, version
+            FROM doctors
+            WHERE id = ANY($1)",
+            &ids,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (DoctorId(row.id), row.decrypt(&self.cipher)))
+            .collect())
+    }
+}
+
+impl Loader<TicketId> for Loaders {
+    type Value = TicketRow;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[TicketId]) -> Result<HashMap<TicketId, TicketRow>, Self::Error> {
+        let ids: Vec<i32> = keys.iter().map(|key| key.0).collect();
+        let rows = sqlx::query_as!(
+            TicketRow,
+            "SELECT id, date, time, office_number, version FROM tickets WHERE id = ANY($1)",
+            &ids,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (TicketId(row.id), row))
+            .collect())
+    }
+}
+
+impl Loader<AppointmentsOf> for Loaders {
+    type Value = Vec<ScheduleEntryRow>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(
+        &self,
+        keys: &[AppointmentsOf],
+    ) -> Result<HashMap<AppointmentsOf, Vec<ScheduleEntryRow>>, Self::Error> {
+        let (mut patient_ids, mut doctor_ids, mut ticket_ids) =
+            (Vec::new(), Vec::new(), Vec::new());
+        for key in keys {
+            match *key {
+                AppointmentsOf::Patient(id) => patient_ids.push(id),
+                AppointmentsOf::Doctor(id) => doctor_ids.push(id),
+                AppointmentsOf::Ticket(id) => ticket_ids.push(id),
+            }
+        }
+        let rows = sqlx::query_as!(
+            ScheduleEntryRow,
+            "SELECT schedule.id, schedule.ticket_id, schedule.doctor_id, schedule.patient_id,
+                schedule.version, tickets.date
+            FROM schedule
+            JOIN tickets ON schedule.ticket_id = tickets.id
+            WHERE schedule.patient_id = ANY($1) OR schedule.doctor_id = ANY($2) OR schedule.ticket_id = ANY($3)
+            ORDER BY tickets.date, tickets.time, schedule.id",
+            &patient_ids,
+            &doctor_ids,
+            &ticket_ids,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut appointments: HashMap<AppointmentsOf, Vec<ScheduleEntryRow>> =
+            keys.iter().map(|key| (*key, Vec::new())).collect();
+        for row in rows {
+            for key in [
+                AppointmentsOf::Patient(row.patient_id),
+                AppointmentsOf::Doctor(row.doctor_id),
+                AppointmentsOf::Ticket(row.ticket_id),
+            ] {
+                if let Some(entries) = appointments.get_mut(&key) {
+                    entries.push(row.clone());
+                }
+            }
+        }
+        Ok(appointments)
+    }
+}
+
+async fn load<K, V>(ctx: &Context<'_>, key: K) -> Result<V>
+where
+    K: Send + Sync + std::hash::Hash + Eq + Clone + 'static,
+    Loaders: Loader<K, Value = V, Error = Arc<sqlx::Error>>,
+    V: Send + Sync + Clone + 'static,
+{
+    ctx.data_unchecked::<DataLoader<Loaders>>()
+        .load_one(key)
+        .await
+        .map_err(load_error)?
+        .ok_or_else(|| error("Entry not found", "NOT_FOUND"))
+}
+
+async fn load_optional<K, V>(ctx: &Context<'_>, key: K) -> Result<Option<V>>
+where
+    K: Send + Sync + std::hash::Hash + Eq + Clone + 'static,
+    Loaders: Loader<K, Value = V, Error = Arc<sqlx::Error>>,
+    V: Send + Sync + Clone + 'static,
+{
+    ctx.data_unchecked::<DataLoader<Loaders>>()
+        .load_one(key)
+        .await
+        .map_err(load_error)
+}
+
+async fn appointments(
+    ctx: &Context<'_>,
+    of: AppointmentsOf,
+    from: Option<String>,
+) -> Result<Vec<ScheduleEntryRow>> {
+    let entries = load_optional(ctx, of).await?.unwrap_or_default();
+    Ok(match from {
+        Some(from) => entries
+            .into_iter()
+            .filter(|entry: &ScheduleEntryRow| entry.date >= from)
+            .collect(),
+        None => entries,
+    })
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn patient(&self, ctx: &Context<'_>, id: i32) -> Result<Option<PatientRow>> {
+        load_optional(ctx, PatientId(id)).await
+    }
+
+    /// Patients matching every given field exactly, like `GET /patients`.
+    async fn patients(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<OptionPatient>,
+    ) -> Result<Vec<PatientRow>> {
+        let filter = filter.unwrap_or_default();
+        let cipher = ctx.data_unchecked::<Arc<FieldCipher>>();
+        let rows = sqlx::query_as!(
+            PatientRow,
+            "SELECT id, name, surname, birth_date, phone_number, passport_number, version
+            FROM patients
+            WHERE
+                (COALESCE($1, '') = '' OR name = $1) AND
+                (COALESCE($2, '') = '' OR surname = $2) AND
+                (COALESCE($3, '') = '' OR birth_date = $3) AND
+                (COALESCE($4, '') = '' OR phone_number_index = $4) AND
+                (COALESCE($5, '') = '' OR passport_number_index = $5)
+            ORDER BY id",
+            filter.name,
+            filter.surname,
+            filter.birth_date,
+            cipher.blind_index_filter(&filter.phone_number),
+            cipher.blind_index_filter(&filter.passport_number),
+        )
+        .fetch_all(ctx.data_unchecked::<PgPool>())
+        .await
+        .map_err(|e| load_error(Arc::new(e)))?;
+
+        Ok(rows.into_iter().map(|row| row.decrypt(cipher)).collect())
+    }
+
+    async fn doctor(&self, ctx: &Context<'_>, id: i32) -> Result<Option<DoctorRow>> {
+        load_optional(ctx, DoctorId(id)).await
+    }
+
+    /// Doctors matching every given field exactly, like `GET /doctors`.
+    async fn doctors(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<OptionDoctor>,
+    ) -> Result<Vec<DoctorRow>> {
+        let filter = filter.unwrap_or_default();
+        let cipher = ctx.data_unchecked::<Arc<FieldCipher>>();
+        let rows = sqlx::query_as!(
+            DoctorRow,
+            "SELECT id, name, surname, speciality, phone_number, passport_number, version
+            FROM doctors
+            WHERE
+                (COALESCE($1, '') = '' OR name = $1) AND
+                (COALESCE($2, '') = '' OR surname = $2) AND
+                (COALESCE($3, '') = '' OR speciality = $3) AND
+                (COALESCE($4, '') = '' OR phone_number_index = $4) AND
+                (COALESCE($5, '') = '' OR passport_number_index = $5)
+            ORDER BY id",
+            filter.name,
+            filter.surname,
+            filter.speciality,
+            cipher.blind_index_filter(&filter.phone_number),
+            cipher.blind_index_filter(&filter.passport_number),
+        )
+        .fetch_all(ctx.data_unchecked::<PgPool>())
+        .await
+        .map_err(|e| load_error(Arc::new(e)))?;
+
+        Ok(rows.into_iter().map(|row| row.decrypt(cipher)).collect())
+    }
+
+    async fn ticket(&self, ctx: &Context<'_>, id: i32) -> Result<Option<TicketRow>> {
+        load_optional(ctx, TicketId(id)).await
+    }
+
+    /// Tickets matching every given field exactly, like `GET /tickets`.
+    async fn tickets(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<OptionTicket>,
+    ) -> Result<Vec<TicketRow>> {
+        let filter = filter.unwrap_or_default();
+        sqlx::query_as!(
+            TicketRow,
+            "SELECT id, date, time, office_number, version
+            FROM tickets
+            WHERE
+                (COALESCE($1, '') = '' OR date = $1) AND
+                (COALESCE($2, '') = '' OR time = $2) AND
+                (COALESCE($3, 0) = 0 OR office_number = $3)
+            ORDER BY date, time, id",
+            filter.date,
+            filter.time,
+            filter.office_number,
+        )
+        .fetch_all(ctx.data_unchecked::<PgPool>())
+        .await
+        .map_err(|e| load_error(Arc::new(e)))
+    }
+
+    async fn schedule_entry(&self, ctx: &Context<'_>, id: i32) -> Result<Option<ScheduleEntryRow>> {
+        sqlx::query_as!(
+            ScheduleEntryRow,
+            "SELECT schedule.id, schedule.ticket_id, schedule.doctor_id, schedule.patient_id,
+                schedule.version, tickets.date
+            FROM schedule
+            JOIN tickets ON schedule.ticket_id = tickets.id
+            WHERE schedule.id = $1",
+            id,
+        )
+        .fetch_optional(ctx.data_unchecked::<PgPool>())
+        .await
+        .map_err(|e| load_error(Arc::new(e)))
+    }
+
+    /// Schedule entries matching every given id, like `GET /schedule`.
+    async fn schedule(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<OptionScheduleEntry>,
+    ) -> Result<Vec<ScheduleEntryRow>> {
+        let filter = filter.unwrap_or_default();
+        sqlx::query_as!(
+            ScheduleEntryRow,
+            "SELECT schedule.id, schedule.ticket_id, schedule.doctor_id, schedule.patient_id,
+                schedule.version, tickets.date
+            FROM schedule
+            JOIN tickets ON schedule.ticket_id = tickets.id
+            WHERE
+                (COALESCE($1, 0) = 0 OR schedule.ticket_id = $1) AND
+                (COALESCE($2, 0) = 0 OR schedule.doctor_id = $2) AND
+                (COALESCE($3, 0) = 0 OR schedule.patient_id = $3)
+            ORDER BY tickets.date, tickets.time, schedule.id",
+            filter.ticket_id,
+            filter.doctor_id,
+            filter.patient_id,
+        )
+        .fetch_all(ctx.data_unchecked::<PgPool>())
+        .await
+        .map_err(|e| load_error(Arc::new(e)))
+    }
+}
+
+/// Mutations share their statements with the REST handlers: constraint violations are
+/// `BAD_USER_INPUT`, and `expectedVersion` does what `If-Match` does.
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn create_patient(&self, ctx: &Context<'_>, input: NewPatient) -> Result<PatientRow> {
+        let (pool, cipher) = (
+            ctx.data_unchecked::<PgPool>(),
+            ctx.data_unchecked::<Arc<FieldCipher>>(),
+        );
+        patients::create(pool, cipher, &input)
+            .await
+            .map(PatientRow::from)
+            .map_err(invalid_input)
+    }
+
+    /// Changes the given fields, like `PATCH /patients/{id}`.
+    async fn update_patient(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        input: OptionPatient,
+        expected_version: Option<i32>,
+    ) -> Result<PatientRow> {
+        let expected_versions = expected_versions(ctx, expected_version)?;
+        let (pool, cipher) = (
+            ctx.data_unchecked::<PgPool>(),
+            ctx.data_unchecked::<Arc<FieldCipher>>(),
+        );
+        match patients::update(pool, cipher, id, &input, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?
+        {
+            Some(updated) => Ok(updated.into()),
+            None => Err(precondition_failed_or_not_found(pool, "patients", id).await),
+        }
+    }
+
+    async fn delete_patient(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        expected_version: Option<i32>,
+    ) -> Result<bool> {
+        let expected_versions = expected_versions(ctx, expected_version)?;
+        let pool = ctx.data_unchecked::<PgPool>();
+        match patients::delete(pool, id, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?
+        {
+            true => Ok(true),
+            false => Err(precondition_failed_or_not_found(pool, "patients", id).await),
+        }
+    }
+
+    async fn create_doctor(&self, ctx: &Context<'_>, input: NewDoctor) -> Result<DoctorRow> {
+        let (pool, cipher) = (
+            ctx.data_unchecked::<PgPool>(),
+            ctx.data_unchecked::<Arc<FieldCipher>>(),
+        );
+        doctors::create(pool, cipher, &input)
+            .await
+            .map(DoctorRow::from)
+            .map_err(invalid_input)
+    }
+
+    /// Changes the given fields, like `PATCH /doctors/{id}`.
+    async fn update_doctor(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        input: OptionDoctor,
+        expected_version: Option<i32>,
+    ) -> Result<DoctorRow> {
+        let expected_versions = expected_versions(ctx, expected_version)?;
+        let (pool, cipher) = (
+            ctx.data_unchecked::<PgPool>(),
+            ctx.data_unchecked::<Arc<FieldCipher>>(),
+        );
+        match doctors::update(pool, cipher, id, &input, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?
+        {
+            Some(updated) => Ok(updated.into()),
+            None => Err(precondition_failed_or_not_found(pool, "doctors", id).await),
+        }
+    }
+
+    async fn delete_doctor(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        expected_version: Option<i32>,
+    ) -> Result<bool> {
+        let expected_versions = expected_versions(ctx, expected_version)?;
+        let pool = ctx.data_unchecked::<PgPool>();
+        match doctors::delete(pool, id, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?
+        {
+            true => Ok(true),
+            false => Err(precondition_failed_or_not_found(pool, "doctors", id).await),
+        }
+    }
+
+    async fn create_ticket(&self, ctx: &Context<'_>, input: NewTicket) -> Result<TicketRow> {
+        tickets::create(ctx.data_unchecked::<PgPool>(), &input)
+            .await
+            .map(TicketRow::from)
+            .map_err(invalid_input)
+    }
+
+    /// Changes the given fields, like `PATCH /tickets/{id}`.
+    async fn update_ticket(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        input: OptionTicket,
+        expected_version: Option<i32>,
+    ) -> Result<TicketRow> {
+        let expected_versions = expected_versions(ctx, expected_version)?;
+        let pool = ctx.data_unchecked::<PgPool>();
+        match tickets::update(pool, id, &input, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?
+        {
+            Some(updated) => Ok(updated.into()),
+            None => Err(precondition_failed_or_not_found(pool, "tickets", id).await),
+        }
+    }
+
+    async fn delete_ticket(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        expected_version: Option<i32>,
+    ) -> Result<bool> {
+        let expected_versions = expected_versions(ctx, expected_version)?;
+        let pool = ctx.data_unchecked::<PgPool>();
+        match tickets::delete(pool, id, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?
+        {
+            true => Ok(true),
+            false => Err(precondition_failed_or_not_found(pool, "tickets", id).await),
+        }
+    }
+
+    async fn create_schedule_entry(
+        &self,
+        ctx: &Context<'_>,
+        input: NewScheduleEntry,
+    ) -> Result<ScheduleEntryRow> {
+        let created = schedule::create(ctx.data_unchecked::<PgPool>(), &input)
+            .await
+            .map_err(invalid_input)?;
+        schedule_entry_row(ctx, created).await
+    }
+
+    /// Changes the given ids, like `PATCH /schedule/{id}`.
+    async fn update_schedule_entry(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        input: OptionScheduleEntry,
+        expected_version: Option<i32>,
+    ) -> Result<ScheduleEntryRow> {
+        let expected_versions = expected_versions(ctx, expected_version)?;
+        let pool = ctx.data_unchecked::<PgPool>();
+        match schedule::update(pool, id, &input, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?
+        {
+            Some(updated) => schedule_entry_row(ctx, updated).await,
+            None => Err(precondition_failed_or_not_found(pool, "schedule", id).await),
+        }
+    }
+
+    async fn delete_schedule_entry(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        expected_version: Option<i32>,
+    ) -> Result<bool> {
+        let expected_versions = expected_versions(ctx, expected_version)?;
+        let pool = ctx.data_unchecked::<PgPool>();
+        match schedule::delete(pool, id, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?
+        {
+            true => Ok(true),
+            false => Err(precondition_failed_or_not_found(pool, "schedule", id).await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::patch_patient;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+    use serde_json::{json, Value};
+
+    fn cipher() -> Arc<FieldCipher> {
+        Arc::new(FieldCipher::new(&Default::default()).unwrap())
+    }
+
+    async fn execute(schema: &HospitalSchema, pool: &PgPool, query: String) -> Value {
+        let request = with_loaders(Request::new(query), pool, &cipher());
+        serde_json::to_value(schema.execute(request).await).unwrap()
+    }
+
+    fn first_error(response: &Value) -> (&str, &str) {
+        let error = &response["errors"][0];
+        (
+            error["message"].as_str().unwrap_or_default(),
+            error["extensions"]["code"].as_str().unwrap_or_default(),
+        )
+    }
+
+    #[sqlx::test]
+    async fn deep_and_complex_queries_are_rejected(pool: PgPool) {
+        let settings = Arc::new(ConcurrencySettings {
+            strict_if_match: false,
+        });
+        let schema = schema(pool.clone(), cipher(), settings);
+
+        // Every patient and appointment pair adds two levels.
+        let nested = |pairs: usize| {
+            let mut selection = "id".to_string();
+            for _ in 0..pairs {
+                selection = format!("appointments {{ id patient {{ {} }} }}", selection);
+            }
+            format!("{{ patient(id: 1) {{ {} }} }}", selection)
+        };
+        let response = execute(&schema, &pool, nested(6)).await;
+        assert!(response["errors"].is_null(), "{}", response);
+        let response = execute(&schema, &pool, nested(8)).await;
+        assert_eq!(first_error(&response).0, "Query is nested too deep.");
+        assert!(response["data"].is_null());
+
+        let fields = (0..MAX_COMPLEXITY / 5)
+            .map(|n| format!("p{}: patient(id: {}) {{ id name surname birthDate }}", n, n))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let response = execute(&schema, &pool, format!("{{ {} }}", fields)).await;
+        assert!(response["errors"].is_null(), "{}", response);
+        let fields = format!("{} extra: patient(id: 0) {{ id }}", fields);
+        let response = execute(&schema, &pool, format!("{{ {} }}", fields)).await;
+        assert_eq!(first_error(&response).0, "Query is too complex.");
+    }
+
+    /// Runs the same conditional update through `PATCH /patients/{id}` and `updatePatient`,
+    /// returning the REST status and the GraphQL error code.
+    async fn update_both_ways(
+        pool: &PgPool,
+        strict_if_match: bool,
+        id: i32,
+        version: Option<i32>,
+    ) -> (u16, String) {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(
+                    FieldCipher::new(&Default::default()).unwrap(),
+                ))
+                .app_data(web::Data::new(ConcurrencySettings { strict_if_match }))
+                .service(patch_patient),
+        )
+        .await;
+        let mut req = TestRequest::patch()
+            .uri(&format!("/patients/{}", id))
+            .set_json(json!({ "name": "Anna" }));
+        if let Some(version) = version {
+            req = req.insert_header(("If-Match", format!("\"{}\"", version)));
+        }
+        let status = call_service(&app, req.to_request()).await.status().as_u16();
+
+        let schema = schema(
+            pool.clone(),
+            cipher(),
+            Arc::new(ConcurrencySettings { strict_if_match }),
+        );
+        let expected_version = version.map_or("null".to_string(), |v| v.to_string());
+        let response = execute(
+            &schema,
+            pool,
+            format!(
+                r#"mutation {{
+                    updatePatient(id: {}, input: {{ name: "Anna" }}, expectedVersion: {}) {{
+                        version
+                    }}
+                }}"#,
+                id, expected_version
+            ),
+        )
+        .await;
+        let code = match response["errors"].is_null() {
+            true => "OK".to_string(),
+            false => first_error(&response).1.to_string(),
+        };
+        (status, code)
+    }
+
+    #[sqlx::test]
+    async fn expected_versions_fail_like_if_match(pool: PgPool) {
+        let id = sqlx::query_scalar!(
+            "INSERT INTO patients (name, surname, birth_date, phone_number, passport_number)
+            VALUES ('Ann', 'Petrova', '1990-04-02', '', '') RETURNING id"
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(
+            update_both_ways(&pool, false, id, Some(7)).await,
+            (412, "PRECONDITION_FAILED".to_string())
+        );
+        assert_eq!(
+            update_both_ways(&pool, false, id + 1, Some(1)).await,
+            (404, "NOT_FOUND".to_string())
+        );
+        assert_eq!(
+            update_both_ways(&pool, true, id, None).await,
+            (428, "PRECONDITION_REQUIRED".to_string())
+        );
+        // The REST update bumps the version, the GraphQL one then sees the old one.
+        assert_eq!(
+            update_both_ways(&pool, false, id, Some(1)).await,
+            (200, "PRECONDITION_FAILED".to_string())
+        );
+    }
+}
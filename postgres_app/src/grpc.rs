@@ -0,0 +1,772 @@
+use crate::concurrency::{row_exists, ConcurrencySettings};
+use crate::crypto::FieldCipher;
+use crate::models::{self, ScheduleEventFilter, ScheduleEventKind};
+use crate::schedule_events::{self, ScheduleEvents};
+use crate::{doctors, patients, schedule, tickets};
+use proto::hospital_server::{Hospital, HospitalServer};
+use sqlx::PgPool;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::{error, warn};
+
+pub mod proto {
+    tonic::include_proto!("hospital.v1");
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("hospital_descriptor");
+}
+
+pub struct GrpcSettings {
+    /// Loopback address of the gRPC server, which is plaintext. `None` disables it.
+    pub address: Option<SocketAddr>,
+}
+
+/// Serves the `Hospital` service together with server reflection, so that tools such as
+/// grpcurl can list and call it without the proto file.
+///
+/// Once `shutdown` completes no new calls are accepted, and running ones get `timeout` to
+/// finish. `WatchSchedule` streams never finish on their own, so they are cut off then.
+pub async fn serve(
+    listener: TcpListener,
+    service: HospitalService,
+    shutdown: impl Future<Output = ()>,
+    timeout: Duration,
+) -> Result<(), String> {
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+        .build_v1()
+        .map_err(|e| e.to_string())?;
+    let reflection_v1alpha = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+        .build_v1alpha()
+        .map_err(|e| e.to_string())?;
+
+    let incoming = TcpIncoming::from_listener(listener, true, None).map_err(|e| e.to_string())?;
+
+    let (stopping, stopped) = oneshot::channel();
+    let server = Server::builder()
+        .add_service(HospitalServer::new(service))
+        .add_service(reflection)
+        .add_service(reflection_v1alpha)
+        .serve_with_incoming_shutdown(incoming, async {
+            shutdown.await;
+            let _ = stopping.send(());
+        });
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => return result.map_err(|e| e.to_string()),
+        _ = stopped => {}
+    }
+    match tokio::time::timeout(timeout, server).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => {
+            warn!("gRPC calls still running at the shutdown timeout were cancelled");
+            Ok(())
+        }
+    }
+}
+
+pub struct HospitalService {
+    pool: PgPool,
+    cipher: Arc<FieldCipher>,
+    settings: Arc<ConcurrencySettings>,
+    events: Arc<ScheduleEvents>,
+}
+
+impl HospitalService {
+    pub fn new(
+        pool: PgPool,
+        cipher: Arc<FieldCipher>,
+        settings: Arc<ConcurrencySettings>,
+        events: Arc<ScheduleEvents>,
+    ) -> Self {
+        HospitalService {
+            pool,
+            cipher,
+            settings,
+            events,
+        }
+    }
+
+    /// `expected_version` stands in for `If-Match` and follows the same strict mode.
+    #[allow(clippy::result_large_err)] // tonic handlers return `Status` unboxed
+    fn expected_versions(&self, expected_version: Option<i32>) -> Result<Option<Vec<i32>>, Status> {
+        match expected_version {
+            Some(version) => Ok(Some(vec![version])),
+            None if self.settings.strict_if_match => {
+                Err(Status::failed_precondition("expected_version is required"))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Tells apart a conditional write that found no row from one that lost the version race.
+    async fn aborted_or_not_found(&self, table: &'static str, id: i32) -> Status {
+        if row_exists(&self.pool, table, id).await {
+            Status::aborted("Entry was modified by someone else")
+        } else {
+            Status::not_found("Entry not found")
+        }
+    }
+
+    fn decrypt_patient(&self, mut patient: proto::Patient) -> proto::Patient {
+        patient.phone_number = self.cipher.decrypt(&patient.phone_number);
+        patient.passport_number = self.cipher.decrypt(&patient.passport_number);
+        patient
+    }
+
+    fn decrypt_doctor(&self, mut doctor: proto::Doctor) -> proto::Doctor {
+        doctor.phone_number = self.cipher.decrypt(&doctor.phone_number);
+        doctor.passport_number = self.cipher.decrypt(&doctor.passport_number);
+        doctor
+    }
+}
+
+fn invalid_input(e: sqlx::Error) -> Status {
+    warn!(error = %e, "Database request failed");
+    Status::invalid_argument("Invalid input")
+}
+
+fn load_error(e: sqlx::Error) -> Status {
+    error!(error = %e, "gRPC request failed to load rows");
+    Status::internal("Failed to load")
+}
+
+impl From<proto::NewPatient> for models::NewPatient {
+    fn from(patient: proto::NewPatient) -> Self {
+        models::NewPatient {
+            name: patient.name,
+            surname: patient.surname,
+            birth_date: patient.birth_date,
+            phone_number: patient.phone_number,
+            passport_number: patient.passport_number,
+        }
+    }
+}
+
+impl From<proto::OptionPatient> for models::OptionPatient {
+    fn from(changes: proto::OptionPatient) -> Self {
+        models::OptionPatient {
+            name: changes.name,
+            surname: changes.surname,
+            birth_date: changes.birth_date,
+            phone_number: changes.phone_number,
+            passport_number: changes.passport_number,
+        }
+    }
+}
+
+impl From<(models::Patient, i32)> for proto::Patient {
+    fn from((patient, version): (models::Patient, i32)) -> Self {
+        proto::Patient {
+            id: patient.id,
+            name: patient.name,
+            surname: patient.surname,
+            birth_date: patient.birth_date,
+            phone_number: patient.phone_number,
+            passport_number: patient.passport_number,
+            version,
+        }
+    }
+}
+
+impl From<proto::NewDoctor> for models::NewDoctor {
+    fn from(doctor: proto::NewDoctor) -> Self {
+        models::NewDoctor {
+            name: doctor.name,
+            surname: doctor.surname,
+            speciality: doctor.speciality,
+            phone_number: doctor.phone_number,
+            passport_number: doctor.passport_number,
+        }
+    }
+}
+
+impl From<proto::OptionDoctor> for models::OptionDoctor {
+    fn from(changes: proto::OptionDoctor) -> Self {
+        models::OptionDoctor {
+            name: changes.name,
+            surname: changes.surname,
+            speciality: changes.speciality,
+            phone_number: changes.phone_number,
+            passport_number: changes.passport_number,
+        }
+    }
+}
+
+impl From<(models::Doctor, i32)> for proto::Doctor {
+    fn from((doctor, version): (models::Doctor, i32)) -> Self {
+        proto::Doctor {
+            id: doctor.id,
+            name: doctor.name,
+            surname: doctor.surname,
+            speciality: doctor.speciality,
+            phone_number: doctor.phone_number,
+            passport_number: doctor.passport_number,
+            version,
+        }
+    }
+}
+
+impl From<proto::NewTicket> for models::NewTicket {
+    fn from(ticket: proto::NewTicket) -> Self {
+        models::NewTicket {
+            date: ticket.date,
+            time: ticket.time,
+            office_number: ticket.office_number,
+        }
+    }
+}
+
+impl From<proto::OptionTicket> for models::OptionTicket {
+    fn from(changes: proto::OptionTicket) -> Self {
+        models::OptionTicket {
+            date: changes.date,
+            time: changes.time,
+            office_number: changes.office_number,
+        }
+    }
+}
+
+impl From<(models::Ticket, i32)> for proto::Ticket {
+    fn from((ticket, version): (models::Ticket, i32)) -> Self {
+        proto::Ticket {
+            id: ticket.id,
+            date: ticket.date,
+            time: ticket.time,
+            office_number: ticket.office_number,
+            version,
+        }
+    }
+}
+
+impl From<proto::NewScheduleEntry> for models::NewScheduleEntry {
+    fn from(schedule_entry: proto::NewScheduleEntry) -> Self {
+        models::NewScheduleEntry {
+            ticket_id: schedule_entry.ticket_id,
+            doctor_id: schedule_entry.doctor_id,
+            patient_id: schedule_entry.patient_id,
+        }
+    }
+}
+
+impl From<proto::OptionScheduleEntry> for models::OptionScheduleEntry {
+    fn from(changes: proto::OptionScheduleEntry) -> Self {
+        models::OptionScheduleEntry {
+            ticket_id: changes.ticket_id,
+            doctor_id: changes.doctor_id,
+            patient_id: changes.patient_id,
+        }
+    }
+}
+
+impl From<(models::ScheduleEntry, i32)> for proto::ScheduleEntry {
+    fn from((schedule_entry, version): (models::ScheduleEntry, i32)) -> Self {
+        proto::ScheduleEntry {
+            id: schedule_entry.id,
+            ticket_id: schedule_entry.ticket_id,
+            doctor_id: schedule_entry.doctor_id,
+            patient_id: schedule_entry.patient_id,
+            version,
+        }
+    }
+}
+
+impl From<models::FullScheduleEntry> for proto::FullScheduleEntry {
+    fn from(entry: models::FullScheduleEntry) -> Self {
+        proto::FullScheduleEntry {
+            schedule_id: entry.schedule_id,
+            ticket_id: entry.ticket_id,
+            doctor_id: entry.doctor_id,
+            patient_id: entry.patient_id,
+            ticket_date: entry.ticket_date,
+            ticket_time: entry.ticket_time,
+            ticket_office_number: entry.ticket_office_number,
+            doctor_name: entry.doctor_name,
+            doctor_surname: entry.doctor_surname,
+            doctor_speciality: entry.doctor_speciality,
+            doctor_phone_number: entry.doctor_phone_number,
+            doctor_passport_number: entry.doctor_passport_number,
+            patient_name: entry.patient_name,
+            patient_surname: entry.patient_surname,
+            patient_birth_date: entry.patient_birth_date,
+            patient_phone_number: entry.patient_phone_number,
+            patient_passport_number: entry.patient_passport_number,
+        }
+    }
+}
+
+impl From<models::ScheduleEvent> for proto::ScheduleEvent {
+    fn from(event: models::ScheduleEvent) -> Self {
+        let kind = match event.kind {
+            ScheduleEventKind::Created => proto::schedule_event::Kind::Created,
+            ScheduleEventKind::Updated => proto::schedule_event::Kind::Updated,
+            ScheduleEventKind::Cancelled => proto::schedule_event::Kind::Cancelled,
+        };
+        proto::ScheduleEvent {
+            kind: kind.into(),
+            entry: Some(event.entry.into()),
+            previous: event.previous.map(|previous| proto::PreviousAppointment {
+                ticket_id: previous.ticket_id,
+                doctor_id: previous.doctor_id,
+                patient_id: previous.patient_id,
+                date: previous.date,
+                time: previous.time,
+                office_number: previous.office_number,
+            }),
+        }
+    }
+}
+
+type ScheduleEventStream = Pin<Box<dyn Stream<Item = Result<proto::ScheduleEvent, Status>> + Send>>;
+
+/// The statements match the REST handlers: constraint violations are `INVALID_ARGUMENT`, and
+/// `expected_version` does what `If-Match` does, with `ABORTED` for a lost version race.
+#[tonic::async_trait]
+impl Hospital for HospitalService {
+    async fn get_patient(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> Result<Response<proto::Patient>, Status> {
+        let patient = sqlx::query_as!(
+            proto::Patient,
+            "SELECT id, name, surname, birth_date, phone_number, passport_number, version
+            FROM patients
+            WHERE id = $1",
+            request.get_ref().id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(load_error)?
+        .ok_or_else(|| Status::not_found("Entry not found"))?;
+
+        Ok(Response::new(self.decrypt_patient(patient)))
+    }
+
+    async fn list_patients(
+        &self,
+        request: Request<proto::OptionPatient>,
+    ) -> Result<Response<proto::PatientList>, Status> {
+        let filter = request.into_inner();
+        let patients = sqlx::query_as!(
+            proto::Patient,
+            "SELECT id, name, surname, birth_date, phone_number, passport_number, version
+            FROM patients
+            WHERE
+                (COALESCE($1, '') = '' OR name = $1) AND
+                (COALESCE($2, '') = '' OR surname = $2) AND
+                (COALESCE($3, '') = '' OR birth_date = $3) AND
+                (COALESCE($4, '') = '' OR phone_number_index = $4) AND
+                (COALESCE($5, '') = '' OR passport_number_index = $5)
+            ORDER BY id",
+            filter.name,
+            filter.surname,
+            filter.birth_date,
+            self.cipher.blind_index_filter(&filter.phone_number),
+            self.cipher.blind_index_filter(&filter.passport_number),
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(load_error)?;
+
+        Ok(Response::new(proto::PatientList {
+            patients: patients
+                .into_iter()
+                .map(|patient| self.decrypt_patient(patient))
+                .collect(),
+        }))
+    }
+
+    async fn create_patient(
+        &self,
+        request: Request<proto::NewPatient>,
+    ) -> Result<Response<proto::Patient>, Status> {
+        let new_patient = request.into_inner().into();
+        let created = patients::create(&self.pool, &self.cipher, &new_patient)
+            .await
+            .map_err(invalid_input)?;
+
+        Ok(Response::new(created.into()))
+    }
+
+    async fn update_patient(
+        &self,
+        request: Request<proto::UpdatePatientRequest>,
+    ) -> Result<Response<proto::Patient>, Status> {
+        let request = request.into_inner();
+        let expected_versions = self.expected_versions(request.expected_version)?;
+        let changes = request.changes.unwrap_or_default().into();
+
+        let updated = patients::update(
+            &self.pool,
+            &self.cipher,
+            request.id,
+            &changes,
+            expected_versions.as_deref(),
+        )
+        .await
+        .map_err(invalid_input)?;
+
+        match updated {
+            Some(updated) => Ok(Response::new(updated.into())),
+            None => Err(self.aborted_or_not_found("patients", request.id).await),
+        }
+    }
+
+    async fn delete_patient(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let expected_versions = self.expected_versions(request.expected_version)?;
+        let deleted = patients::delete(&self.pool, request.id, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?;
+
+        match deleted {
+            true => Ok(Response::new(())),
+            false => Err(self.aborted_or_not_found("patients", request.id).await),
+        }
+    }
+
+    async fn get_doctor(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> Result<Response<proto::Doctor>, Status> {
+        let doctor = sqlx::query_as!(
+            proto::Doctor,
+            "SELECT id, name, surname, speciality, phone_number, passport_number, version
+            FROM doctors
+            WHERE id = $1",
+            request.get_ref().id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(load_error)?
+        .ok_or_else(|| Status::not_found("Entry not found"))?;
+
+        Ok(Response::new(self.decrypt_doctor(doctor)))
+    }
+
+    async fn list_doctors(
+        &self,
+        request: Request<proto::OptionDoctor>,
+    ) -> Result<Response<proto::DoctorList>, Status> {
+        let filter = request.into_inner();
+        let doctors = sqlx::query_as!(
+            proto::Doctor,
+            "SELECT id, name, surname, speciality, phone_number, passport_number, version
+            FROM doctors
+            WHERE
+                (COALESCE($1, '') = '' OR name = $1) AND
+                (COALESCE($2, '') = '' OR surname = $2) AND
+                (COALESCE($3, '') = '' OR speciality = $3) AND
+                (COALESCE($4, '') = '' OR phone_number_index = $4) AND
+                (COALESCE($5, '') = '' OR passport_number_index = $5)
+            ORDER BY id",
+            filter.name,
+            filter.surname,
+            filter.speciality,
+            self.cipher.blind_index_filter(&filter.phone_number),
+            self.cipher.blind_index_filter(&filter.passport_number),
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(load_error)?;
+
+        Ok(Response::new(proto::DoctorList {
+            doctors: doctors
+                .into_iter()
+                .map(|doctor| self.decrypt_doctor(doctor))
+                .collect(),
+        }))
+    }
+
+    async fn create_doctor(
+        &self,
+        request: Request<proto::NewDoctor>,
+    ) -> Result<Response<proto::Doctor>, Status> {
+        let new_doctor = request.into_inner().into();
+        let created = doctors::create(&self.pool, &self.cipher, &new_doctor)
+            .await
+            .map_err(invalid_input)?;
+
+        Ok(Response::new(created.into()))
+    }
+
+    async fn update_doctor(
+        &self,
+        request: Request<proto::UpdateDoctorRequest>,
+    ) -> Result<Response<proto::Doctor>, Status> {
+        let request = request.into_inner();
+        let expected_versions = self.expected_versions(request.expected_version)?;
+        let changes = request.changes.unwrap_or_default().into();
+
+        let updated = doctors::update(
+            &self.pool,
+            &self.cipher,
+            request.id,
+            &changes,
+            expected_versions.as_deref(),
+        )
+        .await
+        .map_err(invalid_input)?;
+
+        match updated {
+            Some(updated) => Ok(Response::new(updated.into())),
+            None => Err(self.aborted_or_not_found("doctors", request.id).await),
+        }
+    }
+
+    async fn delete_doctor(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let expected_versions = self.expected_versions(request.expected_version)?;
+        let deleted = doctors::delete(&self.pool, request.id, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?;
+
+        match deleted {
+            true => Ok(Response::new(())),
+            false => Err(self.aborted_or_not_found("doctors", request.id).await),
+        }
+    }
+
+    async fn get_ticket(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> Result<Response<proto::Ticket>, Status> {
+        let ticket = sqlx::query_as!(
+            proto::Ticket,
+            "SELECT id, date, time, office_number, version FROM tickets WHERE id = $1",
+            request.get_ref().id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(load_error)?
+        .ok_or_else(|| Status::not_found("Entry not found"))?;
+
+        Ok(Response::new(ticket))
+    }
+
+    async fn list_tickets(
+        &self,
+        request: Request<proto::OptionTicket>,
+    ) -> Result<Response<proto::TicketList>, Status> {
+        let filter = request.into_inner();
+        let tickets = sqlx::query_as!(
+            proto::Ticket,
+            "SELECT id, date, time, office_number, version
+            FROM tickets
+            WHERE
+                (COALESCE($1, '') = '' OR date = $1) AND
+                (COALESCE($2, '') = '' OR time = $2) AND
+                (COALESCE($3, 0) = 0 OR office_number = $3)
+            ORDER BY date, time, id",
+            filter.date,
+            filter.time,
+            filter.office_number,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(load_error)?;
+
+        Ok(Response::new(proto::TicketList { tickets }))
+    }
+
+    async fn create_ticket(
+        &self,
+        request: Request<proto::NewTicket>,
+    ) -> Result<Response<proto::Ticket>, Status> {
+        let new_ticket = request.into_inner().into();
+        let created = tickets::create(&self.pool, &new_ticket)
+            .await
+            .map_err(invalid_input)?;
+
+        Ok(Response::new(created.into()))
+    }
+
+    async fn update_ticket(
+        &self,
+        request: Request<proto::UpdateTicketRequest>,
+    ) -> Result<Response<proto::Ticket>, Status> {
+        let request = request.into_inner();
+        let expected_versions = self.expected_versions(request.expected_version)?;
+        let changes = request.changes.unwrap_or_default().into();
+
+        let updated = tickets::update(
+            &self.pool,
+            request.id,
+            &changes,
+            expected_versions.as_deref(),
+        )
+        .await
+        .map_err(invalid_input)?;
+
+        match updated {
+            Some(updated) => Ok(Response::new(updated.into())),
+            None => Err(self.aborted_or_not_found("tickets", request.id).await),
+        }
+    }
+
+    async fn delete_ticket(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let expected_versions = self.expected_versions(request.expected_version)?;
+        let deleted = tickets::delete(&self.pool, request.id, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?;
+
+        match deleted {
+            true => Ok(Response::new(())),
+            false => Err(self.aborted_or_not_found("tickets", request.id).await),
+        }
+    }
+
+    async fn get_schedule_entry(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> Result<Response<proto::ScheduleEntry>, Status> {
+        let schedule_entry = sqlx::query_as!(
+            proto::ScheduleEntry,
+            "SELECT id, ticket_id, doctor_id, patient_id, version FROM schedule WHERE id = $1",
+            request.get_ref().id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(load_error)?
+        .ok_or_else(|| Status::not_found("Entry not found"))?;
+
+        Ok(Response::new(schedule_entry))
+    }
+
+    async fn list_schedule(
+        &self,
+        request: Request<proto::OptionScheduleEntry>,
+    ) -> Result<Response<proto::FullScheduleEntryList>, Status> {
+        let filter = request.into_inner();
+        let entries = sqlx::query_as!(
+            models::FullScheduleEntry,
+            "SELECT schedule.id as schedule_id, tickets.id as ticket_id, doctors.id as doctor_id, patients.id as patient_id,
+            tickets.date as ticket_date, tickets.time as ticket_time, tickets.office_number as ticket_office_number,
+            doctors.name as doctor_name, doctors.surname as doctor_surname, doctors.speciality as doctor_speciality,
+            doctors.phone_number as doctor_phone_number, doctors.passport_number as doctor_passport_number,
+            patients.name as patient_name, patients.surname as patient_surname, patients.birth_date as patient_birth_date,
+            patients.phone_number as patient_phone_number, patients.passport_number as patient_passport_number
+            FROM schedule
+            JOIN tickets ON schedule.ticket_id = tickets.id
+            JOIN doctors ON schedule.doctor_id = doctors.id
+            JOIN patients ON schedule.patient_id = patients.id
+            WHERE
+                (COALESCE($1, 0) = 0 OR ticket_id = $1) AND
+                (COALESCE($2, 0) = 0 OR doctor_id = $2) AND
+                (COALESCE($3, 0) = 0 OR patient_id = $3)
+            ORDER BY tickets.date, tickets.time, schedule.id",
+            filter.ticket_id,
+            filter.doctor_id,
+            filter.patient_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(load_error)?;
+
+        Ok(Response::new(proto::FullScheduleEntryList {
+            entries: entries
+                .into_iter()
+                .map(|entry| self.cipher.decrypt_schedule_entry(entry).into())
+                .collect(),
+        }))
+    }
+
+    async fn create_schedule_entry(
+        &self,
+        request: Request<proto::NewScheduleEntry>,
+    ) -> Result<Response<proto::ScheduleEntry>, Status> {
+        let new_schedule_entry = request.into_inner().into();
+        let created = schedule::create(&self.pool, &new_schedule_entry)
+            .await
+            .map_err(invalid_input)?;
+
+        Ok(Response::new(created.into()))
+    }
+
+    async fn update_schedule_entry(
+        &self,
+        request: Request<proto::UpdateScheduleEntryRequest>,
+    ) -> Result<Response<proto::ScheduleEntry>, Status> {
+        let request = request.into_inner();
+        let expected_versions = self.expected_versions(request.expected_version)?;
+        let changes = request.changes.unwrap_or_default().into();
+
+        let updated = schedule::update(
+            &self.pool,
+            request.id,
+            &changes,
+            expected_versions.as_deref(),
+        )
+        .await
+        .map_err(invalid_input)?;
+
+        match updated {
+            Some(updated) => Ok(Response::new(updated.into())),
+            None => Err(self.aborted_or_not_found("schedule", request.id).await),
+        }
+    }
+
+    async fn delete_schedule_entry(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let expected_versions = self.expected_versions(request.expected_version)?;
+        let deleted = schedule::delete(&self.pool, request.id, expected_versions.as_deref())
+            .await
+            .map_err(invalid_input)?;
+
+        match deleted {
+            true => Ok(Response::new(())),
+            false => Err(self.aborted_or_not_found("schedule", request.id).await),
+        }
+    }
+
+    type WatchScheduleStream = ScheduleEventStream;
+
+    /// A subscriber that falls too far behind gets `DATA_LOSS` and should watch again.
+    async fn watch_schedule(
+        &self,
+        request: Request<proto::ScheduleEventFilter>,
+    ) -> Result<Response<Self::WatchScheduleStream>, Status> {
+        let request = request.into_inner();
+        let filter = ScheduleEventFilter {
+            doctor_id: request.doctor_id,
+            patient_id: request.patient_id,
+            office_number: request.office_number,
+        };
+
+        let events =
+            BroadcastStream::new(self.events.subscribe()).filter_map(move |event| match event {
+                Ok(event) if schedule_events::matches(&event, &filter) => Some(Ok(event.into())),
+                Ok(_) => None,
+                Err(BroadcastStreamRecvError::Lagged(missed)) => Some(Err(Status::data_loss(
+                    format!("Missed {} schedule events", missed),
+                ))),
+            });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
@@ -1,17 +1,56 @@
+use crate::anonymize::AnonymizedSled;
+use crate::calendar::{self, CalendarOwner, CalendarSettings};
+use crate::concurrency::{
+    etag, expected_versions, precondition_failed, precondition_failed_or_not_found, row_exists,
+    ConcurrencySettings,
+};
+use crate::crypto::{self, FieldCipher};
+use crate::csv_transfer::{self, CsvUpload};
+use crate::doctors;
+use crate::duplicates;
+use crate::fhir::{self, FhirSettings};
+use crate::graphql::{self, HospitalSchema};
+use crate::health::{self, Health};
+use crate::jobs::{self, CancelError, Dataset, JobKind, QueuedUpload, SledJobParams, UploadError};
+use crate::metrics::{self, Metrics};
 use crate::models::{
-    Doctor, FullScheduleEntry, NewDoctor, NewPatient, NewScheduleEntry, NewTicket, OptionDoctor,
-    OptionPatient, OptionScheduleEntry, OptionTicket, Patient, ScheduleEntry, Ticket, UpdateDoctor,
-    UpdatePatient, UpdateScheduleEntry, UpdateTicket,
+    AsOf, CalendarFeedOptions, CalendarToken, CsvImportOptions, Doctor, DoctorHistoryEntry,
+    DuplicateCandidate, DuplicateFilter, ErasureResult, FullScheduleEntry, HealthDetails, Job,
+    JobFilter, MergePatients, MergeResult, NdjsonExportOptions, NewDoctor, NewPatient,
+    NewScheduleEntry, NewTicket, NewWebhookSubscription, NotificationLogEntry,
+    NotificationLogFilter, NotificationPreferences, OptionDoctor, OptionPatient,
+    OptionScheduleEntry, OptionTicket, Patient, PatientHistoryEntry, Readiness, ReencryptResult,
+    RosterOptions, ScheduleEntry, ScheduleEvent, ScheduleEventFilter, ScheduleReportFilter,
+    SledDatasetOptions, SledExportOptions, Ticket, TicketHistoryEntry, UpdateDoctor,
+    UpdateNotificationPreferences, UpdatePatient, UpdateScheduleEntry, UpdateTicket,
+    UpdateWebhookSubscription, WebhookDelivery, WebhookDeliveryFilter, WebhookSubscription,
 };
-use actix_web::{delete, get, patch, post, web, HttpResponse, Responder};
+use crate::ndjson;
+use crate::patients;
+use crate::pdf;
+use crate::privacy;
+use crate::reminders;
+use crate::reports;
+use crate::schedule;
+use crate::schedule_events::{self, ScheduleEvents};
+use crate::telemetry;
+use crate::tickets;
+use crate::tls;
+use crate::webhooks::{self, WebhookSettings};
+use actix_multipart::Multipart;
+use actix_web::http::{header, StatusCode};
+use actix_web::{delete, get, patch, post, put, web, HttpRequest, HttpResponse, Responder};
+use futures_util::StreamExt;
 use sqlx::PgPool;
+use tracing::{error, warn};
 
 #[utoipa::path(
     get,
     path = "/patients",
     tag = "Patients",
     responses(
-        (status = 200, description = "List of patients", body = [Patient])
+        (status = 200, description = "List of patients", body = [Patient]),
+        (status = 500, description = "Failed to load patients")
     ),
     params(
         ("patient" = OptionPatient, Query, description = "Optional filters")
@@ -20,29 +59,41 @@ use sqlx::PgPool;
 #[get("/patients")]
 pub async fn get_patients(
     pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
     option_patient: web::Query<OptionPatient>,
 ) -> impl Responder {
     let rows = sqlx::query_as!(
         Patient,
-        "SELECT * 
+        "SELECT id, name, surname, birth_date, phone_number, passport_number
         FROM patients
-        WHERE 
+        WHERE
             (COALESCE($1, '') = '' OR name = $1) AND
             (COALESCE($2, '') = '' OR surname = $2) AND
             (COALESCE($3, '') = '' OR birth_date = $3) AND
-            (COALESCE($4, '') = '' OR phone_number = $4) AND
-            (COALESCE($5, '') = '' OR passport_number = $5);",
+            (COALESCE($4, '') = '' OR phone_number_index = $4) AND
+            (COALESCE($5, '') = '' OR passport_number_index = $5);",
         option_patient.name,
         option_patient.surname,
         option_patient.birth_date,
-        option_patient.phone_number,
-        option_patient.passport_number,
+        cipher.blind_index_filter(&option_patient.phone_number),
+        cipher.blind_index_filter(&option_patient.passport_number),
     )
     .fetch_all(pool.get_ref())
-    .await
-    .unwrap();
+    .await;
+
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!(error = %e, "Failed to load patients");
+            return HttpResponse::InternalServerError().body("Failed to load patients");
+        }
+    };
 
-    web::Json(rows)
+    let rows: Vec<Patient> = rows
+        .into_iter()
+        .map(|patient| cipher.decrypt_patient(patient))
+        .collect();
+    HttpResponse::Ok().json(rows)
 }
 
 #[utoipa::path(
@@ -53,38 +104,25 @@ pub async fn get_patients(
     responses(
         (status = 201, description = "Entry successfully created", body = Patient),
         (status = 400, description = "Invalid input")
+    ),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe")
     )
 )]
 #[post("/patients")]
 pub async fn add_patient(
     pool: web::Data<sqlx::PgPool>,
+    cipher: web::Data<FieldCipher>,
     new_patient: web::Json<NewPatient>,
 ) -> impl Responder {
-    let result = sqlx::query!(
-        r#"
-        INSERT INTO patients (name, surname, birth_date, phone_number, passport_number)
-        VALUES ($1, $2, $3, $4, $5)
-        RETURNING id, name, surname, birth_date, phone_number, passport_number
-        "#,
-        new_patient.name,
-        new_patient.surname,
-        new_patient.birth_date,
-        new_patient.phone_number,
-        new_patient.passport_number,
-    )
-    .fetch_one(pool.get_ref())
-    .await;
+    let result = patients::create(pool.get_ref(), &cipher, &new_patient).await;
 
     match result {
-        Ok(patient) => HttpResponse::Created().json(Patient {
-            id: patient.id,
-            name: patient.name,
-            surname: patient.surname,
-            birth_date: patient.birth_date,
-            phone_number: patient.phone_number,
-            passport_number: patient.passport_number,
-        }),
-        Err(_) => HttpResponse::BadRequest().body("Invalid input"),
+        Ok((patient, _)) => HttpResponse::Created().json(patient),
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
     }
 }
 
@@ -96,45 +134,79 @@ pub async fn add_patient(
     responses(
         (status = 200, description = "Entry successfully updated"),
         (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("If-Match" = Option<String>, Header, description = "ETags of the versions being updated, one per matched entry")
     )
 )]
 #[patch("/patients")]
 pub async fn update_patient(
+    req: HttpRequest,
     pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<ConcurrencySettings>,
     request: web::Json<UpdatePatient>,
 ) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+
     let result = sqlx::query!(
         r#"
-        UPDATE patients
-        SET name = COALESCE($1, name),
-            surname = COALESCE($2, surname),
-            birth_date = COALESCE($3, birth_date),
-            phone_number = COALESCE($4, phone_number),
-            passport_number = COALESCE($5, passport_number)
-       WHERE 
-            ($6::TEXT IS NULL OR name = $6) AND
-            ($7::TEXT IS NULL OR surname = $7) AND
-            ($8::TEXT IS NULL OR birth_date = $8) AND
-            ($9::TEXT IS NULL OR phone_number = $9) AND
-            ($10::TEXT IS NULL OR passport_number = $10);
+        WITH matched AS (
+            SELECT id, version FROM patients
+            WHERE
+                ($6::TEXT IS NULL OR name = $6) AND
+                ($7::TEXT IS NULL OR surname = $7) AND
+                ($8::TEXT IS NULL OR birth_date = $8) AND
+                ($9::TEXT IS NULL OR phone_number_index = $9) AND
+                ($10::TEXT IS NULL OR passport_number_index = $10)
+            FOR UPDATE
+        ), updated AS (
+            UPDATE patients
+            SET name = COALESCE($1, name),
+                surname = COALESCE($2, surname),
+                birth_date = COALESCE($3, birth_date),
+                phone_number = COALESCE($4, phone_number),
+                passport_number = COALESCE($5, passport_number),
+                phone_number_index = COALESCE($11, phone_number_index),
+                passport_number_index = COALESCE($12, passport_number_index)
+            FROM matched
+            WHERE patients.id = matched.id AND NOT EXISTS (
+                SELECT 1 FROM matched AS stale
+                WHERE NOT ($13::INT[] IS NULL OR stale.version = ANY($13))
+            )
+            RETURNING patients.id
+        )
+        SELECT (SELECT COUNT(*) FROM matched) as "matched!", (SELECT COUNT(*) FROM updated) as "updated!"
         "#,
         request.update_name,
         request.update_surname,
         request.update_birth_date,
-        request.update_phone_number,
-        request.update_passport_number,
+        request.update_phone_number.as_deref().map(|value| cipher.encrypt(value)),
+        request.update_passport_number.as_deref().map(|value| cipher.encrypt(value)),
         request.condition_name,
         request.condition_surname,
         request.condition_birth_date,
-        request.condition_phone_number,
-        request.condition_passport_number
+        request.condition_phone_number.as_deref().map(|value| cipher.blind_index(value)),
+        request.condition_passport_number.as_deref().map(|value| cipher.blind_index(value)),
+        request.update_phone_number.as_deref().map(|value| cipher.blind_index(value)),
+        request.update_passport_number.as_deref().map(|value| cipher.blind_index(value)),
+        expected_versions.as_deref(),
     )
-    .execute(pool.get_ref())
+    .fetch_one(pool.get_ref())
     .await;
 
     match result {
+        Ok(counts) if counts.updated < counts.matched => precondition_failed(),
         Ok(_) => HttpResponse::Ok().body("Entry successfully updated"),
-        Err(_) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::NotFound().body("Entry not found")
+        }
     }
 }
 
@@ -145,851 +217,4114 @@ pub async fn update_patient(
     request_body = OptionPatient,
     responses(
         (status = 204, description = "Entry successfully deleted"),
-        (status = 404, description = "Entry not found")
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("If-Match" = Option<String>, Header, description = "ETags of the versions being deleted, one per matched entry")
     )
 )]
 #[delete("/patients")]
 pub async fn delete_patient(
+    req: HttpRequest,
     pool: web::Data<sqlx::PgPool>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<ConcurrencySettings>,
     option_patient: web::Json<OptionPatient>,
 ) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+
     let result = sqlx::query!(
         r#"
-        DELETE FROM patients
-        WHERE 
-            (COALESCE($1, '') = '' OR name = $1) AND
-            (COALESCE($2, '') = '' OR surname = $2) AND
-            (COALESCE($3, '') = '' OR birth_date = $3) AND
-            (COALESCE($4, '') = '' OR phone_number = $4) AND
-            (COALESCE($5, '') = '' OR passport_number = $5);
+        WITH matched AS (
+            SELECT id, version FROM patients
+            WHERE
+                (COALESCE($1, '') = '' OR name = $1) AND
+                (COALESCE($2, '') = '' OR surname = $2) AND
+                (COALESCE($3, '') = '' OR birth_date = $3) AND
+                (COALESCE($4, '') = '' OR phone_number_index = $4) AND
+                (COALESCE($5, '') = '' OR passport_number_index = $5)
+            FOR UPDATE
+        ), deleted AS (
+            DELETE FROM patients
+            USING matched
+            WHERE patients.id = matched.id AND NOT EXISTS (
+                SELECT 1 FROM matched AS stale
+                WHERE NOT ($6::INT[] IS NULL OR stale.version = ANY($6))
+            )
+            RETURNING patients.id
+        )
+        SELECT (SELECT COUNT(*) FROM matched) as "matched!", (SELECT COUNT(*) FROM deleted) as "deleted!"
         "#,
         option_patient.name,
         option_patient.surname,
         option_patient.birth_date,
-        option_patient.phone_number,
-        option_patient.passport_number,
+        cipher.blind_index_filter(&option_patient.phone_number),
+        cipher.blind_index_filter(&option_patient.passport_number),
+        expected_versions.as_deref(),
     )
-    .execute(pool.get_ref())
+    .fetch_one(pool.get_ref())
     .await;
 
     match result {
-        Ok(rows) if rows.rows_affected() > 0 => HttpResponse::NoContent().finish(),
+        Ok(counts) if counts.deleted < counts.matched => precondition_failed(),
+        Ok(counts) if counts.deleted > 0 => HttpResponse::NoContent().finish(),
         _ => HttpResponse::NotFound().body("Entry not found"),
     }
 }
 
-
 #[utoipa::path(
     get,
-    path = "/patients/export",
+    path = "/patients/export.ndjson",
     tag = "Patients",
     responses(
-        (status = 200, description = "Exported number of patients", body = [Patient])
+        (status = 200, description = "All patients as JSON lines, streamed row by row", body = [Patient], content_type = "application/x-ndjson")
     ),
+    params(
+        ("options" = NdjsonExportOptions, Query, description = "Phone and passport numbers stay encrypted unless `decrypted=true` is passed")
+    )
 )]
-#[get("/patients/export")]
-pub async fn export_patients(
+#[get("/patients/export.ndjson")]
+pub async fn export_patients_ndjson(
     pool: web::Data<PgPool>,
-    sled_db: web::Data<sled::Db>,
+    cipher: web::Data<FieldCipher>,
+    options: web::Query<NdjsonExportOptions>,
 ) -> impl Responder {
-    let patients = sqlx::query_as!(
-        Patient,
-        "SELECT id, name, surname, birth_date, phone_number, passport_number FROM patients"
-    )
-    .fetch_all(pool.get_ref())
-    .await;
-
-    match patients {
-        Ok(patients) => {
-            for patient in &patients {
-                let key = format!("patient:{}", patient.id);
-                let value = serde_json::to_vec(patient).unwrap();
-                
-                sled_db.insert(key, value).expect("Не удалось вставить данные в Sled");
-            }
-            let mut count = 0;
-            sled_db.scan_prefix("").for_each(|item| {
-            if let Ok(_) = item {
-                count += 1;
+    let pool = pool.get_ref().clone();
+    let decrypted = options.decrypted.unwrap_or(false);
+    let lines = async_stream::stream! {
+        let mut rows = sqlx::query_as!(
+            Patient,
+            "SELECT id, name, surname, birth_date, phone_number, passport_number FROM patients ORDER BY id"
+        )
+        .fetch(&pool);
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(row) if decrypted => yield Ok(ndjson::line(&cipher.decrypt_patient(row))),
+                Ok(row) => yield Ok(ndjson::line(&row)),
+                Err(e) => {
+                    yield Err(actix_web::error::ErrorInternalServerError(e));
+                    break;
+                }
             }
-            });
-            HttpResponse::Ok().body(format!("Экспортировано {} пациентов в Sled", count))
         }
-        Err(_) => HttpResponse::InternalServerError().body("Не удалось получить данные пациентов из PostgreSQL"),
-    }
+    };
+
+    HttpResponse::Ok()
+        .content_type(ndjson::CONTENT_TYPE)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"patients.ndjson\"",
+        ))
+        .streaming(lines)
 }
 
 #[utoipa::path(
     post,
-    path = "/patients/import",
+    path = "/patients/import.ndjson",
     tag = "Patients",
+    request_body(content = [Patient], content_type = "application/x-ndjson", description = "One Patient JSON object per line, as written by `/patients/export.ndjson`. Existing ids are overwritten. Encrypted values are re-encrypted with the current key"),
     responses(
-        (status = 200, description = "Patients imported successfully"),
-        (status = 500, description = "Failed to import patients"),
-    ),
+        (status = 202, description = "Import queued, follow it at /jobs/{id}. The lines are imported in one transaction, a line that is invalid or violates a constraint fails the job and nothing is imported", body = Job),
+        (status = 400, description = "The body cannot be read")
+    )
 )]
-#[post("/patients/import")]
-pub async fn import_patients(
+#[post("/patients/import.ndjson")]
+pub async fn import_patients_ndjson(
     pool: web::Data<PgPool>,
-    sled_db: web::Data<sled::Db>,
+    payload: web::Payload,
 ) -> impl Responder {
-    let mut count = 0;
-
-    let iter = sled_db.iter();
-
-    for item in iter {
-        if let Ok((_, value)) = item {
-            let patient: Patient = serde_json::from_slice(&value).unwrap();
-            let insert_query = sqlx::query!(
-                "INSERT INTO patients (id, name, surname, birth_date, phone_number, passport_number) VALUES ($1, $2, $3, $4, $5, $6)",
-                patient.id,
-                patient.name,
-                patient.surname,
-                patient.birth_date,
-                patient.phone_number,
-                patient.passport_number
-            );
+    queue_ndjson_import(pool.get_ref(), Dataset::Patients, payload).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/patients/export.csv",
+    tag = "Patients",
+    responses(
+        (status = 200, description = "All patients as CSV, streamed row by row", content_type = "text/csv")
+    )
+)]
+#[get("/patients/export.csv")]
+pub async fn export_patients_csv(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+) -> impl Responder {
+    let pool = pool.get_ref().clone();
+    let lines = async_stream::stream! {
+        yield Ok::<_, actix_web::Error>(csv_transfer::header_line(csv_transfer::PATIENT_COLUMNS));
 
-            if let Err(e) = insert_query.execute(pool.get_ref()).await {
-                eprintln!("Не удалось вставить пациента с id {}: {:?}", patient.id, e);
-            } else {
-                count += 1;
+        let mut rows = sqlx::query_as!(
+            Patient,
+            "SELECT id, name, surname, birth_date, phone_number, passport_number FROM patients ORDER BY id"
+        )
+        .fetch(&pool);
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(row) => {
+                    let row = cipher.decrypt_patient(row);
+                    yield Ok(csv_transfer::csv_line(&[
+                        &row.id.to_string(),
+                        &row.name,
+                        &row.surname,
+                        &row.birth_date,
+                        &row.phone_number,
+                        &row.passport_number,
+                    ]));
+                }
+                Err(e) => {
+                    yield Err(actix_web::error::ErrorInternalServerError(e));
+                    break;
+                }
             }
         }
-    }
+    };
 
-    HttpResponse::Ok().body(format!("Импортировано {} пациентов из Sled", count))
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"patients.csv\"",
+        ))
+        .streaming(lines)
 }
 
-
-
-
-
 #[utoipa::path(
-    get,
-    path = "/doctors",
-    tag = "Doctors",
+    post,
+    path = "/patients/import.csv",
+    tag = "Patients",
+    request_body(content = String, content_type = "multipart/form-data", description = "CSV file in the `file` field. Rows with an `id` update that patient, rows without one are added"),
     responses(
-        (status = 200, description = "List of doctors", body = [Doctor])
+        (status = 202, description = "Import queued, follow it at /jobs/{id}. The job result sums the rows up, `errors` lists the rows that were skipped", body = Job),
+        (status = 400, description = "The upload cannot be read or misses columns")
     ),
     params(
-        ("doctor" = OptionDoctor, Query, description = "Optional filters")
+        ("options" = CsvImportOptions, Query, description = "Dry run, header mapping and delimiter")
     )
-    
 )]
-#[get("/doctors")]
-pub async fn get_doctors(
+#[post("/patients/import.csv")]
+pub async fn import_patients_csv(
     pool: web::Data<PgPool>,
-    option_doctor: web::Query<OptionDoctor>
+    options: web::Query<CsvImportOptions>,
+    payload: Multipart,
 ) -> impl Responder {
-    let rows = sqlx::query_as!(
-        Doctor,
-        "SELECT * 
-        FROM doctors
-        WHERE 
-            (COALESCE($1, '') = '' OR name = $1) AND
-            (COALESCE($2, '') = '' OR surname = $2) AND
-            (COALESCE($3, '') = '' OR speciality = $3) AND
-            (COALESCE($4, '') = '' OR phone_number = $4) AND
-            (COALESCE($5, '') = '' OR passport_number = $5);",
-        option_doctor.name,
-        option_doctor.surname,
-        option_doctor.speciality,
-        option_doctor.phone_number,
-        option_doctor.passport_number,
+    queue_csv_import(
+        pool.get_ref(),
+        Dataset::Patients,
+        options.into_inner(),
+        payload,
     )
-    .fetch_all(pool.get_ref())
     .await
-    .unwrap();
-
-    web::Json(rows)
 }
 
 #[utoipa::path(
-    post,
-    path = "/doctors",
-    tag = "Doctors",
-    request_body = NewDoctor,
+    get,
+    path = "/patients/export",
+    tag = "Patients",
     responses(
-        (status = 201, description = "Entry successfully created", body = Doctor),
-        (status = 400, description = "Invalid input")
+        (status = 202, description = "Export of the patients to Sled queued, follow it at /jobs/{id}", body = Job)
+    ),
+    params(
+        ("options" = SledExportOptions, Query, description = "Phone and passport numbers stay encrypted in Sled unless `decrypted=true` is passed. `anonymized=true` writes pseudonymized records to the anonymized Sled database")
     )
 )]
-#[post("/doctors")]
-pub async fn add_doctor(
-    pool: web::Data<sqlx::PgPool>,
-    new_doctor: web::Json<NewDoctor>,
+#[get("/patients/export")]
+pub async fn export_patients(
+    pool: web::Data<PgPool>,
+    options: web::Query<SledExportOptions>,
 ) -> impl Responder {
-    let result = sqlx::query!(
-        r#"
-        INSERT INTO doctors (name, surname, speciality, phone_number, passport_number)
-        VALUES ($1, $2, $3, $4, $5)
-        RETURNING id, name, surname, speciality, phone_number, passport_number
-        "#,
-        new_doctor.name,
-        new_doctor.surname,
-        new_doctor.speciality,
-        new_doctor.phone_number,
-        new_doctor.passport_number,
+    let params = SledJobParams {
+        anonymized: options.anonymized.unwrap_or(false),
+        decrypted: options.decrypted.unwrap_or(false),
+    };
+    job_queued(
+        jobs::enqueue(
+            pool.get_ref(),
+            JobKind::SledExport(Dataset::Patients),
+            &params,
+        )
+        .await,
     )
-    .fetch_one(pool.get_ref())
-    .await;
-
-    match result {
-        Ok(doctor) => HttpResponse::Created().json(Doctor {
-            id: doctor.id,
-            name: doctor.name,
-            surname: doctor.surname,
-            speciality: doctor.speciality,
-            phone_number: doctor.phone_number,
-            passport_number: doctor.passport_number,
-        }),
-        Err(_) => HttpResponse::BadRequest().body("Invalid input"),
-    }
 }
 
 #[utoipa::path(
-    patch,
-    path = "/doctors",
-    tag = "Doctors",
-    request_body = UpdateDoctor,
+    post,
+    path = "/patients/import",
+    tag = "Patients",
     responses(
-        (status = 200, description = "Entry successfully updated"),
-        (status = 404, description = "Entry not found"),
+        (status = 202, description = "Import of the patients from Sled queued, follow it at /jobs/{id}", body = Job),
+        (status = 500, description = "Failed to queue the import"),
+    ),
+    params(
+        ("options" = SledDatasetOptions, Query, description = "`anonymized=true` reads from the anonymized Sled database")
     )
 )]
-#[patch("/doctors")]
-pub async fn update_doctor(
+#[post("/patients/import")]
+pub async fn import_patients(
     pool: web::Data<PgPool>,
-    request: web::Json<UpdateDoctor>,
+    options: web::Query<SledDatasetOptions>,
 ) -> impl Responder {
-    let result = sqlx::query!(
-        r#"
-        UPDATE doctors
-        SET name = COALESCE($1, name),
-            surname = COALESCE($2, surname),
-            speciality = COALESCE($3, speciality),
-            phone_number = COALESCE($4, phone_number),
-            passport_number = COALESCE($5, passport_number)
-       WHERE 
-            ($6::TEXT IS NULL OR name = $6) AND
-            ($7::TEXT IS NULL OR surname = $7) AND
-            ($8::TEXT IS NULL OR speciality = $8) AND
-            ($9::TEXT IS NULL OR phone_number = $9) AND
-            ($10::TEXT IS NULL OR passport_number = $10);
-        "#,
-        request.update_name,
-        request.update_surname,
-        request.update_speciality,
-        request.update_phone_number,
-        request.update_passport_number,
-        request.condition_name,
-        request.condition_surname,
-        request.condition_speciality,
-        request.condition_phone_number,
-        request.condition_passport_number
+    let params = SledJobParams {
+        anonymized: options.anonymized.unwrap_or(false),
+        ..Default::default()
+    };
+    job_queued(
+        jobs::enqueue(
+            pool.get_ref(),
+            JobKind::SledImport(Dataset::Patients),
+            &params,
+        )
+        .await,
     )
-    .execute(pool.get_ref())
-    .await;
-
-    match result {
-        Ok(_) => HttpResponse::Ok().body("Entry successfully updated"),
-        Err(_) => HttpResponse::NotFound().body("Entry not found"),
-    }
 }
 
 #[utoipa::path(
-    delete,
-    path = "/doctors",
-    tag = "Doctors",
-    request_body = OptionDoctor,
+    get,
+    path = "/patients/duplicates",
+    tag = "Patients",
     responses(
-        (status = 204, description = "Entry successfully deleted"),
-        (status = 404, description = "Entry not found")
+        (status = 200, description = "Likely duplicate patients, best matches first", body = [DuplicateCandidate])
+    ),
+    params(
+        ("filter" = DuplicateFilter, Query, description = "Minimal score between 0 and 1, 0.6 by default")
     )
 )]
-#[delete("/doctors")]
-pub async fn delete_doctor(
-    pool: web::Data<sqlx::PgPool>,
-    option_doctor: web::Json<OptionDoctor>,
+#[get("/patients/duplicates")]
+pub async fn get_patient_duplicates(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    filter: web::Query<DuplicateFilter>,
 ) -> impl Responder {
-    let result = sqlx::query!(
-        r#"
-        DELETE FROM doctors
-        WHERE 
-            (COALESCE($1, '') = '' OR name = $1) AND
-            (COALESCE($2, '') = '' OR surname = $2) AND
-            (COALESCE($3, '') = '' OR speciality = $3) AND
-            (COALESCE($4, '') = '' OR phone_number = $4) AND
-            (COALESCE($5, '') = '' OR passport_number = $5);
-        "#,
-        option_doctor.name,
-        option_doctor.surname,
-        option_doctor.speciality,
-        option_doctor.phone_number,
-        option_doctor.passport_number,
+    let patients = sqlx::query_as!(
+        Patient,
+        "SELECT id, name, surname, birth_date, phone_number, passport_number FROM patients"
     )
-    .execute(pool.get_ref())
+    .fetch_all(pool.get_ref())
     .await;
+    let patients: Vec<Patient> = match patients {
+        Ok(patients) => patients
+            .into_iter()
+            .map(|patient| cipher.decrypt_patient(patient))
+            .collect(),
+        Err(e) => {
+            error!(error = %e, "Failed to load patients");
+            return HttpResponse::InternalServerError().body("Failed to load patients");
+        }
+    };
 
-    match result {
-        Ok(rows) if rows.rows_affected() > 0 => HttpResponse::NoContent().finish(),
-        _ => HttpResponse::NotFound().body("Entry not found"),
-    }
+    let min_score = filter.min_score.unwrap_or(duplicates::DEFAULT_MIN_SCORE);
+    HttpResponse::Ok().json(duplicates::find_duplicates(&patients, min_score))
 }
 
 #[utoipa::path(
-    get,
-    path = "/doctors/export",
-    tag = "Doctors",
+    post,
+    path = "/patients/merge",
+    tag = "Patients",
+    request_body = MergePatients,
     responses(
-        (status = 200, description = "Exported number of doctors", body = [Doctor])
-    ),
+        (status = 200, description = "Patients successfully merged", body = MergeResult),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found")
+    )
 )]
-#[get("/doctors/export")]
-pub async fn export_doctors(
+#[post("/patients/merge")]
+pub async fn merge_patients(
     pool: web::Data<PgPool>,
-    sled_db: web::Data<sled::Db>,
+    cipher: web::Data<FieldCipher>,
+    request: web::Json<MergePatients>,
 ) -> impl Responder {
-    let doctors = sqlx::query_as!(
-        Doctor,
-        "SELECT id, name, surname, speciality, phone_number, passport_number FROM doctors"
-    )
-    .fetch_all(pool.get_ref())
-    .await;
+    if request.survivor_id == request.loser_id {
+        return HttpResponse::BadRequest().body("Cannot merge a patient into itself");
+    }
 
-    match doctors {
-        Ok(doctors) => {
-            for doctor in &doctors {
-                let key = format!("doctor:{}", doctor.id);
-                let value = serde_json::to_vec(doctor).unwrap();
-                
-                sled_db.insert(key, value).expect("Не удалось вставить данные в Sled");
-            }
-            let mut count = 0;
-            sled_db.scan_prefix("").for_each(|item| {
-            if let Ok(_) = item {
-                count += 1;
-            }
-            });
-            HttpResponse::Ok().body(format!("Экспортировано {} докторов в Sled", count))
+    match merge_patients_in_transaction(pool.get_ref(), request.survivor_id, request.loser_id).await
+    {
+        Ok(Some(mut result)) => {
+            result.survivor = cipher.decrypt_patient(result.survivor);
+            HttpResponse::Ok().json(result)
         }
-        Err(_) => HttpResponse::InternalServerError().body("Не удалось получить данные докторов из PostgreSQL"),
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to merge patients");
+            HttpResponse::InternalServerError().body("Failed to merge patients")
+        }
+    }
+}
+
+/// Moves all schedule entries of the loser to the survivor and deletes the loser.
+/// The loser's last history version is closed with the `MERGE` operation.
+async fn merge_patients_in_transaction(
+    pool: &PgPool,
+    survivor_id: i32,
+    loser_id: i32,
+) -> Result<Option<MergeResult>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query!("SELECT set_config('hospital.history_operation', 'MERGE', true)")
+        .fetch_one(&mut *transaction)
+        .await?;
+
+    let patients = sqlx::query_as!(
+        Patient,
+        "SELECT id, name, surname, birth_date, phone_number, passport_number
+        FROM patients
+        WHERE id = $1 OR id = $2
+        FOR UPDATE",
+        survivor_id,
+        loser_id,
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    let survivor = match patients
+        .into_iter()
+        .find(|patient| patient.id == survivor_id)
+    {
+        Some(survivor) => survivor,
+        None => return Ok(None),
+    };
+
+    let moved = sqlx::query!(
+        "UPDATE schedule SET patient_id = $1 WHERE patient_id = $2",
+        survivor_id,
+        loser_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let moved_schedule_entries = moved.rows_affected() as i32;
+
+    let deleted = sqlx::query!("DELETE FROM patients WHERE id = $1", loser_id)
+        .execute(&mut *transaction)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Ok(None);
     }
+
+    sqlx::query!(
+        "INSERT INTO patient_merges (survivor_id, loser_id, moved_schedule_entries)
+        VALUES ($1, $2, $3)",
+        survivor_id,
+        loser_id,
+        moved_schedule_entries,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(Some(MergeResult {
+        survivor,
+        loser_id,
+        moved_schedule_entries,
+    }))
+}
+
+/// Data exceptions (SQLSTATE class 22), as an `as_of` that is not a timestamp, are bad input
+/// rather than a failure of the database.
+fn is_data_exception(error: &sqlx::Error) -> bool {
+    matches!(error, sqlx::Error::Database(e) if e.code().is_some_and(|code| code.starts_with("22")))
 }
 
 #[utoipa::path(
-    post,
-    path = "/doctors/import",
-    tag = "Doctors",
+    get,
+    path = "/patients/{id}",
+    tag = "Patients",
     responses(
-        (status = 200, description = "Doctors imported successfully"),
-        (status = 500, description = "Failed to import doctors"),
+        (status = 200, description = "Patient, as it is now or as it was at the given moment", body = Patient),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found")
     ),
+    params(
+        ("id" = i32, Path, description = "Patient id"),
+        ("as_of" = AsOf, Query, description = "Optional point in time")
+    )
 )]
-#[post("/doctors/import")]
-pub async fn import_doctors(
+#[get("/patients/{id}")]
+pub async fn get_patient(
     pool: web::Data<PgPool>,
-    sled_db: web::Data<sled::Db>,
+    cipher: web::Data<FieldCipher>,
+    id: web::Path<i32>,
+    as_of: web::Query<AsOf>,
 ) -> impl Responder {
-    let mut count = 0;
-
-    let iter = sled_db.iter();
-
-    for item in iter {
-        if let Ok((_, value)) = item {
-            let doctor: Doctor = serde_json::from_slice(&value).unwrap();
-            let insert_query = sqlx::query!(
-                "INSERT INTO doctors (id, name, surname, speciality, phone_number, passport_number) VALUES ($1, $2, $3, $4, $5, $6)",
-                doctor.id,
-                doctor.name,
-                doctor.surname,
-                doctor.speciality,
-                doctor.phone_number,
-                doctor.passport_number
-            );
+    let id = id.into_inner();
+    let result = match &as_of.as_of {
+        Some(as_of) => sqlx::query_as!(
+            Patient,
+            "SELECT id, name, surname, birth_date, phone_number, passport_number
+            FROM patients_history
+            WHERE id = $1 AND
+                valid_from <= $2::TEXT::TIMESTAMPTZ AND
+                (valid_to IS NULL OR valid_to > $2::TEXT::TIMESTAMPTZ);",
+            id,
+            as_of,
+        )
+        .fetch_optional(pool.get_ref())
+        .await
+        .map(|row| row.map(|patient| (patient, None))),
+        None => sqlx::query!(
+            "SELECT id, name, surname, birth_date, phone_number, passport_number, version
+            FROM patients
+            WHERE id = $1;",
+            id,
+        )
+        .fetch_optional(pool.get_ref())
+        .await
+        .map(|row| {
+            row.map(|row| {
+                let patient = Patient {
+                    id: row.id,
+                    name: row.name,
+                    surname: row.surname,
+                    birth_date: row.birth_date,
+                    phone_number: row.phone_number,
+                    passport_number: row.passport_number,
+                };
+                (patient, Some(row.version))
+            })
+        }),
+    };
 
-            if let Err(e) = insert_query.execute(pool.get_ref()).await {
-                eprintln!("Не удалось вставить доктора с id {}: {:?}", doctor.id, e);
-            } else {
-                count += 1;
-            }
+    match result {
+        Ok(Some((patient, Some(version)))) => HttpResponse::Ok()
+            .insert_header(etag(version))
+            .json(cipher.decrypt_patient(patient)),
+        Ok(Some((patient, None))) => HttpResponse::Ok().json(cipher.decrypt_patient(patient)),
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) if is_data_exception(&e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+        Err(e) => {
+            error!(error = %e, "Failed to load the patient");
+            HttpResponse::InternalServerError().body("Failed to load the patient")
         }
     }
-
-    HttpResponse::Ok().body(format!("Импортировано {} докторов из Sled", count))
 }
 
-
 #[utoipa::path(
     get,
-    path = "/tickets",
-    tag = "Tickets",
+    path = "/patients/{id}/history",
+    tag = "Patients",
     responses(
-        (status = 200, description = "List of tickets", body = [Ticket])
+        (status = 200, description = "All versions of the patient, oldest first", body = [PatientHistoryEntry]),
+        (status = 404, description = "Entry not found")
     ),
     params(
-        ("ticket" = OptionTicket, Query, description = "Optional filters")
+        ("id" = i32, Path, description = "Patient id")
     )
 )]
-#[get("/tickets")]
-pub async fn get_tickets(
+#[get("/patients/{id}/history")]
+pub async fn get_patient_history(
     pool: web::Data<PgPool>,
-    option_ticket: web::Query<OptionTicket>
+    cipher: web::Data<FieldCipher>,
+    id: web::Path<i32>,
 ) -> impl Responder {
     let rows = sqlx::query_as!(
-        Ticket,
-        "SELECT * 
-        FROM tickets
-        WHERE 
-            (COALESCE($1, '') = '' OR date = $1) AND
-            (COALESCE($2, '') = '' OR time = $2) AND
-            (COALESCE($3, 0) = 0 OR office_number = $3);",
-        option_ticket.date,
-        option_ticket.time,
-        option_ticket.office_number
+        PatientHistoryEntry,
+        r#"SELECT history_id, id, name, surname, birth_date, phone_number, passport_number, operation,
+            valid_from::TEXT as "valid_from!", valid_to::TEXT as valid_to, closed_by
+        FROM patients_history
+        WHERE id = $1
+        ORDER BY valid_from, history_id;"#,
+        id.into_inner(),
     )
     .fetch_all(pool.get_ref())
-    .await
-    .unwrap();
+    .await;
+
+    let rows = match rows {
+        Ok(rows) if rows.is_empty() => return HttpResponse::NotFound().body("Entry not found"),
+        Ok(rows) => rows,
+        Err(e) => {
+            error!(error = %e, "Failed to load the patient history");
+            return HttpResponse::InternalServerError().body("Failed to load the patient history");
+        }
+    };
+    let rows: Vec<PatientHistoryEntry> = rows
+        .into_iter()
+        .map(|entry| cipher.decrypt_patient_history(entry))
+        .collect();
+    HttpResponse::Ok().json(rows)
+}
+
+#[utoipa::path(
+    get,
+    path = "/patients/{id}/data-export",
+    tag = "Patients",
+    responses(
+        (status = 200, description = "Zip archive with the patient, schedule entries, history, merges and erasures", content_type = "application/zip"),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Patient id")
+    )
+)]
+#[get("/patients/{id}/data-export")]
+pub async fn export_patient_data(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    id: web::Path<i32>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let export = match privacy::collect_patient_data(pool.get_ref(), cipher.get_ref(), id).await {
+        Ok(Some(export)) => export,
+        Ok(None) => return HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to collect patient data");
+            return HttpResponse::InternalServerError().body("Failed to collect patient data");
+        }
+    };
 
-    web::Json(rows)
+    match privacy::build_archive(&export) {
+        Ok(archive) => HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"patient-{}.zip\"", id),
+            ))
+            .body(archive),
+        Err(e) => {
+            error!(patient_id = id, error = %e, "Failed to build data export");
+            HttpResponse::InternalServerError().body("Failed to build data export")
+        }
+    }
 }
 
 #[utoipa::path(
     post,
-    path = "/tickets",
-    tag = "Tickets",
-    request_body = NewTicket,
+    path = "/patients/{id}/erase",
+    tag = "Patients",
     responses(
-        (status = 201, description = "Entry successfully created", body = Ticket),
-        (status = 400, description = "Invalid input")
+        (status = 200, description = "Patient anonymized, schedule entries are kept", body = ErasureResult),
+        (status = 404, description = "Entry not found"),
+        (status = 500, description = "Nothing was erased, the request can be retried")
+    ),
+    params(
+        ("id" = i32, Path, description = "Patient id")
     )
 )]
-#[post("/tickets")]
-pub async fn add_ticket(
-    pool: web::Data<sqlx::PgPool>,
-    new_ticket: web::Json<NewTicket>,
+#[post("/patients/{id}/erase")]
+pub async fn erase_patient(
+    pool: web::Data<PgPool>,
+    sled_db: web::Data<sled::Db>,
+    anonymized_sled: web::Data<AnonymizedSled>,
+    cipher: web::Data<FieldCipher>,
+    id: web::Path<i32>,
+) -> impl Responder {
+    let sled_dbs = [sled_db.get_ref(), &anonymized_sled.0];
+    match privacy::erase_patient(pool.get_ref(), &sled_dbs, cipher.get_ref(), id.into_inner()).await
+    {
+        Ok(Some(result)) => HttpResponse::Ok().json(result),
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(privacy::ErasureError::Sled(e)) => {
+            error!(error = %e, "Failed to scrub exported patients in Sled");
+            HttpResponse::InternalServerError()
+                .body("Failed to scrub exported patients in Sled, nothing was erased")
+        }
+        Err(e) => {
+            error!(error = %e, "Failed to erase patient");
+            HttpResponse::InternalServerError().body("Failed to erase patient")
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/patients/{id}/calendar-tokens",
+    tag = "Patients",
+    responses(
+        (status = 201, description = "New token and the feed URL that carries it", body = CalendarToken),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Patient id")
+    )
+)]
+#[post("/patients/{id}/calendar-tokens")]
+pub async fn issue_patient_calendar_token(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+) -> impl Responder {
+    issue_calendar_token(
+        &req,
+        pool.get_ref(),
+        CalendarOwner::Patient(id.into_inner()),
+    )
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/patients/{id}/calendar.ics",
+    tag = "Patients",
+    responses(
+        (status = 200, description = "iCalendar feed of the patient's appointments, cancelled ones included", content_type = "text/calendar"),
+        (status = 403, description = "Invalid calendar token"),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Patient id"),
+        ("options" = CalendarFeedOptions, Query, description = "Token issued for this feed")
+    )
+)]
+#[get("/patients/{id}/calendar.ics")]
+pub async fn get_patient_calendar(
+    pool: web::Data<PgPool>,
+    settings: web::Data<CalendarSettings>,
+    id: web::Path<i32>,
+    options: web::Query<CalendarFeedOptions>,
+) -> impl Responder {
+    let owner = CalendarOwner::Patient(id.into_inner());
+    calendar_feed(pool.get_ref(), settings.get_ref(), owner, &options.token).await
+}
+
+async fn issue_calendar_token(
+    req: &HttpRequest,
+    pool: &PgPool,
+    owner: CalendarOwner,
+) -> HttpResponse {
+    match calendar::issue_token(pool, owner).await {
+        Ok(Some((id, token))) => {
+            let connection = req.connection_info();
+            let url = format!(
+                "{}://{}{}?token={}",
+                connection.scheme(),
+                connection.host(),
+                owner.feed_path(),
+                token
+            );
+            HttpResponse::Created().json(CalendarToken { id, token, url })
+        }
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to issue a calendar token");
+            HttpResponse::InternalServerError().body("Failed to issue a calendar token")
+        }
+    }
+}
+
+async fn calendar_feed(
+    pool: &PgPool,
+    settings: &CalendarSettings,
+    owner: CalendarOwner,
+    token: &str,
+) -> HttpResponse {
+    match calendar::token_is_valid(pool, owner, token).await {
+        Ok(true) => telemetry::record_principal(&owner.principal()),
+        Ok(false) => return HttpResponse::Forbidden().body("Invalid calendar token"),
+        Err(e) => {
+            error!(error = %e, "Failed to check the token");
+            return HttpResponse::InternalServerError().body("Failed to check the token");
+        }
+    }
+
+    match calendar::fetch_feed(pool, owner, settings).await {
+        Ok(Some((name, events))) => HttpResponse::Ok()
+            .content_type(calendar::CONTENT_TYPE)
+            .body(calendar::render(&name, &events, settings)),
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to load the calendar");
+            HttpResponse::InternalServerError().body("Failed to load the calendar")
+        }
+    }
+}
+
+#[utoipa::path(
+    patch,
+    path = "/patients/{id}",
+    tag = "Patients",
+    request_body = OptionPatient,
+    responses(
+        (status = 200, description = "Entry successfully updated", body = Patient),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Patient id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being modified")
+    )
+)]
+#[patch("/patients/{id}")]
+pub async fn patch_patient(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+    option_patient: web::Json<OptionPatient>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
+    let result = patients::update(
+        pool.get_ref(),
+        &cipher,
+        id,
+        &option_patient,
+        expected_versions.as_deref(),
+    )
+    .await;
+
+    match result {
+        Ok(Some((patient, version))) => HttpResponse::Ok()
+            .insert_header(etag(version))
+            .json(patient),
+        Ok(None) => precondition_failed_or_not_found(pool.get_ref(), "patients", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/patients/{id}",
+    tag = "Patients",
+    request_body = NewPatient,
+    responses(
+        (status = 200, description = "Entry successfully replaced", body = Patient),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Patient id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being replaced")
+    )
+)]
+#[put("/patients/{id}")]
+pub async fn put_patient(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+    new_patient: web::Json<NewPatient>,
 ) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
     let result = sqlx::query!(
         r#"
-        INSERT INTO tickets (date, time, office_number)
-        VALUES ($1, $2, $3)
-        RETURNING id, date, time, office_number
+        UPDATE patients
+        SET name = $2,
+            surname = $3,
+            birth_date = $4,
+            phone_number = $5,
+            passport_number = $6,
+            phone_number_index = $8,
+            passport_number_index = $9
+        WHERE id = $1 AND ($7::INT[] IS NULL OR version = ANY($7))
+        RETURNING id, name, surname, birth_date, phone_number, passport_number, version
         "#,
-        new_ticket.date,
-        new_ticket.time,
-        new_ticket.office_number,
+        id,
+        new_patient.name,
+        new_patient.surname,
+        new_patient.birth_date,
+        cipher.encrypt(&new_patient.phone_number),
+        cipher.encrypt(&new_patient.passport_number),
+        expected_versions.as_deref(),
+        cipher.blind_index(&new_patient.phone_number),
+        cipher.blind_index(&new_patient.passport_number),
     )
-    .fetch_one(pool.get_ref())
+    .fetch_optional(pool.get_ref())
     .await;
 
     match result {
-        Ok(ticket) => HttpResponse::Created().json(Ticket {
-            id: ticket.id,
-            date: ticket.date,
-            time: ticket.time,
-            office_number: ticket.office_number,
-        }),
-        Err(_) => HttpResponse::BadRequest().body("Invalid input"),
+        Ok(Some(patient)) => HttpResponse::Ok()
+            .insert_header(etag(patient.version))
+            .json(cipher.decrypt_patient(Patient {
+                id: patient.id,
+                name: patient.name,
+                surname: patient.surname,
+                birth_date: patient.birth_date,
+                phone_number: patient.phone_number,
+                passport_number: patient.passport_number,
+            })),
+        Ok(None) => precondition_failed_or_not_found(pool.get_ref(), "patients", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/patients/{id}",
+    tag = "Patients",
+    responses(
+        (status = 204, description = "Entry successfully deleted"),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Patient id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being deleted")
+    )
+)]
+#[delete("/patients/{id}")]
+pub async fn delete_patient_by_id(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
+    let result = patients::delete(pool.get_ref(), id, expected_versions.as_deref()).await;
+
+    match result {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => precondition_failed_or_not_found(pool.get_ref(), "patients", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/doctors",
+    tag = "Doctors",
+    responses(
+        (status = 200, description = "List of doctors", body = [Doctor]),
+        (status = 500, description = "Failed to load doctors")
+    ),
+    params(
+        ("doctor" = OptionDoctor, Query, description = "Optional filters")
+    )
+)]
+#[get("/doctors")]
+pub async fn get_doctors(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    option_doctor: web::Query<OptionDoctor>,
+) -> impl Responder {
+    let rows = sqlx::query_as!(
+        Doctor,
+        "SELECT id, name, surname, speciality, phone_number, passport_number
+        FROM doctors
+        WHERE
+            (COALESCE($1, '') = '' OR name = $1) AND
+            (COALESCE($2, '') = '' OR surname = $2) AND
+            (COALESCE($3, '') = '' OR speciality = $3) AND
+            (COALESCE($4, '') = '' OR phone_number_index = $4) AND
+            (COALESCE($5, '') = '' OR passport_number_index = $5);",
+        option_doctor.name,
+        option_doctor.surname,
+        option_doctor.speciality,
+        cipher.blind_index_filter(&option_doctor.phone_number),
+        cipher.blind_index_filter(&option_doctor.passport_number),
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!(error = %e, "Failed to load doctors");
+            return HttpResponse::InternalServerError().body("Failed to load doctors");
+        }
+    };
+
+    let rows: Vec<Doctor> = rows
+        .into_iter()
+        .map(|doctor| cipher.decrypt_doctor(doctor))
+        .collect();
+    HttpResponse::Ok().json(rows)
+}
+
+#[utoipa::path(
+    post,
+    path = "/doctors",
+    tag = "Doctors",
+    request_body = NewDoctor,
+    responses(
+        (status = 201, description = "Entry successfully created", body = Doctor),
+        (status = 400, description = "Invalid input")
+    ),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe")
+    )
+)]
+#[post("/doctors")]
+pub async fn add_doctor(
+    pool: web::Data<sqlx::PgPool>,
+    cipher: web::Data<FieldCipher>,
+    new_doctor: web::Json<NewDoctor>,
+) -> impl Responder {
+    let result = doctors::create(pool.get_ref(), &cipher, &new_doctor).await;
+
+    match result {
+        Ok((doctor, _)) => HttpResponse::Created().json(doctor),
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
     }
 }
 
 #[utoipa::path(
     patch,
-    path = "/tickets",
-    tag = "Tickets",
-    request_body = UpdateTicket,
+    path = "/doctors",
+    tag = "Doctors",
+    request_body = UpdateDoctor,
     responses(
         (status = 200, description = "Entry successfully updated"),
         (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("If-Match" = Option<String>, Header, description = "ETags of the versions being updated, one per matched entry")
     )
 )]
-#[patch("/tickets")]
-pub async fn update_ticket(
+#[patch("/doctors")]
+pub async fn update_doctor(
+    req: HttpRequest,
     pool: web::Data<PgPool>,
-    request: web::Json<UpdateTicket>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<ConcurrencySettings>,
+    request: web::Json<UpdateDoctor>,
 ) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+
     let result = sqlx::query!(
         r#"
-        UPDATE tickets
-        SET date = COALESCE($1, date),
-            time = COALESCE($2, time),
-            office_number = COALESCE($3, office_number)
-       WHERE 
-            ($4::TEXT IS NULL OR date = $4) AND
-            ($5::TEXT IS NULL OR time = $5) AND
-            ($6::INT IS NULL OR office_number = $6);
+        WITH matched AS (
+            SELECT id, version FROM doctors
+            WHERE
+                ($6::TEXT IS NULL OR name = $6) AND
+                ($7::TEXT IS NULL OR surname = $7) AND
+                ($8::TEXT IS NULL OR speciality = $8) AND
+                ($9::TEXT IS NULL OR phone_number_index = $9) AND
+                ($10::TEXT IS NULL OR passport_number_index = $10)
+            FOR UPDATE
+        ), updated AS (
+            UPDATE doctors
+            SET name = COALESCE($1, name),
+                surname = COALESCE($2, surname),
+                speciality = COALESCE($3, speciality),
+                phone_number = COALESCE($4, phone_number),
+                passport_number = COALESCE($5, passport_number),
+                phone_number_index = COALESCE($11, phone_number_index),
+                passport_number_index = COALESCE($12, passport_number_index)
+            FROM matched
+            WHERE doctors.id = matched.id AND NOT EXISTS (
+                SELECT 1 FROM matched AS stale
+                WHERE NOT ($13::INT[] IS NULL OR stale.version = ANY($13))
+            )
+            RETURNING doctors.id
+        )
+        SELECT (SELECT COUNT(*) FROM matched) as "matched!", (SELECT COUNT(*) FROM updated) as "updated!"
         "#,
-        request.update_date,
-        request.update_time,
-        request.update_office_number,
-        request.condition_date,
-        request.condition_time,
-        request.condition_office_number,
+        request.update_name,
+        request.update_surname,
+        request.update_speciality,
+        request.update_phone_number.as_deref().map(|value| cipher.encrypt(value)),
+        request.update_passport_number.as_deref().map(|value| cipher.encrypt(value)),
+        request.condition_name,
+        request.condition_surname,
+        request.condition_speciality,
+        request.condition_phone_number.as_deref().map(|value| cipher.blind_index(value)),
+        request.condition_passport_number.as_deref().map(|value| cipher.blind_index(value)),
+        request.update_phone_number.as_deref().map(|value| cipher.blind_index(value)),
+        request.update_passport_number.as_deref().map(|value| cipher.blind_index(value)),
+        expected_versions.as_deref(),
     )
-    .execute(pool.get_ref())
+    .fetch_one(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(counts) if counts.updated < counts.matched => precondition_failed(),
+        Ok(_) => HttpResponse::Ok().body("Entry successfully updated"),
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::NotFound().body("Entry not found")
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/doctors",
+    tag = "Doctors",
+    request_body = OptionDoctor,
+    responses(
+        (status = 204, description = "Entry successfully deleted"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("If-Match" = Option<String>, Header, description = "ETags of the versions being deleted, one per matched entry")
+    )
+)]
+#[delete("/doctors")]
+pub async fn delete_doctor(
+    req: HttpRequest,
+    pool: web::Data<sqlx::PgPool>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<ConcurrencySettings>,
+    option_doctor: web::Json<OptionDoctor>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+
+    let result = sqlx::query!(
+        r#"
+        WITH matched AS (
+            SELECT id, version FROM doctors
+            WHERE
+                (COALESCE($1, '') = '' OR name = $1) AND
+                (COALESCE($2, '') = '' OR surname = $2) AND
+                (COALESCE($3, '') = '' OR speciality = $3) AND
+                (COALESCE($4, '') = '' OR phone_number_index = $4) AND
+                (COALESCE($5, '') = '' OR passport_number_index = $5)
+            FOR UPDATE
+        ), deleted AS (
+            DELETE FROM doctors
+            USING matched
+            WHERE doctors.id = matched.id AND NOT EXISTS (
+                SELECT 1 FROM matched AS stale
+                WHERE NOT ($6::INT[] IS NULL OR stale.version = ANY($6))
+            )
+            RETURNING doctors.id
+        )
+        SELECT (SELECT COUNT(*) FROM matched) as "matched!", (SELECT COUNT(*) FROM deleted) as "deleted!"
+        "#,
+        option_doctor.name,
+        option_doctor.surname,
+        option_doctor.speciality,
+        cipher.blind_index_filter(&option_doctor.phone_number),
+        cipher.blind_index_filter(&option_doctor.passport_number),
+        expected_versions.as_deref(),
+    )
+    .fetch_one(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(counts) if counts.deleted < counts.matched => precondition_failed(),
+        Ok(counts) if counts.deleted > 0 => HttpResponse::NoContent().finish(),
+        _ => HttpResponse::NotFound().body("Entry not found"),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/doctors/export.ndjson",
+    tag = "Doctors",
+    responses(
+        (status = 200, description = "All doctors as JSON lines, streamed row by row", body = [Doctor], content_type = "application/x-ndjson")
+    ),
+    params(
+        ("options" = NdjsonExportOptions, Query, description = "Phone and passport numbers stay encrypted unless `decrypted=true` is passed")
+    )
+)]
+#[get("/doctors/export.ndjson")]
+pub async fn export_doctors_ndjson(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    options: web::Query<NdjsonExportOptions>,
+) -> impl Responder {
+    let pool = pool.get_ref().clone();
+    let decrypted = options.decrypted.unwrap_or(false);
+    let lines = async_stream::stream! {
+        let mut rows = sqlx::query_as!(
+            Doctor,
+            "SELECT id, name, surname, speciality, phone_number, passport_number FROM doctors ORDER BY id"
+        )
+        .fetch(&pool);
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(row) if decrypted => yield Ok(ndjson::line(&cipher.decrypt_doctor(row))),
+                Ok(row) => yield Ok(ndjson::line(&row)),
+                Err(e) => {
+                    yield Err(actix_web::error::ErrorInternalServerError(e));
+                    break;
+                }
+            }
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type(ndjson::CONTENT_TYPE)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"doctors.ndjson\"",
+        ))
+        .streaming(lines)
+}
+
+#[utoipa::path(
+    post,
+    path = "/doctors/import.ndjson",
+    tag = "Doctors",
+    request_body(content = [Doctor], content_type = "application/x-ndjson", description = "One Doctor JSON object per line, as written by `/doctors/export.ndjson`. Existing ids are overwritten. Encrypted values are re-encrypted with the current key"),
+    responses(
+        (status = 202, description = "Import queued, follow it at /jobs/{id}. The lines are imported in one transaction, a line that is invalid or violates a constraint fails the job and nothing is imported", body = Job),
+        (status = 400, description = "The body cannot be read")
+    )
+)]
+#[post("/doctors/import.ndjson")]
+pub async fn import_doctors_ndjson(
+    pool: web::Data<PgPool>,
+    payload: web::Payload,
+) -> impl Responder {
+    queue_ndjson_import(pool.get_ref(), Dataset::Doctors, payload).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/doctors/export.csv",
+    tag = "Doctors",
+    responses(
+        (status = 200, description = "All doctors as CSV, streamed row by row", content_type = "text/csv")
+    )
+)]
+#[get("/doctors/export.csv")]
+pub async fn export_doctors_csv(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+) -> impl Responder {
+    let pool = pool.get_ref().clone();
+    let lines = async_stream::stream! {
+        yield Ok::<_, actix_web::Error>(csv_transfer::header_line(csv_transfer::DOCTOR_COLUMNS));
+
+        let mut rows = sqlx::query_as!(
+            Doctor,
+            "SELECT id, name, surname, speciality, phone_number, passport_number FROM doctors ORDER BY id"
+        )
+        .fetch(&pool);
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(row) => {
+                    let row = cipher.decrypt_doctor(row);
+                    yield Ok(csv_transfer::csv_line(&[
+                        &row.id.to_string(),
+                        &row.name,
+                        &row.surname,
+                        &row.speciality,
+                        &row.phone_number,
+                        &row.passport_number,
+                    ]));
+                }
+                Err(e) => {
+                    yield Err(actix_web::error::ErrorInternalServerError(e));
+                    break;
+                }
+            }
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"doctors.csv\"",
+        ))
+        .streaming(lines)
+}
+
+#[utoipa::path(
+    post,
+    path = "/doctors/import.csv",
+    tag = "Doctors",
+    request_body(content = String, content_type = "multipart/form-data", description = "CSV file in the `file` field. Rows with an `id` update that doctor, rows without one are added"),
+    responses(
+        (status = 202, description = "Import queued, follow it at /jobs/{id}. The job result sums the rows up, `errors` lists the rows that were skipped", body = Job),
+        (status = 400, description = "The upload cannot be read or misses columns")
+    ),
+    params(
+        ("options" = CsvImportOptions, Query, description = "Dry run, header mapping and delimiter")
+    )
+)]
+#[post("/doctors/import.csv")]
+pub async fn import_doctors_csv(
+    pool: web::Data<PgPool>,
+    options: web::Query<CsvImportOptions>,
+    payload: Multipart,
+) -> impl Responder {
+    queue_csv_import(
+        pool.get_ref(),
+        Dataset::Doctors,
+        options.into_inner(),
+        payload,
+    )
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/doctors/export",
+    tag = "Doctors",
+    responses(
+        (status = 202, description = "Export of the doctors to Sled queued, follow it at /jobs/{id}", body = Job)
+    ),
+    params(
+        ("options" = SledExportOptions, Query, description = "Phone and passport numbers stay encrypted in Sled unless `decrypted=true` is passed. `anonymized=true` writes pseudonymized records to the anonymized Sled database")
+    )
+)]
+#[get("/doctors/export")]
+pub async fn export_doctors(
+    pool: web::Data<PgPool>,
+    options: web::Query<SledExportOptions>,
+) -> impl Responder {
+    let params = SledJobParams {
+        anonymized: options.anonymized.unwrap_or(false),
+        decrypted: options.decrypted.unwrap_or(false),
+    };
+    job_queued(
+        jobs::enqueue(
+            pool.get_ref(),
+            JobKind::SledExport(Dataset::Doctors),
+            &params,
+        )
+        .await,
+    )
+}
+
+#[utoipa::path(
+    post,
+    path = "/doctors/import",
+    tag = "Doctors",
+    responses(
+        (status = 202, description = "Import of the doctors from Sled queued, follow it at /jobs/{id}", body = Job),
+        (status = 500, description = "Failed to queue the import"),
+    ),
+    params(
+        ("options" = SledDatasetOptions, Query, description = "`anonymized=true` reads from the anonymized Sled database")
+    )
+)]
+#[post("/doctors/import")]
+pub async fn import_doctors(
+    pool: web::Data<PgPool>,
+    options: web::Query<SledDatasetOptions>,
+) -> impl Responder {
+    let params = SledJobParams {
+        anonymized: options.anonymized.unwrap_or(false),
+        ..Default::default()
+    };
+    job_queued(
+        jobs::enqueue(
+            pool.get_ref(),
+            JobKind::SledImport(Dataset::Doctors),
+            &params,
+        )
+        .await,
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/doctors/{id}",
+    tag = "Doctors",
+    responses(
+        (status = 200, description = "Doctor, as it is now or as it was at the given moment", body = Doctor),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Doctor id"),
+        ("as_of" = AsOf, Query, description = "Optional point in time")
+    )
+)]
+#[get("/doctors/{id}")]
+pub async fn get_doctor(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    id: web::Path<i32>,
+    as_of: web::Query<AsOf>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let result = match &as_of.as_of {
+        Some(as_of) => sqlx::query_as!(
+            Doctor,
+            "SELECT id, name, surname, speciality, phone_number, passport_number
+            FROM doctors_history
+            WHERE id = $1 AND
+                valid_from <= $2::TEXT::TIMESTAMPTZ AND
+                (valid_to IS NULL OR valid_to > $2::TEXT::TIMESTAMPTZ);",
+            id,
+            as_of,
+        )
+        .fetch_optional(pool.get_ref())
+        .await
+        .map(|row| row.map(|doctor| (doctor, None))),
+        None => sqlx::query!(
+            "SELECT id, name, surname, speciality, phone_number, passport_number, version
+            FROM doctors
+            WHERE id = $1;",
+            id,
+        )
+        .fetch_optional(pool.get_ref())
+        .await
+        .map(|row| {
+            row.map(|row| {
+                let doctor = Doctor {
+                    id: row.id,
+                    name: row.name,
+                    surname: row.surname,
+                    speciality: row.speciality,
+                    phone_number: row.phone_number,
+                    passport_number: row.passport_number,
+                };
+                (doctor, Some(row.version))
+            })
+        }),
+    };
+
+    match result {
+        Ok(Some((doctor, Some(version)))) => HttpResponse::Ok()
+            .insert_header(etag(version))
+            .json(cipher.decrypt_doctor(doctor)),
+        Ok(Some((doctor, None))) => HttpResponse::Ok().json(cipher.decrypt_doctor(doctor)),
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) if is_data_exception(&e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+        Err(e) => {
+            error!(error = %e, "Failed to load the doctor");
+            HttpResponse::InternalServerError().body("Failed to load the doctor")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/doctors/{id}/roster.pdf",
+    tag = "Doctors",
+    responses(
+        (status = 200, description = "Printable list of the doctor's appointments on one day", content_type = "application/pdf"),
+        (status = 400, description = "Invalid date"),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Doctor id"),
+        ("options" = RosterOptions, Query, description = "Day of the roster")
+    )
+)]
+#[get("/doctors/{id}/roster.pdf")]
+pub async fn get_doctor_roster(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    id: web::Path<i32>,
+    options: web::Query<RosterOptions>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let date = match options.date.as_deref() {
+        None | Some("") => time::OffsetDateTime::now_utc().date(),
+        Some(date) => match reports::parse_date(date) {
+            Some(date) => date,
+            None => {
+                return HttpResponse::BadRequest().body("`date` must be a date like 2024-01-31")
+            }
+        },
+    };
+
+    let doctor = sqlx::query_as!(
+        Doctor,
+        "SELECT id, name, surname, speciality, phone_number, passport_number
+        FROM doctors
+        WHERE id = $1;",
+        id,
+    )
+    .fetch_optional(pool.get_ref())
+    .await;
+    let doctor = match doctor {
+        Ok(Some(doctor)) => cipher.decrypt_doctor(doctor),
+        Ok(None) => return HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to load the doctor");
+            return HttpResponse::InternalServerError().body("Failed to load the doctor");
+        }
+    };
+
+    let filter = ScheduleReportFilter {
+        from: None,
+        to: None,
+        doctor_id: Some(id),
+        speciality: None,
+    };
+    let entries = match reports::fetch_schedule(
+        pool.get_ref(),
+        cipher.get_ref(),
+        &filter,
+        date,
+        date,
+    )
+    .await
+    {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!(error = %e, "Failed to load the schedule");
+            return HttpResponse::InternalServerError().body("Failed to load the schedule");
+        }
+    };
+
+    match pdf::doctor_roster(&doctor, date, &entries) {
+        Ok(roster) => HttpResponse::Ok()
+            .content_type(pdf::PDF_CONTENT_TYPE)
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "inline; filename=\"roster-{}-{}.pdf\"",
+                    id,
+                    reports::format_date(date)
+                ),
+            ))
+            .body(roster),
+        Err(e) => {
+            error!(doctor_id = id, error = %e, "Failed to render the roster");
+            HttpResponse::InternalServerError().body("Failed to render the roster")
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/doctors/{id}/calendar-tokens",
+    tag = "Doctors",
+    responses(
+        (status = 201, description = "New token and the feed URL that carries it", body = CalendarToken),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Doctor id")
+    )
+)]
+#[post("/doctors/{id}/calendar-tokens")]
+pub async fn issue_doctor_calendar_token(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+) -> impl Responder {
+    issue_calendar_token(&req, pool.get_ref(), CalendarOwner::Doctor(id.into_inner())).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/doctors/{id}/calendar.ics",
+    tag = "Doctors",
+    responses(
+        (status = 200, description = "iCalendar feed of the doctor's appointments, cancelled ones included", content_type = "text/calendar"),
+        (status = 403, description = "Invalid calendar token"),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Doctor id"),
+        ("options" = CalendarFeedOptions, Query, description = "Token issued for this feed")
+    )
+)]
+#[get("/doctors/{id}/calendar.ics")]
+pub async fn get_doctor_calendar(
+    pool: web::Data<PgPool>,
+    settings: web::Data<CalendarSettings>,
+    id: web::Path<i32>,
+    options: web::Query<CalendarFeedOptions>,
+) -> impl Responder {
+    let owner = CalendarOwner::Doctor(id.into_inner());
+    calendar_feed(pool.get_ref(), settings.get_ref(), owner, &options.token).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/calendar-tokens/{id}",
+    tag = "Calendar",
+    responses(
+        (status = 204, description = "Token revoked, its feed URL stops working"),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Calendar token id")
+    )
+)]
+#[delete("/calendar-tokens/{id}")]
+pub async fn revoke_calendar_token(pool: web::Data<PgPool>, id: web::Path<i32>) -> impl Responder {
+    match calendar::revoke_token(pool.get_ref(), id.into_inner()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to revoke the token");
+            HttpResponse::InternalServerError().body("Failed to revoke the token")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/doctors/{id}/history",
+    tag = "Doctors",
+    responses(
+        (status = 200, description = "All versions of the doctor, oldest first", body = [DoctorHistoryEntry]),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Doctor id")
+    )
+)]
+#[get("/doctors/{id}/history")]
+pub async fn get_doctor_history(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    id: web::Path<i32>,
+) -> impl Responder {
+    let rows = sqlx::query_as!(
+        DoctorHistoryEntry,
+        r#"SELECT history_id, id, name, surname, speciality, phone_number, passport_number, operation,
+            valid_from::TEXT as "valid_from!", valid_to::TEXT as valid_to, closed_by
+        FROM doctors_history
+        WHERE id = $1
+        ORDER BY valid_from, history_id;"#,
+        id.into_inner(),
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+
+    let rows = match rows {
+        Ok(rows) if rows.is_empty() => return HttpResponse::NotFound().body("Entry not found"),
+        Ok(rows) => rows,
+        Err(e) => {
+            error!(error = %e, "Failed to load the doctor history");
+            return HttpResponse::InternalServerError().body("Failed to load the doctor history");
+        }
+    };
+    let rows: Vec<DoctorHistoryEntry> = rows
+        .into_iter()
+        .map(|entry| cipher.decrypt_doctor_history(entry))
+        .collect();
+    HttpResponse::Ok().json(rows)
+}
+
+#[utoipa::path(
+    patch,
+    path = "/doctors/{id}",
+    tag = "Doctors",
+    request_body = OptionDoctor,
+    responses(
+        (status = 200, description = "Entry successfully updated", body = Doctor),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Doctor id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being modified")
+    )
+)]
+#[patch("/doctors/{id}")]
+pub async fn patch_doctor(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+    option_doctor: web::Json<OptionDoctor>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
+    let result = doctors::update(
+        pool.get_ref(),
+        &cipher,
+        id,
+        &option_doctor,
+        expected_versions.as_deref(),
+    )
+    .await;
+
+    match result {
+        Ok(Some((doctor, version))) => HttpResponse::Ok().insert_header(etag(version)).json(doctor),
+        Ok(None) => precondition_failed_or_not_found(pool.get_ref(), "doctors", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/doctors/{id}",
+    tag = "Doctors",
+    request_body = NewDoctor,
+    responses(
+        (status = 200, description = "Entry successfully replaced", body = Doctor),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Doctor id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being replaced")
+    )
+)]
+#[put("/doctors/{id}")]
+pub async fn put_doctor(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+    new_doctor: web::Json<NewDoctor>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE doctors
+        SET name = $2,
+            surname = $3,
+            speciality = $4,
+            phone_number = $5,
+            passport_number = $6,
+            phone_number_index = $8,
+            passport_number_index = $9
+        WHERE id = $1 AND ($7::INT[] IS NULL OR version = ANY($7))
+        RETURNING id, name, surname, speciality, phone_number, passport_number, version
+        "#,
+        id,
+        new_doctor.name,
+        new_doctor.surname,
+        new_doctor.speciality,
+        cipher.encrypt(&new_doctor.phone_number),
+        cipher.encrypt(&new_doctor.passport_number),
+        expected_versions.as_deref(),
+        cipher.blind_index(&new_doctor.phone_number),
+        cipher.blind_index(&new_doctor.passport_number),
+    )
+    .fetch_optional(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(Some(doctor)) => {
+            HttpResponse::Ok()
+                .insert_header(etag(doctor.version))
+                .json(cipher.decrypt_doctor(Doctor {
+                    id: doctor.id,
+                    name: doctor.name,
+                    surname: doctor.surname,
+                    speciality: doctor.speciality,
+                    phone_number: doctor.phone_number,
+                    passport_number: doctor.passport_number,
+                }))
+        }
+        Ok(None) => precondition_failed_or_not_found(pool.get_ref(), "doctors", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/doctors/{id}",
+    tag = "Doctors",
+    responses(
+        (status = 204, description = "Entry successfully deleted"),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Doctor id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being deleted")
+    )
+)]
+#[delete("/doctors/{id}")]
+pub async fn delete_doctor_by_id(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
+    let result = doctors::delete(pool.get_ref(), id, expected_versions.as_deref()).await;
+
+    match result {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => precondition_failed_or_not_found(pool.get_ref(), "doctors", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/tickets",
+    tag = "Tickets",
+    responses(
+        (status = 200, description = "List of tickets", body = [Ticket]),
+        (status = 500, description = "Failed to load tickets")
+    ),
+    params(
+        ("ticket" = OptionTicket, Query, description = "Optional filters")
+    )
+)]
+#[get("/tickets")]
+pub async fn get_tickets(
+    pool: web::Data<PgPool>,
+    option_ticket: web::Query<OptionTicket>,
+) -> impl Responder {
+    let rows = sqlx::query_as!(
+        Ticket,
+        "SELECT id, date, time, office_number
+        FROM tickets
+        WHERE
+            (COALESCE($1, '') = '' OR date = $1) AND
+            (COALESCE($2, '') = '' OR time = $2) AND
+            (COALESCE($3, 0) = 0 OR office_number = $3);",
+        option_ticket.date,
+        option_ticket.time,
+        option_ticket.office_number
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!(error = %e, "Failed to load tickets");
+            return HttpResponse::InternalServerError().body("Failed to load tickets");
+        }
+    };
+
+    HttpResponse::Ok().json(rows)
+}
+
+#[utoipa::path(
+    post,
+    path = "/tickets",
+    tag = "Tickets",
+    request_body = NewTicket,
+    responses(
+        (status = 201, description = "Entry successfully created", body = Ticket),
+        (status = 400, description = "Invalid input")
+    ),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe")
+    )
+)]
+#[post("/tickets")]
+pub async fn add_ticket(
+    pool: web::Data<sqlx::PgPool>,
+    new_ticket: web::Json<NewTicket>,
+) -> impl Responder {
+    let result = tickets::create(pool.get_ref(), &new_ticket).await;
+
+    match result {
+        Ok((ticket, _)) => HttpResponse::Created().json(ticket),
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    patch,
+    path = "/tickets",
+    tag = "Tickets",
+    request_body = UpdateTicket,
+    responses(
+        (status = 200, description = "Entry successfully updated"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("If-Match" = Option<String>, Header, description = "ETags of the versions being updated, one per matched entry")
+    )
+)]
+#[patch("/tickets")]
+pub async fn update_ticket(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    request: web::Json<UpdateTicket>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+
+    let result = sqlx::query!(
+        r#"
+        WITH matched AS (
+            SELECT id, version FROM tickets
+            WHERE
+                ($4::TEXT IS NULL OR date = $4) AND
+                ($5::TEXT IS NULL OR time = $5) AND
+                ($6::INT IS NULL OR office_number = $6)
+            FOR UPDATE
+        ), updated AS (
+            UPDATE tickets
+            SET date = COALESCE($1, date),
+                time = COALESCE($2, time),
+                office_number = COALESCE($3, office_number)
+            FROM matched
+            WHERE tickets.id = matched.id AND NOT EXISTS (
+                SELECT 1 FROM matched AS stale
+                WHERE NOT ($7::INT[] IS NULL OR stale.version = ANY($7))
+            )
+            RETURNING tickets.id
+        )
+        SELECT (SELECT COUNT(*) FROM matched) as "matched!", (SELECT COUNT(*) FROM updated) as "updated!"
+        "#,
+        request.update_date,
+        request.update_time,
+        request.update_office_number,
+        request.condition_date,
+        request.condition_time,
+        request.condition_office_number,
+        expected_versions.as_deref(),
+    )
+    .fetch_one(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(counts) if counts.updated < counts.matched => precondition_failed(),
+        Ok(_) => HttpResponse::Ok().body("Entry successfully updated"),
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::NotFound().body("Entry not found")
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/tickets",
+    tag = "Tickets",
+    request_body = OptionTicket,
+    responses(
+        (status = 204, description = "Entry successfully deleted"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("If-Match" = Option<String>, Header, description = "ETags of the versions being deleted, one per matched entry")
+    )
+)]
+#[delete("/tickets")]
+pub async fn delete_ticket(
+    req: HttpRequest,
+    pool: web::Data<sqlx::PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    option_ticket: web::Json<OptionTicket>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+
+    let result = sqlx::query!(
+        r#"
+        WITH matched AS (
+            SELECT id, version FROM tickets
+            WHERE
+                (COALESCE($1, '') = '' OR date = $1) AND
+                (COALESCE($2, '') = '' OR time = $2) AND
+                (COALESCE($3, 0) = 0 OR office_number = $3)
+            FOR UPDATE
+        ), deleted AS (
+            DELETE FROM tickets
+            USING matched
+            WHERE tickets.id = matched.id AND NOT EXISTS (
+                SELECT 1 FROM matched AS stale
+                WHERE NOT ($4::INT[] IS NULL OR stale.version = ANY($4))
+            )
+            RETURNING tickets.id
+        )
+        SELECT (SELECT COUNT(*) FROM matched) as "matched!", (SELECT COUNT(*) FROM deleted) as "deleted!"
+        "#,
+        option_ticket.date,
+        option_ticket.time,
+        option_ticket.office_number,
+        expected_versions.as_deref(),
+    )
+    .fetch_one(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(counts) if counts.deleted < counts.matched => precondition_failed(),
+        Ok(counts) if counts.deleted > 0 => HttpResponse::NoContent().finish(),
+        _ => HttpResponse::NotFound().body("Entry not found"),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/tickets/export.ndjson",
+    tag = "Tickets",
+    responses(
+        (status = 200, description = "All tickets as JSON lines, streamed row by row", body = [Ticket], content_type = "application/x-ndjson")
+    )
+)]
+#[get("/tickets/export.ndjson")]
+pub async fn export_tickets_ndjson(pool: web::Data<PgPool>) -> impl Responder {
+    let pool = pool.get_ref().clone();
+    let lines = async_stream::stream! {
+        let mut rows = sqlx::query_as!(
+            Ticket,
+            "SELECT id, date, time, office_number FROM tickets ORDER BY id"
+        )
+        .fetch(&pool);
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(row) => yield Ok(ndjson::line(&row)),
+                Err(e) => {
+                    yield Err(actix_web::error::ErrorInternalServerError(e));
+                    break;
+                }
+            }
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type(ndjson::CONTENT_TYPE)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"tickets.ndjson\"",
+        ))
+        .streaming(lines)
+}
+
+#[utoipa::path(
+    post,
+    path = "/tickets/import.ndjson",
+    tag = "Tickets",
+    request_body(content = [Ticket], content_type = "application/x-ndjson", description = "One Ticket JSON object per line, as written by `/tickets/export.ndjson`. Existing ids are overwritten"),
+    responses(
+        (status = 202, description = "Import queued, follow it at /jobs/{id}. The lines are imported in one transaction, a line that is invalid or violates a constraint fails the job and nothing is imported", body = Job),
+        (status = 400, description = "The body cannot be read")
+    )
+)]
+#[post("/tickets/import.ndjson")]
+pub async fn import_tickets_ndjson(
+    pool: web::Data<PgPool>,
+    payload: web::Payload,
+) -> impl Responder {
+    queue_ndjson_import(pool.get_ref(), Dataset::Tickets, payload).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/tickets/export.csv",
+    tag = "Tickets",
+    responses(
+        (status = 200, description = "All tickets as CSV, streamed row by row", content_type = "text/csv")
+    )
+)]
+#[get("/tickets/export.csv")]
+pub async fn export_tickets_csv(pool: web::Data<PgPool>) -> impl Responder {
+    let pool = pool.get_ref().clone();
+    let lines = async_stream::stream! {
+        yield Ok::<_, actix_web::Error>(csv_transfer::header_line(csv_transfer::TICKET_COLUMNS));
+
+        let mut rows = sqlx::query_as!(
+            Ticket,
+            "SELECT id, date, time, office_number FROM tickets ORDER BY id"
+        )
+        .fetch(&pool);
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(row) => {
+                    yield Ok(csv_transfer::csv_line(&[
+                        &row.id.to_string(),
+                        &row.date,
+                        &row.time,
+                        &row.office_number.to_string(),
+                    ]));
+                }
+                Err(e) => {
+                    yield Err(actix_web::error::ErrorInternalServerError(e));
+                    break;
+                }
+            }
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"tickets.csv\"",
+        ))
+        .streaming(lines)
+}
+
+#[utoipa::path(
+    post,
+    path = "/tickets/import.csv",
+    tag = "Tickets",
+    request_body(content = String, content_type = "multipart/form-data", description = "CSV file in the `file` field. Rows with an `id` update that ticket, rows without one are added"),
+    responses(
+        (status = 202, description = "Import queued, follow it at /jobs/{id}. The job result sums the rows up, `errors` lists the rows that were skipped", body = Job),
+        (status = 400, description = "The upload cannot be read or misses columns")
+    ),
+    params(
+        ("options" = CsvImportOptions, Query, description = "Dry run, header mapping and delimiter")
+    )
+)]
+#[post("/tickets/import.csv")]
+pub async fn import_tickets_csv(
+    pool: web::Data<PgPool>,
+    options: web::Query<CsvImportOptions>,
+    payload: Multipart,
+) -> impl Responder {
+    queue_csv_import(
+        pool.get_ref(),
+        Dataset::Tickets,
+        options.into_inner(),
+        payload,
+    )
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/tickets/export",
+    tag = "Tickets",
+    responses(
+        (status = 202, description = "Export of the tickets to Sled queued, follow it at /jobs/{id}", body = Job)
+    ),
+    params(
+        ("options" = SledDatasetOptions, Query, description = "`anonymized=true` writes to the anonymized Sled database")
+    )
+)]
+#[get("/tickets/export")]
+pub async fn export_tickets(
+    pool: web::Data<PgPool>,
+    options: web::Query<SledDatasetOptions>,
+) -> impl Responder {
+    let params = SledJobParams {
+        anonymized: options.anonymized.unwrap_or(false),
+        ..Default::default()
+    };
+    job_queued(
+        jobs::enqueue(
+            pool.get_ref(),
+            JobKind::SledExport(Dataset::Tickets),
+            &params,
+        )
+        .await,
+    )
+}
+
+#[utoipa::path(
+    post,
+    path = "/tickets/import",
+    tag = "Tickets",
+    responses(
+        (status = 202, description = "Import of the tickets from Sled queued, follow it at /jobs/{id}", body = Job),
+        (status = 500, description = "Failed to queue the import"),
+    ),
+    params(
+        ("options" = SledDatasetOptions, Query, description = "`anonymized=true` reads from the anonymized Sled database")
+    )
+)]
+#[post("/tickets/import")]
+pub async fn import_tickets(
+    pool: web::Data<PgPool>,
+    options: web::Query<SledDatasetOptions>,
+) -> impl Responder {
+    let params = SledJobParams {
+        anonymized: options.anonymized.unwrap_or(false),
+        ..Default::default()
+    };
+    job_queued(
+        jobs::enqueue(
+            pool.get_ref(),
+            JobKind::SledImport(Dataset::Tickets),
+            &params,
+        )
+        .await,
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/tickets/{id}",
+    tag = "Tickets",
+    responses(
+        (status = 200, description = "Ticket, as it is now or as it was at the given moment", body = Ticket),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Ticket id"),
+        ("as_of" = AsOf, Query, description = "Optional point in time")
+    )
+)]
+#[get("/tickets/{id}")]
+pub async fn get_ticket(
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+    as_of: web::Query<AsOf>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let result = match &as_of.as_of {
+        Some(as_of) => sqlx::query_as!(
+            Ticket,
+            "SELECT id, date, time, office_number
+            FROM tickets_history
+            WHERE id = $1 AND
+                valid_from <= $2::TEXT::TIMESTAMPTZ AND
+                (valid_to IS NULL OR valid_to > $2::TEXT::TIMESTAMPTZ);",
+            id,
+            as_of,
+        )
+        .fetch_optional(pool.get_ref())
+        .await
+        .map(|row| row.map(|ticket| (ticket, None))),
+        None => sqlx::query!(
+            "SELECT id, date, time, office_number, version
+            FROM tickets
+            WHERE id = $1;",
+            id,
+        )
+        .fetch_optional(pool.get_ref())
+        .await
+        .map(|row| {
+            row.map(|row| {
+                let ticket = Ticket {
+                    id: row.id,
+                    date: row.date,
+                    time: row.time,
+                    office_number: row.office_number,
+                };
+                (ticket, Some(row.version))
+            })
+        }),
+    };
+
+    match result {
+        Ok(Some((ticket, Some(version)))) => {
+            HttpResponse::Ok().insert_header(etag(version)).json(ticket)
+        }
+        Ok(Some((ticket, None))) => HttpResponse::Ok().json(ticket),
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) if is_data_exception(&e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+        Err(e) => {
+            error!(error = %e, "Failed to load the ticket");
+            HttpResponse::InternalServerError().body("Failed to load the ticket")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/tickets/{id}/history",
+    tag = "Tickets",
+    responses(
+        (status = 200, description = "All versions of the ticket, oldest first", body = [TicketHistoryEntry]),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Ticket id")
+    )
+)]
+#[get("/tickets/{id}/history")]
+pub async fn get_ticket_history(pool: web::Data<PgPool>, id: web::Path<i32>) -> impl Responder {
+    let rows = sqlx::query_as!(
+        TicketHistoryEntry,
+        r#"SELECT history_id, id, date, time, office_number, operation,
+            valid_from::TEXT as "valid_from!", valid_to::TEXT as valid_to, closed_by
+        FROM tickets_history
+        WHERE id = $1
+        ORDER BY valid_from, history_id;"#,
+        id.into_inner(),
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+
+    let rows = match rows {
+        Ok(rows) if rows.is_empty() => return HttpResponse::NotFound().body("Entry not found"),
+        Ok(rows) => rows,
+        Err(e) => {
+            error!(error = %e, "Failed to load the ticket history");
+            return HttpResponse::InternalServerError().body("Failed to load the ticket history");
+        }
+    };
+    HttpResponse::Ok().json(rows)
+}
+
+#[utoipa::path(
+    patch,
+    path = "/tickets/{id}",
+    tag = "Tickets",
+    request_body = OptionTicket,
+    responses(
+        (status = 200, description = "Entry successfully updated", body = Ticket),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Ticket id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being modified")
+    )
+)]
+#[patch("/tickets/{id}")]
+pub async fn patch_ticket(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+    option_ticket: web::Json<OptionTicket>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
+    let result = tickets::update(
+        pool.get_ref(),
+        id,
+        &option_ticket,
+        expected_versions.as_deref(),
+    )
+    .await;
+
+    match result {
+        Ok(Some((ticket, version))) => HttpResponse::Ok().insert_header(etag(version)).json(ticket),
+        Ok(None) => precondition_failed_or_not_found(pool.get_ref(), "tickets", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/tickets/{id}",
+    tag = "Tickets",
+    request_body = NewTicket,
+    responses(
+        (status = 200, description = "Entry successfully replaced", body = Ticket),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Ticket id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being replaced")
+    )
+)]
+#[put("/tickets/{id}")]
+pub async fn put_ticket(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+    new_ticket: web::Json<NewTicket>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE tickets
+        SET date = $2,
+            time = $3,
+            office_number = $4
+        WHERE id = $1 AND ($5::INT[] IS NULL OR version = ANY($5))
+        RETURNING id, date, time, office_number, version
+        "#,
+        id,
+        new_ticket.date,
+        new_ticket.time,
+        new_ticket.office_number,
+        expected_versions.as_deref(),
+    )
+    .fetch_optional(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(Some(ticket)) => HttpResponse::Ok()
+            .insert_header(etag(ticket.version))
+            .json(Ticket {
+                id: ticket.id,
+                date: ticket.date,
+                time: ticket.time,
+                office_number: ticket.office_number,
+            }),
+        Ok(None) => precondition_failed_or_not_found(pool.get_ref(), "tickets", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/tickets/{id}",
+    tag = "Tickets",
+    responses(
+        (status = 204, description = "Entry successfully deleted"),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Ticket id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being deleted")
+    )
+)]
+#[delete("/tickets/{id}")]
+pub async fn delete_ticket_by_id(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
+    let result = tickets::delete(pool.get_ref(), id, expected_versions.as_deref()).await;
+
+    match result {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => precondition_failed_or_not_found(pool.get_ref(), "tickets", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule",
+    tag = "Schedule",
+    responses(
+        (status = 200, description = "Schedule", body = [FullScheduleEntry]),
+        (status = 500, description = "Failed to load the schedule")
+    ),
+    params(
+        ("schedule entry" = OptionScheduleEntry, Query, description = "Optional filters")
+    )
+)]
+#[get("/schedule")]
+pub async fn get_schedule(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    option_schedule_entry: web::Query<OptionScheduleEntry>,
+) -> impl Responder {
+    let rows = sqlx::query_as!(
+        FullScheduleEntry,
+        "SELECT schedule.id as schedule_id, tickets.id as ticket_id, doctors.id as doctor_id, patients.id as patient_id,
+        tickets.date as ticket_date, tickets.time as ticket_time, tickets.office_number as ticket_office_number,
+        doctors.name as doctor_name, doctors.surname as doctor_surname, doctors.speciality as doctor_speciality,
+        doctors.phone_number as doctor_phone_number, doctors.passport_number as doctor_passport_number,
+        patients.name as patient_name, patients.surname as patient_surname, patients.birth_date as patient_birth_date,
+        patients.phone_number as patient_phone_number, patients.passport_number as patient_passport_number
+        FROM schedule
+        JOIN tickets ON schedule.ticket_id = tickets.id
+        JOIN doctors ON schedule.doctor_id = doctors.id
+        JOIN patients ON schedule.patient_id = patients.id
+        WHERE
+            (COALESCE($1, 0) = 0 OR ticket_id = $1) AND
+            (COALESCE($2, 0) = 0 OR doctor_id = $2) AND
+            (COALESCE($3, 0) = 0 OR patient_id = $3);",
+        option_schedule_entry.ticket_id,
+        option_schedule_entry.doctor_id,
+        option_schedule_entry.patient_id
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!(error = %e, "Failed to load the schedule");
+            return HttpResponse::InternalServerError().body("Failed to load the schedule");
+        }
+    };
+
+    let rows: Vec<FullScheduleEntry> = rows
+        .into_iter()
+        .map(|entry| cipher.decrypt_schedule_entry(entry))
+        .collect();
+    HttpResponse::Ok().json(rows)
+}
+
+#[utoipa::path(
+    post,
+    path = "/schedule",
+    tag = "Schedule",
+    request_body = NewScheduleEntry,
+    responses(
+        (status = 201, description = "Entry successfully created", body = ScheduleEntry),
+        (status = 400, description = "Invalid input")
+    ),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe")
+    )
+)]
+#[post("/schedule")]
+pub async fn add_schedule_entry(
+    pool: web::Data<sqlx::PgPool>,
+    new_schedule_entry: web::Json<NewScheduleEntry>,
+) -> impl Responder {
+    let result = schedule::create(pool.get_ref(), &new_schedule_entry).await;
+
+    match result {
+        Ok((schedule_entry, _)) => HttpResponse::Created().json(schedule_entry),
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    patch,
+    path = "/schedule",
+    tag = "Schedule",
+    request_body = UpdateScheduleEntry,
+    responses(
+        (status = 200, description = "Entry successfully updated"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("If-Match" = Option<String>, Header, description = "ETags of the versions being updated, one per matched entry")
+    )
+)]
+#[patch("/schedule")]
+pub async fn update_schedule_entry(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    request: web::Json<UpdateScheduleEntry>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+
+    let result = sqlx::query!(
+        r#"
+        WITH matched AS (
+            SELECT id, version FROM schedule
+            WHERE
+                ($4::INT IS NULL OR ticket_id = $4) AND
+                ($5::INT IS NULL OR doctor_id = $5) AND
+                ($6::INT IS NULL OR patient_id = $6)
+            FOR UPDATE
+        ), updated AS (
+            UPDATE schedule
+            SET ticket_id = COALESCE($1, ticket_id),
+                doctor_id = COALESCE($2, doctor_id),
+                patient_id = COALESCE($3, patient_id)
+            FROM matched
+            WHERE schedule.id = matched.id AND NOT EXISTS (
+                SELECT 1 FROM matched AS stale
+                WHERE NOT ($7::INT[] IS NULL OR stale.version = ANY($7))
+            )
+            RETURNING schedule.id
+        )
+        SELECT (SELECT COUNT(*) FROM matched) as "matched!", (SELECT COUNT(*) FROM updated) as "updated!"
+        "#,
+        request.update_ticket_id,
+        request.update_doctor_id,
+        request.update_patient_id,
+        request.condition_ticket_id,
+        request.condition_doctor_id,
+        request.condition_patient_id,
+        expected_versions.as_deref(),
+    )
+    .fetch_one(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(counts) if counts.updated < counts.matched => precondition_failed(),
+        Ok(_) => HttpResponse::Ok().body("Entry successfully updated"),
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::NotFound().body("Entry not found")
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/schedule",
+    tag = "Schedule",
+    request_body = OptionScheduleEntry,
+    responses(
+        (status = 204, description = "Entry successfully deleted"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("If-Match" = Option<String>, Header, description = "ETags of the versions being deleted, one per matched entry")
+    )
+)]
+#[delete("/schedule")]
+pub async fn delete_schedule_entry(
+    req: HttpRequest,
+    pool: web::Data<sqlx::PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    option_schedule_entry: web::Json<OptionScheduleEntry>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+
+    let result = sqlx::query!(
+        r#"
+        WITH matched AS (
+            SELECT id, version FROM schedule
+            WHERE
+                (COALESCE($1, 0) = 0 OR ticket_id = $1) AND
+                (COALESCE($2, 0) = 0 OR doctor_id = $2) AND
+                (COALESCE($3, 0) = 0 OR patient_id = $3)
+            FOR UPDATE
+        ), deleted AS (
+            DELETE FROM schedule
+            USING matched
+            WHERE schedule.id = matched.id AND NOT EXISTS (
+                SELECT 1 FROM matched AS stale
+                WHERE NOT ($4::INT[] IS NULL OR stale.version = ANY($4))
+            )
+            RETURNING schedule.id
+        )
+        SELECT (SELECT COUNT(*) FROM matched) as "matched!", (SELECT COUNT(*) FROM deleted) as "deleted!"
+        "#,
+        option_schedule_entry.ticket_id,
+        option_schedule_entry.doctor_id,
+        option_schedule_entry.patient_id,
+        expected_versions.as_deref(),
+    )
+    .fetch_one(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(counts) if counts.deleted < counts.matched => precondition_failed(),
+        Ok(counts) if counts.deleted > 0 => HttpResponse::NoContent().finish(),
+        _ => HttpResponse::NotFound().body("Entry not found"),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/export.xlsx",
+    tag = "Schedule",
+    responses(
+        (status = 200, description = "Workbook with one sheet per doctor", content_type = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+        (status = 400, description = "Invalid period")
+    ),
+    params(
+        ("filter" = ScheduleReportFilter, Query, description = "Period, doctor and speciality")
+    )
+)]
+#[get("/schedule/export.xlsx")]
+pub async fn export_schedule_xlsx(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    filter: web::Query<ScheduleReportFilter>,
+) -> impl Responder {
+    let (from, to) = match reports::report_period(&filter) {
+        Ok(period) => period,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    let entries =
+        match reports::fetch_schedule(pool.get_ref(), cipher.get_ref(), &filter, from, to).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!(error = %e, "Failed to load the schedule");
+                return HttpResponse::InternalServerError().body("Failed to load the schedule");
+            }
+        };
+
+    match reports::schedule_workbook(&entries, from, to) {
+        Ok(workbook) => HttpResponse::Ok()
+            .content_type(reports::XLSX_CONTENT_TYPE)
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"schedule-{}-{}.xlsx\"",
+                    reports::format_date(from),
+                    reports::format_date(to)
+                ),
+            ))
+            .body(workbook),
+        Err(e) => {
+            error!(error = ?e, "Failed to build the schedule workbook");
+            HttpResponse::InternalServerError().body("Failed to build the workbook")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/export.ndjson",
+    tag = "Schedule",
+    responses(
+        (status = 200, description = "All schedule entries as JSON lines, streamed row by row", body = [ScheduleEntry], content_type = "application/x-ndjson")
+    )
+)]
+#[get("/schedule/export.ndjson")]
+pub async fn export_schedule_ndjson(pool: web::Data<PgPool>) -> impl Responder {
+    let pool = pool.get_ref().clone();
+    let lines = async_stream::stream! {
+        let mut rows = sqlx::query_as!(
+            ScheduleEntry,
+            "SELECT id, ticket_id, doctor_id, patient_id FROM schedule ORDER BY id"
+        )
+        .fetch(&pool);
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(row) => yield Ok(ndjson::line(&row)),
+                Err(e) => {
+                    yield Err(actix_web::error::ErrorInternalServerError(e));
+                    break;
+                }
+            }
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type(ndjson::CONTENT_TYPE)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"schedule.ndjson\"",
+        ))
+        .streaming(lines)
+}
+
+#[utoipa::path(
+    post,
+    path = "/schedule/import.ndjson",
+    tag = "Schedule",
+    request_body(content = [ScheduleEntry], content_type = "application/x-ndjson", description = "One ScheduleEntry JSON object per line, as written by `/schedule/export.ndjson`. Existing ids are overwritten"),
+    responses(
+        (status = 202, description = "Import queued, follow it at /jobs/{id}. The lines are imported in one transaction, a line that is invalid or violates a constraint fails the job and nothing is imported", body = Job),
+        (status = 400, description = "The body cannot be read")
+    )
+)]
+#[post("/schedule/import.ndjson")]
+pub async fn import_schedule_ndjson(
+    pool: web::Data<PgPool>,
+    payload: web::Payload,
+) -> impl Responder {
+    queue_ndjson_import(pool.get_ref(), Dataset::Schedule, payload).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/export.csv",
+    tag = "Schedule",
+    responses(
+        (status = 200, description = "All schedule entries as CSV, streamed row by row", content_type = "text/csv")
+    )
+)]
+#[get("/schedule/export.csv")]
+pub async fn export_schedule_csv(pool: web::Data<PgPool>) -> impl Responder {
+    let pool = pool.get_ref().clone();
+    let lines = async_stream::stream! {
+        yield Ok::<_, actix_web::Error>(csv_transfer::header_line(csv_transfer::SCHEDULE_COLUMNS));
+
+        let mut rows = sqlx::query_as!(
+            ScheduleEntry,
+            "SELECT id, ticket_id, doctor_id, patient_id FROM schedule ORDER BY id"
+        )
+        .fetch(&pool);
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(row) => {
+                    yield Ok(csv_transfer::csv_line(&[
+                        &row.id.to_string(),
+                        &row.ticket_id.to_string(),
+                        &row.doctor_id.to_string(),
+                        &row.patient_id.to_string(),
+                    ]));
+                }
+                Err(e) => {
+                    yield Err(actix_web::error::ErrorInternalServerError(e));
+                    break;
+                }
+            }
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"schedule.csv\"",
+        ))
+        .streaming(lines)
+}
+
+#[utoipa::path(
+    post,
+    path = "/schedule/import.csv",
+    tag = "Schedule",
+    request_body(content = String, content_type = "multipart/form-data", description = "CSV file in the `file` field. Rows with an `id` update that schedule entry, rows without one are added"),
+    responses(
+        (status = 202, description = "Import queued, follow it at /jobs/{id}. The job result sums the rows up, `errors` lists the rows that were skipped", body = Job),
+        (status = 400, description = "The upload cannot be read or misses columns")
+    ),
+    params(
+        ("options" = CsvImportOptions, Query, description = "Dry run, header mapping and delimiter")
+    )
+)]
+#[post("/schedule/import.csv")]
+pub async fn import_schedule_csv(
+    pool: web::Data<PgPool>,
+    options: web::Query<CsvImportOptions>,
+    payload: Multipart,
+) -> impl Responder {
+    queue_csv_import(
+        pool.get_ref(),
+        Dataset::Schedule,
+        options.into_inner(),
+        payload,
+    )
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/export",
+    tag = "Schedule",
+    responses(
+        (status = 202, description = "Export of the schedule entries to Sled queued, follow it at /jobs/{id}", body = Job)
+    ),
+    params(
+        ("options" = SledDatasetOptions, Query, description = "`anonymized=true` writes to the anonymized Sled database")
+    )
+)]
+#[get("/schedule/export")]
+pub async fn export_schedule(
+    pool: web::Data<PgPool>,
+    options: web::Query<SledDatasetOptions>,
+) -> impl Responder {
+    let params = SledJobParams {
+        anonymized: options.anonymized.unwrap_or(false),
+        ..Default::default()
+    };
+    job_queued(
+        jobs::enqueue(
+            pool.get_ref(),
+            JobKind::SledExport(Dataset::Schedule),
+            &params,
+        )
+        .await,
+    )
+}
+
+#[utoipa::path(
+    post,
+    path = "/schedule/import",
+    tag = "Schedule",
+    responses(
+        (status = 202, description = "Import of the schedule entries from Sled queued, follow it at /jobs/{id}", body = Job),
+        (status = 500, description = "Failed to queue the import"),
+    ),
+    params(
+        ("options" = SledDatasetOptions, Query, description = "`anonymized=true` reads from the anonymized Sled database")
+    )
+)]
+#[post("/schedule/import")]
+pub async fn import_schedule(
+    pool: web::Data<PgPool>,
+    options: web::Query<SledDatasetOptions>,
+) -> impl Responder {
+    let params = SledJobParams {
+        anonymized: options.anonymized.unwrap_or(false),
+        ..Default::default()
+    };
+    job_queued(
+        jobs::enqueue(
+            pool.get_ref(),
+            JobKind::SledImport(Dataset::Schedule),
+            &params,
+        )
+        .await,
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/stream",
+    tag = "Schedule",
+    responses(
+        (status = 200, description = "Server-sent events named `created`, `updated` or `cancelled` with a ScheduleEvent as data. A `lagged` event means events were missed and the schedule should be reloaded", body = ScheduleEvent, content_type = "text/event-stream")
+    ),
+    params(
+        ("filter" = ScheduleEventFilter, Query, description = "Only events of this doctor, patient or office")
+    )
+)]
+#[get("/schedule/stream")]
+pub async fn stream_schedule(
+    events: web::Data<ScheduleEvents>,
+    filter: web::Query<ScheduleEventFilter>,
+) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(schedule_events::SSE_CONTENT_TYPE)
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(schedule_events::server_sent_events(
+            &events,
+            filter.into_inner(),
+        ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/ws",
+    tag = "Schedule",
+    responses(
+        (status = 101, description = "WebSocket sending each ScheduleEvent as a JSON text message. `{\"kind\": \"lagged\"}` means events were missed and the schedule should be reloaded", body = ScheduleEvent),
+        (status = 400, description = "Not a WebSocket handshake")
+    ),
+    params(
+        ("filter" = ScheduleEventFilter, Query, description = "Only events of this doctor, patient or office")
+    )
+)]
+#[get("/schedule/ws")]
+pub async fn schedule_websocket(
+    req: HttpRequest,
+    body: web::Payload,
+    events: web::Data<ScheduleEvents>,
+    filter: web::Query<ScheduleEventFilter>,
+) -> actix_web::Result<HttpResponse> {
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+    actix_web::rt::spawn(schedule_events::websocket(
+        session,
+        messages,
+        events.subscribe(),
+        filter.into_inner(),
+    ));
+    Ok(response)
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/{id}",
+    tag = "Schedule",
+    responses(
+        (status = 200, description = "Schedule entry", body = FullScheduleEntry),
+        (status = 404, description = "Entry not found"),
+        (status = 500, description = "Failed to load the schedule entry")
+    ),
+    params(
+        ("id" = i32, Path, description = "Schedule entry id")
+    )
+)]
+#[get("/schedule/{id}")]
+pub async fn get_schedule_entry(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    id: web::Path<i32>,
+) -> impl Responder {
+    let result = sqlx::query!(
+        "SELECT schedule.id as schedule_id, schedule.version, tickets.id as ticket_id, doctors.id as doctor_id, patients.id as patient_id,
+        tickets.date as ticket_date, tickets.time as ticket_time, tickets.office_number as ticket_office_number,
+        doctors.name as doctor_name, doctors.surname as doctor_surname, doctors.speciality as doctor_speciality,
+        doctors.phone_number as doctor_phone_number, doctors.passport_number as doctor_passport_number,
+        patients.name as patient_name, patients.surname as patient_surname, patients.birth_date as patient_birth_date,
+        patients.phone_number as patient_phone_number, patients.passport_number as patient_passport_number
+        FROM schedule
+        JOIN tickets ON schedule.ticket_id = tickets.id
+        JOIN doctors ON schedule.doctor_id = doctors.id
+        JOIN patients ON schedule.patient_id = patients.id
+        WHERE schedule.id = $1;",
+        id.into_inner(),
+    )
+    .fetch_optional(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(Some(row)) => {
+            HttpResponse::Ok()
+                .insert_header(etag(row.version))
+                .json(cipher.decrypt_schedule_entry(FullScheduleEntry {
+                    schedule_id: row.schedule_id,
+                    ticket_id: row.ticket_id,
+                    ticket_date: row.ticket_date,
+                    ticket_time: row.ticket_time,
+                    ticket_office_number: row.ticket_office_number,
+                    doctor_id: row.doctor_id,
+                    doctor_name: row.doctor_name,
+                    doctor_surname: row.doctor_surname,
+                    doctor_speciality: row.doctor_speciality,
+                    doctor_phone_number: row.doctor_phone_number,
+                    doctor_passport_number: row.doctor_passport_number,
+                    patient_id: row.patient_id,
+                    patient_name: row.patient_name,
+                    patient_surname: row.patient_surname,
+                    patient_birth_date: row.patient_birth_date,
+                    patient_phone_number: row.patient_phone_number,
+                    patient_passport_number: row.patient_passport_number,
+                }))
+        }
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to load the schedule entry");
+            HttpResponse::InternalServerError().body("Failed to load the schedule entry")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/{id}/slip.pdf",
+    tag = "Schedule",
+    responses(
+        (status = 200, description = "One-page appointment slip", content_type = "application/pdf"),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Schedule entry id")
+    )
+)]
+#[get("/schedule/{id}/slip.pdf")]
+pub async fn get_schedule_entry_slip(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    id: web::Path<i32>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let entry = match pdf::fetch_schedule_entry(pool.get_ref(), cipher.get_ref(), id).await {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to load the entry");
+            return HttpResponse::InternalServerError().body("Failed to load the entry");
+        }
+    };
+
+    match pdf::appointment_slip(&entry) {
+        Ok(slip) => HttpResponse::Ok()
+            .content_type(pdf::PDF_CONTENT_TYPE)
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!("inline; filename=\"slip-{}.pdf\"", id),
+            ))
+            .body(slip),
+        Err(e) => {
+            error!(schedule_id = id, error = %e, "Failed to render the slip");
+            HttpResponse::InternalServerError().body("Failed to render the slip")
+        }
+    }
+}
+
+#[utoipa::path(
+    patch,
+    path = "/schedule/{id}",
+    tag = "Schedule",
+    request_body = OptionScheduleEntry,
+    responses(
+        (status = 200, description = "Entry successfully updated", body = ScheduleEntry),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Schedule entry id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being modified")
+    )
+)]
+#[patch("/schedule/{id}")]
+pub async fn patch_schedule_entry(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+    option_schedule_entry: web::Json<OptionScheduleEntry>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
+    let result = schedule::update(
+        pool.get_ref(),
+        id,
+        &option_schedule_entry,
+        expected_versions.as_deref(),
+    )
+    .await;
+
+    match result {
+        Ok(Some((schedule_entry, version))) => HttpResponse::Ok()
+            .insert_header(etag(version))
+            .json(schedule_entry),
+        Ok(None) => precondition_failed_or_not_found(pool.get_ref(), "schedule", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/schedule/{id}",
+    tag = "Schedule",
+    request_body = NewScheduleEntry,
+    responses(
+        (status = 200, description = "Entry successfully replaced", body = ScheduleEntry),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Schedule entry id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being replaced")
+    )
+)]
+#[put("/schedule/{id}")]
+pub async fn put_schedule_entry(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+    new_schedule_entry: web::Json<NewScheduleEntry>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE schedule
+        SET ticket_id = $2,
+            doctor_id = $3,
+            patient_id = $4
+        WHERE id = $1 AND ($5::INT[] IS NULL OR version = ANY($5))
+        RETURNING id, ticket_id, doctor_id, patient_id, version
+        "#,
+        id,
+        new_schedule_entry.ticket_id,
+        new_schedule_entry.doctor_id,
+        new_schedule_entry.patient_id,
+        expected_versions.as_deref(),
+    )
+    .fetch_optional(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(Some(schedule_entry)) => HttpResponse::Ok()
+            .insert_header(etag(schedule_entry.version))
+            .json(ScheduleEntry {
+                id: schedule_entry.id,
+                ticket_id: schedule_entry.ticket_id,
+                doctor_id: schedule_entry.doctor_id,
+                patient_id: schedule_entry.patient_id,
+            }),
+        Ok(None) => precondition_failed_or_not_found(pool.get_ref(), "schedule", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/schedule/{id}",
+    tag = "Schedule",
+    responses(
+        (status = 204, description = "Entry successfully deleted"),
+        (status = 400, description = "Invalid input"),
+        (status = 404, description = "Entry not found"),
+        (status = 412, description = "Entry was modified by someone else"),
+        (status = 428, description = "If-Match header is required")
+    ),
+    params(
+        ("id" = i32, Path, description = "Schedule entry id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the version being deleted")
+    )
+)]
+#[delete("/schedule/{id}")]
+pub async fn delete_schedule_entry_by_id(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConcurrencySettings>,
+    id: web::Path<i32>,
+) -> impl Responder {
+    let expected_versions = match expected_versions(&req, &settings) {
+        Ok(expected_versions) => expected_versions,
+        Err(error) => return error.response(),
+    };
+    let id = id.into_inner();
+
+    let result = schedule::delete(pool.get_ref(), id, expected_versions.as_deref()).await;
+
+    match result {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => precondition_failed_or_not_found(pool.get_ref(), "schedule", id).await,
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/pii/reencrypt",
+    tag = "Admin",
+    responses(
+        (status = 200, description = "Phone and passport numbers re-encrypted with the current key", body = ReencryptResult),
+        (status = 500, description = "Failed to re-encrypt")
+    )
+)]
+#[post("/admin/pii/reencrypt")]
+pub async fn reencrypt_pii(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+) -> impl Responder {
+    match crypto::reencrypt_outdated(pool.get_ref(), cipher.get_ref()).await {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => {
+            error!(error = %e, "Failed to re-encrypt");
+            HttpResponse::InternalServerError().body("Failed to re-encrypt")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/fhir/metadata",
+    tag = "FHIR",
+    responses(
+        (status = 200, description = "CapabilityStatement of the FHIR R4 facade", content_type = "application/fhir+json")
+    )
+)]
+#[get("/fhir/metadata")]
+pub async fn get_fhir_metadata(req: HttpRequest) -> impl Responder {
+    fhir::response(StatusCode::OK, &fhir::capability_statement(&req))
+}
+
+#[utoipa::path(
+    get,
+    path = "/fhir/{resource_type}",
+    tag = "FHIR",
+    responses(
+        (status = 200, description = "Searchset Bundle of matching resources", content_type = "application/fhir+json"),
+        (status = 400, description = "Invalid search parameter, as an OperationOutcome"),
+        (status = 404, description = "Resource type not supported, as an OperationOutcome")
+    ),
+    params(
+        ("resource_type" = String, Path, description = "Patient, Practitioner, PractitionerRole, Slot or Appointment")
+    )
+)]
+#[get("/fhir/{resource_type}")]
+pub async fn search_fhir_resources(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<FhirSettings>,
+    resource_type: web::Path<String>,
+    params: web::Query<Vec<(String, String)>>,
+) -> impl Responder {
+    let params = fhir::SearchParams(params.into_inner());
+    let result = fhir::search(
+        pool.get_ref(),
+        cipher.get_ref(),
+        settings.get_ref(),
+        &resource_type,
+        &params,
+    )
+    .await;
+
+    match result {
+        Some(Ok((total, resources))) => fhir::response(
+            StatusCode::OK,
+            &fhir::bundle(&req, &params, total, resources),
+        ),
+        Some(Err(error)) => error.response(),
+        None => unsupported_fhir_type(&resource_type),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/fhir/{resource_type}",
+    tag = "FHIR",
+    request_body(content = String, description = "FHIR resource", content_type = "application/fhir+json"),
+    responses(
+        (status = 201, description = "Resource created", content_type = "application/fhir+json"),
+        (status = 400, description = "Invalid resource, as an OperationOutcome"),
+        (status = 404, description = "Resource type not supported, as an OperationOutcome"),
+        (status = 409, description = "Slot already booked, as an OperationOutcome")
+    ),
+    params(
+        ("resource_type" = String, Path, description = "Patient, Practitioner, Slot or Appointment")
+    )
+)]
+#[post("/fhir/{resource_type}")]
+pub async fn create_fhir_resource(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<FhirSettings>,
+    resource_type: web::Path<String>,
+    body: web::Bytes,
+) -> impl Responder {
+    let (pool, cipher, settings) = (pool.get_ref(), cipher.get_ref(), settings.get_ref());
+    let id = match fhir::create(pool, cipher, settings, &resource_type, &body).await {
+        Some(Ok(id)) => id,
+        Some(Err(error)) => return error.response(),
+        None => return unsupported_fhir_type(&resource_type),
+    };
+
+    match fhir::read(pool, cipher, settings, &resource_type, id).await {
+        Some(Ok(Some(resource))) => fhir::created(&req, &resource),
+        _ => fhir::database_error(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/fhir/{resource_type}/{id}",
+    tag = "FHIR",
+    responses(
+        (status = 200, description = "The resource", content_type = "application/fhir+json"),
+        (status = 404, description = "Resource not found, as an OperationOutcome")
+    ),
+    params(
+        ("resource_type" = String, Path, description = "Patient, Practitioner, PractitionerRole, Slot or Appointment"),
+        ("id" = String, Path, description = "Logical id")
+    )
+)]
+#[get("/fhir/{resource_type}/{id}")]
+pub async fn read_fhir_resource(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<FhirSettings>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (resource_type, id) = path.into_inner();
+    let Some(database_id) = fhir::parse_id(&id) else {
+        return fhir::not_found(&resource_type, &id);
+    };
+
+    let result = fhir::read(
+        pool.get_ref(),
+        cipher.get_ref(),
+        settings.get_ref(),
+        &resource_type,
+        database_id,
+    )
+    .await;
+
+    match result {
+        Some(Ok(Some(resource))) => fhir::response(StatusCode::OK, &resource),
+        Some(Ok(None)) => fhir::not_found(&resource_type, &id),
+        Some(Err(error)) => error.response(),
+        None => unsupported_fhir_type(&resource_type),
+    }
+}
+
+fn unsupported_fhir_type(resource_type: &str) -> HttpResponse {
+    fhir::outcome(
+        StatusCode::NOT_FOUND,
+        "not-supported",
+        &format!("Resource type {} is not supported", resource_type),
+    )
+}
+
+#[utoipa::path(
+    post,
+    path = "/graphql",
+    tag = "GraphQL",
+    request_body(content = String, description = "GraphQL request with `query`, `variables` and `operationName`", content_type = "application/json"),
+    responses(
+        (status = 200, description = "GraphQL response with `data` and `errors`", content_type = "application/json")
+    )
+)]
+#[post("/graphql")]
+pub async fn graphql_request(
+    schema: web::Data<HospitalSchema>,
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    request: web::Json<async_graphql::Request>,
+) -> impl Responder {
+    let cipher = cipher.into_inner();
+    let request = graphql::with_loaders(request.into_inner(), pool.get_ref(), &cipher);
+    web::Json(schema.execute(request).await)
+}
+
+#[utoipa::path(
+    get,
+    path = "/graphql",
+    tag = "GraphQL",
+    responses(
+        (status = 200, description = "GraphiQL playground", content_type = "text/html")
+    )
+)]
+#[get("/graphql")]
+pub async fn graphiql() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(
+            async_graphql::http::GraphiQLSource::build()
+                .endpoint("/graphql")
+                .finish(),
+        )
+}
+
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    tag = "Webhooks",
+    responses(
+        (status = 200, description = "Webhook subscriptions", body = [WebhookSubscription])
+    )
+)]
+#[get("/webhooks")]
+pub async fn get_webhooks(pool: web::Data<PgPool>) -> impl Responder {
+    let result = sqlx::query_as!(
+        WebhookSubscription,
+        r#"SELECT id, url, event_types, active, created_at::TEXT as "created_at!"
+        FROM webhook_subscriptions
+        ORDER BY id"#
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(subscriptions) => HttpResponse::Ok().json(subscriptions),
+        Err(e) => {
+            error!(error = %e, "Failed to load webhook subscriptions");
+            HttpResponse::InternalServerError().body("Failed to load webhook subscriptions")
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    tag = "Webhooks",
+    request_body = NewWebhookSubscription,
+    responses(
+        (status = 201, description = "Subscription created", body = WebhookSubscription),
+        (status = 400, description = "Invalid URL or event types")
+    ),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe")
+    )
+)]
+#[post("/webhooks")]
+pub async fn add_webhook(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<WebhookSettings>,
+    new_subscription: web::Json<NewWebhookSubscription>,
+) -> impl Responder {
+    if let Err(message) = settings
+        .validate_url(&new_subscription.url)
+        .and_then(|_| webhooks::validate_event_types(&new_subscription.event_types))
+    {
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    let result = sqlx::query_as!(
+        WebhookSubscription,
+        r#"INSERT INTO webhook_subscriptions (url, secret, event_types, active)
+        VALUES ($1, $2, $3, COALESCE($4, TRUE))
+        RETURNING id, url, event_types, active, created_at::TEXT as "created_at!""#,
+        new_subscription.url,
+        cipher.encrypt(&new_subscription.secret),
+        &new_subscription.event_types,
+        new_subscription.active,
+    )
+    .fetch_one(pool.get_ref())
     .await;
 
     match result {
-        Ok(_) => HttpResponse::Ok().body("Entry successfully updated"),
-        Err(_) => HttpResponse::NotFound().body("Entry not found"),
+        Ok(subscription) => HttpResponse::Created().json(subscription),
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
     }
 }
 
 #[utoipa::path(
-    delete,
-    path = "/tickets",
-    tag = "Tickets",
-    request_body = OptionTicket,
+    get,
+    path = "/webhooks/{id}",
+    tag = "Webhooks",
     responses(
-        (status = 204, description = "Entry successfully deleted"),
-        (status = 404, description = "Entry not found")
+        (status = 200, description = "Webhook subscription", body = WebhookSubscription),
+        (status = 404, description = "Subscription not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Subscription ID")
     )
 )]
-#[delete("/tickets")]
-pub async fn delete_ticket(
-    pool: web::Data<sqlx::PgPool>,
-    option_ticket: web::Json<OptionTicket>,
-) -> impl Responder {
-    let result = sqlx::query!(
-        r#"
-        DELETE FROM tickets
-        WHERE 
-            (COALESCE($1, '') = '' OR date = $1) AND
-            (COALESCE($2, '') = '' OR time = $2) AND
-            (COALESCE($3, 0) = 0 OR office_number = $3);
-        "#,
-        option_ticket.date,
-        option_ticket.time,
-        option_ticket.office_number,
+#[get("/webhooks/{id}")]
+pub async fn get_webhook(pool: web::Data<PgPool>, id: web::Path<i32>) -> impl Responder {
+    let result = sqlx::query_as!(
+        WebhookSubscription,
+        r#"SELECT id, url, event_types, active, created_at::TEXT as "created_at!"
+        FROM webhook_subscriptions
+        WHERE id = $1"#,
+        id.into_inner(),
     )
-    .execute(pool.get_ref())
+    .fetch_optional(pool.get_ref())
     .await;
 
     match result {
-        Ok(rows) if rows.rows_affected() > 0 => HttpResponse::NoContent().finish(),
-        _ => HttpResponse::NotFound().body("Entry not found"),
+        Ok(Some(subscription)) => HttpResponse::Ok().json(subscription),
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to load the webhook subscription");
+            HttpResponse::InternalServerError().body("Failed to load the webhook subscription")
+        }
     }
 }
 
 #[utoipa::path(
-    get,
-    path = "/tickets/export",
-    tag = "Tickets",
+    patch,
+    path = "/webhooks/{id}",
+    tag = "Webhooks",
+    request_body = UpdateWebhookSubscription,
     responses(
-        (status = 200, description = "Exported number of tickets", body = [Ticket])
+        (status = 200, description = "Subscription updated", body = WebhookSubscription),
+        (status = 400, description = "Invalid URL or event types"),
+        (status = 404, description = "Subscription not found")
     ),
+    params(
+        ("id" = i32, Path, description = "Subscription ID")
+    )
 )]
-#[get("/tickets/export")]
-pub async fn export_tickets(
+#[patch("/webhooks/{id}")]
+pub async fn patch_webhook(
     pool: web::Data<PgPool>,
-    sled_db: web::Data<sled::Db>,
+    cipher: web::Data<FieldCipher>,
+    settings: web::Data<WebhookSettings>,
+    id: web::Path<i32>,
+    changes: web::Json<UpdateWebhookSubscription>,
 ) -> impl Responder {
-    let tickets = sqlx::query_as!(
-        Ticket,
-        "SELECT id, date, time, office_number FROM tickets"
+    let valid = changes
+        .url
+        .as_deref()
+        .map_or(Ok(()), |url| settings.validate_url(url))
+        .and_then(|_| {
+            changes
+                .event_types
+                .as_deref()
+                .map_or(Ok(()), webhooks::validate_event_types)
+        });
+    if let Err(message) = valid {
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    let result = sqlx::query_as!(
+        WebhookSubscription,
+        r#"UPDATE webhook_subscriptions
+        SET url = COALESCE($2, url),
+            secret = COALESCE($3, secret),
+            event_types = COALESCE($4, event_types),
+            active = COALESCE($5, active)
+        WHERE id = $1
+        RETURNING id, url, event_types, active, created_at::TEXT as "created_at!""#,
+        id.into_inner(),
+        changes.url,
+        changes
+            .secret
+            .as_deref()
+            .map(|secret| cipher.encrypt(secret)),
+        changes.event_types.as_deref(),
+        changes.active,
     )
-    .fetch_all(pool.get_ref())
+    .fetch_optional(pool.get_ref())
     .await;
 
-    match tickets {
-        Ok(tickets) => {
-            for ticket in &tickets {
-                let key = format!("ticket:{}", ticket.id);
-                let value = serde_json::to_vec(ticket).unwrap();
-                
-                sled_db.insert(key, value).expect("Не удалось вставить данные в Sled");
-            }
-            let mut count = 0;
-            sled_db.scan_prefix("").for_each(|item| {
-            if let Ok(_) = item {
-                count += 1;
-            }
-            });
-            HttpResponse::Ok().body(format!("Экспортировано {} талонов в Sled", count))
+    match result {
+        Ok(Some(subscription)) => HttpResponse::Ok().json(subscription),
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
         }
-        Err(_) => HttpResponse::InternalServerError().body("Не удалось получить данные талонов из PostgreSQL"),
     }
 }
 
 #[utoipa::path(
-    post,
-    path = "/tickets/import",
-    tag = "Tickets",
+    delete,
+    path = "/webhooks/{id}",
+    tag = "Webhooks",
     responses(
-        (status = 200, description = "Tickets imported successfully"),
-        (status = 500, description = "Failed to import tickets"),
+        (status = 204, description = "Subscription and its delivery log deleted"),
+        (status = 404, description = "Subscription not found")
     ),
+    params(
+        ("id" = i32, Path, description = "Subscription ID")
+    )
 )]
-#[post("/tickets/import")]
-pub async fn import_tickets(
-    pool: web::Data<PgPool>,
-    sled_db: web::Data<sled::Db>,
-) -> impl Responder {
-    let mut count = 0;
-
-    let iter = sled_db.iter();
-
-    for item in iter {
-        if let Ok((_, value)) = item {
-            let ticket: Ticket = serde_json::from_slice(&value).unwrap();
-            let insert_query = sqlx::query!(
-                "INSERT INTO tickets (id, date, time, office_number) VALUES ($1, $2, $3, $4)",
-                ticket.id,
-                ticket.date,
-                ticket.time,
-                ticket.office_number
-            );
+#[delete("/webhooks/{id}")]
+pub async fn delete_webhook(pool: web::Data<PgPool>, id: web::Path<i32>) -> impl Responder {
+    let result = sqlx::query!(
+        "DELETE FROM webhook_subscriptions WHERE id = $1",
+        id.into_inner(),
+    )
+    .execute(pool.get_ref())
+    .await;
 
-            if let Err(e) = insert_query.execute(pool.get_ref()).await {
-                eprintln!("Не удалось вставить данные талонов с id {}: {:?}", ticket.id, e);
-            } else {
-                count += 1;
-            }
+    match result {
+        Ok(rows) if rows.rows_affected() > 0 => HttpResponse::NoContent().finish(),
+        Ok(_) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to delete the webhook subscription");
+            HttpResponse::InternalServerError().body("Failed to delete the webhook subscription")
         }
     }
-
-    HttpResponse::Ok().body(format!("Импортировано {} докторов из Sled", count))
 }
 
 #[utoipa::path(
     get,
-    path = "/schedule",
-    tag = "Schedule",
+    path = "/webhooks/{id}/deliveries",
+    tag = "Webhooks",
     responses(
-        (status = 200, description = "Schedule", body = [FullScheduleEntry])
+        (status = 200, description = "Deliveries to this subscription, most recent first", body = [WebhookDelivery]),
+        (status = 404, description = "Subscription not found")
     ),
     params(
-        ("schedule entry" = OptionScheduleEntry, Query, description = "Optional filters")
+        ("id" = i32, Path, description = "Subscription ID"),
+        ("filter" = WebhookDeliveryFilter, Query, description = "Optional status filter and limit")
     )
 )]
-#[get("/schedule")]
-pub async fn get_schedule(
+#[get("/webhooks/{id}/deliveries")]
+pub async fn get_webhook_deliveries(
     pool: web::Data<PgPool>,
-    option_schedule_entry: web::Query<OptionScheduleEntry>
+    id: web::Path<i32>,
+    filter: web::Query<WebhookDeliveryFilter>,
 ) -> impl Responder {
-    let rows = sqlx::query_as!(
-        FullScheduleEntry,
-        "SELECT schedule.id as schedule_id, tickets.id as ticket_id, doctors.id as doctor_id, patients.id as patient_id,
-        tickets.date as ticket_date, tickets.time as ticket_time, tickets.office_number as ticket_office_number,
-        doctors.name as doctor_name, doctors.surname as doctor_surname, doctors.speciality as doctor_speciality,
-        doctors.phone_number as doctor_phone_number, doctors.passport_number as doctor_passport_number,
-        patients.name as patient_name, patients.surname as patient_surname, patients.birth_date as patient_birth_date,
-        patients.phone_number as patient_phone_number, patients.passport_number as patient_passport_number
-        FROM schedule
-        JOIN tickets ON schedule.ticket_id = tickets.id
-        JOIN doctors ON schedule.doctor_id = doctors.id
-        JOIN patients ON schedule.patient_id = patients.id
-        WHERE 
-            (COALESCE($1, 0) = 0 OR ticket_id = $1) AND
-            (COALESCE($2, 0) = 0 OR doctor_id = $2) AND
-            (COALESCE($3, 0) = 0 OR patient_id = $3);",
-        option_schedule_entry.ticket_id,
-        option_schedule_entry.doctor_id,
-        option_schedule_entry.patient_id
+    let id = id.into_inner();
+    if !row_exists(pool.get_ref(), "webhook_subscriptions", id).await {
+        return HttpResponse::NotFound().body("Entry not found");
+    }
+    let limit = filter.limit.unwrap_or(100).clamp(1, 1000);
+
+    let result = sqlx::query_as!(
+        WebhookDelivery,
+        r#"SELECT webhook_deliveries.id, webhook_deliveries.event_id, webhook_outbox.event_type,
+            webhook_outbox.payload, webhook_deliveries.status, webhook_deliveries.attempts,
+            CASE WHEN webhook_deliveries.status = 'pending' THEN webhook_deliveries.next_attempt_at::TEXT END as next_attempt_at,
+            webhook_deliveries.last_attempt_at::TEXT as last_attempt_at, webhook_deliveries.response_status,
+            webhook_deliveries.error, webhook_outbox.created_at::TEXT as "created_at!"
+        FROM webhook_deliveries
+        JOIN webhook_outbox ON webhook_outbox.id = webhook_deliveries.event_id
+        WHERE webhook_deliveries.subscription_id = $1
+            AND (COALESCE($2, '') = '' OR webhook_deliveries.status = $2)
+        ORDER BY webhook_deliveries.id DESC
+        LIMIT $3"#,
+        id,
+        filter.status,
+        limit,
     )
     .fetch_all(pool.get_ref())
-    .await
-    .unwrap();
+    .await;
 
-    web::Json(rows)
+    match result {
+        Ok(deliveries) => HttpResponse::Ok().json(deliveries),
+        Err(e) => {
+            error!(error = %e, "Failed to load webhook deliveries");
+            HttpResponse::InternalServerError().body("Failed to load webhook deliveries")
+        }
+    }
 }
 
 #[utoipa::path(
-    post,
-    path = "/schedule",
-    tag = "Schedule",
-    request_body = NewScheduleEntry,
+    get,
+    path = "/patients/{id}/notification-preferences",
+    tag = "Reminders",
     responses(
-        (status = 201, description = "Entry successfully created", body = ScheduleEntry),
-        (status = 400, description = "Invalid input")
+        (status = 200, description = "How the patient is reminded of appointments", body = NotificationPreferences),
+        (status = 404, description = "Patient not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Patient ID")
     )
 )]
-#[post("/schedule")]
-pub async fn add_schedule_entry(
-    pool: web::Data<sqlx::PgPool>,
-    new_schedule_entry: web::Json<NewScheduleEntry>,
+#[get("/patients/{id}/notification-preferences")]
+pub async fn get_notification_preferences(
+    pool: web::Data<PgPool>,
+    cipher: web::Data<FieldCipher>,
+    id: web::Path<i32>,
 ) -> impl Responder {
-    let result = sqlx::query!(
-        r#"
-        INSERT INTO schedule (ticket_id, doctor_id, patient_id)
-        VALUES ($1, $2, $3)
-        RETURNING id, ticket_id, doctor_id, patient_id
-        "#,
-        new_schedule_entry.ticket_id,
-        new_schedule_entry.doctor_id,
-        new_schedule_entry.patient_id,
+    let result = sqlx::query_as!(
+        NotificationPreferences,
+        r#"SELECT patients.id as patient_id, notification_preferences.email as "email?",
+            notification_preferences.locale as "locale?",
+            COALESCE(notification_preferences.email_enabled, TRUE) as "email_enabled!",
+            COALESCE(notification_preferences.sms_enabled, TRUE) as "sms_enabled!"
+        FROM patients
+        LEFT JOIN notification_preferences ON notification_preferences.patient_id = patients.id
+        WHERE patients.id = $1"#,
+        id.into_inner(),
     )
-    .fetch_one(pool.get_ref())
+    .fetch_optional(pool.get_ref())
     .await;
 
     match result {
-        Ok(schedule_entry) => HttpResponse::Created().json(ScheduleEntry {
-            id: schedule_entry.id,
-            ticket_id: schedule_entry.ticket_id,
-            doctor_id: schedule_entry.doctor_id,
-            patient_id: schedule_entry.patient_id,
-        }),
-        Err(_) => HttpResponse::BadRequest().body("Invalid input"),
+        Ok(Some(mut preferences)) => {
+            preferences.email = preferences.email.map(|email| cipher.decrypt(&email));
+            HttpResponse::Ok().json(preferences)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to load notification preferences");
+            HttpResponse::InternalServerError().body("Failed to load notification preferences")
+        }
     }
 }
 
 #[utoipa::path(
-    patch,
-    path = "/schedule",
-    tag = "Schedule",
-    request_body = UpdateScheduleEntry,
+    put,
+    path = "/patients/{id}/notification-preferences",
+    tag = "Reminders",
+    request_body = UpdateNotificationPreferences,
     responses(
-        (status = 200, description = "Entry successfully updated"),
-        (status = 404, description = "Entry not found"),
+        (status = 200, description = "Preferences replaced", body = NotificationPreferences),
+        (status = 400, description = "Invalid email address or locale"),
+        (status = 404, description = "Patient not found")
+    ),
+    params(
+        ("id" = i32, Path, description = "Patient ID")
     )
 )]
-#[patch("/schedule")]
-pub async fn update_schedule_entry(
+#[put("/patients/{id}/notification-preferences")]
+pub async fn put_notification_preferences(
     pool: web::Data<PgPool>,
-    request: web::Json<UpdateScheduleEntry>,
+    cipher: web::Data<FieldCipher>,
+    id: web::Path<i32>,
+    preferences: web::Json<UpdateNotificationPreferences>,
 ) -> impl Responder {
+    let id = id.into_inner();
+    if let Err(message) =
+        reminders::validate_preferences(preferences.email.as_deref(), preferences.locale.as_deref())
+    {
+        return HttpResponse::BadRequest().body(message);
+    }
+    if !row_exists(pool.get_ref(), "patients", id).await {
+        return HttpResponse::NotFound().body("Entry not found");
+    }
+
+    let email_enabled = preferences.email_enabled.unwrap_or(true);
+    let sms_enabled = preferences.sms_enabled.unwrap_or(true);
     let result = sqlx::query!(
-        r#"
-        UPDATE schedule
-        SET ticket_id = COALESCE($1, ticket_id),
-            doctor_id = COALESCE($2, doctor_id),
-            patient_id = COALESCE($3, patient_id)
-       WHERE 
-            ($4::INT IS NULL OR ticket_id = $4) AND
-            ($5::INT IS NULL OR doctor_id = $5) AND
-            ($6::INT IS NULL OR patient_id = $6);
-        "#,
-        request.update_ticket_id,
-        request.update_doctor_id,
-        request.update_patient_id,
-        request.condition_ticket_id,
-        request.condition_doctor_id,
-        request.condition_patient_id,
+        "INSERT INTO notification_preferences (patient_id, email, locale, email_enabled, sms_enabled)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (patient_id) DO UPDATE
+        SET email = EXCLUDED.email,
+            locale = EXCLUDED.locale,
+            email_enabled = EXCLUDED.email_enabled,
+            sms_enabled = EXCLUDED.sms_enabled",
+        id,
+        preferences.email.as_deref().map(|email| cipher.encrypt(email)),
+        preferences.locale,
+        email_enabled,
+        sms_enabled,
     )
     .execute(pool.get_ref())
     .await;
 
     match result {
-        Ok(_) => HttpResponse::Ok().body("Entry successfully updated"),
-        Err(_) => HttpResponse::NotFound().body("Entry not found"),
+        Ok(_) => {
+            let preferences = preferences.into_inner();
+            HttpResponse::Ok().json(NotificationPreferences {
+                patient_id: id,
+                email: preferences.email,
+                locale: preferences.locale,
+                email_enabled,
+                sms_enabled,
+            })
+        }
+        Err(e) => {
+            warn!(error = %e, "Database request failed");
+            HttpResponse::BadRequest().body("Invalid input")
+        }
     }
 }
 
 #[utoipa::path(
-    delete,
-    path = "/schedule",
-    tag = "Schedule",
-    request_body = OptionScheduleEntry,
+    get,
+    path = "/notifications",
+    tag = "Reminders",
     responses(
-        (status = 204, description = "Entry successfully deleted"),
-        (status = 404, description = "Entry not found")
+        (status = 200, description = "Reminders sent or being sent, most recent first", body = [NotificationLogEntry])
+    ),
+    params(
+        ("filter" = NotificationLogFilter, Query, description = "Optional filters and limit")
     )
 )]
-#[delete("/schedule")]
-pub async fn delete_schedule_entry(
-    pool: web::Data<sqlx::PgPool>,
-    option_schedule_entry: web::Json<OptionScheduleEntry>,
+#[get("/notifications")]
+pub async fn get_notification_log(
+    pool: web::Data<PgPool>,
+    filter: web::Query<NotificationLogFilter>,
 ) -> impl Responder {
-    let result = sqlx::query!(
-        r#"
-        DELETE FROM schedule
-        WHERE 
-            (COALESCE($1, 0) = 0 OR ticket_id = $1) AND
-            (COALESCE($2, 0) = 0 OR doctor_id = $2) AND
-            (COALESCE($3, 0) = 0 OR patient_id = $3);
-        "#,
-        option_schedule_entry.ticket_id,
-        option_schedule_entry.doctor_id,
-        option_schedule_entry.patient_id
+    let limit = filter.limit.unwrap_or(100).clamp(1, 1000);
+    let result = sqlx::query_as!(
+        NotificationLogEntry,
+        r#"SELECT id, schedule_id, patient_id, reminder, channel, appointment_at::TEXT as "appointment_at!",
+            status, attempts, error, created_at::TEXT as "created_at!", updated_at::TEXT as "updated_at!"
+        FROM notification_log
+        WHERE
+            (COALESCE($1, 0) = 0 OR patient_id = $1) AND
+            (COALESCE($2, 0) = 0 OR schedule_id = $2) AND
+            (COALESCE($3, '') = '' OR status = $3)
+        ORDER BY id DESC
+        LIMIT $4"#,
+        filter.patient_id,
+        filter.schedule_id,
+        filter.status,
+        limit,
     )
-    .execute(pool.get_ref())
+    .fetch_all(pool.get_ref())
     .await;
 
     match result {
-        Ok(rows) if rows.rows_affected() > 0 => HttpResponse::NoContent().finish(),
-        _ => HttpResponse::NotFound().body("Entry not found"),
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            error!(error = %e, "Failed to load the notification log");
+            HttpResponse::InternalServerError().body("Failed to load the notification log")
+        }
+    }
+}
+
+/// 202 Accepted with the queued job, which clients poll at its `Location`.
+fn job_queued(job: Result<Job, sqlx::Error>) -> HttpResponse {
+    match job {
+        Ok(job) => HttpResponse::Accepted()
+            .insert_header((header::LOCATION, format!("/jobs/{}", job.id)))
+            .json(job),
+        Err(e) => {
+            error!(error = %e, "Failed to queue the job");
+            HttpResponse::InternalServerError().body("Failed to queue the job")
+        }
+    }
+}
+
+/// Stores a CSV upload and queues its import. The options and the header are checked before
+/// the job is queued, so a file that cannot be imported at all is refused right away.
+async fn queue_csv_import(
+    pool: &PgPool,
+    dataset: Dataset,
+    options: CsvImportOptions,
+    payload: Multipart,
+) -> HttpResponse {
+    let file = match csv_transfer::file_field(payload).await {
+        Ok(file) => file,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    let upload = QueuedUpload::store(pool, JobKind::CsvImport(dataset), &options, file).await;
+    let mut upload = match upload {
+        Ok(upload) => upload,
+        Err(e) => return upload_failed(e),
+    };
+    if let Err(message) = CsvUpload::open(upload.chunks(), dataset.csv_columns(), &options).await {
+        return HttpResponse::BadRequest().body(message);
+    }
+    job_queued(upload.commit(pool).await)
+}
+
+async fn queue_ndjson_import(
+    pool: &PgPool,
+    dataset: Dataset,
+    payload: web::Payload,
+) -> HttpResponse {
+    let chunks =
+        payload.map(|chunk| chunk.map_err(|e| format!("Failed to read the request body: {}", e)));
+    let params = serde_json::json!({});
+    match QueuedUpload::store(pool, JobKind::NdjsonImport(dataset), &params, chunks).await {
+        Ok(upload) => job_queued(upload.commit(pool).await),
+        Err(e) => upload_failed(e),
     }
 }
 
+fn upload_failed(error: UploadError) -> HttpResponse {
+    match error {
+        UploadError::Invalid(message) => HttpResponse::BadRequest().body(message),
+        UploadError::Database(e) => {
+            error!(error = %e, "Failed to queue the job");
+            HttpResponse::InternalServerError().body("Failed to queue the job")
+        }
+    }
+}
 
 #[utoipa::path(
     get,
-    path = "/schedule/export",
-    tag = "Schedule",
+    path = "/jobs",
+    tag = "Jobs",
     responses(
-        (status = 200, description = "Exported number of schedule entries", body = [ScheduleEntry])
+        (status = 200, description = "Background jobs, most recent first", body = [Job])
     ),
+    params(
+        ("filter" = JobFilter, Query, description = "Optional status and kind filters and limit")
+    )
 )]
-#[get("/schedule/export")]
-pub async fn export_schedule(
-    pool: web::Data<PgPool>,
-    sled_db: web::Data<sled::Db>,
-) -> impl Responder {
-    let schedule_entries = sqlx::query_as!(
-        ScheduleEntry,
-        "SELECT id, ticket_id, doctor_id, patient_id FROM schedule"
+#[get("/jobs")]
+pub async fn get_jobs(pool: web::Data<PgPool>, filter: web::Query<JobFilter>) -> impl Responder {
+    let limit = filter.limit.unwrap_or(100).clamp(1, 1000);
+    let result = sqlx::query_as!(
+        Job,
+        r#"SELECT id, kind, params, status, cancel_requested, processed, failed, total, result,
+            errors, error, attempts, created_at::TEXT as "created_at!", started_at::TEXT as started_at,
+            finished_at::TEXT as finished_at
+        FROM jobs
+        WHERE
+            (COALESCE($1, '') = '' OR status = $1) AND
+            (COALESCE($2, '') = '' OR kind = $2)
+        ORDER BY id DESC
+        LIMIT $3"#,
+        filter.status,
+        filter.kind,
+        limit,
     )
     .fetch_all(pool.get_ref())
     .await;
 
-    match schedule_entries {
-        Ok(entries) => {
-            for entry in &entries {
-                let key = format!("entry:{}", entry.id);
-                let value = serde_json::to_vec(&entry).unwrap();
-                
-                sled_db.insert(key, value).expect("Не удалось вставить данные в Sled");
-            }
-            let mut count = 0;
-            sled_db.scan_prefix("").for_each(|item| {
-            if let Ok(_) = item {
-                count += 1;
-            }
-            });
-            HttpResponse::Ok().body(format!("Экспортировано {} записей расписания в Sled", count))
+    match result {
+        Ok(jobs) => HttpResponse::Ok().json(jobs),
+        Err(e) => {
+            error!(error = %e, "Failed to load jobs");
+            HttpResponse::InternalServerError().body("Failed to load jobs")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    tag = "Jobs",
+    responses(
+        (status = 200, description = "Status, progress, result and record errors of the job", body = Job),
+        (status = 404, description = "Entry not found")
+    ),
+    params(
+        ("id" = i64, Path, description = "Job ID")
+    )
+)]
+#[get("/jobs/{id}")]
+pub async fn get_job(pool: web::Data<PgPool>, id: web::Path<i64>) -> impl Responder {
+    match jobs::fetch(pool.get_ref(), id.into_inner()).await {
+        Ok(Some(job)) => HttpResponse::Ok().json(job),
+        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
+        Err(e) => {
+            error!(error = %e, "Failed to load the job");
+            HttpResponse::InternalServerError().body("Failed to load the job")
         }
-        Err(_) => HttpResponse::InternalServerError().body("Не удалось получить данные записей расписания из PostgreSQL"),
     }
 }
 
 #[utoipa::path(
     post,
-    path = "/schedule/import",
-    tag = "Schedule",
+    path = "/jobs/{id}/cancel",
+    tag = "Jobs",
     responses(
-        (status = 200, description = "Schedule imported successfully"),
-        (status = 500, description = "Failed to import Schedule"),
+        (status = 202, description = "Cancellation requested. Queued jobs are cancelled at once, running ones after their current batch, keeping what earlier batches did", body = Job),
+        (status = 404, description = "Entry not found"),
+        (status = 409, description = "The job already finished", body = Job)
     ),
+    params(
+        ("id" = i64, Path, description = "Job ID")
+    )
 )]
-#[post("/schedule/import")]
-pub async fn import_schedule(
+#[post("/jobs/{id}/cancel")]
+pub async fn cancel_job(pool: web::Data<PgPool>, id: web::Path<i64>) -> impl Responder {
+    match jobs::cancel(pool.get_ref(), id.into_inner()).await {
+        Ok(job) => HttpResponse::Accepted().json(job),
+        Err(CancelError::Finished(job)) => HttpResponse::Conflict().json(job),
+        Err(CancelError::NotFound) => HttpResponse::NotFound().body("Entry not found"),
+        Err(CancelError::Database(e)) => {
+            error!(error = %e, "Failed to cancel the job");
+            HttpResponse::InternalServerError().body("Failed to cancel the job")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    tag = "Health",
+    responses(
+        (status = 200, description = "The process is running and answering requests")
+    )
+)]
+#[get("/health/live")]
+pub async fn health_live() -> impl Responder {
+    HttpResponse::Ok().body("OK")
+}
+
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    tag = "Health",
+    responses(
+        (status = 200, description = "PostgreSQL answers and both Sled databases are writable", body = Readiness),
+        (status = 503, description = "A check failed or the server is shutting down", body = Readiness)
+    )
+)]
+#[get("/health/ready")]
+pub async fn health_ready(
     pool: web::Data<PgPool>,
     sled_db: web::Data<sled::Db>,
+    anonymized_sled: web::Data<AnonymizedSled>,
+    health: web::Data<Health>,
 ) -> impl Responder {
-    let mut count = 0;
+    let (database, sled, anonymized_sled) = futures_util::join!(
+        health::check_database(pool.get_ref()),
+        health::check_sled("sled", sled_db.get_ref()),
+        health::check_sled("anonymized_sled", &anonymized_sled.0),
+    );
+    let checks = vec![database, sled, anonymized_sled];
+    let shutting_down = health.is_shutting_down();
+    let ready = !shutting_down && checks.iter().all(|check| check.ok);
 
-    let iter = sled_db.iter();
+    let mut response = if ready {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    response.json(Readiness {
+        ready,
+        shutting_down,
+        checks,
+    })
+}
 
-    for item in iter {
-        if let Ok((_, value)) = item {
-            let schedule_entry: ScheduleEntry = serde_json::from_slice(&value).unwrap();
-            let insert_query = sqlx::query!(
-                "INSERT INTO schedule (id, ticket_id, doctor_id, patient_id) VALUES ($1, $2, $3, $4)",
-                schedule_entry.id,
-                schedule_entry.ticket_id,
-                schedule_entry.doctor_id,
-                schedule_entry.patient_id
-            );
+#[utoipa::path(
+    get,
+    path = "/health/details",
+    tag = "Health",
+    responses(
+        (status = 200, description = "Checks, connection pool, Sled and migration state and build info", body = HealthDetails),
+        (status = 403, description = "Neither a loopback client nor one with a client certificate")
+    )
+)]
+#[get("/health/details")]
+pub async fn health_details(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    sled_db: web::Data<sled::Db>,
+    anonymized_sled: web::Data<AnonymizedSled>,
+    health: web::Data<Health>,
+) -> impl Responder {
+    if !tls::is_local_or_certified(&req) {
+        return HttpResponse::Forbidden()
+            .body("Health details are only served to local clients or with a client certificate");
+    }
 
-            if let Err(e) = insert_query.execute(pool.get_ref()).await {
-                eprintln!("Не удалось вставить запись расписания с id {}: {:?}", schedule_entry.id, e);
-            } else {
-                count += 1;
-            }
+    let (database, sled, anonymized_sled) = futures_util::join!(
+        health::database_health(pool.get_ref()),
+        health::sled_health("sled", sled_db.get_ref()),
+        health::sled_health("anonymized_sled", &anonymized_sled.0),
+    );
+    let sled = vec![sled, anonymized_sled];
+    let shutting_down = health.is_shutting_down();
+    let ready = !shutting_down && database.check.ok && sled.iter().all(|sled| sled.check.ok);
+
+    HttpResponse::Ok().json(HealthDetails {
+        ready,
+        shutting_down,
+        uptime_seconds: health.uptime().as_secs(),
+        build: health::build_info(),
+        database,
+        sled,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "Metrics",
+    responses(
+        (status = 200, description = "Request, database, Sled job and appointment metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+        (status = 500, description = "Failed to render metrics")
+    )
+)]
+#[get("/metrics")]
+pub async fn get_metrics(pool: web::Data<PgPool>, metrics: web::Data<Metrics>) -> impl Responder {
+    match metrics.render(pool.get_ref()) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(metrics::CONTENT_TYPE)
+            .body(body),
+        Err(e) => {
+            error!(error = %e, "Failed to render metrics");
+            HttpResponse::InternalServerError().body("Failed to render metrics")
         }
     }
-
-    HttpResponse::Ok().body(format!("Импортировано {} записей расписания из Sled", count))
 }
@@ -0,0 +1,179 @@
+use crate::models::{BuildInfo, DatabaseHealth, HealthCheck, SledHealth};
+use actix_web::dev::ServerHandle;
+use futures_util::future::join_all;
+use sqlx::PgPool;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{error, info};
+
+/// Checks taking longer than this fail, a probe should not hang on a stuck database.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Key written and removed again to check that a Sled database is writable. No dataset uses
+/// the `health:` prefix.
+const SLED_PROBE_KEY: &str = "health:probe";
+
+pub struct Health {
+    shutting_down: AtomicBool,
+    /// Set once the drain is over and the servers are told to stop.
+    stopped: watch::Sender<bool>,
+    started: Instant,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            shutting_down: AtomicBool::new(false),
+            stopped: watch::Sender::new(false),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Completes when servers that are not actix-web ones should stop, after the drain.
+    pub async fn stopped(&self) {
+        let mut stopped = self.stopped.subscribe();
+        let _ = stopped.wait_for(|stopped| *stopped).await;
+    }
+}
+
+/// Stops the servers gracefully on SIGTERM or SIGINT. Readiness fails for `drain` before, so
+/// that load balancers stop sending requests while the server still answers them. A second
+/// signal skips the rest of the drain.
+pub async fn stop_on_signal(health: Arc<Health>, servers: Vec<ServerHandle>, drain: Duration) {
+    let (mut terminate, mut interrupt) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        (Err(e), _) | (_, Err(e)) => {
+            error!(error = %e, "Could not listen for shutdown signals");
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+
+    health.shutting_down.store(true, Ordering::Relaxed);
+    if !drain.is_zero() {
+        info!(
+            drain_seconds = drain.as_secs(),
+            "Shutting down after the drain, readiness checks fail meanwhile"
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(drain) => {}
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+    }
+    info!("Shutting down");
+    health.stopped.send_replace(true);
+    join_all(servers.iter().map(|server| server.stop(true))).await;
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        name: env!("CARGO_PKG_NAME").to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        profile: if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        }
+        .to_string(),
+    }
+}
+
+pub async fn check_database(pool: &PgPool) -> HealthCheck {
+    check("database", async {
+        sqlx::query("SELECT 1")
+            .execute(pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+/// Writes, removes and flushes a probe key.
+pub async fn check_sled(name: &str, sled_db: &sled::Db) -> HealthCheck {
+    check(name, async {
+        sled_db
+            .insert(SLED_PROBE_KEY, &[])
+            .map_err(|e| e.to_string())?;
+        sled_db.remove(SLED_PROBE_KEY).map_err(|e| e.to_string())?;
+        sled_db.flush_async().await.map_err(|e| e.to_string())?;
+        Ok(())
+    })
+    .await
+}
+
+pub async fn database_health(pool: &PgPool) -> DatabaseHealth {
+    let check = check_database(pool).await;
+    let migration = sqlx::query!(
+        r#"SELECT version, description, installed_on::TEXT as "installed_on!"
+        FROM _sqlx_migrations
+        WHERE success
+        ORDER BY version DESC
+        LIMIT 1"#
+    )
+    .fetch_optional(pool)
+    .await
+    .ok()
+    .flatten();
+
+    DatabaseHealth {
+        check,
+        pool_size: pool.size(),
+        pool_idle: pool.num_idle() as u32,
+        pool_max_connections: pool.options().get_max_connections(),
+        migration_version: migration.as_ref().map(|m| m.version),
+        migration_description: migration.as_ref().map(|m| m.description.clone()),
+        migration_installed_at: migration.map(|m| m.installed_on),
+        latest_migration_version: sqlx::migrate!()
+            .iter()
+            .map(|migration| migration.version)
+            .max(),
+    }
+}
+
+pub async fn sled_health(name: &str, sled_db: &sled::Db) -> SledHealth {
+    let check = check_sled(name, sled_db).await;
+    SledHealth {
+        check,
+        size_on_disk: sled_db.size_on_disk().ok(),
+        was_recovered: sled_db.was_recovered(),
+    }
+}
+
+async fn check(name: &str, check: impl Future<Output = Result<(), String>>) -> HealthCheck {
+    let started = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| {
+            Err(format!(
+                "No answer within {} seconds",
+                CHECK_TIMEOUT.as_secs()
+            ))
+        });
+
+    HealthCheck {
+        name: name.to_string(),
+        ok: result.is_ok(),
+        error: result.err(),
+        duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+    }
+}
@@ -0,0 +1,812 @@
+use crate::crypto::FieldCipher;
+use crate::models::{NewPatient, NewScheduleEntry, UpdatePatient};
+use crate::reports::format_date;
+use crate::schedule;
+use sqlx::{PgConnection, PgPool};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use time::{Date, OffsetDateTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info_span, warn, Instrument};
+
+/// MLLP frames a message as `<VT> message <FS><CR>`.
+const START_BLOCK: u8 = 0x0b;
+const END_BLOCK: u8 = 0x1c;
+const CARRIAGE_RETURN: u8 = 0x0d;
+
+/// Connections sending a frame larger than this are closed.
+const MAX_MESSAGE_BYTES: usize = 1 << 20;
+
+/// Identifier type code of passport numbers in PID-3 (HL7 table 0203).
+const PASSPORT_TYPE: &str = "PPN";
+
+static ACK_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+pub struct Hl7Settings {
+    /// Loopback address of the MLLP listener. `None` disables it. MLLP carries patient data
+    /// in plaintext without authentication.
+    pub address: Option<SocketAddr>,
+    /// MSH-3 and MSH-4 of acknowledgements.
+    pub application: String,
+    pub facility: String,
+}
+
+/// A message split into segments and fields. Components, repetitions and escapes are
+/// resolved on access with the encoding characters from MSH-2.
+pub struct Message {
+    segments: Vec<Vec<String>>,
+    component_separator: char,
+    repetition_separator: char,
+    escape_character: char,
+    subcomponent_separator: char,
+    field_separator: char,
+}
+
+impl Message {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim_start_matches(['\r', '\n']);
+        if !text.starts_with("MSH") {
+            return Err("The message does not start with an MSH segment".to_string());
+        }
+        let mut header = text[3..].chars();
+        let field_separator = header.next().ok_or("MSH is truncated")?;
+        let encoding: Vec<char> = header.take_while(|c| *c != field_separator).collect();
+        let [component_separator, repetition_separator, escape_character, subcomponent_separator, ..] =
+            encoding[..]
+        else {
+            return Err("MSH-2 must list four encoding characters".to_string());
+        };
+
+        let segments = text
+            .split(['\r', '\n'])
+            .filter(|segment| !segment.trim().is_empty())
+            .map(|segment| segment.split(field_separator).map(str::to_string).collect())
+            .collect();
+
+        Ok(Message {
+            segments,
+            component_separator,
+            repetition_separator,
+            escape_character,
+            subcomponent_separator,
+            field_separator,
+        })
+    }
+
+    fn segment(&self, name: &str) -> Option<&[String]> {
+        self.segments
+            .iter()
+            .find(|segment| segment[0] == name)
+            .map(Vec::as_slice)
+    }
+
+    /// Raw field `number` of the first `name` segment, with repetitions and escapes intact.
+    /// MSH-1 is the field separator itself, so MSH fields are shifted by one.
+    fn raw_field(&self, name: &str, number: usize) -> &str {
+        let index = if name == "MSH" { number - 1 } else { number };
+        self.segment(name)
+            .and_then(|segment| segment.get(index))
+            .map_or("", String::as_str)
+    }
+
+    fn repetitions(&self, name: &str, number: usize) -> impl Iterator<Item = &str> {
+        self.raw_field(name, number)
+            .split(self.repetition_separator)
+    }
+
+    /// Component `component` of the first repetition of a field, unescaped. Subcomponents
+    /// other than the first are dropped.
+    fn component(&self, name: &str, number: usize, component: usize) -> String {
+        let repetition = self.repetitions(name, number).next().unwrap_or_default();
+        self.component_of(repetition, component)
+    }
+
+    fn component_of(&self, repetition: &str, component: usize) -> String {
+        let value = repetition
+            .split(self.component_separator)
+            .nth(component - 1)
+            .unwrap_or_default()
+            .split(self.subcomponent_separator)
+            .next()
+            .unwrap_or_default();
+        self.unescape(value).trim().to_string()
+    }
+
+    fn unescape(&self, value: &str) -> String {
+        let mut parts = value.split(self.escape_character);
+        let mut unescaped = parts.next().unwrap_or_default().to_string();
+        // Escape sequences sit between pairs of escape characters, plain text after them.
+        while let (Some(sequence), Some(text)) = (parts.next(), parts.next()) {
+            match sequence {
+                "F" => unescaped.push(self.field_separator),
+                "S" => unescaped.push(self.component_separator),
+                "R" => unescaped.push(self.repetition_separator),
+                "T" => unescaped.push(self.subcomponent_separator),
+                "E" => unescaped.push(self.escape_character),
+                ".br" => unescaped.push('\n'),
+                _ => {}
+            }
+            unescaped.push_str(text);
+        }
+        unescaped
+    }
+
+    fn control_id(&self) -> String {
+        self.component("MSH", 10, 1)
+    }
+}
+
+/// Reasons a message was not applied. Rejected messages could not be understood and are
+/// logged; errors are messages that were understood but could not be applied.
+pub enum Hl7Error {
+    Reject(String),
+    Error(String),
+}
+
+impl From<sqlx::Error> for Hl7Error {
+    fn from(error: sqlx::Error) -> Self {
+        error!(error = %error, "HL7 message could not be stored");
+        Hl7Error::Error("The database request failed".to_string())
+    }
+}
+
+/// `YYYYMMDD[HHMM[SS]][+ZZZZ]` into a date and an `HH:MM` time, which is `None` when the
+/// timestamp has only a date. Offsets are ignored, as tickets are in local time.
+fn parse_timestamp(value: &str) -> Option<(Date, Option<String>)> {
+    let digits = value.get(..8)?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let date = Date::from_calendar_date(
+        digits[..4].parse().ok()?,
+        digits[4..6].parse::<u8>().ok()?.try_into().ok()?,
+        digits[6..8].parse().ok()?,
+    )
+    .ok()?;
+
+    let time = match value.get(8..12) {
+        Some(time) if time.bytes().all(|b| b.is_ascii_digit()) => {
+            let hour: u8 = time[..2].parse().ok()?;
+            let minute: u8 = time[2..].parse().ok()?;
+            if hour > 23 || minute > 59 {
+                return None;
+            }
+            Some(format!("{:02}:{:02}", hour, minute))
+        }
+        _ => None,
+    };
+    Some((date, time))
+}
+
+fn timestamp(now: OffsetDateTime) -> String {
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        now.year(),
+        now.month() as u8,
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    )
+}
+
+/// A patient as carried by a PID segment.
+struct PatientIdentification {
+    passport_number: String,
+    name: String,
+    surname: String,
+    birth_date: String,
+    phone_number: String,
+}
+
+/// Reads PID. The passport number is the PID-3 identifier of type PPN, which is how patients
+/// are matched; other identifiers are ignored.
+fn patient_identification(message: &Message) -> Result<PatientIdentification, Hl7Error> {
+    if message.segment("PID").is_none() {
+        return Err(Hl7Error::Reject(
+            "The message has no PID segment".to_string(),
+        ));
+    }
+    let passport_number = message
+        .repetitions("PID", 3)
+        .find(|identifier| message.component_of(identifier, 5) == PASSPORT_TYPE)
+        .map(|identifier| message.component_of(identifier, 1))
+        .filter(|passport_number| !passport_number.is_empty())
+        .ok_or_else(|| {
+            Hl7Error::Reject(format!(
+                "PID-3 must carry a passport number of type {}",
+                PASSPORT_TYPE
+            ))
+        })?;
+
+    let birth_date = match message.component("PID", 7, 1).as_str() {
+        "" => String::new(),
+        value => parse_timestamp(value)
+            .map(|(date, _)| format_date(date))
+            .ok_or_else(|| Hl7Error::Reject(format!("PID-7 `{}` is not a date", value)))?,
+    };
+    // XTN-1 is deprecated in favour of XTN-12, but most senders still fill it in.
+    let mut phone_number = message.component("PID", 13, 1);
+    if phone_number.is_empty() {
+        phone_number = message.component("PID", 13, 12);
+    }
+
+    Ok(PatientIdentification {
+        passport_number,
+        surname: message.component("PID", 5, 1),
+        name: message.component("PID", 5, 2),
+        birth_date,
+        phone_number,
+    })
+}
+
+/// Updates the patient with the passport number, or registers them when there is none.
+/// Empty PID fields leave the stored values alone.
+///
+/// Patients with the same passport may already exist until they are merged, so passports
+/// cannot be unique. Instead upserts of one passport take a lock until the end of the
+/// transaction, so that concurrent messages about a new patient register them only once.
+async fn upsert_patient(
+    connection: &mut PgConnection,
+    cipher: &FieldCipher,
+    patient: PatientIdentification,
+) -> Result<i32, Hl7Error> {
+    let non_empty = |value: &str| Some(value.to_string()).filter(|value| !value.is_empty());
+    let update = UpdatePatient {
+        update_name: non_empty(&patient.name),
+        update_surname: non_empty(&patient.surname),
+        update_birth_date: non_empty(&patient.birth_date),
+        update_phone_number: non_empty(&patient.phone_number),
+        update_passport_number: None,
+        condition_name: None,
+        condition_surname: None,
+        condition_birth_date: None,
+        condition_phone_number: None,
+        condition_passport_number: Some(patient.passport_number.clone()),
+    };
+
+    let passport_number_index = cipher.blind_index(&patient.passport_number);
+    sqlx::query!(
+        "SELECT pg_advisory_xact_lock(hashtextextended($1, 0))",
+        passport_number_index,
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    let updated = sqlx::query_scalar!(
+        "UPDATE patients
+        SET name = COALESCE($1, name),
+            surname = COALESCE($2, surname),
+            birth_date = COALESCE($3, birth_date),
+            phone_number = COALESCE($4, phone_number),
+            phone_number_index = COALESCE($5, phone_number_index)
+        WHERE passport_number_index = $6
+        RETURNING id",
+        update.update_name,
+        update.update_surname,
+        update.update_birth_date,
+        update
+            .update_phone_number
+            .as_deref()
+            .map(|value| cipher.encrypt(value)),
+        update
+            .update_phone_number
+            .as_deref()
+            .map(|value| cipher.blind_index(value)),
+        passport_number_index,
+    )
+    .fetch_all(&mut *connection)
+    .await?;
+
+    let id = match updated.first() {
+        Some(id) => *id,
+        None => {
+            if patient.name.is_empty() || patient.surname.is_empty() {
+                return Err(Hl7Error::Reject(
+                    "PID-5 must carry the family and given name of a new patient".to_string(),
+                ));
+            }
+            let new_patient = NewPatient {
+                name: patient.name,
+                surname: patient.surname,
+                birth_date: patient.birth_date,
+                phone_number: patient.phone_number,
+                passport_number: patient.passport_number,
+            };
+            sqlx::query_scalar!(
+                "INSERT INTO patients (name, surname, birth_date, phone_number, passport_number, phone_number_index, passport_number_index)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                RETURNING id",
+                new_patient.name,
+                new_patient.surname,
+                new_patient.birth_date,
+                cipher.encrypt(&new_patient.phone_number),
+                cipher.encrypt(&new_patient.passport_number),
+                cipher.blind_index(&new_patient.phone_number),
+                passport_number_index,
+            )
+            .fetch_one(&mut *connection)
+            .await?
+        }
+    };
+    Ok(id)
+}
+
+/// The ticket an SIU message is about: when, where and with whom.
+struct Booking {
+    date: String,
+    time: String,
+    office_number: i32,
+    doctor_id: i32,
+}
+
+/// Reads the start from TQ1-7, or SCH-11.4 before v2.5, the office from the room or point of
+/// care of AIL-3, and the doctor from the id of AIP-3, which must be a doctor id of ours.
+fn booking(message: &Message) -> Result<Booking, Hl7Error> {
+    let mut start = message.component("TQ1", 7, 1);
+    if start.is_empty() {
+        start = message.component("SCH", 11, 4);
+    }
+    let (date, time) = match parse_timestamp(&start) {
+        Some((date, Some(time))) => (format_date(date), time),
+        _ => {
+            return Err(Hl7Error::Reject(
+                "TQ1-7 or SCH-11 must carry the start as YYYYMMDDHHMM".to_string(),
+            ))
+        }
+    };
+
+    let room = message.component("AIL", 3, 2);
+    let point_of_care = message.component("AIL", 3, 1);
+    let office_number = room
+        .parse()
+        .or_else(|_| point_of_care.parse())
+        .map_err(|_| Hl7Error::Reject("AIL-3 must carry the office number".to_string()))?;
+    let doctor_id = message
+        .component("AIP", 3, 1)
+        .parse()
+        .map_err(|_| Hl7Error::Reject("AIP-3 must carry the doctor id".to_string()))?;
+
+    Ok(Booking {
+        date,
+        time,
+        office_number,
+        doctor_id,
+    })
+}
+
+/// Books the ticket, creating it when it does not exist yet. Booking it again for the same
+/// doctor and patient is accepted, so that resent messages do no harm. The patient, the ticket
+/// and the entry are stored in one transaction, a failed booking leaves nothing behind.
+async fn book(pool: &PgPool, cipher: &FieldCipher, message: &Message) -> Result<(), Hl7Error> {
+    let booking = booking(message)?;
+    let patient = patient_identification(message)?;
+
+    let mut transaction = pool.begin().await?;
+    let patient_id = upsert_patient(&mut transaction, cipher, patient).await?;
+
+    let doctor_exists = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM doctors WHERE id = $1) as "exists!""#,
+        booking.doctor_id,
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    if !doctor_exists {
+        return Err(Hl7Error::Error(format!(
+            "Doctor {} does not exist",
+            booking.doctor_id
+        )));
+    }
+
+    let existing_ticket = sqlx::query_scalar!(
+        "SELECT id FROM tickets WHERE date = $1 AND time = $2 AND office_number = $3 ORDER BY id LIMIT 1",
+        booking.date,
+        booking.time,
+        booking.office_number,
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let ticket_id = match existing_ticket {
+        Some(id) => id,
+        None => {
+            sqlx::query_scalar!(
+                "INSERT INTO tickets (date, time, office_number) VALUES ($1, $2, $3) RETURNING id",
+                booking.date,
+                booking.time,
+                booking.office_number,
+            )
+            .fetch_one(&mut *transaction)
+            .await?
+        }
+    };
+
+    let new_schedule_entry = NewScheduleEntry {
+        ticket_id,
+        doctor_id: booking.doctor_id,
+        patient_id,
+    };
+    if schedule::book(&mut *transaction, &new_schedule_entry)
+        .await?
+        .is_some()
+    {
+        transaction.commit().await?;
+        return Ok(());
+    }
+
+    let booked_by_same = sqlx::query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM schedule WHERE ticket_id = $1 AND doctor_id = $2 AND patient_id = $3
+        ) as "exists!""#,
+        ticket_id,
+        booking.doctor_id,
+        patient_id,
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    if booked_by_same {
+        transaction.commit().await?;
+        Ok(())
+    } else {
+        Err(Hl7Error::Error(format!(
+            "Office {} is already booked on {} at {}",
+            booking.office_number, booking.date, booking.time
+        )))
+    }
+}
+
+/// Deletes the schedule entry of the ticket, doctor and patient. The ticket itself stays.
+async fn cancel(pool: &PgPool, cipher: &FieldCipher, message: &Message) -> Result<(), Hl7Error> {
+    let booking = booking(message)?;
+    let patient = patient_identification(message)?;
+
+    let result = sqlx::query!(
+        "DELETE FROM schedule
+        USING tickets, patients
+        WHERE schedule.ticket_id = tickets.id AND schedule.patient_id = patients.id AND
+            tickets.date = $1 AND tickets.time = $2 AND tickets.office_number = $3 AND
+            schedule.doctor_id = $4 AND patients.passport_number_index = $5",
+        booking.date,
+        booking.time,
+        booking.office_number,
+        booking.doctor_id,
+        cipher.blind_index(&patient.passport_number),
+    )
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Hl7Error::Error(format!(
+            "No appointment of doctor {} in office {} on {} at {}",
+            booking.doctor_id, booking.office_number, booking.date, booking.time
+        )));
+    }
+    Ok(())
+}
+
+/// Applies one message. Supports ADT^A04 and ADT^A08 patient upserts and SIU^S12 bookings
+/// and SIU^S15 cancellations.
+pub async fn apply(pool: &PgPool, cipher: &FieldCipher, message: &Message) -> Result<(), Hl7Error> {
+    let message_type = message.component("MSH", 9, 1);
+    let trigger = message.component("MSH", 9, 2);
+    match (message_type.as_str(), trigger.as_str()) {
+        ("ADT", "A04" | "A08") => {
+            let patient = patient_identification(message)?;
+            let mut transaction = pool.begin().await?;
+            upsert_patient(&mut transaction, cipher, patient).await?;
+            transaction.commit().await?;
+            Ok(())
+        }
+        ("SIU", "S12") => book(pool, cipher, message).await,
+        ("SIU", "S15") => cancel(pool, cipher, message).await,
+        _ => Err(Hl7Error::Reject(format!(
+            "Message type {}^{} is not supported",
+            message_type, trigger
+        ))),
+    }
+}
+
+/// Builds the acknowledgement of a message: `AA` when it was applied, `AE` when it could not
+/// be applied and `AR` when it was rejected. Unparsable messages are acknowledged with the
+/// default encoding characters and an empty control id.
+pub fn acknowledgement(
+    settings: &Hl7Settings,
+    message: Option<&Message>,
+    result: &Result<(), Hl7Error>,
+) -> String {
+    let field = |number, component| {
+        message.map_or(String::new(), |message| {
+            escape(&message.component("MSH", number, component))
+        })
+    };
+    let (code, text) = match result {
+        Ok(()) => ("AA", String::new()),
+        Err(Hl7Error::Error(text)) => ("AE", escape(text)),
+        Err(Hl7Error::Reject(text)) => ("AR", escape(text)),
+    };
+    let version = Some(field(12, 1))
+        .filter(|version| !version.is_empty())
+        .unwrap_or_else(|| "2.5".to_string());
+
+    format!(
+        "MSH|^~\\&|{}|{}|{}|{}|{}||ACK^{}^ACK|{}{}|P|{}\rMSA|{}|{}|{}\r",
+        escape(&settings.application),
+        escape(&settings.facility),
+        field(3, 1),
+        field(4, 1),
+        timestamp(OffsetDateTime::now_utc()),
+        field(9, 2),
+        timestamp(OffsetDateTime::now_utc()),
+        ACK_COUNTER.fetch_add(1, Ordering::Relaxed),
+        version,
+        code,
+        message.map_or(String::new(), |message| escape(&message.control_id())),
+        text
+    )
+}
+
+/// Escapes text for a field of an acknowledgement, which uses the default encoding characters.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\E\\"),
+            '|' => escaped.push_str("\\F\\"),
+            '^' => escaped.push_str("\\S\\"),
+            '~' => escaped.push_str("\\R\\"),
+            '&' => escaped.push_str("\\T\\"),
+            '\r' | '\n' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Parses and applies a frame and returns its acknowledgement.
+async fn handle(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    settings: &Hl7Settings,
+    frame: &[u8],
+) -> String {
+    let text = String::from_utf8_lossy(frame);
+    let message = match Message::parse(&text) {
+        Ok(message) => message,
+        Err(reason) => {
+            // The message itself is not logged, it carries patient data.
+            warn!(reason = %reason, bytes = frame.len(), "Unparsable HL7 message");
+            return acknowledgement(settings, None, &Err(Hl7Error::Reject(reason)));
+        }
+    };
+
+    let result = apply(pool, cipher, &message).await;
+    match &result {
+        Err(Hl7Error::Reject(reason)) => {
+            warn!(control_id = %message.control_id(), reason = %reason, "Rejected HL7 message")
+        }
+        Err(Hl7Error::Error(reason)) => {
+            error!(control_id = %message.control_id(), reason = %reason, "HL7 message was not applied")
+        }
+        Ok(()) => {}
+    }
+    acknowledgement(settings, Some(&message), &result)
+}
+
+/// Reads MLLP frames off a connection and answers each one. Bytes outside frames are skipped.
+async fn serve_connection(
+    mut stream: TcpStream,
+    pool: PgPool,
+    cipher: Arc<FieldCipher>,
+    settings: Arc<Hl7Settings>,
+) -> std::io::Result<()> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 8192];
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+
+        loop {
+            let Some(start) = buffer.iter().position(|b| *b == START_BLOCK) else {
+                buffer.clear();
+                break;
+            };
+            let Some(end) = buffer[start..]
+                .windows(2)
+                .position(|window| window == [END_BLOCK, CARRIAGE_RETURN])
+                .map(|end| start + end)
+            else {
+                buffer.drain(..start);
+                break;
+            };
+
+            let reply = handle(&pool, &cipher, &settings, &buffer[start + 1..end]).await;
+            let mut framed = Vec::with_capacity(reply.len() + 3);
+            framed.push(START_BLOCK);
+            framed.extend_from_slice(reply.as_bytes());
+            framed.extend_from_slice(&[END_BLOCK, CARRIAGE_RETURN]);
+            stream.write_all(&framed).await?;
+            buffer.drain(..end + 2);
+        }
+
+        if buffer.len() > MAX_MESSAGE_BYTES {
+            warn!(
+                max_bytes = MAX_MESSAGE_BYTES,
+                "HL7 frame is too long, closing the connection"
+            );
+            return Ok(());
+        }
+    }
+}
+
+/// Accepts MLLP connections until the process exits, serving each one on its own task.
+pub async fn serve(
+    listener: TcpListener,
+    pool: PgPool,
+    cipher: Arc<FieldCipher>,
+    settings: Arc<Hl7Settings>,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!(error = %e, "Failed to accept an MLLP connection");
+                continue;
+            }
+        };
+        let (pool, cipher, settings) = (pool.clone(), cipher.clone(), settings.clone());
+        tokio::spawn(
+            async move {
+                if let Err(e) = serve_connection(stream, pool, cipher, settings).await {
+                    warn!(error = %e, "MLLP connection failed");
+                }
+            }
+            .instrument(info_span!("mllp_connection", peer = %peer)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PiiConfig;
+
+    const ADMISSION: &str = "MSH|^~\\&|CLINIC|WARD|HOSPITAL|HOSPITAL|20240131093000||ADT^A04^ADT_A01|MSG00001|P|2.5\r\
+        PID|1||123456^^^CLINIC^MR~4510 123456^^^RUS^PPN||Ivanov^Ivan^Ivanovich||19800229|M|||||+7 900 000\\T\\00 00\r";
+
+    fn settings() -> Hl7Settings {
+        Hl7Settings {
+            address: None,
+            application: "HOSPITAL".to_string(),
+            facility: "MAIN|WARD".to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_reads_fields_components_and_escapes() {
+        let message = Message::parse(ADMISSION).unwrap();
+
+        assert_eq!(message.component("MSH", 9, 1), "ADT");
+        assert_eq!(message.component("MSH", 9, 2), "A04");
+        assert_eq!(message.control_id(), "MSG00001");
+        assert_eq!(message.component("PID", 5, 1), "Ivanov");
+        assert_eq!(message.component("PID", 5, 2), "Ivan");
+        assert_eq!(message.component("PID", 13, 1), "+7 900 000&00 00");
+        assert_eq!(message.component("AIL", 3, 1), "");
+
+        let patient = patient_identification(&message).ok().unwrap();
+        assert_eq!(patient.passport_number, "4510 123456");
+        assert_eq!(patient.birth_date, "1980-02-29");
+    }
+
+    #[test]
+    fn parse_uses_the_encoding_characters_of_the_message() {
+        let message = Message::parse("MSH#@*%$#A#B#C#D#E#F#ADT@A08#1\nPID###X@@@@PPN").unwrap();
+
+        assert_eq!(message.component("MSH", 9, 2), "A08");
+        assert_eq!(message.control_id(), "1");
+        assert_eq!(message.component("PID", 3, 1), "X");
+        assert_eq!(message.component("PID", 3, 5), "PPN");
+    }
+
+    #[test]
+    fn parse_rejects_messages_without_a_header() {
+        assert!(Message::parse("PID|1||123").is_err());
+        assert!(Message::parse("MSH|^~").is_err());
+        assert!(Message::parse("").is_err());
+    }
+
+    #[test]
+    fn parse_timestamp_reads_dates_and_times() {
+        let date = Date::from_calendar_date(2024, time::Month::January, 31).unwrap();
+
+        assert_eq!(parse_timestamp("20240131"), Some((date, None)));
+        assert_eq!(
+            parse_timestamp("202401310930"),
+            Some((date, Some("09:30".to_string())))
+        );
+        assert_eq!(
+            parse_timestamp("20240131093015+0300"),
+            Some((date, Some("09:30".to_string())))
+        );
+        assert_eq!(parse_timestamp("20240230"), None);
+        assert_eq!(parse_timestamp("202401312460"), None);
+        assert_eq!(parse_timestamp("2024-01-31"), None);
+        assert_eq!(parse_timestamp("2024"), None);
+    }
+
+    #[test]
+    fn acknowledgement_echoes_the_message_and_escapes_text() {
+        let message = Message::parse(ADMISSION).unwrap();
+        let result = Err(Hl7Error::Error(
+            "Office 5 is booked | try another".to_string(),
+        ));
+        let ack = acknowledgement(&settings(), Some(&message), &result);
+
+        let segments: Vec<&str> = ack.split('\r').collect();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[2], "");
+        let header: Vec<&str> = segments[0].split('|').collect();
+        assert_eq!(
+            &header[..6],
+            [
+                "MSH",
+                "^~\\&",
+                "HOSPITAL",
+                "MAIN\\F\\WARD",
+                "CLINIC",
+                "WARD"
+            ]
+        );
+        assert_eq!(header[8], "ACK^A04^ACK");
+        assert_eq!(header[10], "P");
+        assert_eq!(header[11], "2.5");
+        assert_eq!(
+            segments[1],
+            "MSA|AE|MSG00001|Office 5 is booked \\F\\ try another"
+        );
+    }
+
+    #[test]
+    fn acknowledgement_of_unparsable_messages_has_no_control_id() {
+        let result = Err(Hl7Error::Reject("bad".to_string()));
+        let ack = acknowledgement(&settings(), None, &result);
+
+        assert!(ack.ends_with("\rMSA|AR||bad\r"));
+        assert!(ack.contains("|ACK^^ACK|"));
+    }
+
+    #[tokio::test]
+    async fn serve_answers_framed_messages() {
+        // Unsupported messages are rejected before the database is used.
+        let pool = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+        let cipher = Arc::new(FieldCipher::new(&PiiConfig::default()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, pool, cipher, Arc::new(settings())));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let message = "MSH|^~\\&|CLINIC|WARD|||20240131||ORU^R01|42|P|2.3\r";
+        let mut frame = b"noise".to_vec();
+        frame.push(START_BLOCK);
+        frame.extend_from_slice(message.as_bytes());
+        frame.extend_from_slice(&[END_BLOCK, CARRIAGE_RETURN]);
+        stream.write_all(&frame).await.unwrap();
+
+        let mut reply = Vec::new();
+        while !reply.ends_with(&[END_BLOCK, CARRIAGE_RETURN]) {
+            let mut chunk = [0; 1024];
+            let read = stream.read(&mut chunk).await.unwrap();
+            assert!(read > 0, "the connection was closed");
+            reply.extend_from_slice(&chunk[..read]);
+        }
+
+        assert_eq!(reply[0], START_BLOCK);
+        let reply = String::from_utf8(reply[1..reply.len() - 2].to_vec()).unwrap();
+        assert!(reply.contains("|ACK^R01^ACK|"));
+        assert!(reply.ends_with("|2.3\rMSA|AR|42|Message type ORU\\S\\R01 is not supported\r"));
+    }
+}
@@ -0,0 +1,570 @@
+use crate::crypto::FieldCipher;
+use actix_web::body::{to_bytes, BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::web::Bytes;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use futures_util::{stream, Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::Duration;
+use tracing::{error, info};
+
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+pub const IDEMPOTENCY_REPLAYED: &str = "Idempotency-Replayed";
+
+/// Routes whose POST requests honour `Idempotency-Key`: the create endpoints and the imports,
+/// which answer with the job they queued.
+const IDEMPOTENT_ROUTES: [&str; 18] = [
+    "/patients",
+    "/patients/import",
+    "/patients/import.csv",
+    "/patients/import.ndjson",
+    "/doctors",
+    "/doctors/import",
+    "/doctors/import.csv",
+    "/doctors/import.ndjson",
+    "/tickets",
+    "/tickets/import",
+    "/tickets/import.csv",
+    "/tickets/import.ndjson",
+    "/schedule",
+    "/schedule/import",
+    "/schedule/import.csv",
+    "/schedule/import.ndjson",
+    "/webhooks",
+    "/fhir/{resource_type}",
+];
+
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+pub struct IdempotencySettings {
+    /// How long a stored response is replayed for, in seconds.
+    pub ttl_seconds: i64,
+}
+
+/// Makes POST requests to the create and import endpoints carrying an `Idempotency-Key`
+/// header safe to retry.
+///
+/// The first request with a key is executed and its response is stored, encrypted, together
+/// with a hash of the request. Keys are scoped to the method and path. Retries with the same
+/// key and the same request get the stored response back, retries with a different request get
+/// `422`, and retries that arrive while the first request is still running get `409`.
+///
+/// Bodies are hashed while the handler streams them rather than buffered, so uploads of any
+/// size are covered.
+pub async fn idempotency(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let idempotent_route = req.method() == Method::POST
+        && req
+            .match_pattern()
+            .is_some_and(|pattern| IDEMPOTENT_ROUTES.contains(&pattern.as_str()));
+    let key = match req.headers().get(IDEMPOTENCY_KEY) {
+        Some(key) if idempotent_route => match key.to_str() {
+            Ok(key) if !key.is_empty() => key.to_owned(),
+            _ => {
+                let response = HttpResponse::BadRequest().body("Invalid Idempotency-Key header");
+                return Ok(req.into_response(response));
+            }
+        },
+        _ => return next.call(req).await.map(|res| res.map_into_boxed_body()),
+    };
+
+    let (pool, cipher, ttl_seconds) = match (
+        req.app_data::<web::Data<PgPool>>(),
+        req.app_data::<web::Data<FieldCipher>>(),
+        req.app_data::<web::Data<IdempotencySettings>>(),
+    ) {
+        (Some(pool), Some(cipher), Some(settings)) => {
+            (pool.get_ref().clone(), cipher.clone(), settings.ttl_seconds)
+        }
+        _ => return next.call(req).await.map(|res| res.map_into_boxed_body()),
+    };
+    let method = req.method().to_string();
+    let path = req.path().to_owned();
+
+    // An expired key is claimed again as if it had never been used.
+    let claimed = sqlx::query!(
+        "INSERT INTO idempotency_keys (method, path, key) VALUES ($1, $2, $3)
+        ON CONFLICT (method, path, key) DO UPDATE
+        SET request_hash = NULL, status = NULL, content_type = NULL, body = NULL,
+            resource_id = NULL, created_at = now()
+        WHERE idempotency_keys.created_at < now() - make_interval(secs => $4)
+        RETURNING key",
+        method,
+        path,
+        key,
+        ttl_seconds as f64,
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    if claimed.is_none() {
+        let stored = sqlx::query!(
+            "SELECT request_hash, status, content_type, body FROM idempotency_keys
+            WHERE method = $1 AND path = $2 AND key = $3",
+            method,
+            path,
+            key,
+        )
+        .fetch_optional(&pool)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+        let response = match stored {
+            Some(stored) => match (stored.status, stored.request_hash) {
+                (Some(status), Some(stored_hash)) => {
+                    let body = BodyHash::new(&mut req);
+                    if BodyHash::finish(body).await? != stored_hash {
+                        HttpResponse::UnprocessableEntity()
+                            .body("Idempotency-Key was already used with a different request")
+                    } else {
+                        let mut response = HttpResponse::build(
+                            StatusCode::from_u16(status as u16)
+                                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+                        );
+                        if let Some(content_type) = stored.content_type {
+                            response.insert_header((CONTENT_TYPE, content_type));
+                        }
+                        response.insert_header((IDEMPOTENCY_REPLAYED, "true")).body(
+                            stored
+                                .body
+                                .map(|body| cipher.decrypt(&body))
+                                .unwrap_or_default(),
+                        )
+                    }
+                }
+                _ => HttpResponse::Conflict()
+                    .body("A request with this Idempotency-Key is still being processed"),
+            },
+            // The key expired between the insert and the lookup.
+            None => HttpResponse::Conflict().body("Idempotency-Key expired, retry the request"),
+        };
+        return Ok(req.into_response(response));
+    }
+
+    let body = BodyHash::new(&mut req);
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(BodyHash::stream(body.clone())),
+    });
+    let res = match next.call(req).await {
+        Ok(res) => res,
+        Err(error) => {
+            release_key(&pool, &method, &path, &key).await;
+            return Err(error);
+        }
+    };
+
+    let status = res.status();
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let (req, res) = res.into_parts();
+    let (res, response_body) = res.into_parts();
+    let response_body = match to_bytes(response_body).await {
+        Ok(body) => body,
+        Err(e) => {
+            let e: Box<dyn std::error::Error> = e.into();
+            error!(idempotency_key = %key, error = %e, "Failed to read the response body");
+            release_key(&pool, &method, &path, &key).await;
+            return Err(actix_web::error::ErrorInternalServerError(
+                "Failed to read response body",
+            ));
+        }
+    };
+    // Handlers that answer before reading the whole body, as on invalid input, leave the rest
+    // to be hashed here.
+    let request_hash = BodyHash::finish(body).await;
+
+    // Server errors are not final, so the client should be able to retry them for real.
+    // Create endpoints answer with text or JSON, anything else is not worth replaying.
+    match (std::str::from_utf8(&response_body), request_hash) {
+        (Ok(text), Ok(request_hash)) if !status.is_server_error() => {
+            let stored = sqlx::query!(
+                "UPDATE idempotency_keys
+                SET request_hash = $4, status = $5, content_type = $6, body = $7, resource_id = $8
+                WHERE method = $1 AND path = $2 AND key = $3",
+                method,
+                path,
+                key,
+                request_hash,
+                status.as_u16() as i16,
+                content_type,
+                cipher.encrypt(text),
+                resource_id(text),
+            )
+            .execute(&pool)
+            .await;
+
+            if let Err(e) = stored {
+                error!(idempotency_key = %key, error = %e, "Failed to store the response");
+            }
+        }
+        _ => release_key(&pool, &method, &path, &key).await,
+    }
+
+    let res = res.set_body(response_body);
+    Ok(ServiceResponse::new(req, res).map_into_boxed_body())
+}
+
+/// The `id` of the created entry or queued job. FHIR resources carry theirs as a string.
+fn resource_id(response: &str) -> Option<i64> {
+    let value = serde_json::from_str::<serde_json::Value>(response).ok()?;
+    let id = value.get("id")?;
+    id.as_i64().or_else(|| id.as_str()?.parse().ok())
+}
+
+/// The request body, passed on to the handler and hashed together with the method and URI as
+/// it is read.
+///
+/// Clients pick a new multipart boundary every time they build a request, retries included, so
+/// boundaries are hashed as a placeholder. The last bytes of a chunk are held back until the
+/// next one arrives, in case a boundary spans both.
+struct BodyHash {
+    payload: Payload,
+    hasher: Sha256,
+    boundary: Option<Vec<u8>>,
+    held_back: Vec<u8>,
+}
+
+impl BodyHash {
+    fn new(req: &mut ServiceRequest) -> Rc<RefCell<Self>> {
+        let mut hasher = Sha256::new();
+        hasher.update(req.method().as_str());
+        hasher.update(req.uri().to_string());
+        let boundary = req
+            .mime_type()
+            .ok()
+            .flatten()
+            .filter(|mime| mime.essence_str() == "multipart/form-data")
+            .and_then(|mime| Some(mime.get_param("boundary")?.as_str().as_bytes().to_vec()))
+            .filter(|boundary| !boundary.is_empty());
+        Rc::new(RefCell::new(BodyHash {
+            payload: req.take_payload(),
+            hasher,
+            boundary,
+            held_back: Vec::new(),
+        }))
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        let Some(boundary) = &self.boundary else {
+            self.hasher.update(chunk);
+            return;
+        };
+        self.held_back.extend_from_slice(chunk);
+        let mut start = 0;
+        while let Some(found) = self.held_back[start..]
+            .windows(boundary.len())
+            .position(|window| window == boundary.as_slice())
+        {
+            self.hasher.update(&self.held_back[start..start + found]);
+            self.hasher.update(b"boundary");
+            start += found + boundary.len();
+        }
+        let end = self.held_back.len() - (self.held_back.len() - start).min(boundary.len() - 1);
+        self.hasher.update(&self.held_back[start..end]);
+        self.held_back.drain(..end);
+    }
+
+    fn stream(body: Rc<RefCell<Self>>) -> impl Stream<Item = Result<Bytes, PayloadError>> {
+        stream::poll_fn(move |cx| {
+            let mut body = body.borrow_mut();
+            let polled = body.payload.poll_next_unpin(cx);
+            if let Poll::Ready(Some(Ok(chunk))) = &polled {
+                body.update(chunk);
+            }
+            polled
+        })
+    }
+
+    /// Hashes what is left of the body and returns the hash of the whole request.
+    async fn finish(body: Rc<RefCell<Self>>) -> Result<String, PayloadError> {
+        let mut rest = Self::stream(body.clone());
+        while let Some(chunk) = rest.next().await {
+            chunk?;
+        }
+        let mut body = body.borrow_mut();
+        let held_back = std::mem::take(&mut body.held_back);
+        body.hasher.update(&held_back);
+        Ok(hex::encode(body.hasher.clone().finalize()))
+    }
+}
+
+async fn release_key(pool: &PgPool, method: &str, path: &str, key: &str) {
+    if let Err(e) = sqlx::query!(
+        "DELETE FROM idempotency_keys WHERE method = $1 AND path = $2 AND key = $3",
+        method,
+        path,
+        key,
+    )
+    .execute(pool)
+    .await
+    {
+        error!(idempotency_key = %key, error = %e, "Failed to release the key");
+    }
+}
+
+/// Deletes expired keys and their stored responses every few minutes.
+pub async fn expire_keys(pool: PgPool, ttl_seconds: i64) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let expired = sqlx::query!(
+            "DELETE FROM idempotency_keys WHERE created_at < now() - make_interval(secs => $1)",
+            ttl_seconds as f64,
+        )
+        .execute(&pool)
+        .await;
+
+        match expired {
+            Ok(result) if result.rows_affected() > 0 => {
+                info!(
+                    keys = result.rows_affected(),
+                    "Deleted expired idempotency keys"
+                )
+            }
+            Ok(_) => {}
+            Err(e) => error!(error = %e, "Failed to delete expired idempotency keys"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PiiConfig;
+    use actix_web::dev::ServiceFactory;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Notify;
+
+    /// Counts handler runs and lets a test hold a request while it is being processed.
+    #[derive(Default)]
+    struct Calls {
+        count: AtomicUsize,
+        started: Notify,
+        release: Notify,
+    }
+
+    async fn create(calls: web::Data<Calls>, body: Bytes) -> HttpResponse {
+        let id = calls.count.fetch_add(1, Ordering::SeqCst) + 1;
+        HttpResponse::Created().json(serde_json::json!({
+            "id": id,
+            "name": String::from_utf8_lossy(&body),
+        }))
+    }
+
+    /// Fails the first time and succeeds afterwards.
+    async fn flaky(calls: web::Data<Calls>) -> HttpResponse {
+        match calls.count.fetch_add(1, Ordering::SeqCst) {
+            0 => HttpResponse::InternalServerError().body("Failed to create the entry"),
+            _ => HttpResponse::Created().json(serde_json::json!({ "id": 1 })),
+        }
+    }
+
+    async fn slow(calls: web::Data<Calls>) -> HttpResponse {
+        calls.count.fetch_add(1, Ordering::SeqCst);
+        calls.started.notify_one();
+        calls.release.notified().await;
+        HttpResponse::Created().json(serde_json::json!({ "id": 1 }))
+    }
+
+    /// Queues a job without looking at the upload, as an import rejecting it early might.
+    async fn import(calls: web::Data<Calls>) -> HttpResponse {
+        let id = calls.count.fetch_add(1, Ordering::SeqCst) + 1;
+        HttpResponse::Accepted().json(serde_json::json!({ "id": id, "status": "queued" }))
+    }
+
+    fn app(
+        pool: PgPool,
+        calls: web::Data<Calls>,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = Error,
+            InitError = (),
+        >,
+    > {
+        App::new()
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::new(
+                FieldCipher::new(&PiiConfig::default()).unwrap(),
+            ))
+            .app_data(web::Data::new(IdempotencySettings { ttl_seconds: 3600 }))
+            .app_data(calls)
+            .wrap(from_fn(idempotency))
+            .route("/patients", web::post().to(create))
+            .route("/doctors", web::post().to(flaky))
+            .route("/tickets", web::post().to(slow))
+            .route("/patients/import.csv", web::post().to(import))
+    }
+
+    fn post(path: &str, key: &str, body: &'static str) -> TestRequest {
+        TestRequest::post()
+            .uri(path)
+            .insert_header((IDEMPOTENCY_KEY, key))
+            .set_payload(body)
+    }
+
+    #[sqlx::test]
+    async fn retries_get_the_stored_response(pool: PgPool) {
+        let calls = web::Data::new(Calls::default());
+        let app = init_service(app(pool, calls.clone())).await;
+
+        let first = call_service(&app, post("/patients", "a", "Ivan").to_request()).await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert!(first.headers().get(IDEMPOTENCY_REPLAYED).is_none());
+        let first = read_body(first).await;
+
+        let retry = call_service(&app, post("/patients", "a", "Ivan").to_request()).await;
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.headers().get(IDEMPOTENCY_REPLAYED).unwrap(), "true");
+        assert_eq!(read_body(retry).await, first);
+
+        let other_key = call_service(&app, post("/patients", "b", "Ivan").to_request()).await;
+        assert_eq!(other_key.status(), StatusCode::CREATED);
+        assert_eq!(calls.count.load(Ordering::SeqCst), 2);
+    }
+
+    #[sqlx::test]
+    async fn a_different_request_with_the_same_key_is_rejected(pool: PgPool) {
+        let calls = web::Data::new(Calls::default());
+        let app = init_service(app(pool, calls.clone())).await;
+
+        call_service(&app, post("/patients", "a", "Ivan").to_request()).await;
+        let retry = call_service(&app, post("/patients", "a", "Petr").to_request()).await;
+        assert_eq!(retry.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(calls.count.load(Ordering::SeqCst), 1);
+    }
+
+    #[sqlx::test]
+    async fn uploads_are_hashed_even_when_the_handler_does_not_read_them(pool: PgPool) {
+        let calls = web::Data::new(Calls::default());
+        let app = init_service(app(pool, calls.clone())).await;
+
+        let first = call_service(
+            &app,
+            post("/patients/import.csv", "a", "id\n1\n").to_request(),
+        )
+        .await;
+        assert_eq!(first.status(), StatusCode::ACCEPTED);
+        let retry = call_service(
+            &app,
+            post("/patients/import.csv", "a", "id\n1\n").to_request(),
+        )
+        .await;
+        assert_eq!(retry.status(), StatusCode::ACCEPTED);
+        assert_eq!(retry.headers().get(IDEMPOTENCY_REPLAYED).unwrap(), "true");
+        let other = call_service(
+            &app,
+            post("/patients/import.csv", "a", "id\n2\n").to_request(),
+        )
+        .await;
+        assert_eq!(other.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(calls.count.load(Ordering::SeqCst), 1);
+    }
+
+    #[sqlx::test]
+    async fn retries_while_the_request_runs_get_409(pool: PgPool) {
+        let calls = web::Data::new(Calls::default());
+        let app = init_service(app(pool, calls.clone())).await;
+
+        let (first, retry) = futures_util::join!(
+            call_service(&app, post("/tickets", "a", "{}").to_request()),
+            async {
+                calls.started.notified().await;
+                let retry = call_service(&app, post("/tickets", "a", "{}").to_request()).await;
+                calls.release.notify_one();
+                retry
+            }
+        );
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert_eq!(retry.status(), StatusCode::CONFLICT);
+        assert_eq!(calls.count.load(Ordering::SeqCst), 1);
+    }
+
+    #[sqlx::test]
+    async fn server_errors_release_the_key(pool: PgPool) {
+        let calls = web::Data::new(Calls::default());
+        let app = init_service(app(pool, calls.clone())).await;
+
+        let first = call_service(&app, post("/doctors", "a", "{}").to_request()).await;
+        assert_eq!(first.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let retry = call_service(&app, post("/doctors", "a", "{}").to_request()).await;
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert!(retry.headers().get(IDEMPOTENCY_REPLAYED).is_none());
+        assert_eq!(calls.count.load(Ordering::SeqCst), 2);
+    }
+
+    #[sqlx::test]
+    async fn expired_keys_are_used_again(pool: PgPool) {
+        let calls = web::Data::new(Calls::default());
+        let app = init_service(app(pool.clone(), calls.clone())).await;
+
+        call_service(&app, post("/patients", "a", "Ivan").to_request()).await;
+        sqlx::query!("UPDATE idempotency_keys SET created_at = now() - interval '2 hours'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let retry = call_service(&app, post("/patients", "a", "Petr").to_request()).await;
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert!(retry.headers().get(IDEMPOTENCY_REPLAYED).is_none());
+        assert_eq!(calls.count.load(Ordering::SeqCst), 2);
+    }
+
+    #[sqlx::test]
+    async fn multipart_boundaries_do_not_change_the_hash(pool: PgPool) {
+        let calls = web::Data::new(Calls::default());
+        let app = init_service(app(pool, calls.clone())).await;
+        let upload = |boundary: &str, csv: &str| {
+            TestRequest::post()
+                .uri("/patients/import.csv")
+                .insert_header((IDEMPOTENCY_KEY, "a"))
+                .insert_header((
+                    CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={}", boundary),
+                ))
+                .set_payload(format!(
+                    "--{0}\r\nContent-Disposition: form-data; name=\"file\"\r\n\r\n\
+                    {1}\r\n--{0}--\r\n",
+                    boundary, csv
+                ))
+                .to_request()
+        };
+
+        let first = call_service(&app, upload("first-boundary", "id\n1\n")).await;
+        assert_eq!(first.status(), StatusCode::ACCEPTED);
+        let retry = call_service(&app, upload("another-one", "id\n1\n")).await;
+        assert_eq!(retry.headers().get(IDEMPOTENCY_REPLAYED).unwrap(), "true");
+        let other = call_service(&app, upload("another-one", "id\n2\n")).await;
+        assert_eq!(other.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(calls.count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn resource_ids_are_read_from_numbers_and_fhir_strings() {
+        assert_eq!(resource_id(r#"{"id":12,"name":"Ivan"}"#), Some(12));
+        assert_eq!(
+            resource_id(r#"{"resourceType":"Patient","id":"12"}"#),
+            Some(12)
+        );
+        assert_eq!(resource_id("Invalid input"), None);
+    }
+}
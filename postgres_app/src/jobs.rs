@@ -0,0 +1,1371 @@
+use crate::anonymize::Pseudonymizer;
+use crate::crypto::FieldCipher;
+use crate::csv_transfer::{self, advance_id_sequence, database_error, CsvRow, CsvUpload};
+use crate::metrics::Metrics;
+use crate::models::{CsvImportOptions, Doctor, Job, Patient, ScheduleEntry, Ticket};
+use crate::ndjson::{self, NdjsonLines};
+use actix_web::web::{Bytes, BytesMut};
+use futures_util::future::BoxFuture;
+use futures_util::stream::{self, BoxStream};
+use futures_util::{FutureExt, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Acquire, PgConnection, PgExecutor, PgPool, Postgres, Transaction};
+use std::any::Any;
+use std::ops::Bound;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info_span, warn, Instrument};
+
+/// How often idle workers look for queued jobs.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A worker renews its lease after every batch; a job whose lease runs out is claimed again.
+const LEASE_SECONDS: f64 = 60.0;
+
+/// A job is claimed at most this many times. One whose lease runs out after that, because
+/// it keeps stopping its workers, is failed instead.
+const MAX_ATTEMPTS: i32 = 3;
+
+/// Records handled, and committed together with the checkpoint, at once.
+const BATCH_SIZE: i64 = 500;
+
+/// Record errors kept in `jobs.errors`. Further ones are only counted.
+const MAX_ERRORS: i64 = 100;
+
+/// Uploaded files are stored in `job_uploads` in chunks of this size.
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Copy)]
+pub enum Dataset {
+    Patients,
+    Doctors,
+    Tickets,
+    Schedule,
+}
+
+impl Dataset {
+    fn name(self) -> &'static str {
+        match self {
+            Dataset::Patients => "patients",
+            Dataset::Doctors => "doctors",
+            Dataset::Tickets => "tickets",
+            Dataset::Schedule => "schedule",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "patients" => Some(Dataset::Patients),
+            "doctors" => Some(Dataset::Doctors),
+            "tickets" => Some(Dataset::Tickets),
+            "schedule" => Some(Dataset::Schedule),
+            _ => None,
+        }
+    }
+
+    /// Prefix of the Sled keys, followed by the id.
+    fn sled_prefix(self) -> &'static str {
+        match self {
+            Dataset::Patients => "patient:",
+            Dataset::Doctors => "doctor:",
+            Dataset::Tickets => "ticket:",
+            Dataset::Schedule => "entry:",
+        }
+    }
+
+    /// Columns of CSV uploads besides the optional `id`.
+    pub fn csv_columns(self) -> &'static [&'static str] {
+        match self {
+            Dataset::Patients => csv_transfer::PATIENT_COLUMNS,
+            Dataset::Doctors => csv_transfer::DOCTOR_COLUMNS,
+            Dataset::Tickets => csv_transfer::TICKET_COLUMNS,
+            Dataset::Schedule => csv_transfer::SCHEDULE_COLUMNS,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum JobKind {
+    /// Copies a table into Sled. The checkpoint is the last id exported.
+    SledExport(Dataset),
+    /// Inserts the Sled records into a table. The checkpoint is the last Sled key imported.
+    SledImport(Dataset),
+    /// Applies an uploaded CSV file row by row, with the options it was uploaded with. The
+    /// checkpoint is the last row applied.
+    CsvImport(Dataset),
+    /// Restores an uploaded NDJSON backup in one transaction, so that it is imported
+    /// completely or not at all. A resumed job starts over.
+    NdjsonImport(Dataset),
+}
+
+impl JobKind {
+    pub fn name(self) -> String {
+        match self {
+            JobKind::SledExport(dataset) => format!("{}.export", dataset.name()),
+            JobKind::SledImport(dataset) => format!("{}.import", dataset.name()),
+            JobKind::CsvImport(dataset) => format!("{}.import.csv", dataset.name()),
+            JobKind::NdjsonImport(dataset) => format!("{}.import.ndjson", dataset.name()),
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name.split_once('.')? {
+            (dataset, "export") => Dataset::parse(dataset).map(JobKind::SledExport),
+            (dataset, "import") => Dataset::parse(dataset).map(JobKind::SledImport),
+            (dataset, "import.csv") => Dataset::parse(dataset).map(JobKind::CsvImport),
+            (dataset, "import.ndjson") => Dataset::parse(dataset).map(JobKind::NdjsonImport),
+            _ => None,
+        }
+    }
+}
+
+/// Options of Sled imports and exports, as given to the endpoint that queued the job.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SledJobParams {
+    #[serde(default)]
+    pub anonymized: bool,
+    #[serde(default)]
+    pub decrypted: bool,
+}
+
+pub struct JobSettings {
+    /// Jobs run at once by this instance.
+    pub workers: usize,
+}
+
+/// What jobs need besides the database. Sled databases are local to an instance, so with
+/// several instances a Sled job reads or writes the snapshot of whichever instance claims it.
+#[derive(Clone)]
+pub struct JobContext {
+    pub pool: PgPool,
+    pub cipher: Arc<FieldCipher>,
+    pub pseudonymizer: Arc<Pseudonymizer>,
+    pub sled_db: sled::Db,
+    pub anonymized_sled: sled::Db,
+    pub metrics: Arc<Metrics>,
+}
+
+async fn insert(
+    executor: impl PgExecutor<'_>,
+    kind: JobKind,
+    params: &impl Serialize,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        "INSERT INTO jobs (kind, params) VALUES ($1, $2) RETURNING id",
+        kind.name(),
+        json!(params),
+    )
+    .fetch_one(executor)
+    .await
+}
+
+pub async fn enqueue(
+    pool: &PgPool,
+    kind: JobKind,
+    params: &impl Serialize,
+) -> Result<Job, sqlx::Error> {
+    let id = insert(pool, kind, params).await?;
+
+    Ok(fetch(pool, id)
+        .await?
+        .expect("a job is visible right after it is queued"))
+}
+
+pub enum UploadError {
+    /// The upload could not be read.
+    Invalid(String),
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for UploadError {
+    fn from(error: sqlx::Error) -> Self {
+        UploadError::Database(error)
+    }
+}
+
+/// A job queued with an uploaded file. Workers see it once it is committed, after the whole
+/// file is stored.
+pub struct QueuedUpload {
+    transaction: Transaction<'static, Postgres>,
+    id: i64,
+}
+
+impl QueuedUpload {
+    pub async fn store<S>(
+        pool: &PgPool,
+        kind: JobKind,
+        params: &impl Serialize,
+        mut chunks: S,
+    ) -> Result<Self, UploadError>
+    where
+        S: Stream<Item = Result<Bytes, String>> + Unpin,
+    {
+        let mut transaction = pool.begin().await?;
+        let id = insert(&mut *transaction, kind, params).await?;
+
+        let mut buffer = BytesMut::new();
+        let mut position = 0;
+        loop {
+            let chunk = chunks
+                .next()
+                .await
+                .transpose()
+                .map_err(UploadError::Invalid)?;
+            let eof = chunk.is_none();
+            buffer.extend_from_slice(&chunk.unwrap_or_default());
+            if buffer.len() >= UPLOAD_CHUNK_SIZE || (eof && !buffer.is_empty()) {
+                sqlx::query!(
+                    "INSERT INTO job_uploads (job_id, position, data) VALUES ($1, $2, $3)",
+                    id,
+                    position,
+                    &buffer.split()[..],
+                )
+                .execute(&mut *transaction)
+                .await?;
+                position += 1;
+            }
+            if eof {
+                return Ok(QueuedUpload { transaction, id });
+            }
+        }
+    }
+
+    /// The stored file, to check it before the job is queued.
+    pub fn chunks(&mut self) -> BoxStream<'_, Result<Bytes, String>> {
+        let id = self.id;
+        Box::pin(stream::try_unfold(
+            (&mut *self.transaction, 0),
+            move |(connection, position)| async move {
+                let chunk = upload_chunk(&mut *connection, id, position).await?;
+                Ok(chunk.map(|chunk| (chunk, (connection, position + 1))))
+            },
+        ))
+    }
+
+    pub async fn commit(self, pool: &PgPool) -> Result<Job, sqlx::Error> {
+        self.transaction.commit().await?;
+
+        Ok(fetch(pool, self.id)
+            .await?
+            .expect("a job is visible right after it is queued"))
+    }
+}
+
+/// The file uploaded for a job, read a chunk at a time.
+fn stored_upload(pool: &PgPool, id: i64) -> BoxStream<'_, Result<Bytes, String>> {
+    Box::pin(stream::try_unfold(0, move |position| async move {
+        let chunk = upload_chunk(pool, id, position).await?;
+        Ok(chunk.map(|chunk| (chunk, position + 1)))
+    }))
+}
+
+async fn upload_chunk(
+    executor: impl PgExecutor<'_>,
+    id: i64,
+    position: i32,
+) -> Result<Option<Bytes>, String> {
+    sqlx::query_scalar!(
+        "SELECT data FROM job_uploads WHERE job_id = $1 AND position = $2",
+        id,
+        position,
+    )
+    .fetch_optional(executor)
+    .await
+    .map(|chunk| chunk.map(Bytes::from))
+    .map_err(|e| format!("Failed to read the upload: {}", e))
+}
+
+pub async fn fetch(pool: &PgPool, id: i64) -> Result<Option<Job>, sqlx::Error> {
+    sqlx::query_as!(
+        Job,
+        r#"SELECT id, kind, params, status, cancel_requested, processed, failed, total, result,
+            errors, error, attempts, created_at::TEXT as "created_at!", started_at::TEXT as started_at,
+            finished_at::TEXT as finished_at
+        FROM jobs
+        WHERE id = $1"#,
+        id,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub enum CancelError {
+    NotFound,
+    /// The job already succeeded, failed or was cancelled.
+    Finished(Box<Job>),
+    Database(sqlx::Error),
+}
+
+/// Queued jobs are cancelled at once. Running ones stop after their current batch; what earlier
+/// batches imported or exported stays.
+pub async fn cancel(pool: &PgPool, id: i64) -> Result<Job, CancelError> {
+    let cancelled = sqlx::query_scalar!(
+        "UPDATE jobs
+        SET cancel_requested = TRUE,
+            status = CASE WHEN status = 'queued' THEN 'cancelled' ELSE status END,
+            finished_at = CASE WHEN status = 'queued' THEN now() ELSE finished_at END
+        WHERE id = $1 AND status IN ('queued', 'running')
+        RETURNING id",
+        id,
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(CancelError::Database)?;
+
+    match fetch(pool, id).await.map_err(CancelError::Database)? {
+        Some(job) if cancelled.is_some() => Ok(job),
+        Some(job) => Err(CancelError::Finished(Box::new(job))),
+        None => Err(CancelError::NotFound),
+    }
+}
+
+/// Runs `settings.workers` workers until the process exits.
+pub async fn run(context: JobContext, settings: JobSettings) {
+    for _ in 0..settings.workers {
+        tokio::spawn(work(context.clone()));
+    }
+}
+
+async fn work(context: JobContext) {
+    loop {
+        match claim(&context.pool).await {
+            Ok(Some(job)) => {
+                let started = Instant::now();
+                // A panicking job fails like one that returned an error, and the worker goes on.
+                let result = AssertUnwindSafe(execute(&context, &job))
+                    .catch_unwind()
+                    .instrument(info_span!("job", job_id = job.id, kind = %job.kind))
+                    .await
+                    .unwrap_or_else(|panic| {
+                        Err(format!("The job panicked: {}", panic_message(&*panic)))
+                    });
+                context
+                    .metrics
+                    .job_finished(&job.kind, result.is_ok(), started.elapsed());
+                if let Err(e) = result {
+                    error!(job_id = job.id, kind = %job.kind, error = %e, "Job failed");
+                    let finished = sqlx::query!(
+                        "UPDATE jobs
+                        SET status = 'failed', error = $2, locked_until = NULL, finished_at = now()
+                        WHERE id = $1 AND attempts = $3 AND status = 'running'",
+                        job.id,
+                        e,
+                        job.attempts,
+                    )
+                    .execute(&context.pool)
+                    .await;
+                    if let Err(e) = finished {
+                        error!(job_id = job.id, error = %e, "Could not mark the job as failed");
+                    }
+                }
+            }
+            Ok(None) => tokio::time::sleep(POLL_INTERVAL).await,
+            Err(e) => {
+                error!(error = %e, "Could not claim a job");
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// The message `panic!`, `unwrap` and `expect` put in the payload of a panic.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("no message")
+}
+
+struct ClaimedJob {
+    id: i64,
+    kind: String,
+    params: serde_json::Value,
+    processed: i64,
+    checkpoint: Option<String>,
+    /// Tells this claim of the job from later ones. Updates of the job check it along with
+    /// the lease, so a worker whose job was claimed again cannot overwrite its progress.
+    attempts: i32,
+}
+
+async fn claim(pool: &PgPool) -> Result<Option<ClaimedJob>, sqlx::Error> {
+    let abandoned = sqlx::query_scalar!(
+        "UPDATE jobs
+        SET status = 'failed',
+            error = 'The job stopped responding ' || attempts || ' times',
+            locked_until = NULL,
+            finished_at = now()
+        WHERE status = 'running' AND locked_until < now() AND attempts >= $1
+        RETURNING id",
+        MAX_ATTEMPTS,
+    )
+    .fetch_all(pool)
+    .await?;
+    for id in abandoned {
+        error!(job_id = id, "Job failed, its lease ran out too many times");
+    }
+
+    sqlx::query_as!(
+        ClaimedJob,
+        "UPDATE jobs
+        SET status = 'running',
+            attempts = attempts + 1,
+            locked_until = now() + make_interval(secs => $1),
+            started_at = COALESCE(started_at, now())
+        WHERE id = (
+            SELECT id
+            FROM jobs
+            WHERE status = 'queued' OR (status = 'running' AND locked_until < now())
+            ORDER BY id
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, kind, params, processed, checkpoint, attempts",
+        LEASE_SECONDS,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Where a job stands after its last committed batch.
+struct Progress {
+    processed: i64,
+    checkpoint: Option<String>,
+}
+
+/// Whether a worker may go on with its job after saving its progress.
+#[derive(PartialEq)]
+enum Lease {
+    Held,
+    /// A cancellation was requested, the job is now cancelled.
+    Cancelled,
+    /// The lease ran out, or the job was claimed again. Nothing was saved.
+    Lost,
+}
+
+async fn execute(context: &JobContext, job: &ClaimedJob) -> Result<(), String> {
+    let kind =
+        JobKind::parse(&job.kind).ok_or_else(|| format!("Unknown job kind `{}`", job.kind))?;
+    let progress = Progress {
+        processed: job.processed,
+        checkpoint: job.checkpoint.clone(),
+    };
+
+    match kind {
+        JobKind::SledExport(dataset) => export_to_sled(context, job, dataset, progress).await,
+        JobKind::SledImport(dataset) => import_from_sled(context, job, dataset, progress).await,
+        JobKind::CsvImport(dataset) => import_csv(context, job, dataset, progress).await,
+        JobKind::NdjsonImport(dataset) => {
+            let cipher = &context.cipher;
+            match dataset {
+                Dataset::Patients => {
+                    import_ndjson(context, job, dataset, |connection, batch| {
+                        let cipher = cipher.clone();
+                        Box::pin(async move {
+                            ndjson::insert_patients(connection, &cipher, batch).await
+                        })
+                    })
+                    .await
+                }
+                Dataset::Doctors => {
+                    import_ndjson(context, job, dataset, |connection, batch| {
+                        let cipher = cipher.clone();
+                        Box::pin(
+                            async move { ndjson::insert_doctors(connection, &cipher, batch).await },
+                        )
+                    })
+                    .await
+                }
+                Dataset::Tickets => {
+                    import_ndjson(context, job, dataset, |connection, batch| {
+                        Box::pin(ndjson::insert_tickets(connection, batch))
+                    })
+                    .await
+                }
+                Dataset::Schedule => {
+                    import_ndjson(context, job, dataset, |connection, batch| {
+                        Box::pin(ndjson::insert_schedule_entries(connection, batch))
+                    })
+                    .await
+                }
+            }
+        }
+    }
+}
+
+fn sled_params(
+    context: &JobContext,
+    job: &ClaimedJob,
+) -> Result<(SledJobParams, sled::Db), String> {
+    let params: SledJobParams =
+        serde_json::from_value(job.params.clone()).map_err(|e| e.to_string())?;
+    let sled_db = if params.anonymized {
+        context.anonymized_sled.clone()
+    } else {
+        context.sled_db.clone()
+    };
+    Ok((params, sled_db))
+}
+
+async fn export_to_sled(
+    context: &JobContext,
+    job: &ClaimedJob,
+    dataset: Dataset,
+    mut progress: Progress,
+) -> Result<(), String> {
+    let (params, sled_db) = sled_params(context, job)?;
+    let total = count_rows(&context.pool, dataset)
+        .await
+        .map_err(database_error)?;
+    if !set_total(&context.pool, job, total)
+        .await
+        .map_err(database_error)?
+    {
+        return Ok(());
+    }
+    loop {
+        let after = progress
+            .checkpoint
+            .as_deref()
+            .and_then(|id| id.parse().ok())
+            .unwrap_or(i32::MIN);
+        let records = export_records(context, dataset, &params, after)
+            .await
+            .map_err(database_error)?;
+        let Some(&(last_id, _, _)) = records.last() else {
+            break;
+        };
+        for (_, key, value) in &records {
+            sled_db
+                .insert(key, value.as_slice())
+                .map_err(|e| e.to_string())?;
+        }
+        sled_db.flush_async().await.map_err(|e| e.to_string())?;
+        context.metrics.job_records(&job.kind, records.len(), 0);
+
+        progress.processed += records.len() as i64;
+        progress.checkpoint = Some(last_id.to_string());
+        let mut connection = context.pool.acquire().await.map_err(database_error)?;
+        if save_progress(&mut connection, job, &progress, &[])
+            .await
+            .map_err(database_error)?
+            != Lease::Held
+        {
+            return Ok(());
+        }
+    }
+    finish(
+        &context.pool,
+        job,
+        json!({ "exported": progress.processed }),
+    )
+    .await
+    .map_err(database_error)?;
+    Ok(())
+}
+
+async fn import_from_sled(
+    context: &JobContext,
+    job: &ClaimedJob,
+    dataset: Dataset,
+    mut progress: Progress,
+) -> Result<(), String> {
+    let (_, sled_db) = sled_params(context, job)?;
+    let total = sled_db.scan_prefix(dataset.sled_prefix()).count() as i64;
+    if !set_total(&context.pool, job, total)
+        .await
+        .map_err(database_error)?
+    {
+        return Ok(());
+    }
+    loop {
+        let records = sled_records(&sled_db, dataset, progress.checkpoint.as_deref())?;
+        let Some((last_key, _)) = records.last() else {
+            break;
+        };
+        progress.checkpoint = Some(last_key.clone());
+        progress.processed += records.len() as i64;
+
+        let mut transaction = context.pool.begin().await.map_err(database_error)?;
+        let mut errors = Vec::new();
+        for (key, value) in &records {
+            let mut savepoint = transaction.begin().await.map_err(database_error)?;
+            match import_record(&mut savepoint, &context.cipher, dataset, value).await {
+                Ok(()) => savepoint.commit().await.map_err(database_error)?,
+                Err(message) => {
+                    savepoint.rollback().await.map_err(database_error)?;
+                    errors.push(json!({ "key": key, "message": message }));
+                }
+            }
+        }
+        advance_id_sequence(&mut transaction, dataset.name())
+            .await
+            .map_err(database_error)?;
+        let lease = commit_batch(transaction, job, &progress, &errors)
+            .await
+            .map_err(database_error)?;
+        if lease == Lease::Lost {
+            return Ok(());
+        }
+        context
+            .metrics
+            .job_records(&job.kind, records.len() - errors.len(), errors.len());
+        if lease == Lease::Cancelled {
+            return Ok(());
+        }
+    }
+    let failed = failed_records(&context.pool, job.id)
+        .await
+        .map_err(database_error)?;
+    finish(
+        &context.pool,
+        job,
+        json!({ "imported": progress.processed - failed, "failed": failed }),
+    )
+    .await
+    .map_err(database_error)?;
+    Ok(())
+}
+
+/// Rows are applied in batches, each in a transaction with a savepoint per row, so a row that
+/// fails validation or a constraint is reported and skipped without affecting the others. A
+/// dry run rolls every batch back, which still checks every row against the database.
+async fn import_csv(
+    context: &JobContext,
+    job: &ClaimedJob,
+    dataset: Dataset,
+    mut progress: Progress,
+) -> Result<(), String> {
+    let options: CsvImportOptions =
+        serde_json::from_value(job.params.clone()).map_err(|e| e.to_string())?;
+    let dry_run = options.dry_run.unwrap_or(false);
+    let mut upload = CsvUpload::open(
+        stored_upload(&context.pool, job.id),
+        dataset.csv_columns(),
+        &options,
+    )
+    .await?;
+    // Rows up to the checkpoint were applied before the job was resumed.
+    let applied: u64 = progress
+        .checkpoint
+        .as_deref()
+        .and_then(|row| row.parse().ok())
+        .unwrap_or(0);
+
+    loop {
+        let mut transaction = context.pool.begin().await.map_err(database_error)?;
+        let mut rows = 0;
+        let mut errors = Vec::new();
+        while rows < BATCH_SIZE {
+            let Some(row) = upload.next_row().await? else {
+                break;
+            };
+            let number = match &row {
+                Ok(row) => row.row,
+                Err(error) => error.row,
+            };
+            if number <= applied {
+                continue;
+            }
+            rows += 1;
+            progress.checkpoint = Some(number.to_string());
+
+            let row = match row {
+                Ok(row) => row,
+                Err(error) => {
+                    errors.push(json!(error));
+                    continue;
+                }
+            };
+            let mut savepoint = transaction.begin().await.map_err(database_error)?;
+            match import_csv_row(&mut savepoint, &context.cipher, dataset, row).await {
+                Ok(()) => savepoint.commit().await.map_err(database_error)?,
+                Err(message) => {
+                    savepoint.rollback().await.map_err(database_error)?;
+                    errors.push(json!({ "row": number, "message": message }));
+                }
+            }
+        }
+        if rows == 0 {
+            break;
+        }
+        progress.processed += rows;
+
+        let lease = if dry_run {
+            transaction.rollback().await.map_err(database_error)?;
+            let mut connection = context.pool.acquire().await.map_err(database_error)?;
+            save_progress(&mut connection, job, &progress, &errors).await
+        } else {
+            advance_id_sequence(&mut transaction, dataset.name())
+                .await
+                .map_err(database_error)?;
+            commit_batch(transaction, job, &progress, &errors).await
+        }
+        .map_err(database_error)?;
+        if lease == Lease::Lost {
+            return Ok(());
+        }
+        context
+            .metrics
+            .job_records(&job.kind, rows as usize - errors.len(), errors.len());
+        if lease == Lease::Cancelled {
+            return Ok(());
+        }
+    }
+
+    let failed = failed_records(&context.pool, job.id)
+        .await
+        .map_err(database_error)?;
+    finish(
+        &context.pool,
+        job,
+        json!({
+            "dry_run": dry_run,
+            "rows": progress.processed,
+            "imported": progress.processed - failed,
+            "failed": failed,
+            "ignored_columns": upload.ignored_columns,
+        }),
+    )
+    .await
+    .map_err(database_error)?;
+    Ok(())
+}
+
+async fn import_csv_row(
+    connection: &mut PgConnection,
+    cipher: &FieldCipher,
+    dataset: Dataset,
+    row: CsvRow,
+) -> Result<(), String> {
+    match dataset {
+        Dataset::Patients => csv_transfer::import_patient_row(connection, cipher, row).await,
+        Dataset::Doctors => csv_transfer::import_doctor_row(connection, cipher, row).await,
+        Dataset::Tickets => csv_transfer::import_ticket_row(connection, row).await,
+        Dataset::Schedule => csv_transfer::import_schedule_row(connection, row).await,
+    }
+}
+
+/// Inserts every line of the upload in batches, all in one transaction. Progress is saved
+/// beside the transaction, and the job is finished in it, so that a worker that lost the job
+/// commits nothing. Existing ids are overwritten.
+async fn import_ndjson<T, F>(
+    context: &JobContext,
+    job: &ClaimedJob,
+    dataset: Dataset,
+    mut insert_batch: F,
+) -> Result<(), String>
+where
+    T: DeserializeOwned + Send,
+    F: for<'c> FnMut(&'c mut PgConnection, Vec<T>) -> BoxFuture<'c, Result<u64, sqlx::Error>>,
+{
+    let mut lines = NdjsonLines::new(stored_upload(&context.pool, job.id));
+    let mut transaction = context.pool.begin().await.map_err(database_error)?;
+    let mut progress = Progress {
+        processed: 0,
+        checkpoint: None,
+    };
+    let mut batch = Vec::with_capacity(ndjson::BATCH_SIZE);
+
+    loop {
+        let record = lines.next::<T>().await?;
+        let eof = record.is_none();
+        batch.extend(record);
+        if batch.len() == ndjson::BATCH_SIZE || (eof && !batch.is_empty()) {
+            progress.processed += insert_batch(&mut transaction, std::mem::take(&mut batch))
+                .await
+                .map_err(database_error)? as i64;
+            let mut connection = context.pool.acquire().await.map_err(database_error)?;
+            if save_progress(&mut connection, job, &progress, &[])
+                .await
+                .map_err(database_error)?
+                != Lease::Held
+            {
+                return Ok(());
+            }
+        }
+        if eof {
+            break;
+        }
+    }
+
+    advance_id_sequence(&mut transaction, dataset.name())
+        .await
+        .map_err(database_error)?;
+    let finished = finish(
+        &mut *transaction,
+        job,
+        json!({ "imported": progress.processed }),
+    )
+    .await
+    .map_err(database_error)?;
+    if finished {
+        transaction.commit().await.map_err(database_error)?;
+    }
+    Ok(())
+}
+
+async fn count_rows(pool: &PgPool, dataset: Dataset) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", dataset.name()))
+        .fetch_one(pool)
+        .await
+}
+
+async fn failed_records(pool: &PgPool, id: i64) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!("SELECT failed FROM jobs WHERE id = $1", id)
+        .fetch_one(pool)
+        .await
+}
+
+/// Returns `false` when the lease was lost.
+async fn set_total(pool: &PgPool, job: &ClaimedJob, total: i64) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query!(
+        "UPDATE jobs SET total = $3
+        WHERE id = $1 AND attempts = $2 AND status = 'running' AND locked_until > now()",
+        job.id,
+        job.attempts,
+        total,
+    )
+    .execute(pool)
+    .await?;
+    let held = updated.rows_affected() > 0;
+    if !held {
+        lost(job);
+    }
+    Ok(held)
+}
+
+/// Records the batch and renews the lease. Marks the job cancelled when a cancellation was
+/// requested meanwhile.
+async fn save_progress(
+    connection: &mut PgConnection,
+    job: &ClaimedJob,
+    progress: &Progress,
+    errors: &[serde_json::Value],
+) -> Result<Lease, sqlx::Error> {
+    let cancel_requested = sqlx::query_scalar!(
+        "UPDATE jobs
+        SET processed = $3,
+            checkpoint = $4,
+            failed = failed + $5,
+            errors = (
+                SELECT COALESCE(jsonb_agg(element.value ORDER BY element.position), '[]')
+                FROM jsonb_array_elements(errors || $6) WITH ORDINALITY element (value, position)
+                WHERE element.position <= $7
+            ),
+            locked_until = now() + make_interval(secs => $8),
+            status = CASE WHEN cancel_requested THEN 'cancelled' ELSE status END,
+            finished_at = CASE WHEN cancel_requested THEN now() ELSE finished_at END
+        WHERE id = $1 AND attempts = $2 AND status = 'running' AND locked_until > now()
+        RETURNING cancel_requested",
+        job.id,
+        job.attempts,
+        progress.processed,
+        progress.checkpoint,
+        errors.len() as i64,
+        json!(errors),
+        MAX_ERRORS,
+        LEASE_SECONDS,
+    )
+    .fetch_optional(&mut *connection)
+    .await?;
+
+    Ok(match cancel_requested {
+        Some(false) => Lease::Held,
+        Some(true) => Lease::Cancelled,
+        None => {
+            lost(job);
+            Lease::Lost
+        }
+    })
+}
+
+/// Saves the progress in the transaction of the batch and commits both, unless the lease was
+/// lost, in which case the batch is rolled back.
+async fn commit_batch(
+    mut transaction: Transaction<'_, Postgres>,
+    job: &ClaimedJob,
+    progress: &Progress,
+    errors: &[serde_json::Value],
+) -> Result<Lease, sqlx::Error> {
+    let lease = save_progress(&mut transaction, job, progress, errors).await?;
+    if lease != Lease::Lost {
+        transaction.commit().await?;
+    }
+    Ok(lease)
+}
+
+/// Returns `false` when the lease was lost.
+async fn finish(
+    executor: impl PgExecutor<'_>,
+    job: &ClaimedJob,
+    result: serde_json::Value,
+) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query!(
+        "UPDATE jobs
+        SET status = 'succeeded', result = $3, locked_until = NULL, finished_at = now()
+        WHERE id = $1 AND attempts = $2 AND status = 'running' AND locked_until > now()",
+        job.id,
+        job.attempts,
+        result,
+    )
+    .execute(executor)
+    .await?;
+    let held = updated.rows_affected() > 0;
+    if !held {
+        lost(job);
+    }
+    Ok(held)
+}
+
+fn lost(job: &ClaimedJob) {
+    warn!(
+        job_id = job.id,
+        attempt = job.attempts,
+        "Stopped the job, its lease ran out or it was claimed again"
+    );
+}
+
+/// The next batch of rows after `after`, as `(id, Sled key, JSON)`.
+async fn export_records(
+    context: &JobContext,
+    dataset: Dataset,
+    params: &SledJobParams,
+    after: i32,
+) -> Result<Vec<(i32, String, Vec<u8>)>, sqlx::Error> {
+    let pool = &context.pool;
+    let cipher = &context.cipher;
+
+    let records = match dataset {
+        Dataset::Patients => sqlx::query_as!(
+            Patient,
+            "SELECT id, name, surname, birth_date, phone_number, passport_number
+            FROM patients
+            WHERE id > $1
+            ORDER BY id
+            LIMIT $2",
+            after,
+            BATCH_SIZE,
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|patient| {
+            let patient = if params.anonymized {
+                context.pseudonymizer.anonymize_patient(&cipher.decrypt_patient(patient))
+            } else if params.decrypted {
+                cipher.decrypt_patient(patient)
+            } else {
+                patient
+            };
+            sled_record(dataset, patient.id, &patient)
+        })
+        .collect(),
+        Dataset::Doctors => sqlx::query_as!(
+            Doctor,
+            "SELECT id, name, surname, speciality, phone_number, passport_number
+            FROM doctors
+            WHERE id > $1
+            ORDER BY id
+            LIMIT $2",
+            after,
+            BATCH_SIZE,
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|doctor| {
+            let doctor = if params.anonymized {
+                context.pseudonymizer.anonymize_doctor(&cipher.decrypt_doctor(doctor))
+            } else if params.decrypted {
+                cipher.decrypt_doctor(doctor)
+            } else {
+                doctor
+            };
+            sled_record(dataset, doctor.id, &doctor)
+        })
+        .collect(),
+        Dataset::Tickets => sqlx::query_as!(
+            Ticket,
+            "SELECT id, date, time, office_number FROM tickets WHERE id > $1 ORDER BY id LIMIT $2",
+            after,
+            BATCH_SIZE,
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|ticket| sled_record(dataset, ticket.id, &ticket))
+        .collect(),
+        Dataset::Schedule => sqlx::query_as!(
+            ScheduleEntry,
+            "SELECT id, ticket_id, doctor_id, patient_id FROM schedule WHERE id > $1 ORDER BY id LIMIT $2",
+            after,
+            BATCH_SIZE,
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|entry| sled_record(dataset, entry.id, &entry))
+        .collect(),
+    };
+    Ok(records)
+}
+
+fn sled_record<T: Serialize>(dataset: Dataset, id: i32, value: &T) -> (i32, String, Vec<u8>) {
+    let value = serde_json::to_vec(value).expect("records serialize to JSON");
+    (id, format!("{}{}", dataset.sled_prefix(), id), value)
+}
+
+/// The next batch of Sled records of the dataset after the key `after`.
+fn sled_records(
+    sled_db: &sled::Db,
+    dataset: Dataset,
+    after: Option<&str>,
+) -> Result<Vec<(String, sled::IVec)>, String> {
+    let prefix = dataset.sled_prefix();
+    let start = match after {
+        Some(key) => Bound::Excluded(key.as_bytes().to_vec()),
+        None => Bound::Included(prefix.as_bytes().to_vec()),
+    };
+    sled_db
+        .range((start, Bound::Unbounded))
+        .take_while(|item| {
+            item.as_ref()
+                .map_or(true, |(key, _)| key.starts_with(prefix.as_bytes()))
+        })
+        .take(BATCH_SIZE as usize)
+        .map(|item| {
+            let (key, value) = item.map_err(|e| e.to_string())?;
+            Ok((String::from_utf8_lossy(&key).into_owned(), value))
+        })
+        .collect()
+}
+
+async fn import_record(
+    connection: &mut PgConnection,
+    cipher: &FieldCipher,
+    dataset: Dataset,
+    value: &[u8],
+) -> Result<(), String> {
+    let invalid = |e: serde_json::Error| format!("Invalid record: {}", e);
+    let result = match dataset {
+        Dataset::Patients => {
+            let patient = cipher.decrypt_patient(serde_json::from_slice(value).map_err(invalid)?);
+            sqlx::query!(
+                "INSERT INTO patients (id, name, surname, birth_date, phone_number, passport_number, phone_number_index, passport_number_index)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                patient.id,
+                patient.name,
+                patient.surname,
+                patient.birth_date,
+                cipher.encrypt(&patient.phone_number),
+                cipher.encrypt(&patient.passport_number),
+                cipher.blind_index(&patient.phone_number),
+                cipher.blind_index(&patient.passport_number),
+            )
+            .execute(connection)
+            .await
+        }
+        Dataset::Doctors => {
+            let doctor = cipher.decrypt_doctor(serde_json::from_slice(value).map_err(invalid)?);
+            sqlx::query!(
+                "INSERT INTO doctors (id, name, surname, speciality, phone_number, passport_number, phone_number_index, passport_number_index)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                doctor.id,
+                doctor.name,
+                doctor.surname,
+                doctor.speciality,
+                cipher.encrypt(&doctor.phone_number),
+                cipher.encrypt(&doctor.passport_number),
+                cipher.blind_index(&doctor.phone_number),
+                cipher.blind_index(&doctor.passport_number),
+            )
+            .execute(connection)
+            .await
+        }
+        Dataset::Tickets => {
+            let ticket: Ticket = serde_json::from_slice(value).map_err(invalid)?;
+            sqlx::query!(
+                "INSERT INTO tickets (id, date, time, office_number) VALUES ($1, $2, $3, $4)",
+                ticket.id,
+                ticket.date,
+                ticket.time,
+                ticket.office_number,
+            )
+            .execute(connection)
+            .await
+        }
+        Dataset::Schedule => {
+            let entry: ScheduleEntry = serde_json::from_slice(value).map_err(invalid)?;
+            sqlx::query!(
+                "INSERT INTO schedule (id, ticket_id, doctor_id, patient_id) VALUES ($1, $2, $3, $4)",
+                entry.id,
+                entry.ticket_id,
+                entry.doctor_id,
+                entry.patient_id,
+            )
+            .execute(connection)
+            .await
+        }
+    };
+    result.map(|_| ()).map_err(database_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PiiConfig;
+
+    fn context(pool: PgPool) -> JobContext {
+        let temporary = || sled::Config::new().temporary(true).open().unwrap();
+        JobContext {
+            pool,
+            cipher: Arc::new(FieldCipher::new(&PiiConfig::default()).unwrap()),
+            pseudonymizer: Arc::new(Pseudonymizer::new(None)),
+            sled_db: temporary(),
+            anonymized_sled: temporary(),
+            metrics: Arc::new(Metrics::new()),
+        }
+    }
+
+    async fn queue_upload(pool: &PgPool, kind: JobKind, params: serde_json::Value, file: &str) {
+        let chunks = stream::iter(
+            file.as_bytes()
+                .chunks(7)
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect::<Vec<_>>(),
+        );
+        QueuedUpload::store(pool, kind, &params, chunks)
+            .await
+            .ok()
+            .unwrap()
+            .commit(pool)
+            .await
+            .unwrap();
+    }
+
+    /// Claims the next job and runs it like a worker would, without the retries.
+    async fn run_next(context: &JobContext) -> Job {
+        let job = claim(&context.pool).await.unwrap().unwrap();
+        if let Err(e) = execute(context, &job).await {
+            sqlx::query!(
+                "UPDATE jobs SET status = 'failed', error = $2 WHERE id = $1",
+                job.id,
+                e,
+            )
+            .execute(&context.pool)
+            .await
+            .unwrap();
+        }
+        fetch(&context.pool, job.id).await.unwrap().unwrap()
+    }
+
+    async fn expire_leases(pool: &PgPool) {
+        sqlx::query!("UPDATE jobs SET locked_until = now() - interval '1 second'")
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn kinds_round_trip_through_their_names() {
+        for kind in [
+            JobKind::SledExport(Dataset::Patients),
+            JobKind::SledImport(Dataset::Doctors),
+            JobKind::CsvImport(Dataset::Tickets),
+            JobKind::NdjsonImport(Dataset::Schedule),
+        ] {
+            assert_eq!(JobKind::parse(&kind.name()).unwrap().name(), kind.name());
+        }
+        assert!(JobKind::parse("patients.import.xml").is_none());
+    }
+
+    #[test]
+    fn panic_message_reads_both_payload_types() {
+        let payload = std::panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "static");
+        let payload = std::panic::catch_unwind(|| panic!("row {}", 3)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "row 3");
+    }
+
+    #[sqlx::test]
+    async fn csv_import_reports_rows_and_skips_invalid_ones(pool: PgPool) {
+        let context = context(pool);
+        queue_upload(
+            &context.pool,
+            JobKind::CsvImport(Dataset::Tickets),
+            json!({ "delimiter": ";" }),
+            "date;time;office;note\n2026-01-05;09:00;12;a\n2026-01-05;09:30;twelve;b\n2026-01-06;10:00;14;c\n",
+        )
+        .await;
+
+        let job = run_next(&context).await;
+        assert_eq!(job.status, "succeeded");
+        assert_eq!(
+            job.result.unwrap(),
+            json!({
+                "dry_run": false,
+                "rows": 3,
+                "imported": 2,
+                "failed": 1,
+                "ignored_columns": ["note"],
+            })
+        );
+        assert_eq!(
+            job.errors,
+            json!([{ "row": 3, "message": "`office_number` must be an integer, got `twelve`" }])
+        );
+        let tickets = sqlx::query_scalar!("SELECT COUNT(*) FROM tickets")
+            .fetch_one(&context.pool)
+            .await
+            .unwrap();
+        assert_eq!(tickets, Some(2));
+        let uploads = sqlx::query_scalar!("SELECT COUNT(*) FROM job_uploads")
+            .fetch_one(&context.pool)
+            .await
+            .unwrap();
+        assert_eq!(uploads, Some(0));
+    }
+
+    #[sqlx::test]
+    async fn csv_dry_run_imports_nothing(pool: PgPool) {
+        let context = context(pool);
+        queue_upload(
+            &context.pool,
+            JobKind::CsvImport(Dataset::Tickets),
+            json!({ "dry_run": true }),
+            "date,time,office_number\n2026-01-05,09:00,12\n",
+        )
+        .await;
+
+        let job = run_next(&context).await;
+        assert_eq!(job.status, "succeeded");
+        assert_eq!(job.result.unwrap()["imported"], 1);
+        let tickets = sqlx::query_scalar!("SELECT COUNT(*) FROM tickets")
+            .fetch_one(&context.pool)
+            .await
+            .unwrap();
+        assert_eq!(tickets, Some(0));
+    }
+
+    #[sqlx::test]
+    async fn ndjson_import_is_all_or_nothing(pool: PgPool) {
+        let context = context(pool);
+        let line = |id: i32| {
+            format!(
+                r#"{{"id":{},"date":"2026-01-05","time":"09:00","office_number":12}}"#,
+                id
+            )
+        };
+        queue_upload(
+            &context.pool,
+            JobKind::NdjsonImport(Dataset::Tickets),
+            json!({}),
+            &format!("{}\n\n{}\n{{\"id\":3}}\n", line(1), line(2)),
+        )
+        .await;
+        queue_upload(
+            &context.pool,
+            JobKind::NdjsonImport(Dataset::Tickets),
+            json!({}),
+            &format!("{}\n{}", line(1), line(2)),
+        )
+        .await;
+
+        let job = run_next(&context).await;
+        assert_eq!(job.status, "failed");
+        assert!(job.error.unwrap().starts_with("Line 4 is invalid"));
+        let tickets = sqlx::query_scalar!("SELECT COUNT(*) FROM tickets")
+            .fetch_one(&context.pool)
+            .await
+            .unwrap();
+        assert_eq!(tickets, Some(0));
+
+        let job = run_next(&context).await;
+        assert_eq!(job.status, "succeeded");
+        assert_eq!(job.result.unwrap(), json!({ "imported": 2 }));
+    }
+
+    #[sqlx::test]
+    async fn a_reclaimed_job_fences_off_its_previous_worker(pool: PgPool) {
+        let job = enqueue(&pool, JobKind::SledExport(Dataset::Tickets), &json!({}))
+            .await
+            .unwrap();
+        let first = claim(&pool).await.unwrap().unwrap();
+        expire_leases(&pool).await;
+        let second = claim(&pool).await.unwrap().unwrap();
+        assert_eq!((first.id, second.id), (job.id, job.id));
+        assert_eq!((first.attempts, second.attempts), (1, 2));
+
+        let progress = Progress {
+            processed: 10,
+            checkpoint: Some("10".to_string()),
+        };
+        let mut connection = pool.acquire().await.unwrap();
+        assert!(
+            save_progress(&mut connection, &first, &progress, &[])
+                .await
+                .unwrap()
+                == Lease::Lost
+        );
+        assert!(!set_total(&pool, &first, 10).await.unwrap());
+        assert!(!finish(&pool, &first, json!({})).await.unwrap());
+        assert!(
+            save_progress(&mut connection, &second, &progress, &[])
+                .await
+                .unwrap()
+                == Lease::Held
+        );
+
+        // A worker whose lease ran out stops even when nobody claimed the job since.
+        expire_leases(&pool).await;
+        assert!(!finish(&pool, &second, json!({})).await.unwrap());
+        let job = fetch(&pool, job.id).await.unwrap().unwrap();
+        assert_eq!((job.status.as_str(), job.processed), ("running", 10));
+    }
+
+    #[sqlx::test]
+    async fn a_job_that_keeps_stopping_fails(pool: PgPool) {
+        let job = enqueue(&pool, JobKind::SledExport(Dataset::Tickets), &json!({}))
+            .await
+            .unwrap();
+        for _ in 0..MAX_ATTEMPTS {
+            assert!(claim(&pool).await.unwrap().is_some());
+            expire_leases(&pool).await;
+        }
+
+        assert!(claim(&pool).await.unwrap().is_none());
+        let job = fetch(&pool, job.id).await.unwrap().unwrap();
+        assert_eq!(job.status, "failed");
+        assert_eq!(
+            job.error.as_deref(),
+            Some("The job stopped responding 3 times")
+        );
+    }
+
+    #[sqlx::test]
+    async fn cancelling_stops_a_running_job(pool: PgPool) {
+        let job = enqueue(&pool, JobKind::SledExport(Dataset::Tickets), &json!({}))
+            .await
+            .unwrap();
+        let claimed = claim(&pool).await.unwrap().unwrap();
+        assert!(cancel(&pool, job.id).await.is_ok());
+
+        let progress = Progress {
+            processed: 0,
+            checkpoint: None,
+        };
+        let mut connection = pool.acquire().await.unwrap();
+        assert!(
+            save_progress(&mut connection, &claimed, &progress, &[])
+                .await
+                .unwrap()
+                == Lease::Cancelled
+        );
+        let job = fetch(&pool, job.id).await.unwrap().unwrap();
+        assert_eq!(job.status, "cancelled");
+    }
+}
@@ -1,66 +1,430 @@
+use crate::anonymize::{AnonymizedSled, Pseudonymizer};
 use crate::api::ApiDoc;
+use crate::config::ServerConfig;
+use crate::crypto::FieldCipher;
+use crate::grpc::HospitalService;
+use crate::health::Health;
+use crate::jobs::JobContext;
+use crate::metrics::Metrics;
+use crate::schedule_events::ScheduleEvents;
+use actix_web::middleware::{from_fn, Condition};
 use actix_web::{web, App, HttpServer};
 use dotenv::dotenv;
-use sled::Db;
+use futures_util::future;
 use sqlx::postgres::PgPoolOptions;
-use std::env;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tracing::{error, info, warn};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
+mod anonymize;
 mod api;
+mod calendar;
+mod concurrency;
+mod config;
+mod crypto;
+mod csv_transfer;
+mod doctors;
+mod duplicates;
+mod fhir;
+mod graphql;
+mod grpc;
 mod handlers;
+mod health;
+mod hl7;
+mod idempotency;
+mod jobs;
+mod metrics;
 mod models;
+mod ndjson;
+mod patients;
+mod pdf;
+mod privacy;
+mod reminders;
+mod reports;
+mod schedule;
+mod schedule_events;
+mod telemetry;
+mod tickets;
+mod tls;
+mod webhooks;
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
+async fn main() -> ExitCode {
     dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // Configuration errors come before logging is set up.
+            if tracing::dispatcher::has_been_set() {
+                error!("{}", e);
+            } else {
+                eprintln!("{}", e);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> Result<(), String> {
+    let config = ServerConfig::load().map_err(|e| format!("Invalid configuration:\n{}", e))?;
+    let metrics = Arc::new(Metrics::new());
+    let _telemetry = telemetry::init(&config.logging, metrics.clone())?;
+    let tls_config = config.tls.as_ref().map(tls::server_config).transpose()?;
 
-    let sled_db: Db = sled::open("hospital").expect("Не удалось открыть базу данных sled");
+    let open_sled = |path: &std::path::Path| {
+        sled::open(path).map_err(|e| {
+            format!(
+                "Could not open the Sled database `{}`: {}",
+                path.display(),
+                e
+            )
+        })
+    };
+    let sled_db = open_sled(&config.sled_path)?;
+    let anonymized_sled = web::Data::new(AnonymizedSled(open_sled(&config.anonymized_sled_path)?));
 
     let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
+        .max_connections(config.database.max_connections)
+        .min_connections(config.database.min_connections)
+        .acquire_timeout(config.database.acquire_timeout)
+        .idle_timeout(config.database.idle_timeout)
+        .connect(&config.database.url)
         .await
-        .expect("Could not connect to the database");
+        .map_err(|e| format!("Could not connect to the database: {}", e))?;
+
+    sqlx::migrate!()
+        .run(&pool)
+        .await
+        .map_err(|e| format!("Could not run database migrations: {}", e))?;
+
+    let cipher = FieldCipher::new(&config.pii)
+        .map_err(|e| format!("Invalid PII encryption settings: {}", e))?;
+    if !cipher.is_enabled() {
+        warn!("No PII encryption keys are set, phone and passport numbers are stored in plaintext");
+    }
+    let reencrypted = crypto::reencrypt_outdated(&pool, &cipher)
+        .await
+        .map_err(|e| format!("Could not re-encrypt phone and passport numbers: {}", e))?;
+    if reencrypted.patients_updated
+        + reencrypted.doctors_updated
+        + reencrypted.history_entries_updated
+        + reencrypted.stored_responses_updated
+        > 0
+    {
+        info!(
+            patients = reencrypted.patients_updated,
+            doctors = reencrypted.doctors_updated,
+            history_entries = reencrypted.history_entries_updated,
+            stored_responses = reencrypted.stored_responses_updated,
+            "Re-encrypted phone and passport numbers"
+        );
+    }
+    let cipher = web::Data::new(cipher);
 
     let openapi = ApiDoc::openapi();
+    let base_url = config.base_url();
+    let concurrency_settings = web::Data::new(config.concurrency);
+    let calendar_settings = web::Data::new(config.calendar);
+    if let Some(time_zone) = &calendar_settings.time_zone {
+        calendar::check_time_zone(&pool, time_zone)
+            .await
+            .map_err(|e| format!("Invalid calendar time zone `{}`: {}", time_zone, e))?;
+    }
+    let fhir_settings = web::Data::new(config.fhir);
+    let idempotency_settings = web::Data::new(config.idempotency);
+    tokio::spawn(idempotency::expire_keys(
+        pool.clone(),
+        idempotency_settings.ttl_seconds,
+    ));
+    if config.anonymization_key.is_none() {
+        warn!("No anonymization key is set, anonymized exports are consistent only until restart");
+    }
+    let pseudonymizer = web::Data::new(Pseudonymizer::new(config.anonymization_key.as_deref()));
+
+    let graphql_schema = web::Data::new(graphql::schema(
+        pool.clone(),
+        cipher.clone().into_inner(),
+        concurrency_settings.clone().into_inner(),
+    ));
+
+    let hl7_settings = config.hl7;
+    if let Some(address) = hl7_settings.address {
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .map_err(|e| format!("Could not listen for HL7 messages at {}: {}", address, e))?;
+        info!(address = %address, "Accepting HL7 messages over MLLP");
+        tokio::spawn(hl7::serve(
+            listener,
+            pool.clone(),
+            cipher.clone().into_inner(),
+            std::sync::Arc::new(hl7_settings),
+        ));
+    }
+
+    let schedule_events = web::Data::new(
+        ScheduleEvents::listen(pool.clone(), cipher.clone().into_inner())
+            .await
+            .map_err(|e| format!("Could not listen for schedule changes: {}", e))?,
+    );
+
+    let health = web::Data::new(Health::new());
 
-    println!("Starting server at http://127.0.0.1:8080");
+    let grpc_server = match config.grpc.address {
+        Some(address) => {
+            let listener = tokio::net::TcpListener::bind(address)
+                .await
+                .map_err(|e| format!("Could not serve gRPC at {}: {}", address, e))?;
+            let service = HospitalService::new(
+                pool.clone(),
+                cipher.clone().into_inner(),
+                concurrency_settings.clone().into_inner(),
+                schedule_events.clone().into_inner(),
+            );
+            info!(address = %address, "Serving gRPC");
+            let health = health.clone().into_inner();
+            Some(grpc::serve(
+                listener,
+                service,
+                async move { health.stopped().await },
+                config.shutdown_timeout,
+            ))
+        }
+        None => None,
+    };
 
-    HttpServer::new(move || {
+    let webhook_settings = web::Data::new(config.webhooks);
+    tokio::spawn(webhooks::dispatch(
+        pool.clone(),
+        cipher.clone().into_inner(),
+        webhook_settings.clone().into_inner(),
+    ));
+
+    let notifiers = reminders::notifiers(config.smtp.as_ref(), config.sms.as_ref())
+        .map_err(|e| format!("Invalid reminder settings: {}", e))?;
+    if notifiers.is_empty() {
+        warn!("Neither an SMTP host nor an SMS gateway is set, appointment reminders are not sent");
+    } else {
+        tokio::spawn(reminders::run(
+            pool.clone(),
+            cipher.clone().into_inner(),
+            config.reminders,
+            notifiers,
+        ));
+    }
+
+    tokio::spawn(metrics::refresh_domain_gauges(
+        metrics.clone(),
+        pool.clone(),
+    ));
+
+    tokio::spawn(jobs::run(
+        JobContext {
+            pool: pool.clone(),
+            cipher: cipher.clone().into_inner(),
+            pseudonymizer: pseudonymizer.clone().into_inner(),
+            sled_db: sled_db.clone(),
+            anonymized_sled: anonymized_sled.0.clone(),
+            metrics: metrics.clone(),
+        },
+        config.jobs,
+    ));
+
+    let swagger = config.swagger;
+    let cors = config.cors.clone();
+    let require_client_certificates = config
+        .tls
+        .as_ref()
+        .is_some_and(|tls| tls.client_ca_path.is_some());
+
+    info!(url = %base_url, "Starting server");
+
+    let metrics = web::Data::from(metrics);
+    let app_health = health.clone();
+    let mut server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
+            .app_data(app_health.clone())
             .app_data(web::Data::new(sled_db.clone()))
+            .app_data(concurrency_settings.clone())
+            .app_data(idempotency_settings.clone())
+            .app_data(calendar_settings.clone())
+            .app_data(fhir_settings.clone())
+            .app_data(webhook_settings.clone())
+            .app_data(graphql_schema.clone())
+            .app_data(schedule_events.clone())
+            .app_data(cipher.clone())
+            .app_data(anonymized_sled.clone())
+            .app_data(pseudonymizer.clone())
+            .app_data(metrics.clone())
+            .wrap(from_fn(idempotency::idempotency))
+            .wrap(Condition::new(
+                require_client_certificates,
+                from_fn(tls::require_client_certificate),
+            ))
+            .wrap(Condition::new(cors.is_enabled(), cors.middleware()))
+            .wrap(from_fn(metrics::record_requests))
+            .wrap(from_fn(telemetry::trace_requests))
             .service(handlers::get_patients)
             .service(handlers::add_patient)
             .service(handlers::update_patient)
             .service(handlers::delete_patient)
+            .service(handlers::export_patients_ndjson)
+            .service(handlers::import_patients_ndjson)
+            .service(handlers::export_patients_csv)
+            .service(handlers::import_patients_csv)
             .service(handlers::export_patients)
             .service(handlers::import_patients)
+            .service(handlers::get_patient_duplicates)
+            .service(handlers::merge_patients)
+            .service(handlers::get_patient_history)
+            .service(handlers::export_patient_data)
+            .service(handlers::erase_patient)
+            .service(handlers::issue_patient_calendar_token)
+            .service(handlers::get_patient_calendar)
+            .service(handlers::get_patient)
+            .service(handlers::patch_patient)
+            .service(handlers::put_patient)
+            .service(handlers::delete_patient_by_id)
             .service(handlers::get_doctors)
             .service(handlers::add_doctor)
             .service(handlers::update_doctor)
             .service(handlers::delete_doctor)
+            .service(handlers::export_doctors_ndjson)
+            .service(handlers::import_doctors_ndjson)
+            .service(handlers::export_doctors_csv)
+            .service(handlers::import_doctors_csv)
             .service(handlers::export_doctors)
             .service(handlers::import_doctors)
+            .service(handlers::get_doctor_history)
+            .service(handlers::get_doctor_roster)
+            .service(handlers::issue_doctor_calendar_token)
+            .service(handlers::get_doctor_calendar)
+            .service(handlers::revoke_calendar_token)
+            .service(handlers::get_doctor)
+            .service(handlers::patch_doctor)
+            .service(handlers::put_doctor)
+            .service(handlers::delete_doctor_by_id)
             .service(handlers::get_tickets)
             .service(handlers::add_ticket)
             .service(handlers::update_ticket)
             .service(handlers::delete_ticket)
+            .service(handlers::export_tickets_ndjson)
+            .service(handlers::import_tickets_ndjson)
+            .service(handlers::export_tickets_csv)
+            .service(handlers::import_tickets_csv)
             .service(handlers::export_tickets)
             .service(handlers::import_tickets)
+            .service(handlers::get_ticket_history)
+            .service(handlers::get_ticket)
+            .service(handlers::patch_ticket)
+            .service(handlers::put_ticket)
+            .service(handlers::delete_ticket_by_id)
             .service(handlers::get_schedule)
             .service(handlers::add_schedule_entry)
             .service(handlers::update_schedule_entry)
             .service(handlers::delete_schedule_entry)
+            .service(handlers::export_schedule_xlsx)
+            .service(handlers::export_schedule_ndjson)
+            .service(handlers::import_schedule_ndjson)
+            .service(handlers::export_schedule_csv)
+            .service(handlers::import_schedule_csv)
             .service(handlers::export_schedule)
             .service(handlers::import_schedule)
-            .service(
-                SwaggerUi::new("/swagger-ui/{_:.*}").url("/api-docs/openapi.json", openapi.clone()),
-            )
+            .service(handlers::stream_schedule)
+            .service(handlers::schedule_websocket)
+            .service(handlers::get_schedule_entry)
+            .service(handlers::get_schedule_entry_slip)
+            .service(handlers::patch_schedule_entry)
+            .service(handlers::put_schedule_entry)
+            .service(handlers::delete_schedule_entry_by_id)
+            .service(handlers::reencrypt_pii)
+            .service(handlers::get_fhir_metadata)
+            .service(handlers::search_fhir_resources)
+            .service(handlers::create_fhir_resource)
+            .service(handlers::read_fhir_resource)
+            .service(handlers::graphql_request)
+            .service(handlers::graphiql)
+            .service(handlers::get_webhooks)
+            .service(handlers::add_webhook)
+            .service(handlers::get_webhook)
+            .service(handlers::patch_webhook)
+            .service(handlers::delete_webhook)
+            .service(handlers::get_webhook_deliveries)
+            .service(handlers::get_notification_preferences)
+            .service(handlers::put_notification_preferences)
+            .service(handlers::get_notification_log)
+            .service(handlers::get_jobs)
+            .service(handlers::get_job)
+            .service(handlers::cancel_job)
+            .service(handlers::health_live)
+            .service(handlers::health_ready)
+            .service(handlers::health_details)
+            .service(handlers::get_metrics)
+            .configure(|cfg| {
+                if swagger {
+                    cfg.service(
+                        SwaggerUi::new("/swagger-ui/{_:.*}")
+                            .url("/api-docs/openapi.json", openapi.clone()),
+                    );
+                }
+            })
     })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    .disable_signals()
+    .on_connect(tls::on_connect)
+    .client_request_timeout(config.request_timeout)
+    .keep_alive(config.keep_alive)
+    .shutdown_timeout(config.shutdown_timeout.as_secs());
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+    let server = match tls_config {
+        Some((tls_config, certificate)) => {
+            tokio::spawn(tls::reload_on_change(certificate));
+            server.bind_rustls_0_23(config.bind_address, tls_config)
+        }
+        None => server.bind(config.bind_address),
+    }
+    .map_err(|e| format!("Could not listen at {}: {}", config.bind_address, e))?
+    .run();
+    let mut servers = vec![server];
+
+    if let Some(redirect_address) = config.tls.as_ref().and_then(|tls| tls.redirect_address) {
+        let https_port = web::Data::new(tls::HttpsPort(config.bind_address.port()));
+        info!(address = %redirect_address, "Redirecting plain HTTP to HTTPS");
+        let redirect = HttpServer::new(move || {
+            App::new()
+                .app_data(https_port.clone())
+                .default_service(web::to(tls::redirect_to_https))
+        })
+        .disable_signals()
+        .workers(1)
+        .bind(redirect_address)
+        .map_err(|e| format!("Could not listen at {}: {}", redirect_address, e))?
+        .run();
+        servers.push(redirect);
+    }
+
+    tokio::spawn(health::stop_on_signal(
+        health.into_inner(),
+        servers.iter().map(|server| server.handle()).collect(),
+        config.shutdown_drain,
+    ));
+    let http_servers = async {
+        future::try_join_all(servers)
+            .await
+            .map_err(|e| format!("HTTP server failed: {}", e))
+    };
+    let grpc_server = async {
+        match grpc_server {
+            Some(server) => server
+                .await
+                .map_err(|e| format!("gRPC server failed: {}", e)),
+            None => Ok(()),
+        }
+    };
+    future::try_join(http_servers, grpc_server)
+        .await
+        .map(|_| ())
 }
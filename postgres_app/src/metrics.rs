@@ -0,0 +1,373 @@
+use crate::reports::format_date;
+use crate::telemetry::QueryEvent;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tracing::{warn, Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+pub const CONTENT_TYPE: &str = prometheus::TEXT_FORMAT;
+
+/// Route label of requests no route matched, so that scanners cannot add a series per path.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Speciality label of free tickets in offices no doctor has been booked in yet.
+const UNASSIGNED_SPECIALITY: &str = "unassigned";
+
+/// How often appointments and free tickets are recounted. Scrapes only read the gauges, so
+/// that frequent scrapes or several Prometheus servers add no load on the database.
+const DOMAIN_GAUGE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    db_pool_connections: IntGaugeVec,
+    db_pool_max_connections: IntGauge,
+    db_query_duration: HistogramVec,
+    job_records: IntCounterVec,
+    job_duration: HistogramVec,
+    appointments_booked_today: IntGaugeVec,
+    free_tickets: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let register = |collector: Box<dyn prometheus::core::Collector>| {
+            registry
+                .register(collector)
+                .expect("metric names are unique");
+        };
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        register(Box::new(http_requests.clone()));
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time until the response head of HTTP requests",
+            ),
+            &["method", "route"],
+        )
+        .unwrap();
+        register(Box::new(http_request_duration.clone()));
+
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new(
+                "db_pool_connections",
+                "Open database connections by whether they are idle or in use",
+            ),
+            &["state"],
+        )
+        .unwrap();
+        register(Box::new(db_pool_connections.clone()));
+        let db_pool_max_connections = IntGauge::new(
+            "db_pool_max_connections",
+            "Connections the database pool opens at most",
+        )
+        .unwrap();
+        register(Box::new(db_pool_max_connections.clone()));
+        let db_query_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "db_query_duration_seconds",
+                "Duration of database queries by statement kind",
+            )
+            .buckets(vec![
+                0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+            ]),
+            &["operation"],
+        )
+        .unwrap();
+        register(Box::new(db_query_duration.clone()));
+
+        let job_records = IntCounterVec::new(
+            Opts::new(
+                "job_records_total",
+                "Records background jobs handled, by whether they failed",
+            ),
+            &["kind", "outcome"],
+        )
+        .unwrap();
+        register(Box::new(job_records.clone()));
+        let job_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "job_duration_seconds",
+                "Duration of background job runs. A resumed job counts once per run",
+            )
+            .buckets(vec![
+                0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0,
+            ]),
+            &["kind", "result"],
+        )
+        .unwrap();
+        register(Box::new(job_duration.clone()));
+
+        let appointments_booked_today = IntGaugeVec::new(
+            Opts::new(
+                "appointments_booked_today",
+                "Appointments on tickets of the current UTC day, by doctor speciality",
+            ),
+            &["speciality"],
+        )
+        .unwrap();
+        register(Box::new(appointments_booked_today.clone()));
+        let free_tickets = IntGaugeVec::new(
+            Opts::new(
+                "free_tickets",
+                "Unbooked tickets from the current UTC day on, by the speciality of the doctor \
+                last booked in their office",
+            ),
+            &["speciality"],
+        )
+        .unwrap();
+        register(Box::new(free_tickets.clone()));
+
+        Metrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            db_pool_connections,
+            db_pool_max_connections,
+            db_query_duration,
+            job_records,
+            job_duration,
+            appointments_booked_today,
+            free_tickets,
+        }
+    }
+
+    pub fn job_records(&self, kind: &str, succeeded: usize, failed: usize) {
+        self.job_records
+            .with_label_values(&[kind, "succeeded"])
+            .inc_by(succeeded as u64);
+        self.job_records
+            .with_label_values(&[kind, "failed"])
+            .inc_by(failed as u64);
+    }
+
+    pub fn job_finished(&self, kind: &str, succeeded: bool, duration: Duration) {
+        let result = if succeeded { "succeeded" } else { "failed" };
+        self.job_duration
+            .with_label_values(&[kind, result])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Updates the connection pool gauges and renders all metrics in the Prometheus text format.
+    pub fn render(&self, pool: &PgPool) -> Result<String, String> {
+        let idle = pool.num_idle() as i64;
+        self.db_pool_connections
+            .with_label_values(&["idle"])
+            .set(idle);
+        self.db_pool_connections
+            .with_label_values(&["in_use"])
+            .set(pool.size() as i64 - idle);
+        self.db_pool_max_connections
+            .set(pool.options().get_max_connections() as i64);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| e.to_string())?;
+        String::from_utf8(buffer).map_err(|e| e.to_string())
+    }
+
+    async fn collect_domain_gauges(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        let today = format_date(OffsetDateTime::now_utc().date());
+
+        let booked = sqlx::query!(
+            r#"SELECT doctors.speciality, COUNT(*) as "count!"
+            FROM schedule
+            JOIN tickets ON schedule.ticket_id = tickets.id
+            JOIN doctors ON schedule.doctor_id = doctors.id
+            WHERE tickets.date = $1
+            GROUP BY doctors.speciality"#,
+            today,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let free = sqlx::query!(
+            r#"WITH office_specialities AS (
+                SELECT DISTINCT ON (tickets.office_number) tickets.office_number, doctors.speciality
+                FROM schedule
+                JOIN tickets ON schedule.ticket_id = tickets.id
+                JOIN doctors ON schedule.doctor_id = doctors.id
+                ORDER BY tickets.office_number, tickets.date DESC, tickets.time DESC, schedule.id DESC
+            )
+            SELECT COALESCE(office_specialities.speciality, $2) as "speciality!", COUNT(*) as "count!"
+            FROM tickets
+            LEFT JOIN office_specialities ON office_specialities.office_number = tickets.office_number
+            WHERE tickets.date >= $1
+                AND NOT EXISTS (SELECT 1 FROM schedule WHERE schedule.ticket_id = tickets.id)
+            GROUP BY 1"#,
+            today,
+            UNASSIGNED_SPECIALITY,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        // Reset only once both queries are done, so that scrapes never see the gauges empty.
+        self.appointments_booked_today.reset();
+        for row in booked {
+            self.appointments_booked_today
+                .with_label_values(&[&row.speciality])
+                .set(row.count);
+        }
+        self.free_tickets.reset();
+        for row in free {
+            self.free_tickets
+                .with_label_values(&[&row.speciality])
+                .set(row.count);
+        }
+        Ok(())
+    }
+}
+
+/// Recounts appointments and free tickets every few seconds. The gauges are left out while
+/// their queries fail, rather than showing stale counts.
+pub async fn refresh_domain_gauges(metrics: Arc<Metrics>, pool: PgPool) {
+    let mut interval = tokio::time::interval(DOMAIN_GAUGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = metrics.collect_domain_gauges(&pool).await {
+            metrics.appointments_booked_today.reset();
+            metrics.free_tickets.reset();
+            warn!(error = %e, "Could not count appointments and free tickets");
+        }
+    }
+}
+
+/// Counts requests and times them by route pattern, as `/patients/{id}`, not by path.
+pub async fn record_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let metrics = req.app_data::<web::Data<Metrics>>().cloned();
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+
+    let result = next.call(req).await;
+
+    if let Some(metrics) = metrics {
+        let status = match &result {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        metrics
+            .http_requests
+            .with_label_values(&[&method, &route, status.as_str()])
+            .inc();
+        metrics
+            .http_request_duration
+            .with_label_values(&[&method, &route])
+            .observe(started.elapsed().as_secs_f64());
+    }
+    result
+}
+
+/// Records the duration of every query sqlx logs. sqlx only measures queries while its
+/// `sqlx::query` target is enabled at `DEBUG`, so the subscriber must enable it for this layer.
+pub struct QueryMetrics(pub Arc<Metrics>);
+
+impl<S: Subscriber> Layer<S> for QueryMetrics {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if let Some(query) = QueryEvent::from_event(event) {
+            self.0
+                .db_query_duration
+                .with_label_values(&[query.operation()])
+                .observe(query.elapsed.as_secs_f64());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PiiConfig;
+    use crate::crypto::FieldCipher;
+    use crate::models::{NewDoctor, NewPatient, NewScheduleEntry, NewTicket};
+    use crate::{doctors, patients, schedule, tickets};
+
+    #[sqlx::test]
+    async fn domain_gauges_are_counted_apart_from_scrapes(pool: PgPool) {
+        let cipher = FieldCipher::new(&PiiConfig::default()).unwrap();
+        let (doctor, _) = doctors::create(
+            &pool,
+            &cipher,
+            &NewDoctor {
+                name: "Anna".to_string(),
+                surname: "Petrova".to_string(),
+                speciality: "Cardiologist".to_string(),
+                phone_number: "+71111111111".to_string(),
+                passport_number: "1111 111111".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        let (patient, _) = patients::create(
+            &pool,
+            &cipher,
+            &NewPatient {
+                name: "Ivan".to_string(),
+                surname: "Ivanov".to_string(),
+                birth_date: "1990-01-01".to_string(),
+                phone_number: "+72222222222".to_string(),
+                passport_number: "2222 222222".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        let today = format_date(OffsetDateTime::now_utc().date());
+        let mut ticket_ids = Vec::new();
+        for time in ["10:00", "11:00", "12:00"] {
+            let (ticket, _) = tickets::create(
+                &pool,
+                &NewTicket {
+                    date: today.clone(),
+                    time: time.to_string(),
+                    office_number: 12,
+                },
+            )
+            .await
+            .unwrap();
+            ticket_ids.push(ticket.id);
+        }
+        schedule::book(
+            &pool,
+            &NewScheduleEntry {
+                ticket_id: ticket_ids[0],
+                doctor_id: doctor.id,
+                patient_id: patient.id,
+            },
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        let metrics = Metrics::new();
+        let rendered = metrics.render(&pool).unwrap();
+        assert!(!rendered.contains("appointments_booked_today{"));
+
+        metrics.collect_domain_gauges(&pool).await.unwrap();
+        let rendered = metrics.render(&pool).unwrap();
+        assert!(rendered.contains("appointments_booked_today{speciality=\"Cardiologist\"} 1"));
+        assert!(rendered.contains("free_tickets{speciality=\"Cardiologist\"} 2"));
+    }
+}
@@ -1,7 +1,8 @@
+use async_graphql::InputObject;
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
-#[derive(Serialize, Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
 pub struct Patient {
     pub id: i32,
     pub name: String,
@@ -11,7 +12,7 @@ pub struct Patient {
     pub passport_number: String,
 }
 
-#[derive(Serialize, Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema, InputObject)]
 pub struct NewPatient {
     pub name: String,
     pub surname: String,
@@ -20,7 +21,7 @@ pub struct NewPatient {
     pub passport_number: String,
 }
 
-#[derive(Serialize, Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema, InputObject, Default)]
 pub struct OptionPatient {
     pub name: Option<String>,
     pub surname: Option<String>,
@@ -43,6 +44,21 @@ pub struct UpdatePatient {
     pub condition_passport_number: Option<String>,
 }
 
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PatientHistoryEntry {
+    pub history_id: i32,
+    pub id: i32,
+    pub name: String,
+    pub surname: String,
+    pub birth_date: String,
+    pub phone_number: String,
+    pub passport_number: String,
+    pub operation: String,
+    pub valid_from: String,
+    pub valid_to: Option<String>,
+    pub closed_by: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, ToSchema)]
 pub struct Doctor {
     pub id: i32,
@@ -53,7 +69,7 @@ pub struct Doctor {
     pub passport_number: String,
 }
 
-#[derive(Serialize, Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema, InputObject)]
 pub struct NewDoctor {
     pub name: String,
     pub surname: String,
@@ -62,7 +78,7 @@ pub struct NewDoctor {
     pub passport_number: String,
 }
 
-#[derive(Serialize, Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema, InputObject, Default)]
 pub struct OptionDoctor {
     pub name: Option<String>,
     pub surname: Option<String>,
@@ -85,6 +101,21 @@ pub struct UpdateDoctor {
     pub condition_passport_number: Option<String>,
 }
 
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct DoctorHistoryEntry {
+    pub history_id: i32,
+    pub id: i32,
+    pub name: String,
+    pub surname: String,
+    pub speciality: String,
+    pub phone_number: String,
+    pub passport_number: String,
+    pub operation: String,
+    pub valid_from: String,
+    pub valid_to: Option<String>,
+    pub closed_by: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, ToSchema)]
 pub struct Ticket {
     pub id: i32,
@@ -93,14 +124,14 @@ pub struct Ticket {
     pub office_number: i32,
 }
 
-#[derive(Serialize, Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema, InputObject)]
 pub struct NewTicket {
     pub date: String,
     pub time: String,
     pub office_number: i32,
 }
 
-#[derive(Serialize, Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema, InputObject, Default)]
 pub struct OptionTicket {
     pub date: Option<String>,
     pub time: Option<String>,
@@ -117,6 +148,19 @@ pub struct UpdateTicket {
     pub condition_office_number: Option<i32>,
 }
 
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TicketHistoryEntry {
+    pub history_id: i32,
+    pub id: i32,
+    pub date: String,
+    pub time: String,
+    pub office_number: i32,
+    pub operation: String,
+    pub valid_from: String,
+    pub valid_to: Option<String>,
+    pub closed_by: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, ToSchema)]
 pub struct ScheduleEntry {
     pub id: i32,
@@ -125,14 +169,14 @@ pub struct ScheduleEntry {
     pub patient_id: i32,
 }
 
-#[derive(Serialize, Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema, InputObject)]
 pub struct NewScheduleEntry {
     pub ticket_id: i32,
     pub doctor_id: i32,
     pub patient_id: i32,
 }
 
-#[derive(Serialize, Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema, InputObject, Default)]
 pub struct OptionScheduleEntry {
     pub ticket_id: Option<i32>,
     pub doctor_id: Option<i32>,
@@ -149,7 +193,7 @@ pub struct UpdateScheduleEntry {
     pub condition_patient_id: Option<i32>,
 }
 
-#[derive(Serialize, Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
 pub struct FullScheduleEntry {
     pub schedule_id: i32,
 
@@ -172,3 +216,390 @@ pub struct FullScheduleEntry {
     pub patient_phone_number: String,
     pub patient_passport_number: String,
 }
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AsOf {
+    pub as_of: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct DuplicateFilter {
+    pub min_score: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct DuplicateCandidate {
+    pub patient: Patient,
+    pub duplicate: Patient,
+    pub score: f64,
+    pub matched_on: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct MergePatients {
+    pub survivor_id: i32,
+    pub loser_id: i32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct MergeResult {
+    pub survivor: Patient,
+    pub loser_id: i32,
+    pub moved_schedule_entries: i32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SledExportOptions {
+    /// Write phone and passport numbers in plaintext instead of keeping them encrypted.
+    pub decrypted: Option<bool>,
+    /// Write pseudonymized records to the anonymized Sled database instead.
+    pub anonymized: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SledDatasetOptions {
+    /// Use the anonymized Sled database instead of the regular one.
+    pub anonymized: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ReencryptResult {
+    pub patients_updated: u64,
+    pub doctors_updated: u64,
+    /// Past versions of patients and doctors.
+    pub history_entries_updated: u64,
+    /// Responses kept for `Idempotency-Key` retries.
+    pub stored_responses_updated: u64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PatientMergeEntry {
+    pub id: i32,
+    pub survivor_id: i32,
+    pub loser_id: i32,
+    pub moved_schedule_entries: i32,
+    pub merged_at: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PatientErasureEntry {
+    pub id: i32,
+    pub patient_id: i32,
+    pub erased_ids: Vec<i32>,
+    pub history_entries_scrubbed: i32,
+    pub erased_at: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PatientDataExport {
+    pub patient: Patient,
+    pub schedule: Vec<FullScheduleEntry>,
+    pub history: Vec<PatientHistoryEntry>,
+    pub merges: Vec<PatientMergeEntry>,
+    pub erasures: Vec<PatientErasureEntry>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ErasureResult {
+    pub patient_id: i32,
+    /// The patient and every patient that was merged into it.
+    pub erased_ids: Vec<i32>,
+    pub history_entries_scrubbed: u64,
+    pub sled_entries_scrubbed: u64,
+    /// Responses about the patient kept for `Idempotency-Key` retries.
+    pub stored_responses_deleted: u64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CsvImportOptions {
+    /// Check every row against the database and roll everything back.
+    pub dry_run: Option<bool>,
+    /// Extra header names as `CSV header:column` pairs separated by commas.
+    pub mapping: Option<String>,
+    /// Field delimiter, `,` by default. Use `;` for spreadsheets saved in many European locales.
+    pub delimiter: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CsvRowError {
+    /// Row number in the file, the header being row 1.
+    pub row: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NdjsonExportOptions {
+    /// Write phone and passport numbers in plaintext, so the backup can be restored with
+    /// different encryption keys.
+    pub decrypted: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ScheduleReportFilter {
+    /// First day of the report, `YYYY-MM-DD`. The current week is used when both ends are empty.
+    pub from: Option<String>,
+    /// Last day of the report, `YYYY-MM-DD`.
+    pub to: Option<String>,
+    pub doctor_id: Option<i32>,
+    pub speciality: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RosterOptions {
+    /// Day of the roster, `YYYY-MM-DD`. Today when empty.
+    pub date: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CalendarToken {
+    pub id: i32,
+    /// Shown only once; the server keeps a hash of it.
+    pub token: String,
+    /// Feed URL with the token, ready to be added to a calendar app.
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CalendarFeedOptions {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleEventKind {
+    Created,
+    Updated,
+    Cancelled,
+}
+
+/// Where an entry was before an update moved it to another ticket, doctor or patient, or
+/// before its ticket was moved.
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct PreviousAppointment {
+    pub ticket_id: i32,
+    pub doctor_id: i32,
+    pub patient_id: i32,
+    pub date: String,
+    pub time: String,
+    pub office_number: i32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct ScheduleEvent {
+    pub kind: ScheduleEventKind,
+    /// The entry as it is now, or as it was before it was cancelled.
+    pub entry: FullScheduleEntry,
+    pub previous: Option<PreviousAppointment>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Default)]
+pub struct ScheduleEventFilter {
+    /// Only events of this doctor's entries, before or after the change.
+    pub doctor_id: Option<i32>,
+    pub patient_id: Option<i32>,
+    pub office_number: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct WebhookSubscription {
+    pub id: i32,
+    pub url: String,
+    /// Some of `appointment.created`, `appointment.cancelled` and `appointment.rescheduled`.
+    pub event_types: Vec<String>,
+    /// Inactive subscriptions get no new events, and their pending deliveries wait.
+    pub active: bool,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NewWebhookSubscription {
+    pub url: String,
+    /// Key of the `X-Webhook-Signature` HMAC. It is never returned.
+    pub secret: String,
+    pub event_types: Vec<String>,
+    pub active: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct UpdateWebhookSubscription {
+    pub url: Option<String>,
+    pub secret: Option<String>,
+    pub event_types: Option<Vec<String>>,
+    pub active: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub event_id: i64,
+    pub event_type: String,
+    /// The `data` of the delivered event.
+    #[schema(value_type = Object)]
+    pub payload: serde_json::Value,
+    /// `pending`, `delivered` or `failed`.
+    pub status: String,
+    pub attempts: i32,
+    /// When a pending delivery is attempted next.
+    pub next_attempt_at: Option<String>,
+    pub last_attempt_at: Option<String>,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct WebhookDeliveryFilter {
+    pub status: Option<String>,
+    /// Most recent deliveries returned, 100 by default.
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NotificationPreferences {
+    pub patient_id: i32,
+    /// Address for email reminders. Without it the patient gets SMS reminders only.
+    pub email: Option<String>,
+    /// `ru` or `en`. The server default is used when empty.
+    pub locale: Option<String>,
+    pub email_enabled: bool,
+    pub sms_enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct UpdateNotificationPreferences {
+    pub email: Option<String>,
+    pub locale: Option<String>,
+    /// `true` when not given.
+    pub email_enabled: Option<bool>,
+    /// `true` when not given.
+    pub sms_enabled: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NotificationLogEntry {
+    pub id: i64,
+    pub schedule_id: i32,
+    pub patient_id: i32,
+    /// `24h` or `2h`.
+    pub reminder: String,
+    /// `email` or `sms`.
+    pub channel: String,
+    pub appointment_at: String,
+    /// `sending`, `sent` or `failed`.
+    pub status: String,
+    pub attempts: i32,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NotificationLogFilter {
+    pub patient_id: Option<i32>,
+    pub schedule_id: Option<i32>,
+    pub status: Option<String>,
+    /// Most recent entries returned, 100 by default.
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Job {
+    pub id: i64,
+    /// What the job does, such as `patients.export`, `schedule.import` or
+    /// `doctors.import.csv`.
+    pub kind: String,
+    /// Options the job was queued with.
+    #[schema(value_type = Object)]
+    pub params: serde_json::Value,
+    /// `queued`, `running`, `succeeded`, `failed` or `cancelled`.
+    pub status: String,
+    /// A running job stops after its current batch.
+    pub cancel_requested: bool,
+    /// Records handled so far, including failed ones.
+    pub processed: i64,
+    pub failed: i64,
+    /// Records to handle, known once the job started.
+    pub total: Option<i64>,
+    /// Summary of a succeeded job.
+    #[schema(value_type = Option<Object>)]
+    pub result: Option<serde_json::Value>,
+    /// The first records that could not be handled, as `message` and the Sled `key` or CSV
+    /// `row`.
+    #[schema(value_type = Vec<Object>)]
+    pub errors: serde_json::Value,
+    /// Why the job failed, such as an invalid line of an NDJSON import.
+    pub error: Option<String>,
+    /// More than one when the job was resumed after its worker stopped.
+    pub attempts: i32,
+    pub created_at: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct JobFilter {
+    pub status: Option<String>,
+    pub kind: Option<String>,
+    /// Most recent jobs returned, 100 by default.
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct HealthCheck {
+    /// `database`, `sled` or `anonymized_sled`.
+    pub name: String,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub duration_ms: f64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Readiness {
+    /// False while shutting down or when a check failed.
+    pub ready: bool,
+    pub shutting_down: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct BuildInfo {
+    pub name: String,
+    pub version: String,
+    /// `debug` or `release`.
+    pub profile: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct DatabaseHealth {
+    pub check: HealthCheck,
+    /// Open connections, idle or in use.
+    pub pool_size: u32,
+    pub pool_idle: u32,
+    pub pool_max_connections: u32,
+    /// Last migration applied to the database.
+    pub migration_version: Option<i64>,
+    pub migration_description: Option<String>,
+    pub migration_installed_at: Option<String>,
+    /// Last migration this build knows. Differs from `migration_version` while an older or
+    /// newer build runs against the same database.
+    pub latest_migration_version: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SledHealth {
+    /// Its check writes, removes and flushes a probe key.
+    pub check: HealthCheck,
+    pub size_on_disk: Option<u64>,
+    /// Whether the database was recovered from a previous crash when it was opened.
+    pub was_recovered: bool,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct HealthDetails {
+    pub ready: bool,
+    pub shutting_down: bool,
+    pub uptime_seconds: u64,
+    pub build: BuildInfo,
+    pub database: DatabaseHealth,
+    pub sled: Vec<SledHealth>,
+}
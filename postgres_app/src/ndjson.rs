@@ -0,0 +1,366 @@
+use crate::crypto::FieldCipher;
+use crate::models::{Doctor, Patient, ScheduleEntry, Ticket};
+use actix_web::web::{Bytes, BytesMut};
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sqlx::PgConnection;
+use std::collections::HashSet;
+
+pub const CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Records sent to the database in one multi-row insert.
+pub const BATCH_SIZE: usize = 1000;
+
+/// Uploads with a longer line, line break included, fail instead of being buffered whole.
+const MAX_LINE_BYTES: usize = 1 << 20;
+
+/// Encodes one record as a JSON line.
+pub fn line<T: Serialize>(value: &T) -> Bytes {
+    let mut line = serde_json::to_vec(value).expect("Models always serialize to JSON");
+    line.push(b'\n');
+    Bytes::from(line)
+}
+
+/// Splits an upload into JSON lines as chunks arrive. Blank lines are skipped.
+pub struct NdjsonLines<'a> {
+    chunks: BoxStream<'a, Result<Bytes, String>>,
+    buffer: BytesMut,
+    scanned: usize,
+    line: u64,
+    eof: bool,
+}
+
+impl<'a> NdjsonLines<'a> {
+    pub fn new(chunks: BoxStream<'a, Result<Bytes, String>>) -> Self {
+        NdjsonLines {
+            chunks,
+            buffer: BytesMut::new(),
+            scanned: 0,
+            line: 0,
+            eof: false,
+        }
+    }
+
+    pub async fn next<T: DeserializeOwned>(&mut self) -> Result<Option<T>, String> {
+        loop {
+            let end = match self.buffer[self.scanned..].iter().position(|b| *b == b'\n') {
+                Some(position) => Some(self.scanned + position + 1),
+                None if self.eof && !self.buffer.is_empty() => Some(self.buffer.len()),
+                None if self.eof => return Ok(None),
+                None => None,
+            };
+
+            let end = match end {
+                Some(end) if end > MAX_LINE_BYTES => return Err(self.too_long()),
+                Some(end) => end,
+                None if self.buffer.len() > MAX_LINE_BYTES => return Err(self.too_long()),
+                None => {
+                    self.scanned = self.buffer.len();
+                    match self.chunks.next().await {
+                        Some(Ok(chunk)) => self.buffer.extend_from_slice(&chunk),
+                        Some(Err(message)) => return Err(message),
+                        None => self.eof = true,
+                    }
+                    continue;
+                }
+            };
+
+            let line = self.buffer.split_to(end);
+            self.scanned = 0;
+            self.line += 1;
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            return serde_json::from_slice(&line)
+                .map(Some)
+                .map_err(|e| format!("Line {} is invalid: {}", self.line, e));
+        }
+    }
+
+    fn too_long(&self) -> String {
+        format!(
+            "Line {} is longer than {} bytes",
+            self.line + 1,
+            MAX_LINE_BYTES
+        )
+    }
+}
+
+/// Keeps the last record of every id, in the order of those last records. A multi-row
+/// `ON CONFLICT DO UPDATE` fails when it would update the same row twice.
+fn last_per_id<T>(records: Vec<T>, id: impl Fn(&T) -> i32) -> Vec<T> {
+    let mut seen = HashSet::with_capacity(records.len());
+    let mut records: Vec<T> = records
+        .into_iter()
+        .rev()
+        .filter(|record| seen.insert(id(record)))
+        .collect();
+    records.reverse();
+    records
+}
+
+pub async fn insert_patients(
+    connection: &mut PgConnection,
+    cipher: &FieldCipher,
+    patients: Vec<Patient>,
+) -> Result<u64, sqlx::Error> {
+    let patients = last_per_id(patients, |patient| patient.id);
+    let mut ids = Vec::with_capacity(patients.len());
+    let mut names = Vec::with_capacity(patients.len());
+    let mut surnames = Vec::with_capacity(patients.len());
+    let mut birth_dates = Vec::with_capacity(patients.len());
+    let mut phone_numbers = Vec::with_capacity(patients.len());
+    let mut passport_numbers = Vec::with_capacity(patients.len());
+    let mut phone_number_indexes = Vec::with_capacity(patients.len());
+    let mut passport_number_indexes = Vec::with_capacity(patients.len());
+    for patient in patients {
+        // Backups may hold values encrypted with any configured key, or plaintext.
+        let patient = cipher.decrypt_patient(patient);
+        ids.push(patient.id);
+        names.push(patient.name);
+        surnames.push(patient.surname);
+        birth_dates.push(patient.birth_date);
+        phone_numbers.push(cipher.encrypt(&patient.phone_number));
+        passport_numbers.push(cipher.encrypt(&patient.passport_number));
+        phone_number_indexes.push(cipher.blind_index(&patient.phone_number));
+        passport_number_indexes.push(cipher.blind_index(&patient.passport_number));
+    }
+
+    let inserted = sqlx::query!(
+        "INSERT INTO patients (id, name, surname, birth_date, phone_number, passport_number, phone_number_index, passport_number_index)
+        SELECT * FROM UNNEST($1::INT[], $2::TEXT[], $3::TEXT[], $4::TEXT[], $5::TEXT[], $6::TEXT[], $7::TEXT[], $8::TEXT[])
+        ON CONFLICT (id) DO UPDATE SET
+            name = EXCLUDED.name, surname = EXCLUDED.surname, birth_date = EXCLUDED.birth_date,
+            phone_number = EXCLUDED.phone_number, passport_number = EXCLUDED.passport_number,
+            phone_number_index = EXCLUDED.phone_number_index,
+            passport_number_index = EXCLUDED.passport_number_index",
+        &ids,
+        &names,
+        &surnames,
+        &birth_dates,
+        &phone_numbers,
+        &passport_numbers,
+        &phone_number_indexes,
+        &passport_number_indexes,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(inserted.rows_affected())
+}
+
+pub async fn insert_doctors(
+    connection: &mut PgConnection,
+    cipher: &FieldCipher,
+    doctors: Vec<Doctor>,
+) -> Result<u64, sqlx::Error> {
+    let doctors = last_per_id(doctors, |doctor| doctor.id);
+    let mut ids = Vec::with_capacity(doctors.len());
+    let mut names = Vec::with_capacity(doctors.len());
+    let mut surnames = Vec::with_capacity(doctors.len());
+    let mut specialities = Vec::with_capacity(doctors.len());
+    let mut phone_numbers = Vec::with_capacity(doctors.len());
+    let mut passport_numbers = Vec::with_capacity(doctors.len());
+    let mut phone_number_indexes = Vec::with_capacity(doctors.len());
+    let mut passport_number_indexes = Vec::with_capacity(doctors.len());
+    for doctor in doctors {
+        let doctor = cipher.decrypt_doctor(doctor);
+        ids.push(doctor.id);
+        names.push(doctor.name);
+        surnames.push(doctor.surname);
+        specialities.push(doctor.speciality);
+        phone_numbers.push(cipher.encrypt(&doctor.phone_number));
+        passport_numbers.push(cipher.encrypt(&doctor.passport_number));
+        phone_number_indexes.push(cipher.blind_index(&doctor.phone_number));
+        passport_number_indexes.push(cipher.blind_index(&doctor.passport_number));
+    }
+
+    let inserted = sqlx::query!(
+        "INSERT INTO doctors (id, name, surname, speciality, phone_number, passport_number, phone_number_index, passport_number_index)
+        SELECT * FROM UNNEST($1::INT[], $2::TEXT[], $3::TEXT[], $4::TEXT[], $5::TEXT[], $6::TEXT[], $7::TEXT[], $8::TEXT[])
+        ON CONFLICT (id) DO UPDATE SET
+            name = EXCLUDED.name, surname = EXCLUDED.surname, speciality = EXCLUDED.speciality,
+            phone_number = EXCLUDED.phone_number, passport_number = EXCLUDED.passport_number,
+            phone_number_index = EXCLUDED.phone_number_index,
+            passport_number_index = EXCLUDED.passport_number_index",
+        &ids,
+        &names,
+        &surnames,
+        &specialities,
+        &phone_numbers,
+        &passport_numbers,
+        &phone_number_indexes,
+        &passport_number_indexes,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(inserted.rows_affected())
+}
+
+pub async fn insert_tickets(
+    connection: &mut PgConnection,
+    tickets: Vec<Ticket>,
+) -> Result<u64, sqlx::Error> {
+    let tickets = last_per_id(tickets, |ticket| ticket.id);
+    let mut ids = Vec::with_capacity(tickets.len());
+    let mut dates = Vec::with_capacity(tickets.len());
+    let mut times = Vec::with_capacity(tickets.len());
+    let mut office_numbers = Vec::with_capacity(tickets.len());
+    for ticket in tickets {
+        ids.push(ticket.id);
+        dates.push(ticket.date);
+        times.push(ticket.time);
+        office_numbers.push(ticket.office_number);
+    }
+
+    let inserted = sqlx::query!(
+        "INSERT INTO tickets (id, date, time, office_number)
+        SELECT * FROM UNNEST($1::INT[], $2::TEXT[], $3::TEXT[], $4::INT[])
+        ON CONFLICT (id) DO UPDATE SET
+            date = EXCLUDED.date, time = EXCLUDED.time, office_number = EXCLUDED.office_number",
+        &ids,
+        &dates,
+        &times,
+        &office_numbers,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(inserted.rows_affected())
+}
+
+pub async fn insert_schedule_entries(
+    connection: &mut PgConnection,
+    entries: Vec<ScheduleEntry>,
+) -> Result<u64, sqlx::Error> {
+    let entries = last_per_id(entries, |entry| entry.id);
+    let mut ids = Vec::with_capacity(entries.len());
+    let mut ticket_ids = Vec::with_capacity(entries.len());
+    let mut doctor_ids = Vec::with_capacity(entries.len());
+    let mut patient_ids = Vec::with_capacity(entries.len());
+    for entry in entries {
+        ids.push(entry.id);
+        ticket_ids.push(entry.ticket_id);
+        doctor_ids.push(entry.doctor_id);
+        patient_ids.push(entry.patient_id);
+    }
+
+    let inserted = sqlx::query!(
+        "INSERT INTO schedule (id, ticket_id, doctor_id, patient_id)
+        SELECT * FROM UNNEST($1::INT[], $2::INT[], $3::INT[], $4::INT[])
+        ON CONFLICT (id) DO UPDATE SET
+            ticket_id = EXCLUDED.ticket_id, doctor_id = EXCLUDED.doctor_id,
+            patient_id = EXCLUDED.patient_id",
+        &ids,
+        &ticket_ids,
+        &doctor_ids,
+        &patient_ids,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(inserted.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use sqlx::PgPool;
+
+    fn upload(chunks: &[&[u8]]) -> NdjsonLines<'static> {
+        let chunks: Vec<Result<Bytes, String>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        NdjsonLines::new(stream::iter(chunks).boxed())
+    }
+
+    fn ticket(id: i32, office_number: i32) -> Ticket {
+        Ticket {
+            id,
+            date: "2026-01-05".into(),
+            time: "09:00".into(),
+            office_number,
+        }
+    }
+
+    #[tokio::test]
+    async fn lines_are_split_across_chunks() {
+        let mut lines = upload(&[
+            br#"{"id":1,"ticket_id":2,"#,
+            b"\"doctor_id\":3,\"patient_id\":4}\n\n  \r\n{\"id\":5,",
+            br#""ticket_id":6,"doctor_id":7,"patient_id":8}"#,
+        ]);
+        let first: ScheduleEntry = lines.next().await.unwrap().unwrap();
+        assert_eq!((first.id, first.patient_id), (1, 4));
+        let last: ScheduleEntry = lines.next().await.unwrap().unwrap();
+        assert_eq!((last.id, last.patient_id), (5, 8));
+        assert!(lines.next::<ScheduleEntry>().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn errors_name_their_line() {
+        let mut lines =
+            upload(&[b"{\"id\":1,\"ticket_id\":2,\"doctor_id\":3,\"patient_id\":4}\n\n{\"id\":\n"]);
+        assert!(lines.next::<ScheduleEntry>().await.is_ok());
+        let error = lines.next::<ScheduleEntry>().await.err().unwrap();
+        assert!(error.starts_with("Line 3 is invalid"), "{}", error);
+
+        let mut lines = upload(&[b"[1]\n"]);
+        assert!(lines.next::<ScheduleEntry>().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_rejected() {
+        let long = vec![b' '; MAX_LINE_BYTES];
+        let mut lines = upload(&[b"{}\n", &long, &long]);
+        assert!(lines.next::<serde_json::Value>().await.unwrap().is_some());
+        assert_eq!(
+            lines.next::<serde_json::Value>().await.err().unwrap(),
+            format!("Line 2 is longer than {} bytes", MAX_LINE_BYTES)
+        );
+
+        let mut long = vec![b' '; MAX_LINE_BYTES + 1];
+        long.push(b'\n');
+        let mut lines = upload(&[&long]);
+        assert!(lines.next::<serde_json::Value>().await.is_err());
+    }
+
+    #[test]
+    fn records_encode_as_lines() {
+        assert_eq!(
+            line(&ticket(1, 12)),
+            Bytes::from_static(
+                b"{\"id\":1,\"date\":\"2026-01-05\",\"time\":\"09:00\",\"office_number\":12}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn the_last_record_of_an_id_wins() {
+        let records = last_per_id(vec![(1, 'a'), (2, 'b'), (1, 'c'), (3, 'd')], |r| r.0);
+        assert_eq!(records, [(2, 'b'), (1, 'c'), (3, 'd')]);
+    }
+
+    #[sqlx::test]
+    async fn batches_may_repeat_an_id(pool: PgPool) {
+        let mut connection = pool.acquire().await.unwrap();
+        let inserted = insert_tickets(
+            &mut connection,
+            vec![ticket(1, 12), ticket(2, 14), ticket(1, 16)],
+        )
+        .await
+        .unwrap();
+        assert_eq!(inserted, 2);
+        let office_number = sqlx::query_scalar!("SELECT office_number FROM tickets WHERE id = 1")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(office_number, 16);
+    }
+}
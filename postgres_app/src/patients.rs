@@ -0,0 +1,111 @@
+use crate::crypto::FieldCipher;
+use crate::models::{NewPatient, OptionPatient, Patient};
+use sqlx::PgPool;
+
+/// Registers a patient. Returns them decrypted, with the version of the row.
+pub async fn create(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    new_patient: &NewPatient,
+) -> Result<(Patient, i32), sqlx::Error> {
+    let row = sqlx::query!(
+        "INSERT INTO patients (name, surname, birth_date, phone_number, passport_number, phone_number_index, passport_number_index)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id, name, surname, birth_date, phone_number, passport_number, version",
+        new_patient.name,
+        new_patient.surname,
+        new_patient.birth_date,
+        cipher.encrypt(&new_patient.phone_number),
+        cipher.encrypt(&new_patient.passport_number),
+        cipher.blind_index(&new_patient.phone_number),
+        cipher.blind_index(&new_patient.passport_number),
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let patient = Patient {
+        id: row.id,
+        name: row.name,
+        surname: row.surname,
+        birth_date: row.birth_date,
+        phone_number: row.phone_number,
+        passport_number: row.passport_number,
+    };
+    Ok((cipher.decrypt_patient(patient), row.version))
+}
+
+/// Changes the given fields of a patient whose version is one of `expected_versions`, or of
+/// any version without them. `None` when no such patient was found.
+pub async fn update(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    id: i32,
+    option_patient: &OptionPatient,
+    expected_versions: Option<&[i32]>,
+) -> Result<Option<(Patient, i32)>, sqlx::Error> {
+    let row = sqlx::query!(
+        "UPDATE patients
+        SET name = COALESCE($2, name),
+            surname = COALESCE($3, surname),
+            birth_date = COALESCE($4, birth_date),
+            phone_number = COALESCE($5, phone_number),
+            passport_number = COALESCE($6, passport_number),
+            phone_number_index = COALESCE($8, phone_number_index),
+            passport_number_index = COALESCE($9, passport_number_index)
+        WHERE id = $1 AND ($7::INT[] IS NULL OR version = ANY($7))
+        RETURNING id, name, surname, birth_date, phone_number, passport_number, version",
+        id,
+        option_patient.name,
+        option_patient.surname,
+        option_patient.birth_date,
+        option_patient
+            .phone_number
+            .as_deref()
+            .map(|value| cipher.encrypt(value)),
+        option_patient
+            .passport_number
+            .as_deref()
+            .map(|value| cipher.encrypt(value)),
+        expected_versions,
+        option_patient
+            .phone_number
+            .as_deref()
+            .map(|value| cipher.blind_index(value)),
+        option_patient
+            .passport_number
+            .as_deref()
+            .map(|value| cipher.blind_index(value)),
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| {
+        let patient = Patient {
+            id: row.id,
+            name: row.name,
+            surname: row.surname,
+            birth_date: row.birth_date,
+            phone_number: row.phone_number,
+            passport_number: row.passport_number,
+        };
+        (cipher.decrypt_patient(patient), row.version)
+    }))
+}
+
+/// Deletes a patient whose version is one of `expected_versions`, or of any version without
+/// them. `false` when no such patient was found.
+pub async fn delete(
+    pool: &PgPool,
+    id: i32,
+    expected_versions: Option<&[i32]>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM patients WHERE id = $1 AND ($2::INT[] IS NULL OR version = ANY($2))",
+        id,
+        expected_versions,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
@@ -0,0 +1,403 @@
+use crate::crypto::FieldCipher;
+use crate::models::{Doctor, FullScheduleEntry};
+use crate::reports::{format_date, parse_date, weekday_name};
+use owned_ttf_parser::{AsFaceRef, GlyphId, OwnedFace};
+use printpdf::{
+    lopdf, Color, Error, FontData, FontMetrics, GlyphMetrics, Greyscale, IndirectFontRef, Line, Mm,
+    PdfDocument, PdfDocumentReference, PdfLayerReference, Point,
+};
+use sqlx::PgPool;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, OnceLock};
+use time::Date;
+
+pub const PDF_CONTENT_TYPE: &str = "application/pdf";
+
+/// DejaVu Sans covers Cyrillic, which the built-in PDF fonts do not.
+const REGULAR_FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSans.ttf");
+const BOLD_FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSans-Bold.ttf");
+
+/// Characters the embedded fonts are reduced to: Latin with accents, Cyrillic, digits and
+/// common punctuation. Anything else prints as an empty box.
+const CHARACTERS: &[RangeInclusive<char>] = &[
+    ' '..='~',
+    '\u{a0}'..='\u{17f}',
+    '\u{400}'..='\u{4ff}',
+    '\u{2013}'..='\u{2014}',
+    '\u{2026}'..='\u{2026}',
+    '\u{2116}'..='\u{2116}',
+];
+
+/// Slips are A6, so four of them fit on an A4 sheet.
+const SLIP_WIDTH: f32 = 105.0;
+const SLIP_HEIGHT: f32 = 148.0;
+
+const ROSTER_WIDTH: f32 = 210.0;
+const ROSTER_HEIGHT: f32 = 297.0;
+const ROSTER_ROWS_PER_PAGE: usize = 32;
+const ROSTER_ROW_HEIGHT: f32 = 7.0;
+
+const ROSTER_COLUMNS: &[(&str, f32, usize)] = &[
+    ("Time", 15.0, 8),
+    ("Office", 33.0, 8),
+    ("Patient", 53.0, 36),
+    ("Birth date", 123.0, 12),
+    ("Phone", 151.0, 20),
+];
+
+const MARGIN: f32 = 10.0;
+const ROSTER_MARGIN: f32 = 15.0;
+
+/// Loads one schedule entry with its ticket, doctor and patient.
+pub async fn fetch_schedule_entry(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    id: i32,
+) -> Result<Option<FullScheduleEntry>, sqlx::Error> {
+    let entry = sqlx::query_as!(
+        FullScheduleEntry,
+        "SELECT schedule.id as schedule_id, tickets.id as ticket_id, doctors.id as doctor_id, patients.id as patient_id,
+        tickets.date as ticket_date, tickets.time as ticket_time, tickets.office_number as ticket_office_number,
+        doctors.name as doctor_name, doctors.surname as doctor_surname, doctors.speciality as doctor_speciality,
+        doctors.phone_number as doctor_phone_number, doctors.passport_number as doctor_passport_number,
+        patients.name as patient_name, patients.surname as patient_surname, patients.birth_date as patient_birth_date,
+        patients.phone_number as patient_phone_number, patients.passport_number as patient_passport_number
+        FROM schedule
+        JOIN tickets ON schedule.ticket_id = tickets.id
+        JOIN doctors ON schedule.doctor_id = doctors.id
+        JOIN patients ON schedule.patient_id = patients.id
+        WHERE schedule.id = $1",
+        id,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(entry.map(|entry| cipher.decrypt_schedule_entry(entry)))
+}
+
+/// Glyph lookup and metrics come from the full face, so glyph ids match the subset, which
+/// only drops outlines and keeps ids as they are.
+#[derive(Clone, Debug)]
+struct SubsetFace {
+    face: Arc<OwnedFace>,
+    glyphs: Arc<BTreeMap<u16, char>>,
+}
+
+impl FontData for SubsetFace {
+    fn font_metrics(&self) -> FontMetrics {
+        let face = self.face.as_face_ref();
+        FontMetrics {
+            ascent: face.ascender(),
+            descent: face.descender(),
+            units_per_em: face.units_per_em(),
+        }
+    }
+
+    fn glyph_id(&self, c: char) -> Option<u16> {
+        self.face.as_face_ref().glyph_index(c).map(|id| id.0)
+    }
+
+    fn glyph_ids(&self) -> HashMap<u16, char> {
+        self.glyphs.iter().map(|(id, c)| (*id, *c)).collect()
+    }
+
+    fn glyph_count(&self) -> u16 {
+        self.face.as_face_ref().number_of_glyphs()
+    }
+
+    fn glyph_metrics(&self, glyph_id: u16) -> Option<GlyphMetrics> {
+        if glyph_id != 0 && !self.glyphs.contains_key(&glyph_id) {
+            return None;
+        }
+        let face = self.face.as_face_ref();
+        let width = face.glyph_hor_advance(GlyphId(glyph_id))?;
+        let height = face
+            .glyph_bounding_box(GlyphId(glyph_id))
+            .map(|bbox| bbox.y_max - bbox.y_min - face.descender())
+            .unwrap_or(1000);
+        Some(GlyphMetrics {
+            width: width as u32,
+            height: height as u32,
+        })
+    }
+}
+
+/// A bundled font reduced to `CHARACTERS`, which keeps every document small instead of
+/// embedding the whole face into each of them.
+struct EmbeddedFont {
+    face: SubsetFace,
+    bytes: Vec<u8>,
+}
+
+impl EmbeddedFont {
+    fn new(data: &'static [u8]) -> Self {
+        let face = OwnedFace::from_vec(data.to_vec(), 0).expect("Bundled fonts are valid");
+        let mut glyphs = BTreeMap::new();
+        for c in CHARACTERS.iter().cloned().flatten() {
+            if let Some(id) = face.as_face_ref().glyph_index(c) {
+                glyphs.entry(id.0).or_insert(c);
+            }
+        }
+
+        let ids: Vec<u16> = std::iter::once(0).chain(glyphs.keys().copied()).collect();
+        let bytes = subsetter::subset(data, 0, subsetter::Profile::pdf(&ids))
+            .expect("Bundled fonts can be subset");
+
+        EmbeddedFont {
+            face: SubsetFace {
+                face: Arc::new(face),
+                glyphs: Arc::new(glyphs),
+            },
+            bytes,
+        }
+    }
+
+    fn add_to(&self, document: &PdfDocumentReference) -> Result<IndirectFontRef, Error> {
+        document.add_external_font_data(self.bytes.clone(), self.face.clone())
+    }
+}
+
+struct Fonts {
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+}
+
+impl Fonts {
+    fn add_to(document: &PdfDocumentReference) -> Result<Self, Error> {
+        static REGULAR: OnceLock<EmbeddedFont> = OnceLock::new();
+        static BOLD: OnceLock<EmbeddedFont> = OnceLock::new();
+
+        Ok(Fonts {
+            regular: REGULAR
+                .get_or_init(|| EmbeddedFont::new(REGULAR_FONT))
+                .add_to(document)?,
+            bold: BOLD
+                .get_or_init(|| EmbeddedFont::new(BOLD_FONT))
+                .add_to(document)?,
+        })
+    }
+}
+
+/// Shortens text that would not fit into a column. There is no text measuring, so the limit
+/// is a number of characters.
+fn fit(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut fitted: String = text.chars().take(max_chars - 1).collect();
+    fitted.push('…');
+    fitted
+}
+
+fn grey(percent: f32) -> Color {
+    Color::Greyscale(Greyscale::new(percent, None))
+}
+
+fn rule(layer: &PdfLayerReference, from_x: f32, to_x: f32, y: f32) {
+    layer.add_line(Line {
+        points: vec![
+            (Point::new(Mm(from_x), Mm(y)), false),
+            (Point::new(Mm(to_x), Mm(y)), false),
+        ],
+        is_closed: false,
+    });
+}
+
+/// printpdf writes font streams uncompressed, while the outlines dropped from the subsets
+/// leave long runs of zeros that compress to almost nothing.
+fn save(document: PdfDocumentReference) -> Result<Vec<u8>, String> {
+    let bytes = document.save_to_bytes().map_err(|e| e.to_string())?;
+    let mut document = lopdf::Document::load_mem(&bytes).map_err(|e| e.to_string())?;
+    document.compress();
+
+    let mut compressed = Vec::new();
+    document
+        .save_to(&mut compressed)
+        .map_err(|e| e.to_string())?;
+    Ok(compressed)
+}
+
+/// `2024-01-31, Wednesday` for dates in the usual format, the stored text otherwise.
+fn day_label(date: &str) -> String {
+    match parse_date(date) {
+        Some(date) => format!("{}, {}", format_date(date), weekday_name(date.weekday())),
+        None => date.to_string(),
+    }
+}
+
+/// Renders a one-page slip the patient takes to the appointment.
+pub fn appointment_slip(entry: &FullScheduleEntry) -> Result<Vec<u8>, String> {
+    let (document, page, layer) = PdfDocument::new(
+        format!("Appointment slip {}", entry.schedule_id),
+        Mm(SLIP_WIDTH),
+        Mm(SLIP_HEIGHT),
+        "Slip",
+    );
+    let fonts = Fonts::add_to(&document).map_err(|e| e.to_string())?;
+    let layer = document.get_page(page).get_layer(layer);
+    let right = SLIP_WIDTH - MARGIN;
+
+    layer.use_text("Appointment slip", 14.0, Mm(MARGIN), Mm(134.0), &fonts.bold);
+    layer.set_fill_color(grey(0.4));
+    layer.use_text(
+        format!("No. {}", entry.schedule_id),
+        9.0,
+        Mm(MARGIN),
+        Mm(128.0),
+        &fonts.regular,
+    );
+    layer.set_outline_thickness(0.5);
+    rule(&layer, MARGIN, right, 124.0);
+
+    let rows = [
+        (
+            "Patient",
+            format!("{} {}", entry.patient_surname, entry.patient_name),
+        ),
+        ("Birth date", entry.patient_birth_date.clone()),
+        (
+            "Doctor",
+            format!("{} {}", entry.doctor_surname, entry.doctor_name),
+        ),
+        ("Speciality", entry.doctor_speciality.clone()),
+        ("Date", day_label(&entry.ticket_date)),
+        ("Time", entry.ticket_time.clone()),
+        ("Office", entry.ticket_office_number.to_string()),
+    ];
+
+    let mut y = 116.0;
+    for (label, value) in rows {
+        layer.set_fill_color(grey(0.4));
+        layer.use_text(label, 8.0, Mm(MARGIN), Mm(y), &fonts.regular);
+        layer.set_fill_color(grey(0.0));
+        layer.use_text(fit(&value, 34), 11.0, Mm(MARGIN), Mm(y - 5.0), &fonts.bold);
+        y -= 12.5;
+    }
+
+    rule(&layer, MARGIN, right, 22.0);
+    layer.set_fill_color(grey(0.4));
+    layer.use_text(
+        "Please arrive 10 minutes before the appointment.",
+        7.0,
+        Mm(MARGIN),
+        Mm(16.0),
+        &fonts.regular,
+    );
+
+    save(document)
+}
+
+fn roster_page(
+    layer: &PdfLayerReference,
+    fonts: &Fonts,
+    doctor: &Doctor,
+    date: Date,
+    page: usize,
+    pages: usize,
+) {
+    let right = ROSTER_WIDTH - ROSTER_MARGIN;
+
+    layer.use_text(
+        fit(&format!("{} {}", doctor.surname, doctor.name), 48),
+        16.0,
+        Mm(ROSTER_MARGIN),
+        Mm(279.0),
+        &fonts.bold,
+    );
+    layer.use_text(
+        fit(&doctor.speciality, 60),
+        11.0,
+        Mm(ROSTER_MARGIN),
+        Mm(272.0),
+        &fonts.regular,
+    );
+    layer.use_text(
+        format!("{}, {}", format_date(date), weekday_name(date.weekday())),
+        11.0,
+        Mm(ROSTER_MARGIN),
+        Mm(266.0),
+        &fonts.regular,
+    );
+
+    for (name, x, _) in ROSTER_COLUMNS {
+        layer.use_text(*name, 9.0, Mm(*x), Mm(256.0), &fonts.bold);
+    }
+    layer.set_outline_thickness(0.5);
+    rule(layer, ROSTER_MARGIN, right, 254.0);
+
+    layer.set_fill_color(grey(0.4));
+    layer.use_text(
+        format!("Page {} of {}", page, pages),
+        8.0,
+        Mm(ROSTER_MARGIN),
+        Mm(10.0),
+        &fonts.regular,
+    );
+    layer.set_fill_color(grey(0.0));
+}
+
+/// Renders the appointments of a doctor on one day, one row per entry, continued on further
+/// pages when they do not fit on one. `entries` must be ordered by time.
+pub fn doctor_roster(
+    doctor: &Doctor,
+    date: Date,
+    entries: &[FullScheduleEntry],
+) -> Result<Vec<u8>, String> {
+    let (document, first_page, first_layer) = PdfDocument::new(
+        format!(
+            "Roster {} {}, {}",
+            doctor.surname,
+            doctor.name,
+            format_date(date)
+        ),
+        Mm(ROSTER_WIDTH),
+        Mm(ROSTER_HEIGHT),
+        "Roster",
+    );
+    let fonts = Fonts::add_to(&document).map_err(|e| e.to_string())?;
+    let pages = entries.len().div_ceil(ROSTER_ROWS_PER_PAGE).max(1);
+
+    let layer = document.get_page(first_page).get_layer(first_layer);
+    roster_page(&layer, &fonts, doctor, date, 1, pages);
+    if entries.is_empty() {
+        layer.use_text(
+            "No appointments",
+            10.0,
+            Mm(ROSTER_MARGIN),
+            Mm(246.0),
+            &fonts.regular,
+        );
+    }
+
+    for (index, page_entries) in entries.chunks(ROSTER_ROWS_PER_PAGE).enumerate() {
+        let layer = if index == 0 {
+            layer.clone()
+        } else {
+            let (page, layer) = document.add_page(Mm(ROSTER_WIDTH), Mm(ROSTER_HEIGHT), "Roster");
+            let layer = document.get_page(page).get_layer(layer);
+            roster_page(&layer, &fonts, doctor, date, index + 1, pages);
+            layer
+        };
+
+        layer.set_outline_color(grey(0.8));
+        layer.set_outline_thickness(0.2);
+        let mut y = 248.0;
+        for entry in page_entries {
+            let cells = [
+                entry.ticket_time.clone(),
+                entry.ticket_office_number.to_string(),
+                format!("{} {}", entry.patient_surname, entry.patient_name),
+                entry.patient_birth_date.clone(),
+                entry.patient_phone_number.clone(),
+            ];
+            for ((_, x, max_chars), cell) in ROSTER_COLUMNS.iter().zip(cells) {
+                layer.use_text(fit(&cell, *max_chars), 9.0, Mm(*x), Mm(y), &fonts.regular);
+            }
+            rule(&layer, ROSTER_MARGIN, ROSTER_WIDTH - ROSTER_MARGIN, y - 2.5);
+            y -= ROSTER_ROW_HEIGHT;
+        }
+    }
+
+    save(document)
+}
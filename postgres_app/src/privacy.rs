@@ -0,0 +1,298 @@
+use crate::crypto::FieldCipher;
+use crate::models::{
+    ErasureResult, FullScheduleEntry, Patient, PatientDataExport, PatientErasureEntry,
+    PatientHistoryEntry, PatientMergeEntry,
+};
+use sqlx::PgPool;
+use std::fmt;
+use std::io::{Cursor, Write};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+/// Value written over names of erased patients.
+pub const ERASED_NAME: &str = "Erased";
+
+pub enum ErasureError {
+    Database(sqlx::Error),
+    /// A Sled snapshot could not be scrubbed, so nothing was erased in the database.
+    Sled(sled::Error),
+}
+
+impl From<sqlx::Error> for ErasureError {
+    fn from(error: sqlx::Error) -> Self {
+        ErasureError::Database(error)
+    }
+}
+
+impl From<sled::Error> for ErasureError {
+    fn from(error: sled::Error) -> Self {
+        ErasureError::Sled(error)
+    }
+}
+
+impl fmt::Display for ErasureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErasureError::Database(error) => write!(f, "{}", error),
+            ErasureError::Sled(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Returns the patient and every patient that was merged into it, directly or through
+/// an earlier merge.
+async fn patient_identities<'e, E>(executor: E, id: i32) -> Result<Vec<i32>, sqlx::Error>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let ids = sqlx::query_scalar!(
+        r#"WITH RECURSIVE identities (id) AS (
+            SELECT $1::INT
+            UNION
+            SELECT patient_merges.loser_id
+            FROM patient_merges
+            JOIN identities ON patient_merges.survivor_id = identities.id
+        )
+        SELECT id as "id!" FROM identities ORDER BY id"#,
+        id,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(ids)
+}
+
+/// Collects everything stored about a patient, with phone and passport numbers decrypted.
+pub async fn collect_patient_data(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    id: i32,
+) -> Result<Option<PatientDataExport>, sqlx::Error> {
+    let patient = sqlx::query_as!(
+        Patient,
+        "SELECT id, name, surname, birth_date, phone_number, passport_number
+        FROM patients
+        WHERE id = $1",
+        id,
+    )
+    .fetch_optional(pool)
+    .await?;
+    let patient = match patient {
+        Some(patient) => cipher.decrypt_patient(patient),
+        None => return Ok(None),
+    };
+
+    let ids = patient_identities(pool, id).await?;
+
+    let schedule = sqlx::query_as!(
+        FullScheduleEntry,
+        "SELECT schedule.id as schedule_id, tickets.id as ticket_id, doctors.id as doctor_id, patients.id as patient_id,
+        tickets.date as ticket_date, tickets.time as ticket_time, tickets.office_number as ticket_office_number,
+        doctors.name as doctor_name, doctors.surname as doctor_surname, doctors.speciality as doctor_speciality,
+        doctors.phone_number as doctor_phone_number, doctors.passport_number as doctor_passport_number,
+        patients.name as patient_name, patients.surname as patient_surname, patients.birth_date as patient_birth_date,
+        patients.phone_number as patient_phone_number, patients.passport_number as patient_passport_number
+        FROM schedule
+        JOIN tickets ON schedule.ticket_id = tickets.id
+        JOIN doctors ON schedule.doctor_id = doctors.id
+        JOIN patients ON schedule.patient_id = patients.id
+        WHERE schedule.patient_id = $1
+        ORDER BY schedule.id",
+        id,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|entry| cipher.decrypt_schedule_entry(entry))
+    .collect();
+
+    let history = sqlx::query_as!(
+        PatientHistoryEntry,
+        r#"SELECT history_id, id, name, surname, birth_date, phone_number, passport_number, operation,
+            valid_from::TEXT as "valid_from!", valid_to::TEXT as valid_to, closed_by
+        FROM patients_history
+        WHERE id = ANY($1)
+        ORDER BY valid_from, history_id"#,
+        &ids,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|entry| cipher.decrypt_patient_history(entry))
+    .collect();
+
+    let merges = sqlx::query_as!(
+        PatientMergeEntry,
+        r#"SELECT id, survivor_id, loser_id, moved_schedule_entries, merged_at::TEXT as "merged_at!"
+        FROM patient_merges
+        WHERE survivor_id = ANY($1) OR loser_id = ANY($1)
+        ORDER BY merged_at, id"#,
+        &ids,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let erasures = sqlx::query_as!(
+        PatientErasureEntry,
+        r#"SELECT id, patient_id, erased_ids, history_entries_scrubbed, erased_at::TEXT as "erased_at!"
+        FROM patient_erasures
+        WHERE patient_id = ANY($1)
+        ORDER BY erased_at, id"#,
+        &ids,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(Some(PatientDataExport {
+        patient,
+        schedule,
+        history,
+        merges,
+        erasures,
+    }))
+}
+
+/// Packs the export into a zip archive with one JSON file per kind of record.
+pub fn build_archive(export: &PatientDataExport) -> Result<Vec<u8>, String> {
+    let files = [
+        ("patient.json", serde_json::to_vec_pretty(&export.patient)),
+        ("schedule.json", serde_json::to_vec_pretty(&export.schedule)),
+        ("history.json", serde_json::to_vec_pretty(&export.history)),
+        ("merges.json", serde_json::to_vec_pretty(&export.merges)),
+        ("erasures.json", serde_json::to_vec_pretty(&export.erasures)),
+    ];
+
+    let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, contents) in files {
+        let contents = contents.map_err(|e| e.to_string())?;
+        archive
+            .start_file(name, SimpleFileOptions::default())
+            .map_err(|e| e.to_string())?;
+        archive.write_all(&contents).map_err(|e| e.to_string())?;
+    }
+
+    let archive = archive.finish().map_err(|e| e.to_string())?;
+    Ok(archive.into_inner())
+}
+
+/// Anonymizes a patient and every patient merged into it.
+///
+/// Rows are kept, so schedule entries still point at them and schedule statistics do not
+/// change, but names, birth dates, phone and passport numbers are overwritten in the table,
+/// in every history version and in every Sled snapshot, anonymized ones included. Responses
+/// about the patient kept for `Idempotency-Key` retries are deleted.
+///
+/// Sled snapshots are scrubbed before the database transaction commits, so a failure there
+/// leaves the patient unerased and can be retried.
+pub async fn erase_patient(
+    pool: &PgPool,
+    sled_dbs: &[&sled::Db],
+    cipher: &FieldCipher,
+    id: i32,
+) -> Result<Option<ErasureResult>, ErasureError> {
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query!("SELECT set_config('hospital.history_operation', 'ERASE', true)")
+        .fetch_one(&mut *transaction)
+        .await?;
+
+    let erased = sqlx::query!(
+        "UPDATE patients
+        SET name = $2, surname = $2, birth_date = '',
+            phone_number = $3, phone_number_index = $4,
+            passport_number = $3, passport_number_index = $4
+        WHERE id = $1
+        RETURNING id",
+        id,
+        ERASED_NAME,
+        cipher.encrypt(""),
+        cipher.blind_index(""),
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    if erased.is_none() {
+        return Ok(None);
+    }
+
+    let erased_ids = patient_identities(&mut *transaction, id).await?;
+
+    let scrubbed = sqlx::query!(
+        "UPDATE patients_history
+        SET name = $2, surname = $2, birth_date = '', phone_number = $3, passport_number = $3
+        WHERE id = ANY($1)",
+        &erased_ids,
+        ERASED_NAME,
+        cipher.encrypt(""),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let history_entries_scrubbed = scrubbed.rows_affected();
+
+    sqlx::query!(
+        "DELETE FROM notification_preferences WHERE patient_id = ANY($1)",
+        &erased_ids,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let stored_responses_deleted = sqlx::query!(
+        "DELETE FROM idempotency_keys
+        WHERE path IN ('/patients', '/fhir/Patient') AND resource_id = ANY($1)",
+        &erased_ids.iter().map(|&id| id as i64).collect::<Vec<_>>(),
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+
+    sqlx::query!(
+        "INSERT INTO patient_erasures (patient_id, erased_ids, history_entries_scrubbed)
+        VALUES ($1, $2, $3)",
+        id,
+        &erased_ids,
+        history_entries_scrubbed as i32,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let mut sled_entries_scrubbed = 0;
+    for sled_db in sled_dbs {
+        sled_entries_scrubbed += scrub_sled(sled_db, &erased_ids)?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(Some(ErasureResult {
+        patient_id: id,
+        erased_ids,
+        history_entries_scrubbed,
+        sled_entries_scrubbed,
+        stored_responses_deleted,
+    }))
+}
+
+/// Overwrites exported patients with anonymized copies. Keys stay in place, so that
+/// importing the snapshot still restores schedule entries pointing at them.
+pub fn scrub_sled(sled_db: &sled::Db, ids: &[i32]) -> Result<u64, sled::Error> {
+    let mut scrubbed = 0;
+    for &id in ids {
+        let key = format!("patient:{}", id);
+        if !sled_db.contains_key(&key)? {
+            continue;
+        }
+
+        let patient = Patient {
+            id,
+            name: ERASED_NAME.to_string(),
+            surname: ERASED_NAME.to_string(),
+            birth_date: String::new(),
+            phone_number: String::new(),
+            passport_number: String::new(),
+        };
+        let value = serde_json::to_vec(&patient).unwrap();
+        sled_db.insert(key, value)?;
+        scrubbed += 1;
+    }
+
+    sled_db.flush()?;
+    Ok(scrubbed)
+}
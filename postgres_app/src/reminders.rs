@@ -0,0 +1,746 @@
+use crate::config::{SmsConfig, SmtpConfig, SmtpSecurity};
+use crate::crypto::FieldCipher;
+use crate::reports::{format_date, parse_date, parse_time};
+use async_trait::async_trait;
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use serde_json::json;
+use sqlx::PgPool;
+use std::sync::Arc;
+use time::{Date, Duration, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+use tracing::error;
+
+/// Reminders and how many hours ahead of the appointment they are sent, earliest first. A
+/// reminder is skipped when the appointment is booked after the next one is already due.
+pub const REMINDERS: [(&str, i64); 2] = [("24h", 24), ("2h", 2)];
+
+/// How often upcoming appointments are checked.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Failed sends are retried on later passes, at most this many times in all.
+const MAX_ATTEMPTS: i32 = 3;
+
+pub const LOCALES: [&str; 2] = ["ru", "en"];
+
+#[derive(Clone, Copy)]
+pub enum Locale {
+    Ru,
+    En,
+}
+
+impl Locale {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ru" => Some(Locale::Ru),
+            "en" => Some(Locale::En),
+            _ => None,
+        }
+    }
+}
+
+pub struct ReminderSettings {
+    /// Offset of ticket dates and times from UTC, `+03:00` style.
+    pub utc_offset: UtcOffset,
+    /// Language of patients who have not chosen one.
+    pub default_locale: Locale,
+}
+
+pub fn validate_preferences(email: Option<&str>, locale: Option<&str>) -> Result<(), String> {
+    if let Some(email) = email {
+        if email.parse::<Mailbox>().is_err() {
+            return Err(format!("`{}` is not an email address", email));
+        }
+    }
+    if let Some(locale) = locale {
+        if Locale::parse(locale).is_none() {
+            return Err(format!(
+                "Locale `{}` must be one of {}",
+                locale,
+                LOCALES.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// An appointment in the reminder window, with the patient's contacts decrypted.
+pub struct Appointment {
+    schedule_id: i32,
+    patient_id: i32,
+    date: String,
+    time: String,
+    office_number: i32,
+    doctor_name: String,
+    doctor_surname: String,
+    doctor_speciality: String,
+    patient_name: String,
+    phone_number: String,
+    email: Option<String>,
+    locale: Option<String>,
+    email_enabled: bool,
+    sms_enabled: bool,
+}
+
+pub struct ReminderText {
+    pub subject: String,
+    pub body: String,
+}
+
+/// A way of reaching patients. Each backend picks its recipient from the appointment, so that
+/// adding one does not change the scheduler.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// Name of the channel in the notification log.
+    fn channel(&self) -> &'static str;
+
+    /// `None` when the patient cannot be or does not want to be reached this way.
+    fn recipient(&self, appointment: &Appointment) -> Option<String>;
+
+    async fn send(&self, recipient: &str, text: &ReminderText) -> Result<(), String>;
+}
+
+pub struct SmtpNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpNotifier {
+    pub fn new(config: &SmtpConfig) -> Result<Self, String> {
+        let builder = match config.security {
+            SmtpSecurity::Plain => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
+            }
+            SmtpSecurity::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+                    .map_err(|e| e.to_string())?
+            }
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)
+                .map_err(|e| e.to_string())?,
+        };
+        let builder = match &config.credentials {
+            Some((username, password)) => builder
+                .port(config.port)
+                .credentials(Credentials::new(username.clone(), password.clone())),
+            None => builder.port(config.port),
+        };
+
+        Ok(SmtpNotifier {
+            transport: builder.build(),
+            from: config.from.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for SmtpNotifier {
+    fn channel(&self) -> &'static str {
+        "email"
+    }
+
+    fn recipient(&self, appointment: &Appointment) -> Option<String> {
+        appointment
+            .email
+            .clone()
+            .filter(|email| appointment.email_enabled && !email.is_empty())
+    }
+
+    async fn send(&self, recipient: &str, text: &ReminderText) -> Result<(), String> {
+        let to = recipient
+            .parse()
+            .map_err(|_| format!("`{}` is not an email address", recipient))?;
+        let message = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&text.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(text.body.clone())
+            .map_err(|e| e.to_string())?;
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Posts `{"to": ..., "text": ..., "sender": ...}` as JSON to the gateway URL, with the
+/// gateway token as a bearer token when it is set. Most gateways take such a request
+/// directly or through a small adapter.
+pub struct SmsGatewayNotifier {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+    sender: Option<String>,
+}
+
+impl SmsGatewayNotifier {
+    pub fn new(config: &SmsConfig) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        Ok(SmsGatewayNotifier {
+            client,
+            url: config.gateway_url.clone(),
+            token: config.gateway_token.clone(),
+            sender: config.sender.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for SmsGatewayNotifier {
+    fn channel(&self) -> &'static str {
+        "sms"
+    }
+
+    fn recipient(&self, appointment: &Appointment) -> Option<String> {
+        Some(appointment.phone_number.clone())
+            .filter(|phone_number| appointment.sms_enabled && !phone_number.is_empty())
+    }
+
+    async fn send(&self, recipient: &str, text: &ReminderText) -> Result<(), String> {
+        let mut request = self.client.post(&self.url).json(&json!({
+            "to": recipient,
+            "text": text.body,
+            "sender": self.sender,
+        }));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("SMS gateway answered {}", response.status()))
+        }
+    }
+}
+
+/// The configured backends; empty when neither SMTP nor an SMS gateway is set up.
+pub fn notifiers(
+    smtp: Option<&SmtpConfig>,
+    sms: Option<&SmsConfig>,
+) -> Result<Vec<Box<dyn Notifier>>, String> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    if let Some(smtp) = smtp {
+        notifiers.push(Box::new(SmtpNotifier::new(smtp)?));
+    }
+    if let Some(sms) = sms {
+        notifiers.push(Box::new(SmsGatewayNotifier::new(sms)?));
+    }
+    Ok(notifiers)
+}
+
+fn render(locale: Locale, appointment: &Appointment, date: Date) -> ReminderText {
+    let (month, day) = (u8::from(date.month()), date.day());
+    match locale {
+        Locale::Ru => ReminderText {
+            subject: format!(
+                "Напоминание о приёме {:02}.{:02}.{} в {}",
+                day,
+                month,
+                date.year(),
+                appointment.time
+            ),
+            body: format!(
+                "Здравствуйте, {}! Напоминаем о приёме у врача {} {} ({}) {:02}.{:02}.{} в {}, кабинет {}. \
+                Если вы не сможете прийти, пожалуйста, сообщите нам заранее.",
+                appointment.patient_name,
+                appointment.doctor_name,
+                appointment.doctor_surname,
+                appointment.doctor_speciality,
+                day,
+                month,
+                date.year(),
+                appointment.time,
+                appointment.office_number
+            ),
+        },
+        Locale::En => ReminderText {
+            subject: format!(
+                "Appointment reminder: {} at {}",
+                appointment.date, appointment.time
+            ),
+            body: format!(
+                "Hello {}! This is a reminder of your appointment with {} {} ({}) on {} at {}, office {}. \
+                If you cannot come, please let us know in advance.",
+                appointment.patient_name,
+                appointment.doctor_name,
+                appointment.doctor_surname,
+                appointment.doctor_speciality,
+                appointment.date,
+                appointment.time,
+                appointment.office_number
+            ),
+        },
+    }
+}
+
+/// The reminder due for an appointment at `appointment_at`, if any.
+fn due_reminder(appointment_at: OffsetDateTime, now: OffsetDateTime) -> Option<&'static str> {
+    let ahead = appointment_at - now;
+    REMINDERS
+        .iter()
+        .enumerate()
+        .find(|(i, (_, hours))| {
+            let next_hours = REMINDERS.get(i + 1).map_or(0, |(_, hours)| *hours);
+            ahead <= Duration::hours(*hours) && ahead > Duration::hours(next_hours)
+        })
+        .map(|(_, (reminder, _))| *reminder)
+}
+
+/// Sends due reminders until the process exits.
+pub async fn run(
+    pool: PgPool,
+    cipher: Arc<FieldCipher>,
+    settings: ReminderSettings,
+    notifiers: Vec<Box<dyn Notifier>>,
+) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = send_due(&pool, &cipher, &settings, &notifiers).await {
+            error!(error = %e, "Failed to send appointment reminders");
+        }
+    }
+}
+
+async fn send_due(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    settings: &ReminderSettings,
+    notifiers: &[Box<dyn Notifier>],
+) -> Result<(), sqlx::Error> {
+    let now = OffsetDateTime::now_utc();
+    let longest_lead = Duration::hours(REMINDERS[0].1);
+    let first_day = now.to_offset(settings.utc_offset).date();
+    let last_day = (now + longest_lead).to_offset(settings.utc_offset).date();
+
+    let appointments = sqlx::query_as!(
+        Appointment,
+        r#"SELECT schedule.id as schedule_id, schedule.patient_id, tickets.date, tickets.time,
+            tickets.office_number, doctors.name as doctor_name, doctors.surname as doctor_surname,
+            doctors.speciality as doctor_speciality, patients.name as patient_name, patients.phone_number,
+            notification_preferences.email as "email?", notification_preferences.locale as "locale?",
+            COALESCE(notification_preferences.email_enabled, TRUE) as "email_enabled!",
+            COALESCE(notification_preferences.sms_enabled, TRUE) as "sms_enabled!"
+        FROM schedule
+        JOIN tickets ON schedule.ticket_id = tickets.id
+        JOIN doctors ON schedule.doctor_id = doctors.id
+        JOIN patients ON schedule.patient_id = patients.id
+        LEFT JOIN notification_preferences ON notification_preferences.patient_id = patients.id
+        WHERE tickets.date BETWEEN $1 AND $2
+        ORDER BY schedule.id"#,
+        format_date(first_day),
+        format_date(last_day),
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for mut appointment in appointments {
+        let (Some(date), Some((hour, minute))) =
+            (parse_date(&appointment.date), parse_time(&appointment.time))
+        else {
+            continue;
+        };
+        let Ok(time) = Time::from_hms(hour as u8, minute, 0) else {
+            continue;
+        };
+        let appointment_at = PrimitiveDateTime::new(date, time).assume_offset(settings.utc_offset);
+        let Some(reminder) = due_reminder(appointment_at, now) else {
+            continue;
+        };
+
+        appointment.phone_number = cipher.decrypt(&appointment.phone_number);
+        appointment.email = appointment.email.map(|email| cipher.decrypt(&email));
+        let locale = appointment
+            .locale
+            .as_deref()
+            .and_then(Locale::parse)
+            .unwrap_or(settings.default_locale);
+        let text = render(locale, &appointment, date);
+
+        for notifier in notifiers {
+            let Some(recipient) = notifier.recipient(&appointment) else {
+                continue;
+            };
+            let Some(log_id) = claim(
+                pool,
+                &appointment,
+                reminder,
+                notifier.channel(),
+                appointment_at,
+            )
+            .await?
+            else {
+                continue;
+            };
+
+            let error = notifier.send(&recipient, &text).await.err();
+            if let Some(error) = &error {
+                error!(
+                    reminder = %reminder,
+                    channel = notifier.channel(),
+                    schedule_id = appointment.schedule_id,
+                    error = %error,
+                    "Failed to send a reminder"
+                );
+            }
+            sqlx::query!(
+                "UPDATE notification_log
+                SET status = CASE WHEN $2::TEXT IS NULL THEN 'sent' ELSE 'failed' END,
+                    error = $2,
+                    updated_at = now()
+                WHERE id = $1",
+                log_id,
+                error,
+            )
+            .execute(pool)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Records that a reminder is being sent and returns the log entry, or `None` when it has
+/// already been sent, is being sent, or failed too often or too recently to be retried.
+async fn claim(
+    pool: &PgPool,
+    appointment: &Appointment,
+    reminder: &str,
+    channel: &str,
+    appointment_at: OffsetDateTime,
+) -> Result<Option<i64>, sqlx::Error> {
+    let claimed = sqlx::query_scalar!(
+        "INSERT INTO notification_log (schedule_id, patient_id, reminder, channel, appointment_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (schedule_id, reminder, channel, appointment_at) DO UPDATE
+        SET status = 'sending',
+            attempts = notification_log.attempts + 1,
+            error = NULL,
+            updated_at = now()
+        WHERE notification_log.status = 'failed'
+            AND notification_log.attempts < $6
+            AND notification_log.updated_at < now() - interval '5 minutes'
+        RETURNING id",
+        appointment.schedule_id,
+        appointment.patient_id,
+        reminder,
+        channel,
+        appointment_at,
+        MAX_ATTEMPTS,
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(claimed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PiiConfig;
+    use crate::models::{NewDoctor, NewPatient, NewScheduleEntry, NewTicket};
+    use crate::{doctors, patients, schedule, tickets};
+    use std::sync::Mutex;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    fn appointment() -> Appointment {
+        Appointment {
+            schedule_id: 1,
+            patient_id: 1,
+            date: "2026-03-05".to_string(),
+            time: "09:30".to_string(),
+            office_number: 12,
+            doctor_name: "Anna".to_string(),
+            doctor_surname: "Petrova".to_string(),
+            doctor_speciality: "Cardiologist".to_string(),
+            patient_name: "Ivan".to_string(),
+            phone_number: "+70000000000".to_string(),
+            email: Some("ivan@example.com".to_string()),
+            locale: None,
+            email_enabled: true,
+            sms_enabled: true,
+        }
+    }
+
+    /// Records sent reminders, or fails every send when `fail` is set.
+    struct RecordingNotifier {
+        sent: Arc<Mutex<Vec<String>>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl Notifier for RecordingNotifier {
+        fn channel(&self) -> &'static str {
+            "sms"
+        }
+
+        fn recipient(&self, appointment: &Appointment) -> Option<String> {
+            Some(appointment.phone_number.clone())
+        }
+
+        async fn send(&self, recipient: &str, text: &ReminderText) -> Result<(), String> {
+            self.sent
+                .lock()
+                .unwrap()
+                .push(format!("{}: {}", recipient, text.subject));
+            if self.fail {
+                Err("gateway is down".to_string())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Books an appointment starting in an hour and a half, due for the 2h reminder.
+    async fn book_soon(pool: &PgPool, cipher: &FieldCipher) -> i32 {
+        let (doctor, _) = doctors::create(
+            pool,
+            cipher,
+            &NewDoctor {
+                name: "Anna".to_string(),
+                surname: "Petrova".to_string(),
+                speciality: "Cardiologist".to_string(),
+                phone_number: "+71111111111".to_string(),
+                passport_number: "1111 111111".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        let (patient, _) = patients::create(
+            pool,
+            cipher,
+            &NewPatient {
+                name: "Ivan".to_string(),
+                surname: "Ivanov".to_string(),
+                birth_date: "1990-01-01".to_string(),
+                phone_number: "+72222222222".to_string(),
+                passport_number: "2222 222222".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        let at = OffsetDateTime::now_utc() + Duration::minutes(90);
+        let (ticket, _) = tickets::create(
+            pool,
+            &NewTicket {
+                date: format_date(at.date()),
+                time: format!("{:02}:{:02}", at.hour(), at.minute()),
+                office_number: 12,
+            },
+        )
+        .await
+        .unwrap();
+        schedule::book(
+            pool,
+            &NewScheduleEntry {
+                ticket_id: ticket.id,
+                doctor_id: doctor.id,
+                patient_id: patient.id,
+            },
+        )
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    fn settings() -> ReminderSettings {
+        ReminderSettings {
+            utc_offset: UtcOffset::UTC,
+            default_locale: Locale::En,
+        }
+    }
+
+    #[test]
+    fn render_fills_the_template_of_the_locale() {
+        let appointment = appointment();
+        let date = parse_date(&appointment.date).unwrap();
+
+        let text = render(Locale::En, &appointment, date);
+        assert_eq!(text.subject, "Appointment reminder: 2026-03-05 at 09:30");
+        assert!(text.body.starts_with(
+            "Hello Ivan! This is a reminder of your appointment with Anna Petrova (Cardiologist) \
+            on 2026-03-05 at 09:30, office 12."
+        ));
+
+        let text = render(Locale::Ru, &appointment, date);
+        assert_eq!(text.subject, "Напоминание о приёме 05.03.2026 в 09:30");
+        assert!(text
+            .body
+            .starts_with("Здравствуйте, Ivan! Напоминаем о приёме у врача Anna Petrova"));
+        assert!(text.body.contains("05.03.2026 в 09:30, кабинет 12."));
+    }
+
+    #[test]
+    fn due_reminder_picks_the_latest_window() {
+        let now = OffsetDateTime::now_utc();
+        assert_eq!(due_reminder(now + Duration::hours(30), now), None);
+        assert_eq!(due_reminder(now + Duration::hours(24), now), Some("24h"));
+        assert_eq!(due_reminder(now + Duration::hours(3), now), Some("24h"));
+        assert_eq!(due_reminder(now + Duration::hours(2), now), Some("2h"));
+        assert_eq!(due_reminder(now + Duration::minutes(1), now), Some("2h"));
+        assert_eq!(due_reminder(now - Duration::minutes(1), now), None);
+    }
+
+    #[sqlx::test]
+    async fn send_due_sends_each_reminder_once(pool: PgPool) {
+        let cipher = FieldCipher::new(&PiiConfig::default()).unwrap();
+        let schedule_id = book_soon(&pool, &cipher).await;
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(RecordingNotifier {
+            sent: sent.clone(),
+            fail: false,
+        })];
+
+        send_due(&pool, &cipher, &settings(), &notifiers)
+            .await
+            .unwrap();
+        send_due(&pool, &cipher, &settings(), &notifiers)
+            .await
+            .unwrap();
+
+        let sent = sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].starts_with("+72222222222: Appointment reminder"));
+        let log = sqlx::query!(
+            "SELECT reminder, channel, status, attempts FROM notification_log WHERE schedule_id = $1",
+            schedule_id,
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(
+            (log[0].reminder.as_str(), log[0].channel.as_str()),
+            ("2h", "sms")
+        );
+        assert_eq!((log[0].status.as_str(), log[0].attempts), ("sent", 1));
+    }
+
+    #[sqlx::test]
+    async fn send_due_retries_failures_a_limited_number_of_times(pool: PgPool) {
+        let cipher = FieldCipher::new(&PiiConfig::default()).unwrap();
+        let schedule_id = book_soon(&pool, &cipher).await;
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(RecordingNotifier {
+            sent: sent.clone(),
+            fail: true,
+        })];
+
+        for _ in 0..MAX_ATTEMPTS + 2 {
+            send_due(&pool, &cipher, &settings(), &notifiers)
+                .await
+                .unwrap();
+            // Not retried before the backoff passes.
+            send_due(&pool, &cipher, &settings(), &notifiers)
+                .await
+                .unwrap();
+            sqlx::query!(
+                "UPDATE notification_log SET updated_at = updated_at - interval '10 minutes'"
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(sent.lock().unwrap().len(), MAX_ATTEMPTS as usize);
+        let log = sqlx::query!(
+            "SELECT status, attempts, error FROM notification_log WHERE schedule_id = $1",
+            schedule_id,
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            (log.status.as_str(), log.attempts),
+            ("failed", MAX_ATTEMPTS)
+        );
+        assert_eq!(log.error.as_deref(), Some("gateway is down"));
+    }
+
+    /// Accepts one message over plain SMTP and returns the conversation as received.
+    async fn smtp_sink() -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut received = String::new();
+            let mut in_data = false;
+            writer.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                received.push_str(&line);
+                received.push('\n');
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 Queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250 sink\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+                if reply.starts_with(b"250 Queued") {
+                    break;
+                }
+            }
+            received
+        });
+        (port, handle)
+    }
+
+    #[tokio::test]
+    async fn smtp_notifier_delivers_to_the_relay() {
+        let (port, received) = smtp_sink().await;
+        let notifier = SmtpNotifier {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1")
+                .port(port)
+                .build(),
+            from: "clinic@example.com".parse().unwrap(),
+        };
+        let appointment = appointment();
+        let recipient = notifier.recipient(&appointment).unwrap();
+        let text = render(
+            Locale::En,
+            &appointment,
+            parse_date(&appointment.date).unwrap(),
+        );
+
+        notifier.send(&recipient, &text).await.unwrap();
+
+        let received = received.await.unwrap();
+        assert!(received.contains("MAIL FROM:<clinic@example.com>"));
+        assert!(received.contains("RCPT TO:<ivan@example.com>"));
+        assert!(received.contains("Subject: Appointment reminder: 2026-03-05 at 09:30"));
+        assert!(received.contains("Hello Ivan!"));
+    }
+
+    #[test]
+    fn smtp_notifier_respects_preferences() {
+        let notifier = SmtpNotifier {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1").build(),
+            from: "clinic@example.com".parse().unwrap(),
+        };
+        let mut appointment = appointment();
+        appointment.email_enabled = false;
+        assert_eq!(notifier.recipient(&appointment), None);
+        appointment.email_enabled = true;
+        appointment.email = None;
+        assert_eq!(notifier.recipient(&appointment), None);
+    }
+}
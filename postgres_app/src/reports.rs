@@ -0,0 +1,422 @@
+use crate::crypto::FieldCipher;
+use crate::models::{FullScheduleEntry, ScheduleReportFilter};
+use rust_xlsxwriter::{
+    ExcelDateTime, Format, FormatAlign, FormatBorder, Workbook, Worksheet, XlsxError,
+};
+use sqlx::PgPool;
+use time::{Date, Duration, OffsetDateTime, Weekday};
+
+pub const XLSX_CONTENT_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+/// Longest period a single report may cover.
+const MAX_REPORT_DAYS: i64 = 366;
+
+/// Excel refuses longer sheet names.
+const MAX_SHEET_NAME_LENGTH: usize = 31;
+
+const COLUMNS: &[(&str, f64)] = &[
+    ("Date", 12.0),
+    ("Day", 12.0),
+    ("Time", 8.0),
+    ("Office", 8.0),
+    ("Patient", 30.0),
+    ("Birth date", 12.0),
+    ("Phone", 18.0),
+];
+
+/// Title rows above the table header.
+const HEADER_ROW: u32 = 3;
+
+/// Reads the `YYYY-MM-DD` dates the application writes. The `parse_date` SQL function of
+/// migration 0017 accepts the same values.
+pub fn parse_date(value: &str) -> Option<Date> {
+    let mut parts = value.trim().splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month: u8 = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    Date::from_calendar_date(year, month.try_into().ok()?, day).ok()
+}
+
+pub fn format_date(date: Date) -> String {
+    format!(
+        "{:04}-{:02}-{:02}",
+        date.year(),
+        date.month() as u8,
+        date.day()
+    )
+}
+
+pub fn parse_time(value: &str) -> Option<(u16, u8)> {
+    let mut parts = value.trim().split(':');
+    let hour: u16 = parts.next()?.parse().ok()?;
+    let minute: u8 = parts.next()?.parse().ok()?;
+    (hour < 24 && minute < 60).then_some((hour, minute))
+}
+
+/// Resolves the report period. Without `from` and `to` it is the current week, Monday to
+/// Sunday; with only one of them it is the week starting or ending on that day.
+pub fn report_period(filter: &ScheduleReportFilter) -> Result<(Date, Date), String> {
+    let parse = |value: &Option<String>, name: &str| match value.as_deref() {
+        None | Some("") => Ok(None),
+        Some(value) => parse_date(value)
+            .map(Some)
+            .ok_or_else(|| format!("`{}` must be a date like 2024-01-31", name)),
+    };
+
+    let (from, to) = match (parse(&filter.from, "from")?, parse(&filter.to, "to")?) {
+        (Some(from), Some(to)) => (from, to),
+        (Some(from), None) => (from, from + Duration::days(6)),
+        (None, Some(to)) => (to - Duration::days(6), to),
+        (None, None) => {
+            let today = OffsetDateTime::now_utc().date();
+            let monday = today - Duration::days(today.weekday().number_days_from_monday() as i64);
+            (monday, monday + Duration::days(6))
+        }
+    };
+
+    if to < from {
+        return Err("`to` must not be before `from`".to_string());
+    }
+    if (to - from).whole_days() >= MAX_REPORT_DAYS {
+        return Err(format!(
+            "A report may cover at most {} days",
+            MAX_REPORT_DAYS
+        ));
+    }
+    Ok((from, to))
+}
+
+/// Loads the schedule entries of a report, ordered by doctor and time.
+pub async fn fetch_schedule(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    filter: &ScheduleReportFilter,
+    from: Date,
+    to: Date,
+) -> Result<Vec<FullScheduleEntry>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        FullScheduleEntry,
+        "SELECT schedule.id as schedule_id, tickets.id as ticket_id, doctors.id as doctor_id, patients.id as patient_id,
+        tickets.date as ticket_date, tickets.time as ticket_time, tickets.office_number as ticket_office_number,
+        doctors.name as doctor_name, doctors.surname as doctor_surname, doctors.speciality as doctor_speciality,
+        doctors.phone_number as doctor_phone_number, doctors.passport_number as doctor_passport_number,
+        patients.name as patient_name, patients.surname as patient_surname, patients.birth_date as patient_birth_date,
+        patients.phone_number as patient_phone_number, patients.passport_number as patient_passport_number
+        FROM schedule
+        JOIN tickets ON schedule.ticket_id = tickets.id
+        JOIN doctors ON schedule.doctor_id = doctors.id
+        JOIN patients ON schedule.patient_id = patients.id
+        WHERE
+            parse_date(tickets.date) BETWEEN $1 AND $2 AND
+            ($3::INT IS NULL OR doctors.id = $3) AND
+            ($4::TEXT IS NULL OR lower(doctors.speciality) = lower($4))
+        ORDER BY doctors.surname, doctors.name, doctors.id, parse_date(tickets.date), tickets.time, schedule.id",
+        from,
+        to,
+        filter.doctor_id,
+        filter.speciality.as_deref().filter(|speciality| !speciality.is_empty()),
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|entry| cipher.decrypt_schedule_entry(entry))
+        .collect())
+}
+
+fn sheet_name(entry: &FullScheduleEntry) -> String {
+    let suffix = format!(" ({})", entry.doctor_id);
+    let name: String = format!("{} {}", entry.doctor_surname, entry.doctor_name)
+        .chars()
+        .filter(|c| !matches!(c, '[' | ']' | ':' | '*' | '?' | '/' | '\\' | '\''))
+        .take(MAX_SHEET_NAME_LENGTH - suffix.chars().count())
+        .collect();
+    format!("{}{}", name.trim(), suffix)
+}
+
+pub fn weekday_name(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Monday => "Monday",
+        Weekday::Tuesday => "Tuesday",
+        Weekday::Wednesday => "Wednesday",
+        Weekday::Thursday => "Thursday",
+        Weekday::Friday => "Friday",
+        Weekday::Saturday => "Saturday",
+        Weekday::Sunday => "Sunday",
+    }
+}
+
+struct Formats {
+    title: Format,
+    header: Format,
+    text: Format,
+    date: Format,
+    time: Format,
+    office: Format,
+}
+
+impl Formats {
+    fn new() -> Self {
+        let cell = Format::new().set_border(FormatBorder::Thin);
+        Formats {
+            title: Format::new().set_bold().set_font_size(14),
+            header: cell
+                .clone()
+                .set_bold()
+                .set_background_color("#D9E1F2")
+                .set_align(FormatAlign::Center),
+            text: cell.clone(),
+            date: cell.clone().set_num_format("dd.mm.yyyy"),
+            time: cell
+                .clone()
+                .set_num_format("hh:mm")
+                .set_align(FormatAlign::Center),
+            office: cell.set_align(FormatAlign::Center),
+        }
+    }
+}
+
+fn write_title(
+    sheet: &mut Worksheet,
+    formats: &Formats,
+    title: &str,
+    from: Date,
+    to: Date,
+) -> Result<(), XlsxError> {
+    sheet.write_string_with_format(0, 0, title, &formats.title)?;
+    sheet.write_string(1, 0, format!("{} – {}", format_date(from), format_date(to)))?;
+    for (column, (name, width)) in COLUMNS.iter().enumerate() {
+        sheet.set_column_width(column as u16, *width)?;
+        sheet.write_string_with_format(HEADER_ROW, column as u16, *name, &formats.header)?;
+    }
+    sheet.set_freeze_panes(HEADER_ROW + 1, 0)?;
+    Ok(())
+}
+
+fn write_entry(
+    sheet: &mut Worksheet,
+    formats: &Formats,
+    row: u32,
+    entry: &FullScheduleEntry,
+) -> Result<(), XlsxError> {
+    match parse_date(&entry.ticket_date) {
+        Some(date) => {
+            let excel_date =
+                ExcelDateTime::from_ymd(date.year() as u16, date.month() as u8, date.day())?;
+            sheet.write_datetime_with_format(row, 0, &excel_date, &formats.date)?;
+            sheet.write_string_with_format(row, 1, weekday_name(date.weekday()), &formats.text)?;
+        }
+        None => {
+            sheet.write_string_with_format(row, 0, &entry.ticket_date, &formats.text)?;
+            sheet.write_string_with_format(row, 1, "", &formats.text)?;
+        }
+    }
+
+    match parse_time(&entry.ticket_time) {
+        Some((hour, minute)) => {
+            let time = ExcelDateTime::from_hms(hour, minute, 0)?;
+            sheet.write_datetime_with_format(row, 2, &time, &formats.time)?;
+        }
+        None => {
+            sheet.write_string_with_format(row, 2, &entry.ticket_time, &formats.time)?;
+        }
+    }
+
+    sheet.write_number_with_format(row, 3, entry.ticket_office_number, &formats.office)?;
+    sheet.write_string_with_format(
+        row,
+        4,
+        format!("{} {}", entry.patient_surname, entry.patient_name),
+        &formats.text,
+    )?;
+    sheet.write_string_with_format(row, 5, &entry.patient_birth_date, &formats.text)?;
+    sheet.write_string_with_format(row, 6, &entry.patient_phone_number, &formats.text)?;
+    Ok(())
+}
+
+/// Builds a workbook with one sheet per doctor. `entries` must be ordered by doctor.
+pub fn schedule_workbook(
+    entries: &[FullScheduleEntry],
+    from: Date,
+    to: Date,
+) -> Result<Vec<u8>, XlsxError> {
+    let formats = Formats::new();
+    let mut workbook = Workbook::new();
+
+    if entries.is_empty() {
+        let sheet = workbook.add_worksheet().set_name("Schedule")?;
+        write_title(sheet, &formats, "No schedule entries", from, to)?;
+        return workbook.save_to_buffer();
+    }
+
+    for doctor_entries in entries.chunk_by(|a, b| a.doctor_id == b.doctor_id) {
+        let doctor = &doctor_entries[0];
+        let sheet = workbook.add_worksheet().set_name(sheet_name(doctor))?;
+        let title = format!(
+            "{} {}, {}",
+            doctor.doctor_surname, doctor.doctor_name, doctor.doctor_speciality
+        );
+        write_title(sheet, &formats, &title, from, to)?;
+
+        let mut row = HEADER_ROW;
+        for entry in doctor_entries {
+            row += 1;
+            write_entry(sheet, &formats, row, entry)?;
+        }
+        sheet.autofilter(HEADER_ROW, 0, row, COLUMNS.len() as u16 - 1)?;
+    }
+
+    workbook.save_to_buffer()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Month;
+
+    fn filter(from: Option<&str>, to: Option<&str>) -> ScheduleReportFilter {
+        ScheduleReportFilter {
+            from: from.map(str::to_string),
+            to: to.map(str::to_string),
+            doctor_id: None,
+            speciality: None,
+        }
+    }
+
+    fn date(year: i32, month: Month, day: u8) -> Date {
+        Date::from_calendar_date(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn report_periods_default_to_a_week() {
+        assert_eq!(
+            report_period(&filter(Some("2024-01-05"), Some("2024-2-1"))).unwrap(),
+            (
+                date(2024, Month::January, 5),
+                date(2024, Month::February, 1)
+            )
+        );
+        assert_eq!(
+            report_period(&filter(Some("2024-01-05"), Some(""))).unwrap(),
+            (
+                date(2024, Month::January, 5),
+                date(2024, Month::January, 11)
+            )
+        );
+        assert_eq!(
+            report_period(&filter(None, Some("2024-03-01"))).unwrap(),
+            (date(2024, Month::February, 24), date(2024, Month::March, 1))
+        );
+
+        let (from, to) = report_period(&filter(None, None)).unwrap();
+        assert_eq!(from.weekday(), Weekday::Monday);
+        assert_eq!(to.weekday(), Weekday::Sunday);
+        assert_eq!((to - from).whole_days(), 6);
+    }
+
+    #[test]
+    fn invalid_report_periods_are_rejected() {
+        assert_eq!(
+            report_period(&filter(Some("05.01.2024"), None)).unwrap_err(),
+            "`from` must be a date like 2024-01-31"
+        );
+        assert!(report_period(&filter(None, Some("2024-02-30"))).is_err());
+        assert_eq!(
+            report_period(&filter(Some("2024-01-05"), Some("2024-01-04"))).unwrap_err(),
+            "`to` must not be before `from`"
+        );
+        assert!(report_period(&filter(Some("2024-01-01"), Some("2024-12-31"))).is_ok());
+        assert!(report_period(&filter(Some("2024-01-01"), Some("2025-01-01"))).is_err());
+    }
+
+    fn entry(doctor_id: i32, surname: &str, name: &str) -> FullScheduleEntry {
+        FullScheduleEntry {
+            schedule_id: 1,
+            ticket_id: 1,
+            ticket_date: "2024-01-05".into(),
+            ticket_time: "09:00".into(),
+            ticket_office_number: 12,
+            doctor_id,
+            doctor_name: name.into(),
+            doctor_surname: surname.into(),
+            doctor_speciality: "Surgeon".into(),
+            doctor_phone_number: String::new(),
+            doctor_passport_number: String::new(),
+            patient_id: 1,
+            patient_name: "Anna".into(),
+            patient_surname: "Petrova".into(),
+            patient_birth_date: "1990-04-02".into(),
+            patient_phone_number: String::new(),
+            patient_passport_number: String::new(),
+        }
+    }
+
+    #[test]
+    fn sheet_names_fit_excel_rules() {
+        assert_eq!(sheet_name(&entry(7, "Ivanov", "Ivan")), "Ivanov Ivan (7)");
+        assert_eq!(
+            sheet_name(&entry(8, "O'Brien [Jr]", "Sean: A/B?")),
+            "OBrien Jr Sean AB (8)"
+        );
+
+        let long = sheet_name(&entry(123456, "Konstantinopolsky", "Aleksandr"));
+        assert_eq!(long.chars().count(), MAX_SHEET_NAME_LENGTH);
+        assert_eq!(long, "Konstantinopolsky Alek (123456)");
+        let cyrillic = sheet_name(&entry(9, "Константинопольский", "Александр"));
+        assert_eq!(cyrillic.chars().count(), MAX_SHEET_NAME_LENGTH);
+        assert!(cyrillic.ends_with(" (9)"));
+    }
+
+    #[sqlx::test]
+    async fn schedules_are_filtered_on_parsed_dates(pool: PgPool) {
+        let doctor_id = sqlx::query_scalar!(
+            "INSERT INTO doctors (name, surname, speciality, phone_number, passport_number)
+            VALUES ('Ivan', 'Ivanov', 'Surgeon', '', '') RETURNING id"
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let patient_id = sqlx::query_scalar!(
+            "INSERT INTO patients (name, surname, birth_date, phone_number, passport_number)
+            VALUES ('Anna', 'Petrova', '1990-04-02', '', '') RETURNING id"
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        for date in [
+            "2024-01-10",
+            "2024-1-5",
+            "2024-02-31",
+            "05.01.2024",
+            "2024-2-1",
+        ] {
+            sqlx::query!(
+                "WITH ticket AS (
+                    INSERT INTO tickets (date, time, office_number) VALUES ($1, '09:00', 12)
+                    RETURNING id
+                )
+                INSERT INTO schedule (ticket_id, doctor_id, patient_id)
+                SELECT id, $2, $3 FROM ticket",
+                date,
+                doctor_id,
+                patient_id,
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let cipher = FieldCipher::new(&Default::default()).unwrap();
+        let (from, to) = report_period(&filter(Some("2024-01-01"), Some("2024-01-31"))).unwrap();
+        let entries = fetch_schedule(&pool, &cipher, &filter(None, None), from, to)
+            .await
+            .unwrap();
+        let dates: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.ticket_date.as_str())
+            .collect();
+        assert_eq!(dates, ["2024-1-5", "2024-01-10"]);
+    }
+}
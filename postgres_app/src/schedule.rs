@@ -0,0 +1,98 @@
+use crate::models::{NewScheduleEntry, OptionScheduleEntry, ScheduleEntry};
+use sqlx::{PgExecutor, PgPool};
+
+/// Creates a schedule entry. Returns it with the version of the row.
+pub async fn create(
+    pool: &PgPool,
+    new_schedule_entry: &NewScheduleEntry,
+) -> Result<(ScheduleEntry, i32), sqlx::Error> {
+    let row = sqlx::query!(
+        "INSERT INTO schedule (ticket_id, doctor_id, patient_id)
+        VALUES ($1, $2, $3)
+        RETURNING id, ticket_id, doctor_id, patient_id, version",
+        new_schedule_entry.ticket_id,
+        new_schedule_entry.doctor_id,
+        new_schedule_entry.patient_id,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let entry = ScheduleEntry {
+        id: row.id,
+        ticket_id: row.ticket_id,
+        doctor_id: row.doctor_id,
+        patient_id: row.patient_id,
+    };
+    Ok((entry, row.version))
+}
+
+/// Books a ticket. Returns `None` when the ticket is already booked.
+pub async fn book(
+    executor: impl PgExecutor<'_>,
+    new_schedule_entry: &NewScheduleEntry,
+) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "INSERT INTO schedule (ticket_id, doctor_id, patient_id)
+        SELECT $1, $2, $3
+        WHERE NOT EXISTS (SELECT 1 FROM schedule WHERE ticket_id = $1)
+        RETURNING id",
+        new_schedule_entry.ticket_id,
+        new_schedule_entry.doctor_id,
+        new_schedule_entry.patient_id,
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+/// Changes the given ids of an entry whose version is one of `expected_versions`, or of any
+/// version without them. `None` when no such entry was found.
+pub async fn update(
+    pool: &PgPool,
+    id: i32,
+    option_schedule_entry: &OptionScheduleEntry,
+    expected_versions: Option<&[i32]>,
+) -> Result<Option<(ScheduleEntry, i32)>, sqlx::Error> {
+    let row = sqlx::query!(
+        "UPDATE schedule
+        SET ticket_id = COALESCE($2, ticket_id),
+            doctor_id = COALESCE($3, doctor_id),
+            patient_id = COALESCE($4, patient_id)
+        WHERE id = $1 AND ($5::INT[] IS NULL OR version = ANY($5))
+        RETURNING id, ticket_id, doctor_id, patient_id, version",
+        id,
+        option_schedule_entry.ticket_id,
+        option_schedule_entry.doctor_id,
+        option_schedule_entry.patient_id,
+        expected_versions,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| {
+        let entry = ScheduleEntry {
+            id: row.id,
+            ticket_id: row.ticket_id,
+            doctor_id: row.doctor_id,
+            patient_id: row.patient_id,
+        };
+        (entry, row.version)
+    }))
+}
+
+/// Deletes an entry whose version is one of `expected_versions`, or of any version without
+/// them. `false` when no such entry was found.
+pub async fn delete(
+    pool: &PgPool,
+    id: i32,
+    expected_versions: Option<&[i32]>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM schedule WHERE id = $1 AND ($2::INT[] IS NULL OR version = ANY($2))",
+        id,
+        expected_versions,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
@@ -0,0 +1,376 @@
+use crate::crypto::FieldCipher;
+use crate::models::{
+    FullScheduleEntry, PreviousAppointment, ScheduleEvent, ScheduleEventFilter, ScheduleEventKind,
+};
+use actix_web::web::Bytes;
+use actix_ws::{Message, MessageStream, Session};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::error;
+
+/// Channel the schedule triggers notify, see migration 0009.
+pub const CHANNEL: &str = "schedule_events";
+
+/// Events kept for subscribers that fall behind. Slower ones miss events and are told so.
+const BUFFERED_EVENTS: usize = 256;
+
+/// How often idle streams are kept alive, so that proxies do not close them.
+const HEARTBEAT: Duration = Duration::from_secs(15);
+
+pub const SSE_CONTENT_TYPE: &str = "text/event-stream";
+
+/// Payload of a notification.
+#[derive(Deserialize)]
+struct Notification {
+    kind: ScheduleEventKind,
+    schedule_id: i32,
+    ticket_id: i32,
+    doctor_id: i32,
+    patient_id: i32,
+    previous: Option<PreviousAppointment>,
+}
+
+/// Fans schedule changes out to the subscribers of this instance. One connection listens for
+/// the notifications of every instance, and each event is loaded once, whatever the number of
+/// subscribers.
+pub struct ScheduleEvents {
+    sender: broadcast::Sender<ScheduleEvent>,
+}
+
+impl ScheduleEvents {
+    pub async fn listen(pool: PgPool, cipher: Arc<FieldCipher>) -> Result<Self, sqlx::Error> {
+        let mut listener = PgListener::connect_with(&pool).await?;
+        listener.listen(CHANNEL).await?;
+        let (sender, _) = broadcast::channel(BUFFERED_EVENTS);
+
+        let events = sender.clone();
+        tokio::spawn(async move {
+            loop {
+                // The listener reconnects by itself; notifications sent meanwhile are lost.
+                let notification = match listener.recv().await {
+                    Ok(notification) => notification,
+                    Err(e) => {
+                        error!(error = %e, "Schedule event listener failed");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let notification: Notification = match serde_json::from_str(notification.payload())
+                {
+                    Ok(notification) => notification,
+                    Err(e) => {
+                        error!(payload = notification.payload(), error = %e, "Invalid schedule event");
+                        continue;
+                    }
+                };
+                if events.receiver_count() == 0 {
+                    continue;
+                }
+
+                match load_event(&pool, &cipher, notification).await {
+                    Ok(Some(event)) => {
+                        let _ = events.send(event);
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!(error = %e, "Failed to load a schedule event"),
+                }
+            }
+        });
+
+        Ok(ScheduleEvents { sender })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ScheduleEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// Loads the entry from the ids in the notification, as cancelled entries are gone from
+/// `schedule`. `None` when its ticket, doctor or patient was deleted as well.
+async fn load_event(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    notification: Notification,
+) -> Result<Option<ScheduleEvent>, sqlx::Error> {
+    let entry = sqlx::query_as!(
+        FullScheduleEntry,
+        r#"SELECT $1::INT as "schedule_id!", tickets.id as ticket_id, doctors.id as doctor_id, patients.id as patient_id,
+        tickets.date as ticket_date, tickets.time as ticket_time, tickets.office_number as ticket_office_number,
+        doctors.name as doctor_name, doctors.surname as doctor_surname, doctors.speciality as doctor_speciality,
+        doctors.phone_number as doctor_phone_number, doctors.passport_number as doctor_passport_number,
+        patients.name as patient_name, patients.surname as patient_surname, patients.birth_date as patient_birth_date,
+        patients.phone_number as patient_phone_number, patients.passport_number as patient_passport_number
+        FROM tickets, doctors, patients
+        WHERE tickets.id = $2 AND doctors.id = $3 AND patients.id = $4"#,
+        notification.schedule_id,
+        notification.ticket_id,
+        notification.doctor_id,
+        notification.patient_id,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(entry.map(|entry| ScheduleEvent {
+        kind: notification.kind,
+        entry: cipher.decrypt_schedule_entry(entry),
+        previous: notification.previous,
+    }))
+}
+
+/// Whether an event concerns the filter, before or after the change.
+pub fn matches(event: &ScheduleEvent, filter: &ScheduleEventFilter) -> bool {
+    let entry = &event.entry;
+    let previous = event.previous.as_ref();
+    let check = |wanted: Option<i32>, now: i32, before: Option<i32>| {
+        wanted.is_none_or(|wanted| wanted == now || before == Some(wanted))
+    };
+
+    check(
+        filter.doctor_id,
+        entry.doctor_id,
+        previous.map(|previous| previous.doctor_id),
+    ) && check(
+        filter.patient_id,
+        entry.patient_id,
+        previous.map(|previous| previous.patient_id),
+    ) && check(
+        filter.office_number,
+        entry.ticket_office_number,
+        previous.map(|previous| previous.office_number),
+    )
+}
+
+/// Server-sent events named after the event kind, with the `ScheduleEvent` as data. A
+/// subscriber that falls behind gets a `lagged` event with the number of missed events and
+/// should reload the schedule.
+pub fn server_sent_events(
+    events: &ScheduleEvents,
+    filter: ScheduleEventFilter,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    let mut receiver = events.subscribe();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT);
+    async_stream::stream! {
+        loop {
+            tokio::select! {
+                event = receiver.recv() => match event {
+                    Ok(event) if matches(&event, &filter) => {
+                        let kind = json!(event.kind);
+                        let data = serde_json::to_string(&event).unwrap_or_default();
+                        yield Ok(Bytes::from(format!(
+                            "event: {}\ndata: {}\n\n",
+                            kind.as_str().unwrap_or_default(),
+                            data
+                        )));
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        yield Ok(Bytes::from(format!(
+                            "event: lagged\ndata: {}\n\n",
+                            json!({ "missed": missed })
+                        )));
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = heartbeat.tick() => yield Ok(Bytes::from_static(b": keep-alive\n\n")),
+            }
+        }
+    }
+}
+
+/// Sends each matching `ScheduleEvent` as a JSON text message until the client goes away.
+/// A subscriber that falls behind gets `{"kind": "lagged", "missed": n}` and should reload
+/// the schedule.
+pub async fn websocket(
+    mut session: Session,
+    mut messages: MessageStream,
+    mut receiver: broadcast::Receiver<ScheduleEvent>,
+    filter: ScheduleEventFilter,
+) {
+    let mut heartbeat = tokio::time::interval(HEARTBEAT);
+    let reason = loop {
+        let sent = tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) if matches(&event, &filter) => {
+                    session.text(serde_json::to_string(&event).unwrap_or_default()).await
+                }
+                Ok(_) => Ok(()),
+                Err(RecvError::Lagged(missed)) => {
+                    session.text(json!({ "kind": "lagged", "missed": missed }).to_string()).await
+                }
+                Err(RecvError::Closed) => break None,
+            },
+            message = messages.next() => match message {
+                Some(Ok(Message::Ping(bytes))) => session.pong(&bytes).await,
+                Some(Ok(Message::Close(reason))) => break reason,
+                Some(Ok(_)) => Ok(()),
+                Some(Err(_)) | None => break None,
+            },
+            _ = heartbeat.tick() => session.ping(b"").await,
+        };
+        if sent.is_err() {
+            return;
+        }
+    };
+    let _ = session.close(reason).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: ScheduleEventKind, previous: Option<PreviousAppointment>) -> ScheduleEvent {
+        ScheduleEvent {
+            kind,
+            entry: FullScheduleEntry {
+                schedule_id: 1,
+                ticket_id: 2,
+                ticket_date: "2026-01-05".into(),
+                ticket_time: "09:00".into(),
+                ticket_office_number: 12,
+                doctor_id: 3,
+                doctor_name: "Ivan".into(),
+                doctor_surname: "Ivanov".into(),
+                doctor_speciality: "Surgeon".into(),
+                doctor_phone_number: String::new(),
+                doctor_passport_number: String::new(),
+                patient_id: 4,
+                patient_name: "Anna".into(),
+                patient_surname: "Petrova".into(),
+                patient_birth_date: "1990-04-02".into(),
+                patient_phone_number: String::new(),
+                patient_passport_number: String::new(),
+            },
+            previous,
+        }
+    }
+
+    fn moved_from(doctor_id: i32, patient_id: i32, office_number: i32) -> PreviousAppointment {
+        PreviousAppointment {
+            ticket_id: 5,
+            doctor_id,
+            patient_id,
+            date: "2026-01-04".into(),
+            time: "10:00".into(),
+            office_number,
+        }
+    }
+
+    fn filter(
+        doctor_id: Option<i32>,
+        patient_id: Option<i32>,
+        office_number: Option<i32>,
+    ) -> ScheduleEventFilter {
+        ScheduleEventFilter {
+            doctor_id,
+            patient_id,
+            office_number,
+        }
+    }
+
+    #[test]
+    fn filters_match_the_entry_as_it_is_now() {
+        let created = event(ScheduleEventKind::Created, None);
+        assert!(matches(&created, &ScheduleEventFilter::default()));
+        assert!(matches(&created, &filter(Some(3), Some(4), Some(12))));
+        assert!(!matches(&created, &filter(Some(30), None, None)));
+        assert!(!matches(&created, &filter(None, Some(40), None)));
+        assert!(!matches(&created, &filter(None, None, Some(120))));
+        assert!(!matches(&created, &filter(Some(3), Some(40), None)));
+    }
+
+    #[test]
+    fn filters_match_the_entry_as_it_was_before_a_change() {
+        let moved = event(ScheduleEventKind::Updated, Some(moved_from(30, 40, 120)));
+        assert!(matches(&moved, &filter(Some(3), None, None)));
+        assert!(matches(&moved, &filter(Some(30), None, None)));
+        assert!(matches(&moved, &filter(None, Some(40), Some(120))));
+        assert!(matches(&moved, &filter(Some(30), Some(4), Some(12))));
+        assert!(!matches(&moved, &filter(Some(31), None, None)));
+        assert!(!matches(&moved, &filter(None, Some(41), None)));
+        assert!(!matches(&moved, &filter(None, None, Some(121))));
+    }
+
+    /// Next frame of a stream, skipping the keep-alive comments.
+    async fn next_event(
+        stream: &mut (impl Stream<Item = Result<Bytes, Infallible>> + Unpin),
+    ) -> String {
+        loop {
+            let frame = tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .expect("an event is sent")
+                .unwrap()
+                .unwrap();
+            let frame = String::from_utf8(frame.to_vec()).unwrap();
+            if frame != ": keep-alive\n\n" {
+                return frame;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn server_sent_events_are_named_after_their_kind() {
+        let (sender, _) = broadcast::channel(BUFFERED_EVENTS);
+        let events = ScheduleEvents { sender };
+        let mut stream = Box::pin(server_sent_events(&events, filter(Some(3), None, None)));
+
+        let mut other_doctor = event(ScheduleEventKind::Created, None);
+        other_doctor.entry.doctor_id = 30;
+        events.sender.send(other_doctor).ok().unwrap();
+        events
+            .sender
+            .send(event(ScheduleEventKind::Cancelled, None))
+            .ok()
+            .unwrap();
+
+        let frame = next_event(&mut stream).await;
+        let (head, data) = frame.split_once('\n').unwrap();
+        assert_eq!(head, "event: cancelled");
+        assert!(frame.ends_with("\n\n"));
+        let data: serde_json::Value =
+            serde_json::from_str(data.strip_prefix("data: ").unwrap().trim_end()).unwrap();
+        assert_eq!(data["kind"], "cancelled");
+        assert_eq!(data["entry"]["doctor_id"], 3);
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_are_told_how_many_events_they_missed() {
+        let (sender, _) = broadcast::channel(2);
+        let events = ScheduleEvents { sender };
+        let mut stream = Box::pin(server_sent_events(&events, ScheduleEventFilter::default()));
+        for _ in 0..5 {
+            events
+                .sender
+                .send(event(ScheduleEventKind::Updated, None))
+                .ok()
+                .unwrap();
+        }
+
+        assert_eq!(
+            next_event(&mut stream).await,
+            "event: lagged\ndata: {\"missed\":3}\n\n"
+        );
+        assert!(next_event(&mut stream)
+            .await
+            .starts_with("event: updated\n"));
+        assert!(next_event(&mut stream)
+            .await
+            .starts_with("event: updated\n"));
+    }
+
+    #[tokio::test]
+    async fn idle_streams_start_with_a_keep_alive() {
+        let (sender, _) = broadcast::channel(BUFFERED_EVENTS);
+        let events = ScheduleEvents { sender };
+        let mut stream = Box::pin(server_sent_events(&events, ScheduleEventFilter::default()));
+        let frame = stream.next().await.unwrap().unwrap();
+        assert_eq!(frame, Bytes::from_static(b": keep-alive\n\n"));
+    }
+}
@@ -0,0 +1,747 @@
+use crate::config::{LogFormat, LoggingConfig};
+use crate::metrics::{Metrics, QueryMetrics, UNMATCHED_ROUTE};
+use crate::tls::ClientCertificate;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{Span as _, SpanKind, Tracer as _, TracerProvider as _};
+use opentelemetry::{Context, KeyValue, Value};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{BatchSpanProcessor, SpanProcessor, Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use serde_json::{Map, Value as JsonValue};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::field::{Field, Visit};
+use tracing::{error, info, Event, Instrument, Level, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime as LogTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::layer::{Context as LayerContext, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Replaces the values of PII fields in logs and exported spans.
+const REDACTED: &str = "[redacted]";
+
+/// Field and key names containing one of these hold PII or secrets, as do `name`, `surname` and
+/// names ending in `_name`.
+const SENSITIVE_NAME_PARTS: [&str; 7] = [
+    "passport", "phone", "email", "birth", "token", "password", "secret",
+];
+
+/// Statement kinds queries are labelled with, anything else counts as `OTHER`.
+const QUERY_OPERATIONS: [&str; 12] = [
+    "SELECT",
+    "INSERT",
+    "UPDATE",
+    "DELETE",
+    "WITH",
+    "BEGIN",
+    "COMMIT",
+    "ROLLBACK",
+    "SAVEPOINT",
+    "RELEASE",
+    "LISTEN",
+    "NOTIFY",
+];
+
+/// Longest request id taken over from clients, longer ones are replaced.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Time an OTLP export and the final flush on shutdown may take, so that an unreachable
+/// collector cannot hold the process open.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Flushes exported spans when dropped.
+pub struct Telemetry {
+    provider: Option<TracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            let (sender, receiver) = mpsc::channel();
+            std::thread::spawn(move || {
+                let _ = sender.send(provider.shutdown());
+            });
+            match receiver.recv_timeout(EXPORT_TIMEOUT) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!(error = %e, "Could not flush exported spans"),
+                Err(_) => error!("Gave up flushing exported spans, the collector did not respond"),
+            }
+        }
+    }
+}
+
+/// Installs the global subscriber: logs in the configured format, query metrics, and spans
+/// exported over OTLP when an endpoint is configured.
+pub fn init(config: &LoggingConfig, metrics: Arc<Metrics>) -> Result<Telemetry, String> {
+    let log_filter = || EnvFilter::try_new(&config.filter).map_err(|e| e.to_string());
+    // sqlx only measures and reports queries while `sqlx::query` is enabled at `DEBUG`.
+    let queries = || Targets::new().with_target("sqlx::query", Level::DEBUG);
+
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = vec![
+        match config.format {
+            LogFormat::Text => tracing_subscriber::fmt::layer()
+                .fmt_fields(RedactedFields::Text)
+                .with_filter(log_filter()?)
+                .boxed(),
+            LogFormat::Json => tracing_subscriber::fmt::layer()
+                .fmt_fields(RedactedFields::Json)
+                .event_format(JsonEvents)
+                .with_filter(log_filter()?)
+                .boxed(),
+        },
+        QueryMetrics(metrics).with_filter(queries()).boxed(),
+    ];
+
+    let provider = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .with_timeout(EXPORT_TIMEOUT)
+                .build()
+                .map_err(|e| format!("Could not export spans to {}: {}", endpoint, e))?;
+            let processor =
+                BatchSpanProcessor::builder(exporter, runtime::TokioCurrentThread).build();
+            let provider = TracerProvider::builder()
+                .with_span_processor(RedactingProcessor(processor))
+                .with_resource(Resource::new([KeyValue::new(
+                    "service.name",
+                    config.service_name.clone(),
+                )]))
+                .build();
+            let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+            opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+            // Query events become spans of their own, they are not repeated as span events.
+            let spans = log_filter()?.add_directive(
+                "sqlx::query=off"
+                    .parse()
+                    .map_err(|e: tracing_subscriber::filter::ParseError| e.to_string())?,
+            );
+            layers.push(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer.clone())
+                    .with_filter(spans)
+                    .boxed(),
+            );
+            layers.push(QuerySpans(tracer).with_filter(queries()).boxed());
+            Some(provider)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .try_init()
+        .map_err(|e| e.to_string())?;
+    if let Some(endpoint) = &config.otlp_endpoint {
+        info!(endpoint = %endpoint, "Exporting spans over OTLP");
+    }
+    Ok(Telemetry { provider })
+}
+
+/// Runs each request in a span carrying its id, route and principal, and logs its outcome.
+/// The id is taken from `X-Request-Id` when the client sent a usable one and is returned in
+/// that header. A W3C `traceparent` header continues the caller's trace.
+pub async fn trace_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id.bytes().all(|byte| byte.is_ascii_graphic())
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+    let principal = match req.conn_data::<ClientCertificate>() {
+        Some(certificate) => format!("certificate:{}", certificate.subject),
+        None => "anonymous".to_string(),
+    };
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        route = %route,
+        principal = %principal,
+        status = tracing::field::Empty,
+        otel.name = %format_args!("{} {}", req.method(), route),
+        otel.kind = "server",
+    );
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    span.set_parent(parent);
+
+    let mut result = next.call(req).instrument(span.clone()).await;
+
+    let status = match &mut result {
+        Ok(res) => {
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            res.status()
+        }
+        Err(e) => e.as_response_error().status_code(),
+    };
+    span.record("status", status.as_u16());
+    span.in_scope(|| {
+        let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+        if status.is_server_error() {
+            error!(status = status.as_u16(), duration_ms, "Request failed");
+        } else {
+            info!(status = status.as_u16(), duration_ms, "Request finished");
+        }
+    });
+    result
+}
+
+/// Records who a request acts for once a handler authenticated it, for example by a calendar
+/// token.
+pub fn record_principal(principal: &str) {
+    tracing::Span::current().record("principal", principal);
+}
+
+/// Whether values of a field or key with this name are redacted.
+/// Fields tracing-opentelemetry reads itself, as `otel.name`, are never redacted.
+pub fn is_sensitive(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    if name.starts_with("otel.") {
+        return false;
+    }
+    let name = name.rsplit('.').next().unwrap_or_default();
+    name == "name"
+        || name.ends_with("_name")
+        || name.ends_with("surname")
+        || SENSITIVE_NAME_PARTS.iter().any(|part| name.contains(part))
+}
+
+/// Redacts the values of sensitive keys inside free text, as in `Debug` output of a record
+/// (`passport_number: "…"`), JSON (`"passport_number":"…"`) or `key=value` pairs.
+pub fn scrub(text: &str) -> Cow<'_, str> {
+    let lowercase = text.to_ascii_lowercase();
+    if !lowercase.contains("name")
+        && !SENSITIVE_NAME_PARTS
+            .iter()
+            .any(|part| lowercase.contains(part))
+    {
+        return Cow::Borrowed(text);
+    }
+
+    let bytes = text.as_bytes();
+    let is_word = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'_';
+    let mut scrubbed = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut position = 0;
+    while position < bytes.len() {
+        if !is_word(bytes[position]) {
+            position += 1;
+            continue;
+        }
+        let word_start = position;
+        while position < bytes.len() && is_word(bytes[position]) {
+            position += 1;
+        }
+        if !is_sensitive(&text[word_start..position]) {
+            continue;
+        }
+
+        let mut cursor = position;
+        if bytes.get(cursor) == Some(&b'"') {
+            cursor += 1;
+        }
+        while bytes.get(cursor) == Some(&b' ') {
+            cursor += 1;
+        }
+        if !matches!(bytes.get(cursor), Some(b':' | b'=')) {
+            continue;
+        }
+        cursor += 1;
+        while bytes.get(cursor) == Some(&b' ') {
+            cursor += 1;
+        }
+
+        let (value_start, value_end) = if bytes.get(cursor) == Some(&b'"') {
+            let mut end = cursor + 1;
+            while end < bytes.len() && bytes[end] != b'"' {
+                end += if bytes[end] == b'\\' { 2 } else { 1 };
+            }
+            (cursor + 1, end.min(bytes.len()))
+        } else {
+            let mut end = cursor;
+            while end < bytes.len() && !b" ,;&)}]\n".contains(&bytes[end]) {
+                end += 1;
+            }
+            (cursor, end)
+        };
+        if value_start == value_end {
+            continue;
+        }
+        scrubbed.push_str(&text[copied..value_start]);
+        scrubbed.push_str(REDACTED);
+        copied = value_end;
+        position = value_end;
+    }
+    scrubbed.push_str(&text[copied..]);
+    Cow::Owned(scrubbed)
+}
+
+fn redact(name: &str, value: String) -> String {
+    if is_sensitive(name) {
+        REDACTED.to_string()
+    } else {
+        match scrub(&value) {
+            Cow::Borrowed(_) => value,
+            Cow::Owned(scrubbed) => scrubbed,
+        }
+    }
+}
+
+/// Fields of a `sqlx::query` event, which sqlx logs when a query finished.
+pub struct QueryEvent {
+    /// The first words of the statement.
+    pub summary: String,
+    /// The whole statement, when it is longer than the summary.
+    pub statement: Option<String>,
+    pub elapsed: Duration,
+    pub rows_returned: Option<u64>,
+    pub rows_affected: Option<u64>,
+}
+
+impl QueryEvent {
+    pub fn from_event(event: &Event<'_>) -> Option<Self> {
+        if event.metadata().target() != "sqlx::query" {
+            return None;
+        }
+        let mut visitor = QueryVisitor::default();
+        event.record(&mut visitor);
+        Some(QueryEvent {
+            summary: visitor.summary?,
+            statement: visitor
+                .statement
+                .map(|statement| statement.trim().to_string())
+                .filter(|statement| !statement.is_empty()),
+            elapsed: Duration::try_from_secs_f64(visitor.elapsed_secs?).ok()?,
+            rows_returned: visitor.rows_returned,
+            rows_affected: visitor.rows_affected,
+        })
+    }
+
+    pub fn operation(&self) -> &'static str {
+        let verb = self
+            .summary
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        QUERY_OPERATIONS
+            .iter()
+            .find(|operation| **operation == verb)
+            .copied()
+            .unwrap_or("OTHER")
+    }
+}
+
+#[derive(Default)]
+struct QueryVisitor {
+    summary: Option<String>,
+    statement: Option<String>,
+    elapsed_secs: Option<f64>,
+    rows_returned: Option<u64>,
+    rows_affected: Option<u64>,
+}
+
+impl Visit for QueryVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if field.name() == "elapsed_secs" {
+            self.elapsed_secs = Some(value);
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "rows_returned" => self.rows_returned = Some(value),
+            "rows_affected" => self.rows_affected = Some(value),
+            _ => {}
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "summary" => self.summary = Some(value.to_string()),
+            "db.statement" => self.statement = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+/// Turns the events sqlx logs after each query into client spans below the current span.
+struct QuerySpans(Tracer);
+
+impl<S: Subscriber> Layer<S> for QuerySpans {
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let Some(query) = QueryEvent::from_event(event) else {
+            return;
+        };
+        let end = SystemTime::now();
+        let parent = tracing::Span::current().context();
+        let operation = query.operation();
+        let mut attributes = vec![
+            KeyValue::new("db.system", "postgresql"),
+            KeyValue::new("db.operation", operation),
+            KeyValue::new("db.statement", query.statement.unwrap_or(query.summary)),
+        ];
+        if let Some(rows) = query.rows_returned {
+            attributes.push(KeyValue::new("db.rows_returned", rows as i64));
+        }
+        if let Some(rows) = query.rows_affected {
+            attributes.push(KeyValue::new("db.rows_affected", rows as i64));
+        }
+        let mut span = self
+            .0
+            .span_builder(operation)
+            .with_kind(SpanKind::Client)
+            .with_start_time(end - query.elapsed)
+            .with_attributes(attributes)
+            .start_with_context(&self.0, &parent);
+        span.end_with_timestamp(end);
+    }
+}
+
+/// Redacts span and event attributes before spans are exported.
+#[derive(Debug)]
+struct RedactingProcessor<P>(P);
+
+impl<P: SpanProcessor> SpanProcessor for RedactingProcessor<P> {
+    fn on_start(&self, span: &mut opentelemetry_sdk::trace::Span, cx: &Context) {
+        self.0.on_start(span, cx);
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        redact_attributes(&mut span.attributes);
+        for event in span.events.events.iter_mut() {
+            redact_attributes(&mut event.attributes);
+            if let Cow::Owned(name) = scrub(&event.name) {
+                event.name = name.into();
+            }
+        }
+        self.0.on_end(span);
+    }
+
+    fn force_flush(&self) -> opentelemetry::trace::TraceResult<()> {
+        self.0.force_flush()
+    }
+
+    fn shutdown(&self) -> opentelemetry::trace::TraceResult<()> {
+        self.0.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.0.set_resource(resource);
+    }
+}
+
+fn redact_attributes(attributes: &mut [KeyValue]) {
+    for attribute in attributes {
+        if let Value::String(value) = &attribute.value {
+            let redacted = redact(attribute.key.as_str(), value.as_str().to_string());
+            if redacted != value.as_str() {
+                attribute.value = Value::from(redacted);
+            }
+        } else if is_sensitive(attribute.key.as_str()) {
+            attribute.value = Value::from(REDACTED);
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Formats span and event fields with sensitive values redacted, as `key=value` pairs or as a
+/// JSON object.
+enum RedactedFields {
+    Text,
+    Json,
+}
+
+impl<'writer> FormatFields<'writer> for RedactedFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut visitor = FieldVisitor::default();
+        fields.record(&mut visitor);
+        match self {
+            RedactedFields::Text => {
+                let mut separator = "";
+                if let Some(message) = visitor.message {
+                    write!(writer, "{}", message)?;
+                    separator = " ";
+                }
+                for (name, value) in visitor.fields {
+                    match value {
+                        JsonValue::String(value) => {
+                            write!(writer, "{}{}={}", separator, name, value)?
+                        }
+                        value => write!(writer, "{}{}={}", separator, name, value)?,
+                    }
+                    separator = " ";
+                }
+                Ok(())
+            }
+            RedactedFields::Json => write!(writer, "{}", JsonValue::Object(visitor.into_map())),
+        }
+    }
+
+    /// Later values of span fields, such as the response status, are merged into the object.
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        match self {
+            RedactedFields::Text => {
+                if !current.fields.is_empty() {
+                    current.fields.push(' ');
+                }
+                self.format_fields(current.as_writer(), fields)
+            }
+            RedactedFields::Json => {
+                let mut visitor = FieldVisitor::default();
+                fields.record(&mut visitor);
+                let mut map = serde_json::from_str::<Map<String, JsonValue>>(&current.fields)
+                    .unwrap_or_default();
+                map.extend(visitor.into_map());
+                current.fields = JsonValue::Object(map).to_string();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Field values in recording order, with sensitive ones redacted.
+#[derive(Default)]
+struct FieldVisitor {
+    message: Option<String>,
+    fields: Vec<(&'static str, JsonValue)>,
+}
+
+impl FieldVisitor {
+    /// `otel.` fields only steer the exported span, they are left out of logs.
+    fn record(&mut self, field: &Field, value: JsonValue) {
+        if field.name().starts_with("otel.") {
+            return;
+        }
+        let value = if is_sensitive(field.name()) {
+            JsonValue::from(REDACTED)
+        } else {
+            value
+        };
+        self.fields.push((field.name(), value));
+    }
+
+    /// The message first, then the other fields in recording order.
+    fn into_entries(self) -> Vec<(&'static str, JsonValue)> {
+        let mut entries = Vec::with_capacity(self.fields.len() + 1);
+        if let Some(message) = self.message {
+            entries.push(("message", message.into()));
+        }
+        entries.extend(self.fields);
+        entries
+    }
+
+    fn into_map(self) -> Map<String, JsonValue> {
+        self.into_entries()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let value = redact(field.name(), value.to_string());
+        match field.name() {
+            "message" => self.message = Some(value),
+            _ => self.record(field, value.into()),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+/// One JSON object per line with the time, level, target, the event's fields and the spans it
+/// happened in, outermost first.
+struct JsonEvents;
+
+impl<S, N> FormatEvent<S, N> for JsonEvents
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        LogTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let mut entries = vec![
+            ("timestamp", timestamp.into()),
+            ("level", event.metadata().level().as_str().into()),
+            ("target", event.metadata().target().into()),
+        ];
+        entries.extend(visitor.into_entries());
+
+        if let Some(scope) = ctx.event_scope() {
+            let spans: Vec<JsonValue> = scope
+                .from_root()
+                .map(|span| {
+                    let mut fields = Map::new();
+                    fields.insert("name".to_string(), span.name().into());
+                    if let Some(recorded) = span
+                        .extensions()
+                        .get::<FormattedFields<N>>()
+                        .and_then(|fields| serde_json::from_str::<Map<_, _>>(&fields.fields).ok())
+                    {
+                        fields.extend(recorded);
+                    }
+                    JsonValue::Object(fields)
+                })
+                .collect();
+            if !spans.is_empty() {
+                entries.push(("spans", spans.into()));
+            }
+        }
+
+        // Written by hand, a `Map` would sort the time and level among the fields.
+        write!(writer, "{{")?;
+        for (index, (name, value)) in entries.iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            write!(writer, "{}{}:{}", separator, JsonValue::from(*name), value)?;
+        }
+        writeln!(writer, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_of_personal_data_and_secrets_are_sensitive() {
+        for name in [
+            "name",
+            "surname",
+            "doctor_name",
+            "patient.phone_number",
+            "passport_number",
+            "Email",
+            "birth_date",
+            "calendar_token",
+            "smtp_password",
+        ] {
+            assert!(is_sensitive(name), "{}", name);
+        }
+        for name in [
+            "otel.name",
+            "http.route",
+            "office_number",
+            "filename",
+            "speciality",
+        ] {
+            assert!(!is_sensitive(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn scrub_redacts_values_of_sensitive_keys() {
+        assert_eq!(
+            scrub(r#"Patient { id: 3, name: "Ivan", birth_date: "1990-01-01" }"#),
+            r#"Patient { id: 3, name: "[redacted]", birth_date: "[redacted]" }"#
+        );
+        assert_eq!(
+            scrub(r#"{"passport_number":"4510 \"123456\"","office_number":12}"#),
+            r#"{"passport_number":"[redacted]","office_number":12}"#
+        );
+        assert_eq!(
+            scrub("phone_number=+79000000000 status=ok token = abc;"),
+            "phone_number=[redacted] status=ok token = [redacted];"
+        );
+    }
+
+    #[test]
+    fn scrub_leaves_other_text_alone() {
+        assert!(matches!(
+            scrub("ticket 12 booked in office 3"),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(
+            scrub("the patient name is unknown, name="),
+            "the patient name is unknown, name="
+        );
+        assert_eq!(scrub("filename: report.csv"), "filename: report.csv");
+    }
+}
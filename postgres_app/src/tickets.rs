@@ -0,0 +1,77 @@
+use crate::models::{NewTicket, OptionTicket, Ticket};
+use sqlx::PgPool;
+
+/// Creates a ticket. Returns it with the version of the row.
+pub async fn create(pool: &PgPool, new_ticket: &NewTicket) -> Result<(Ticket, i32), sqlx::Error> {
+    let row = sqlx::query!(
+        "INSERT INTO tickets (date, time, office_number)
+        VALUES ($1, $2, $3)
+        RETURNING id, date, time, office_number, version",
+        new_ticket.date,
+        new_ticket.time,
+        new_ticket.office_number,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let ticket = Ticket {
+        id: row.id,
+        date: row.date,
+        time: row.time,
+        office_number: row.office_number,
+    };
+    Ok((ticket, row.version))
+}
+
+/// Changes the given fields of a ticket whose version is one of `expected_versions`, or of
+/// any version without them. `None` when no such ticket was found.
+pub async fn update(
+    pool: &PgPool,
+    id: i32,
+    option_ticket: &OptionTicket,
+    expected_versions: Option<&[i32]>,
+) -> Result<Option<(Ticket, i32)>, sqlx::Error> {
+    let row = sqlx::query!(
+        "UPDATE tickets
+        SET date = COALESCE($2, date),
+            time = COALESCE($3, time),
+            office_number = COALESCE($4, office_number)
+        WHERE id = $1 AND ($5::INT[] IS NULL OR version = ANY($5))
+        RETURNING id, date, time, office_number, version",
+        id,
+        option_ticket.date,
+        option_ticket.time,
+        option_ticket.office_number,
+        expected_versions,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| {
+        let ticket = Ticket {
+            id: row.id,
+            date: row.date,
+            time: row.time,
+            office_number: row.office_number,
+        };
+        (ticket, row.version)
+    }))
+}
+
+/// Deletes a ticket whose version is one of `expected_versions`, or of any version without
+/// them. `false` when no such ticket was found.
+pub async fn delete(
+    pool: &PgPool,
+    id: i32,
+    expected_versions: Option<&[i32]>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM tickets WHERE id = $1 AND ($2::INT[] IS NULL OR version = ANY($2))",
+        id,
+        expected_versions,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
@@ -0,0 +1,332 @@
+use crate::config::TlsConfig;
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Extensions, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::rt::net::TcpStream;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::RootCertStore;
+use std::any::Any;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// How often the certificate and key files are checked for changes.
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The certificate chain and key currently on disk. Handshakes pick up a reloaded certificate
+/// at once, established connections keep theirs.
+#[derive(Debug)]
+pub struct ReloadingCertificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCertificate {
+    fn load(
+        cert_path: &Path,
+        key_path: &Path,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Self, String> {
+        let current = RwLock::new(Arc::new(certified_key(cert_path, key_path, &provider)?));
+        Ok(ReloadingCertificate {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            provider,
+            current,
+        })
+    }
+
+    /// Keeps the current certificate when the files cannot be read or do not match, as while
+    /// one of them has been replaced and the other not yet.
+    fn reload(&self) -> Result<(), String> {
+        let key = certified_key(&self.cert_path, &self.key_path, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(&self.cert_path), modified(&self.key_path))
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// rustls settings serving the configured certificate, and asking for client certificates when
+/// a client CA is configured. Clients without one can still connect, routes needing it are
+/// guarded by [`require_client_certificate`].
+pub fn server_config(
+    tls: &TlsConfig,
+) -> Result<(rustls::ServerConfig, Arc<ReloadingCertificate>), String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let certificate = Arc::new(ReloadingCertificate::load(
+        &tls.cert_path,
+        &tls.key_path,
+        provider.clone(),
+    )?);
+
+    let client_verifier = match &tls.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(path)? {
+                roots.add(certificate).map_err(|e| {
+                    format!(
+                        "Invalid client CA certificate in `{}`: {}",
+                        path.display(),
+                        e
+                    )
+                })?;
+            }
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| format!("Invalid client CA `{}`: {}", path.display(), e))?
+        }
+        None => WebPkiClientVerifier::no_client_auth(),
+    };
+
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_client_cert_verifier(client_verifier)
+        .with_cert_resolver(certificate.clone());
+    Ok((config, certificate))
+}
+
+/// Reloads the certificate on SIGHUP and when the certificate or key file changes.
+pub async fn reload_on_change(certificate: Arc<ReloadingCertificate>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            warn!(error = %e, "TLS certificates are not reloaded on SIGHUP");
+            None
+        }
+    };
+    let mut interval = tokio::time::interval(RELOAD_POLL_INTERVAL);
+    let mut modified = certificate.modified();
+    loop {
+        tokio::select! {
+            Some(()) = async { hangup.as_mut()?.recv().await } => {}
+            _ = interval.tick() => {
+                let now = certificate.modified();
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+            }
+        }
+        match certificate.reload() {
+            Ok(()) => info!(
+                path = %certificate.cert_path.display(),
+                "Reloaded the TLS certificate"
+            ),
+            Err(e) => warn!(error = %e, "Keeping the current TLS certificate"),
+        }
+    }
+}
+
+/// Connection data of clients that presented a certificate the client CA verified.
+pub struct ClientCertificate {
+    /// Common name of the certificate, or its whole subject when it has none.
+    pub subject: String,
+}
+
+/// `HttpServer::on_connect` callback recording the client certificate of TLS connections.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    let Some(certificate) = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+    else {
+        return;
+    };
+    let subject = match X509Certificate::from_der(certificate) {
+        Ok((_, certificate)) => certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|name| name.as_str().ok())
+            .map(str::to_string)
+            .unwrap_or_else(|| certificate.subject().to_string()),
+        Err(e) => {
+            warn!(error = %e, "Could not read the subject of a client certificate");
+            "unknown".to_string()
+        }
+    };
+    data.insert(ClientCertificate { subject });
+}
+
+/// Bulk imports and exports, their jobs, maintenance operations, server details and metrics.
+pub fn is_admin_route(path: &str) -> bool {
+    let mut segments = path.trim_start_matches('/').split('/');
+    match (segments.next(), segments.next()) {
+        (Some("admin" | "jobs" | "metrics"), _) | (Some("health"), Some("details")) => true,
+        (Some("patients" | "doctors" | "tickets" | "schedule"), Some(segment)) => {
+            segment.starts_with("export") || segment.starts_with("import")
+        }
+        _ => false,
+    }
+}
+
+/// Whether a request comes from this host or presented a client certificate. Server details are
+/// only shown to such clients, whether or not a client CA is configured.
+pub fn is_local_or_certified(req: &HttpRequest) -> bool {
+    req.conn_data::<ClientCertificate>().is_some()
+        || req
+            .peer_addr()
+            .is_some_and(|addr| addr.ip().to_canonical().is_loopback())
+}
+
+/// Answers admin routes with `403` unless the connection presented a client certificate.
+///
+/// The path is checked percent-decoded, as the router matches it, so that `/%6Detrics` reaches
+/// `/metrics` only with a certificate too.
+pub async fn require_client_certificate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    if is_admin_route(req.match_info().as_str()) && req.conn_data::<ClientCertificate>().is_none() {
+        let response = HttpResponse::Forbidden().body("A client certificate is required");
+        return Ok(req.into_response(response));
+    }
+    next.call(req).await.map(|res| res.map_into_boxed_body())
+}
+
+/// Port the HTTPS server listens on, for redirects.
+pub struct HttpsPort(pub u16);
+
+/// Default service of the plain HTTP listener. `308` keeps the method and body of the request.
+pub async fn redirect_to_https(req: HttpRequest, https_port: web::Data<HttpsPort>) -> HttpResponse {
+    let connection_info = req.connection_info();
+    let host = connection_info.host();
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !name.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let location = match https_port.0 {
+        443 => format!("https://{}{}", host, req.uri()),
+        port => format!("https://{}:{}{}", host, port, req.uri()),
+    };
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+fn certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, String> {
+    let certificates = read_certificates(cert_path)?;
+    let key = provider
+        .key_provider
+        .load_private_key(read_key(key_path)?)
+        .map_err(|e| format!("Unsupported TLS key `{}`: {}", key_path.display(), e))?;
+    let certified = CertifiedKey::new(certificates, key);
+    certified.keys_match().map_err(|e| {
+        format!(
+            "TLS key `{}` does not match the certificate: {}",
+            key_path.display(),
+            e
+        )
+    })?;
+    Ok(certified)
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path)
+        .map_err(|e| format!("Could not open TLS certificate `{}`: {}", path.display(), e))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid TLS certificate `{}`: {}", path.display(), e))?;
+    if certificates.is_empty() {
+        return Err(format!(
+            "TLS certificate `{}` contains no PEM certificate",
+            path.display()
+        ));
+    }
+    Ok(certificates)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path)
+        .map_err(|e| format!("Could not open TLS key `{}`: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("Invalid TLS key `{}`: {}", path.display(), e))?
+        .ok_or_else(|| format!("TLS key `{}` contains no PEM private key", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn percent_encoded_admin_paths_need_a_certificate() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(require_client_certificate))
+                .route("/metrics", web::get().to(HttpResponse::Ok))
+                .route("/patients/export.csv", web::get().to(HttpResponse::Ok))
+                .route("/patients", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for path in [
+            "/metrics",
+            "/%6Detrics",
+            "/patients/%65xport.csv",
+            "//metrics",
+        ] {
+            let response =
+                test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", path);
+        }
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/patients").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn only_loopback_or_certified_clients_are_trusted() {
+        let from = |addr: &str| test::TestRequest::get().peer_addr(addr.parse().unwrap());
+        assert!(is_local_or_certified(
+            &from("127.0.0.1:40000").to_http_request()
+        ));
+        assert!(is_local_or_certified(
+            &from("[::1]:40000").to_http_request()
+        ));
+        assert!(is_local_or_certified(
+            &from("[::ffff:127.0.0.1]:40000").to_http_request()
+        ));
+        assert!(!is_local_or_certified(
+            &from("10.0.0.5:40000").to_http_request()
+        ));
+        assert!(!is_local_or_certified(
+            &test::TestRequest::get().to_http_request()
+        ));
+    }
+}
@@ -0,0 +1,559 @@
+use crate::crypto::FieldCipher;
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect;
+use reqwest::Url;
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::PgPool;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tracing::error;
+
+pub const EVENT_TYPES: [&str; 3] = [
+    "appointment.created",
+    "appointment.cancelled",
+    "appointment.rescheduled",
+];
+
+/// How often the outbox is checked for due deliveries.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Deliveries claimed at once by one instance.
+const BATCH_SIZE: i64 = 20;
+
+/// Kept in `webhook_deliveries.error`; receivers sometimes answer with whole HTML pages.
+const MAX_ERROR_LENGTH: usize = 500;
+
+pub struct WebhookSettings {
+    /// Attempts after which a delivery is given up and marked `failed`.
+    pub max_attempts: i32,
+    /// Delay before the first retry. Every further retry waits twice as long.
+    pub retry_base: Duration,
+    pub retry_max: Duration,
+    pub timeout: Duration,
+    /// Lets subscriptions target loopback, private and link-local addresses, for receivers on
+    /// the hospital network. Off by default so a subscription cannot probe internal services.
+    pub allow_private_targets: bool,
+}
+
+impl WebhookSettings {
+    /// Exponential backoff: the base delay after the first attempt, doubled after each next one.
+    fn retry_delay(&self, attempts: i32) -> Duration {
+        let exponent = attempts.clamp(1, 31) as u32 - 1;
+        self.retry_base
+            .checked_mul(2u32.saturating_pow(exponent))
+            .map_or(self.retry_max, |delay| delay.min(self.retry_max))
+    }
+
+    pub fn validate_url(&self, url: &str) -> Result<(), String> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!(
+                "Webhook URL `{}` must start with http:// or https://",
+                url
+            ));
+        }
+        let url = Url::parse(url).map_err(|e| format!("Invalid webhook URL `{}`: {}", url, e))?;
+        self.check_target(&url)
+    }
+
+    /// Rejects hosts that are not public by their name alone. Names are checked again when
+    /// they are resolved for a delivery, see [`PublicResolver`].
+    fn check_target(&self, url: &Url) -> Result<(), String> {
+        if self.allow_private_targets {
+            return Ok(());
+        }
+        let host = url.host_str().unwrap_or_default();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let public = match host.parse::<IpAddr>() {
+            Ok(address) => is_public(address),
+            Err(_) => {
+                let domain = host.trim_end_matches('.').to_ascii_lowercase();
+                !domain.is_empty() && domain != "localhost" && !domain.ends_with(".localhost")
+            }
+        };
+        if public {
+            Ok(())
+        } else {
+            Err(format!("Webhook URL `{}` must point to a public host", url))
+        }
+    }
+
+    fn client(&self) -> reqwest::Result<reqwest::Client> {
+        let builder = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .redirect(redirect::Policy::none());
+        if self.allow_private_targets {
+            builder.build()
+        } else {
+            builder.dns_resolver(Arc::new(PublicResolver)).build()
+        }
+    }
+}
+
+/// Whether a delivery may connect to the address: loopback, private, link-local, shared
+/// (carrier-grade NAT), multicast and unspecified addresses are internal.
+fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => {
+            let [first, second, ..] = address.octets();
+            !(address.is_loopback()
+                || address.is_private()
+                || address.is_link_local()
+                || address.is_unspecified()
+                || address.is_broadcast()
+                || address.is_multicast()
+                || first == 0
+                || (first == 100 && (64..128).contains(&second)))
+        }
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(address) => is_public(IpAddr::V4(address)),
+            None => {
+                !(address.is_loopback()
+                    || address.is_unspecified()
+                    || address.is_multicast()
+                    || address.is_unique_local()
+                    || address.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Resolves webhook hosts to their public addresses only, so a name pointing at an internal
+/// address cannot get past [`WebhookSettings::validate_url`], however it resolved back then.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addresses = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|address| is_public(address.ip()))
+                .collect::<Vec<SocketAddr>>();
+            if addresses.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+pub fn validate_event_types(event_types: &[String]) -> Result<(), String> {
+    if event_types.is_empty() {
+        return Err(format!(
+            "event_types must list some of {}",
+            EVENT_TYPES.join(", ")
+        ));
+    }
+    match event_types
+        .iter()
+        .find(|event_type| !EVENT_TYPES.contains(&event_type.as_str()))
+    {
+        Some(event_type) => Err(format!(
+            "Unknown event type `{}`, expected one of {}",
+            event_type,
+            EVENT_TYPES.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+/// `X-Webhook-Signature` of a delivery: the hex HMAC-SHA256 of `<timestamp>.<body>` keyed with
+/// the subscription secret. Receivers should also reject old timestamps to stop replays.
+pub fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+struct DueDelivery {
+    id: i64,
+    attempts: i32,
+    url: String,
+    secret: String,
+    event_id: i64,
+    event_type: String,
+    payload: serde_json::Value,
+    created_at: String,
+}
+
+/// Delivers due webhooks until the process exits. Deliveries are claimed with `SKIP LOCKED`
+/// and leased for longer than a request may take, so several instances can dispatch at once
+/// and a delivery left behind by a crashed instance is retried when its lease expires.
+pub async fn dispatch(pool: PgPool, cipher: Arc<FieldCipher>, settings: Arc<WebhookSettings>) {
+    let client = match settings.client() {
+        Ok(client) => client,
+        Err(e) => {
+            error!(error = %e, "Webhooks are not delivered, could not create an HTTP client");
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        loop {
+            match deliver_due(&pool, &cipher, &settings, &client).await {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(e) => {
+                    error!(error = %e, "Failed to deliver webhooks");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the number of deliveries attempted.
+async fn deliver_due(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    settings: &WebhookSettings,
+    client: &reqwest::Client,
+) -> Result<usize, sqlx::Error> {
+    let lease = (settings.timeout + Duration::from_secs(30)).as_secs_f64();
+    let due = sqlx::query_as!(
+        DueDelivery,
+        r#"UPDATE webhook_deliveries
+        SET next_attempt_at = now() + make_interval(secs => $2)
+        FROM webhook_subscriptions, webhook_outbox
+        WHERE webhook_deliveries.id IN (
+                SELECT webhook_deliveries.id
+                FROM webhook_deliveries
+                JOIN webhook_subscriptions ON webhook_subscriptions.id = webhook_deliveries.subscription_id
+                WHERE webhook_deliveries.status = 'pending' AND webhook_deliveries.next_attempt_at <= now()
+                    AND webhook_subscriptions.active
+                ORDER BY webhook_deliveries.next_attempt_at, webhook_deliveries.id
+                LIMIT $1
+                FOR UPDATE OF webhook_deliveries SKIP LOCKED
+            )
+            AND webhook_subscriptions.id = webhook_deliveries.subscription_id
+            AND webhook_outbox.id = webhook_deliveries.event_id
+        RETURNING webhook_deliveries.id, webhook_deliveries.attempts, webhook_subscriptions.url,
+            webhook_subscriptions.secret, webhook_outbox.id as event_id, webhook_outbox.event_type,
+            webhook_outbox.payload, webhook_outbox.created_at::TEXT as "created_at!""#,
+        BATCH_SIZE,
+        lease,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let attempted = due.len();
+    let results = join_all(
+        due.into_iter()
+            .map(|delivery| deliver(pool, cipher, settings, client, delivery)),
+    )
+    .await;
+    for result in results {
+        result?;
+    }
+    Ok(attempted)
+}
+
+async fn deliver(
+    pool: &PgPool,
+    cipher: &FieldCipher,
+    settings: &WebhookSettings,
+    client: &reqwest::Client,
+    delivery: DueDelivery,
+) -> Result<(), sqlx::Error> {
+    let secret = cipher.decrypt(&delivery.secret);
+    let (delivered, response_status, error) = send(settings, client, &delivery, &secret).await;
+    let attempts = delivery.attempts + 1;
+    let retry_delay = settings.retry_delay(attempts).as_secs_f64();
+
+    sqlx::query!(
+        "UPDATE webhook_deliveries
+        SET attempts = $2,
+            last_attempt_at = now(),
+            response_status = $3,
+            error = $4,
+            status = CASE WHEN $5 THEN 'delivered' WHEN $2::INT >= $6::INT THEN 'failed' ELSE 'pending' END,
+            next_attempt_at = now() + make_interval(secs => $7)
+        WHERE id = $1",
+        delivery.id,
+        attempts,
+        response_status,
+        error,
+        delivered,
+        settings.max_attempts,
+        retry_delay,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Posts a delivery once. Returns whether the receiver accepted it, the response status and
+/// the error to keep for the attempt. Redirects are not followed and count as failures.
+async fn send(
+    settings: &WebhookSettings,
+    client: &reqwest::Client,
+    delivery: &DueDelivery,
+    secret: &str,
+) -> (bool, Option<i32>, Option<String>) {
+    let url = match Url::parse(&delivery.url) {
+        Ok(url) => url,
+        Err(e) => return (false, None, Some(format!("Invalid webhook URL: {}", e))),
+    };
+    if let Err(message) = settings.check_target(&url) {
+        return (false, None, Some(message));
+    }
+    let body = json!({
+        "id": delivery.event_id,
+        "type": delivery.event_type,
+        "created_at": delivery.created_at,
+        "data": delivery.payload,
+    })
+    .to_string();
+    let timestamp = OffsetDateTime::now_utc().unix_timestamp();
+
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("X-Webhook-Event", &delivery.event_type)
+        .header("X-Webhook-Delivery", delivery.id.to_string())
+        .header("X-Webhook-Timestamp", timestamp.to_string())
+        .header("X-Webhook-Signature", signature(secret, timestamp, &body))
+        .body(body)
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => {
+            (true, Some(response.status().as_u16() as i32), None)
+        }
+        Ok(response) => {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            let error = format!(
+                "{} {}",
+                status,
+                text.chars().take(MAX_ERROR_LENGTH).collect::<String>()
+            );
+            (false, Some(status.as_u16() as i32), Some(error))
+        }
+        Err(e) => (false, None, Some(error_chain(&e))),
+    }
+}
+
+/// reqwest errors say little more than "error sending request"; the cause is in the sources.
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn settings(allow_private_targets: bool) -> WebhookSettings {
+        WebhookSettings {
+            max_attempts: 8,
+            retry_base: Duration::from_secs(30),
+            retry_max: Duration::from_secs(6 * 60 * 60),
+            timeout: Duration::from_secs(5),
+            allow_private_targets,
+        }
+    }
+
+    fn delivery(url: String) -> DueDelivery {
+        DueDelivery {
+            id: 7,
+            attempts: 0,
+            url,
+            secret: String::new(),
+            event_id: 3,
+            event_type: "appointment.created".to_string(),
+            payload: json!({ "schedule_id": 1 }),
+            created_at: "2026-10-19 08:00:00+00".to_string(),
+        }
+    }
+
+    /// Answers one request with `response` and returns the request as received.
+    async fn stub(response: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(str::to_string)
+                        })
+                        .and_then(|length| length.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                        return text;
+                    }
+                }
+                if read == 0 {
+                    return text;
+                }
+            }
+        });
+        (url, handle)
+    }
+
+    fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+        request.lines().find_map(|line| {
+            let (key, value) = line.split_once(": ")?;
+            key.eq_ignore_ascii_case(name).then_some(value)
+        })
+    }
+
+    #[test]
+    fn signature_is_hmac_of_timestamp_and_body() {
+        assert_eq!(
+            signature("secret", 1_700_000_000, r#"{"id":1}"#),
+            "sha256=3dd1b9aef568d75f6790a84bd2e5dfa1f44409eef3cbdbd3f10b837376100c11"
+        );
+        assert_ne!(
+            signature("secret", 1_700_000_001, r#"{"id":1}"#),
+            signature("secret", 1_700_000_000, r#"{"id":1}"#)
+        );
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_maximum() {
+        let settings = settings(false);
+        assert_eq!(settings.retry_delay(1), Duration::from_secs(30));
+        assert_eq!(settings.retry_delay(2), Duration::from_secs(60));
+        assert_eq!(settings.retry_delay(5), Duration::from_secs(480));
+        assert_eq!(settings.retry_delay(10), Duration::from_secs(15360));
+        assert_eq!(settings.retry_delay(11), settings.retry_max);
+        assert_eq!(settings.retry_delay(1000), settings.retry_max);
+        assert_eq!(settings.retry_delay(0), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn validate_url_rejects_internal_hosts() {
+        let internal = settings(true);
+        assert!(internal.validate_url("http://127.0.0.1:9000/a").is_ok());
+
+        let settings = settings(false);
+        assert!(settings.validate_url("https://hooks.example.com/a").is_ok());
+        assert!(settings.validate_url("http://93.184.216.34/a").is_ok());
+        for url in [
+            "ftp://hooks.example.com/a",
+            "http://localhost:8080/a",
+            "http://api.localhost/a",
+            "http://127.0.0.1/a",
+            "http://10.1.2.3/a",
+            "http://172.16.0.1/a",
+            "http://192.168.1.1/a",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/a",
+            "http://0.0.0.0/a",
+            "http://[::1]/a",
+            "http://[fd00::1]/a",
+            "http://[fe80::1]/a",
+            "http://[::ffff:127.0.0.1]/a",
+        ] {
+            assert!(settings.validate_url(url).is_err(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn resolver_skips_internal_addresses() {
+        let error = PublicResolver
+            .resolve("localhost".parse().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "localhost has no public address");
+    }
+
+    #[tokio::test]
+    async fn send_posts_a_signed_event() {
+        let settings = settings(true);
+        let (url, request) = stub("HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n").await;
+        let delivery = delivery(url);
+
+        let result = send(&settings, &settings.client().unwrap(), &delivery, "secret").await;
+        assert_eq!(result, (true, Some(204), None));
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1"));
+        assert_eq!(
+            header(&request, "X-Webhook-Event"),
+            Some("appointment.created")
+        );
+        assert_eq!(header(&request, "X-Webhook-Delivery"), Some("7"));
+        let timestamp: i64 = header(&request, "X-Webhook-Timestamp")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = request.split_once("\r\n\r\n").unwrap().1;
+        assert_eq!(
+            header(&request, "X-Webhook-Signature"),
+            Some(signature("secret", timestamp, body).as_str())
+        );
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["id"], 3);
+        assert_eq!(body["type"], "appointment.created");
+        assert_eq!(body["data"]["schedule_id"], 1);
+    }
+
+    #[tokio::test]
+    async fn send_does_not_follow_redirects() {
+        let settings = settings(true);
+        let (url, request) = stub(
+            "HTTP/1.1 302 Found\r\nlocation: http://169.254.169.254/\r\ncontent-length: 0\r\n\r\n",
+        )
+        .await;
+
+        let (delivered, status, error) = send(
+            &settings,
+            &settings.client().unwrap(),
+            &delivery(url),
+            "secret",
+        )
+        .await;
+        request.await.unwrap();
+        assert!(!delivered);
+        assert_eq!(status, Some(302));
+        assert!(error.unwrap().starts_with("302 Found"));
+    }
+
+    #[tokio::test]
+    async fn send_refuses_internal_targets() {
+        let settings = settings(false);
+        let (url, _request) = stub("HTTP/1.1 204 No Content\r\n\r\n").await;
+
+        let (delivered, status, error) = send(
+            &settings,
+            &settings.client().unwrap(),
+            &delivery(url),
+            "secret",
+        )
+        .await;
+        assert!(!delivered);
+        assert_eq!(status, None);
+        assert!(error.unwrap().ends_with("must point to a public host"));
+    }
+}